@@ -0,0 +1,132 @@
+use crate::{Algorithm, FORMAT_VERSION};
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong reading or writing a cmprs file
+#[derive(Debug)]
+pub enum CmprsError {
+    Io(io::Error),
+    /// The input isn't a cmprs-compressed file
+    MagicNotFound,
+    /// The decompressed payload doesn't match the stored SHA256
+    HashMismatch {
+        expected: String,
+        actual: String,
+    },
+    /// Written by a newer cmprs than this one
+    UnsupportedVersion(u8),
+    /// The payload was compressed with an algorithm this build doesn't know
+    UnsupportedAlgorithm(u8),
+    /// A known algorithm this build was compiled without
+    AlgorithmNotCompiled(Algorithm),
+    /// The bytes between the magic and the payload don't make sense
+    Malformed(String),
+    /// The payload couldn't be decompressed
+    Decode(io::Error),
+    /// The payload decompressed to a different length than the header records
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    /// The file ends before the payload the header records, like after an
+    /// interrupted download
+    Truncated {
+        expected: u64,
+        actual: u64,
+    },
+    /// The payload decompresses to more than the caller is willing to hold
+    TooLarge {
+        limit: u64,
+    },
+    /// Pack options that can't be used together or are out of range
+    InvalidOptions(String),
+}
+
+impl CmprsError {
+    /// Process exit code for this error, the same in cmprs and dcmprs:
+    ///
+    /// * 1: I/O error
+    /// * 2: invalid options
+    /// * 3: not a cmprs file
+    /// * 4: malformed header, or unsupported version or algorithm
+    /// * 5: corrupted payload
+    /// * 6: payload the decoder rejects
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            CmprsError::Io(_) => 1,
+            CmprsError::InvalidOptions(_) => 2,
+            CmprsError::MagicNotFound => 3,
+            CmprsError::Malformed(_)
+            | CmprsError::UnsupportedVersion(_)
+            | CmprsError::UnsupportedAlgorithm(_)
+            | CmprsError::AlgorithmNotCompiled(_) => 4,
+            CmprsError::HashMismatch { .. }
+            | CmprsError::SizeMismatch { .. }
+            | CmprsError::Truncated { .. }
+            | CmprsError::TooLarge { .. } => 5,
+            CmprsError::Decode(_) => 6,
+        }
+    }
+}
+
+impl fmt::Display for CmprsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CmprsError::Io(err) => write!(f, "{err}"),
+            CmprsError::MagicNotFound => {
+                write!(
+                    f,
+                    "no magic header found - this is not a cmprs-compressed file"
+                )
+            }
+            CmprsError::HashMismatch { expected, actual } => {
+                write!(f, "SHA256 mismatch: expected {expected}, got {actual}")
+            }
+            CmprsError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported format version {version} (this cmprs supports up to {FORMAT_VERSION})"
+            ),
+            CmprsError::UnsupportedAlgorithm(id) => {
+                write!(f, "unsupported compression algorithm id {id}")
+            }
+            CmprsError::AlgorithmNotCompiled(algorithm) => write!(
+                f,
+                "{0} support isn't compiled into this build (enable its `{0}` cargo feature)",
+                algorithm.name()
+            ),
+            CmprsError::Malformed(message) => write!(f, "malformed cmprs header: {message}"),
+            CmprsError::Decode(err) => write!(
+                f,
+                "failed to decompress payload: {err}; the binary may be corrupted or built \
+                 with an incompatible cmprs version"
+            ),
+            CmprsError::SizeMismatch { expected, actual } => write!(
+                f,
+                "decompressed size mismatch: expected {expected}, got {actual}"
+            ),
+            CmprsError::Truncated { expected, actual } => write!(
+                f,
+                "binary appears truncated (expected {expected} bytes, have {actual})"
+            ),
+            CmprsError::TooLarge { limit } => {
+                write!(f, "decompressed payload exceeds the limit of {limit} bytes")
+            }
+            CmprsError::InvalidOptions(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for CmprsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CmprsError::Io(err) | CmprsError::Decode(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CmprsError {
+    fn from(err: io::Error) -> Self {
+        CmprsError::Io(err)
+    }
+}
@@ -0,0 +1,192 @@
+use crate::CmprsError;
+
+/// A reversible transform applied to the payload before it's compressed, to
+/// make it compress better, and undone after it's decompressed. The branch
+/// filters are the ones xz calls BCJ: they turn the relative targets of calls
+/// and jumps into absolute ones, which repeat far more often in a program.
+///
+/// ```
+/// use cmprs_format::Filter;
+///
+/// // x86 calls, each followed by an arm64 BL
+/// let original: Vec<u8> = (0..4096u32)
+///     .flat_map(|i| [0xe8, 0x10, 0x00, 0x00, 0x00, i as u8, 0x00, 0x94])
+///     .collect();
+/// for filter in [Filter::X86, Filter::Arm64, Filter::Delta { distance: 4 }] {
+///     let mut data = original.clone();
+///     filter.encode(&mut data);
+///     assert_ne!(data, original);
+///     filter.decode(&mut data);
+///     assert_eq!(data, original);
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    /// x86 and x86_64 CALL and JMP targets
+    X86,
+    /// arm64 BL and ADRP targets
+    Arm64,
+    /// Each byte as its difference from the one `distance` bytes before it,
+    /// for tables of fixed-size records
+    Delta { distance: u8 },
+}
+
+impl Filter {
+    /// The header field's value: the filter's id, then its parameter if it has one
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        match self {
+            Filter::X86 => vec![0],
+            Filter::Arm64 => vec![1],
+            Filter::Delta { distance } => vec![2, distance],
+        }
+    }
+
+    /// Filters added by newer cmprs versions can't be undone, so they fail
+    pub(crate) fn from_bytes(value: &[u8]) -> Result<Filter, CmprsError> {
+        match value {
+            [0] => Ok(Filter::X86),
+            [1] => Ok(Filter::Arm64),
+            [2, distance] if *distance > 0 => Ok(Filter::Delta {
+                distance: *distance,
+            }),
+            [id, ..] => Err(CmprsError::Malformed(format!(
+                "unsupported payload filter id {id}, from a newer cmprs"
+            ))),
+            [] => Err(CmprsError::Malformed("empty filter field".to_string())),
+        }
+    }
+
+    pub fn name(self) -> String {
+        match self {
+            Filter::X86 => "x86".to_string(),
+            Filter::Arm64 => "arm64".to_string(),
+            Filter::Delta { distance } => format!("delta:{distance}"),
+        }
+    }
+
+    /// Apply the filter to `data`, before compressing it
+    pub fn encode(self, data: &mut [u8]) {
+        match self {
+            Filter::X86 => x86(data, true),
+            Filter::Arm64 => arm64(data, true),
+            Filter::Delta { distance } => {
+                for i in (usize::from(distance)..data.len()).rev() {
+                    data[i] = data[i].wrapping_sub(data[i - usize::from(distance)]);
+                }
+            }
+        }
+    }
+
+    /// Undo the filter on `data`, after decompressing it
+    pub fn decode(self, data: &mut [u8]) {
+        match self {
+            Filter::X86 => x86(data, false),
+            Filter::Arm64 => arm64(data, false),
+            Filter::Delta { distance } => {
+                for i in usize::from(distance)..data.len() {
+                    data[i] = data[i].wrapping_add(data[i - usize::from(distance)]);
+                }
+            }
+        }
+    }
+}
+
+/// Whether `byte` is the top byte of a small positive or negative offset
+fn is_offset_top(byte: u8) -> bool {
+    byte == 0x00 || byte == 0xff
+}
+
+/// xz's x86 BCJ filter over all of `data`, starting at position 0
+fn x86(data: &mut [u8], encode: bool) {
+    const ALLOWED: [bool; 8] = [true, true, true, false, true, false, false, false];
+    const BIT_NUMBER: [u32; 8] = [0, 1, 2, 2, 3, 3, 3, 3];
+
+    if data.len() < 5 {
+        return;
+    }
+    let mut prev_mask = 0u32;
+    let mut prev_pos = 0u32.wrapping_sub(5);
+    let mut pos = 0;
+    while pos <= data.len() - 5 {
+        if data[pos] != 0xe8 && data[pos] != 0xe9 {
+            pos += 1;
+            continue;
+        }
+        let offset = (pos as u32).wrapping_sub(prev_pos);
+        prev_pos = pos as u32;
+        if offset > 5 {
+            prev_mask = 0;
+        } else {
+            for _ in 0..offset {
+                prev_mask &= 0x77;
+                prev_mask <<= 1;
+            }
+        }
+
+        let top = data[pos + 4];
+        if is_offset_top(top) && ALLOWED[((prev_mask >> 1) & 7) as usize] && (prev_mask >> 1) < 0x10
+        {
+            let mut src = u32::from_le_bytes([data[pos + 1], data[pos + 2], data[pos + 3], top]);
+            let mut dest;
+            loop {
+                // Offsets are relative to the end of the 5 byte instruction
+                let next = (pos as u32).wrapping_add(5);
+                dest = if encode {
+                    src.wrapping_add(next)
+                } else {
+                    src.wrapping_sub(next)
+                };
+                if prev_mask == 0 {
+                    break;
+                }
+                let bit = BIT_NUMBER[(prev_mask >> 1) as usize];
+                if !is_offset_top((dest >> (24 - bit * 8)) as u8) {
+                    break;
+                }
+                src = dest ^ (u32::MAX >> (bit * 8));
+            }
+            let bytes = dest.to_le_bytes();
+            data[pos + 1..pos + 4].copy_from_slice(&bytes[..3]);
+            data[pos + 4] = !(((dest >> 24) & 1).wrapping_sub(1)) as u8;
+            pos += 5;
+            prev_mask = 0;
+        } else {
+            pos += 1;
+            prev_mask |= 1;
+            if is_offset_top(top) {
+                prev_mask |= 0x10;
+            }
+        }
+    }
+}
+
+/// xz's arm64 BCJ filter over all of `data`, starting at position 0
+fn arm64(data: &mut [u8], encode: bool) {
+    for (index, word) in data.chunks_exact_mut(4).enumerate() {
+        let pc = (index * 4) as u32;
+        let instruction = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+        let filtered = if instruction >> 26 == 0x25 {
+            // BL, with a 26 bit word offset
+            let pc = pc >> 2;
+            let pc = if encode { pc } else { 0u32.wrapping_sub(pc) };
+            0x9400_0000 | (instruction.wrapping_add(pc) & 0x03ff_ffff)
+        } else if instruction & 0x9f00_0000 == 0x9000_0000 {
+            // ADRP, with a 21 bit page offset, of which only ±512 MiB are
+            // converted so the ones further off are left alone both ways
+            let src = ((instruction >> 29) & 3) | ((instruction >> 3) & 0x001f_fffc);
+            if src.wrapping_add(0x0002_0000) & 0x001c_0000 != 0 {
+                continue;
+            }
+            let pc = pc >> 12;
+            let pc = if encode { pc } else { 0u32.wrapping_sub(pc) };
+            let dest = src.wrapping_add(pc);
+            (instruction & 0x9000_001f)
+                | ((dest & 3) << 29)
+                | ((dest & 0x0003_fffc) << 3)
+                | (0u32.wrapping_sub(dest & 0x0002_0000) & 0x00e0_0000)
+        } else {
+            continue;
+        };
+        word.copy_from_slice(&filtered.to_le_bytes());
+    }
+}
@@ -0,0 +1,1128 @@
+//! The layout of a cmprs self-extractor, shared by the `cmprs` packer and the
+//! `dcmprs` stub so the two can never disagree on it.
+//!
+//! Every integer in the format is little-endian whatever the host, so a file
+//! packed on one machine unpacks on any other.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+pub use error::CmprsError;
+pub use filter::Filter;
+
+mod error;
+mod filter;
+
+// Custom magic header to mark the boundary between dcmprs executable and compressed data
+// Using a unique 16-byte sequence that's unlikely to appear in binaries
+pub const MAGIC_HEADER: &[u8; 16] = b"DCMPRS_DATA_HERE";
+
+// Separator following the magic header. The byte after it is the format version,
+// except for the original layout which used a third ';' and had no version byte.
+pub const SEPARATOR: &[u8; 2] = b";;";
+const LEGACY_VERSION_BYTE: u8 = b';';
+
+// Version 2 added the header, version 3 a checksum in front of its fields
+pub const FORMAT_VERSION: u8 = 3;
+
+pub const SHA256_LEN: usize = 32;
+
+/// Marks the version dcmprs embeds in its own binary, followed by
+/// `MAJOR.MINOR.PATCH` and a NUL. Stubs built before it was added don't have it.
+pub const STUB_VERSION_TAG: &[u8] = b"DCMPRS_VERSION=";
+
+/// The extended attribute `setcap` stores file capabilities in. Restoring it
+/// grants the extracted program privileges, so it's only packed with
+/// --preserve-caps, never with --preserve-xattr alone.
+pub const CAPABILITY_XATTR: &str = "security.capability";
+
+// Header field tags. Every field is stored as [tag u8][len u32 LE][value]
+// so readers can skip fields they don't know about.
+const TAG_MODE: u8 = 1;
+const TAG_XATTRS: u8 = 2;
+const TAG_NAME: u8 = 3;
+const TAG_LAUNCHER: u8 = 4;
+const TAG_ALGORITHM: u8 = 5;
+const TAG_OPTIONS: u8 = 6;
+const TAG_ORIGINAL_SIZE: u8 = 7;
+const TAG_BUNDLE: u8 = 8;
+const TAG_MTIME: u8 = 9;
+const TAG_MARKER: u8 = 10;
+const TAG_BUNDLE_INDEX: u8 = 11;
+const TAG_DICTIONARY: u8 = 12;
+const TAG_CONTENT_TYPE: u8 = 13;
+const TAG_ENTRY_ARGS: u8 = 14;
+const TAG_PACKER_VERSION: u8 = 15;
+const TAG_STRIP_ENV: u8 = 16;
+const TAG_PAYLOAD_SIZE: u8 = 17;
+const TAG_STUB_SHA256: u8 = 18;
+const TAG_SUBCOMMANDS: u8 = 19;
+const TAG_ENV: u8 = 20;
+const TAG_VERIFY_POLICY: u8 = 21;
+const TAG_INTERPRETER: u8 = 22;
+const TAG_MIN_LOADER_VERSION: u8 = 23;
+const TAG_FILTER: u8 = 24;
+const TAG_SOURCE_PATH: u8 = 25;
+const TAG_COMMENT: u8 = 26;
+
+// Bits of the TAG_OPTIONS field
+const OPTION_NO_STUB_FLAGS: u32 = 1 << 0;
+const OPTION_SPLIT_PAYLOAD: u32 = 1 << 1;
+const OPTION_NEEDS_ROOT: u32 = 1 << 2;
+const OPTION_ENTRY_ONLY: u32 = 1 << 3;
+const OPTION_REDUNDANT: u32 = 1 << 4;
+const OPTION_ARGFILES: u32 = 1 << 5;
+const OPTION_VERIFY_STUB: u32 = 1 << 6;
+
+/// How the payload is compressed. Files without an algorithm field use zstd.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    #[default]
+    Zstd,
+    /// Stored uncompressed
+    None,
+    /// LZMA2 in an xz container, slower but smaller than zstd
+    Xz,
+    /// A standard gzip stream, one member per frame, for tools that only know gzip
+    Gzip,
+}
+
+impl Algorithm {
+    /// Every algorithm, in the order of their ids
+    pub const ALL: [Algorithm; 4] = [
+        Algorithm::Zstd,
+        Algorithm::None,
+        Algorithm::Xz,
+        Algorithm::Gzip,
+    ];
+
+    /// The byte identifying the algorithm in the header
+    pub fn id(self) -> u8 {
+        match self {
+            Algorithm::Zstd => 0,
+            Algorithm::None => 1,
+            Algorithm::Xz => 2,
+            Algorithm::Gzip => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Algorithm, CmprsError> {
+        match id {
+            0 => Ok(Algorithm::Zstd),
+            1 => Ok(Algorithm::None),
+            2 => Ok(Algorithm::Xz),
+            3 => Ok(Algorithm::Gzip),
+            _ => Err(CmprsError::UnsupportedAlgorithm(id)),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Zstd => "zstd",
+            Algorithm::None => "none",
+            Algorithm::Xz => "xz",
+            Algorithm::Gzip => "gzip",
+        }
+    }
+}
+
+/// What the payload is, when it isn't a native executable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentType {
+    /// A WebAssembly module, run through the header's launcher (a WASI runtime)
+    Wasm,
+    /// A script starting with a `#!` line naming the header's interpreter
+    Script,
+    /// Anything else that isn't a program the kernel can run, packed without
+    /// a launcher, which dcmprs refuses to exec
+    Data,
+}
+
+impl ContentType {
+    fn id(self) -> u8 {
+        match self {
+            ContentType::Wasm => 0,
+            ContentType::Script => 1,
+            ContentType::Data => 2,
+        }
+    }
+
+    /// `None` for content types added by newer cmprs versions
+    fn from_id(id: u8) -> Option<ContentType> {
+        match id {
+            0 => Some(ContentType::Wasm),
+            1 => Some(ContentType::Script),
+            2 => Some(ContentType::Data),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ContentType::Wasm => "wasm",
+            ContentType::Script => "script",
+            ContentType::Data => "data",
+        }
+    }
+}
+
+/// When dcmprs checks the decompressed payload against its SHA256. The
+/// environment can ask for a stricter policy than the packed one, never a
+/// weaker one, so later variants are stricter.
+///
+/// ```
+/// use cmprs_format::{Header, VerifyPolicy, FORMAT_VERSION};
+///
+/// let header = Header { verify_policy: VerifyPolicy::Once, ..Header::default() };
+/// let bytes = header.to_bytes(&[0; 32]);
+/// let (parsed, _) = Header::parse(&bytes, FORMAT_VERSION, &[0; 32]).unwrap();
+/// assert_eq!(parsed.verify_policy, VerifyPolicy::Once);
+/// assert!(VerifyPolicy::Always > VerifyPolicy::Once);
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VerifyPolicy {
+    /// Only when DCMPRS_VERIFY_ONCE asks for it
+    #[default]
+    Never,
+    /// On the first run, recording the verified hash next to the packed file
+    Once,
+    /// On every run
+    Always,
+}
+
+impl VerifyPolicy {
+    fn id(self) -> u8 {
+        match self {
+            VerifyPolicy::Never => 0,
+            VerifyPolicy::Once => 1,
+            VerifyPolicy::Always => 2,
+        }
+    }
+
+    /// Policies added by newer cmprs versions are taken as [`VerifyPolicy::Always`],
+    /// so an older stub never checks less than the file asks for
+    fn from_id(id: u8) -> VerifyPolicy {
+        match id {
+            0 => VerifyPolicy::Never,
+            1 => VerifyPolicy::Once,
+            _ => VerifyPolicy::Always,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            VerifyPolicy::Never => "never",
+            VerifyPolicy::Once => "once",
+            VerifyPolicy::Always => "always",
+        }
+    }
+}
+
+/// A directory packed with `cmprs <dir> --entry <path>`. Its payload is the
+/// contents of `files`, one after the other.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Bundle {
+    /// Program to run, relative to the bundle root. Empty for a multi-tool
+    /// without a default program, which has only its `subcommands`.
+    pub entry: OsString,
+    pub files: Vec<BundleFile>,
+    /// Compressed size of each file, which is compressed as a frame of its own
+    /// so it can be decompressed without the others. Empty for bundles packed
+    /// as a single frame, before the index existed.
+    pub frames: Vec<u64>,
+    /// Names a first argument can have to run another program than the
+    /// entry, packed with `--subcommand name=path`, with those programs' paths
+    pub subcommands: Vec<(String, OsString)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundleFile {
+    /// Path relative to the bundle root, with `/` separators
+    pub path: OsString,
+    pub size: u64,
+    /// Unix permission bits
+    pub mode: u32,
+}
+
+impl Bundle {
+    /// The program to run for arguments starting with `first_arg`, and
+    /// whether `first_arg` named it as a subcommand, so it isn't passed on.
+    /// None when it names no subcommand and there's no entry to fall back on.
+    ///
+    /// ```
+    /// use cmprs_format::Bundle;
+    /// use std::ffi::OsStr;
+    ///
+    /// let mut bundle = Bundle {
+    ///     entry: "bin/help".into(),
+    ///     subcommands: vec![("build".to_string(), "bin/build".into())],
+    ///     ..Bundle::default()
+    /// };
+    /// assert_eq!(bundle.select(Some("build")), Some((OsStr::new("bin/build"), true)));
+    /// assert_eq!(bundle.select(Some("bulid")), Some((OsStr::new("bin/help"), false)));
+    /// assert_eq!(bundle.select(None), Some((OsStr::new("bin/help"), false)));
+    ///
+    /// bundle.entry.clear();
+    /// assert_eq!(bundle.select(Some("bulid")), None);
+    /// ```
+    pub fn select(&self, first_arg: Option<&str>) -> Option<(&OsStr, bool)> {
+        let subcommand = self
+            .subcommands
+            .iter()
+            .find(|(name, _)| Some(name.as_str()) == first_arg);
+        match subcommand {
+            Some((_, path)) => Some((path, true)),
+            None if self.entry.is_empty() => None,
+            None => Some((&self.entry, false)),
+        }
+    }
+
+    /// Where the frame of the file at `path` is in the compressed payload,
+    /// and the file itself, or None without an index
+    pub fn frame_of(&self, path: &OsStr) -> Option<(Range<u64>, &BundleFile)> {
+        if self.frames.len() != self.files.len() {
+            return None;
+        }
+        let position = self.files.iter().position(|file| file.path == path)?;
+        let start = self.frames[..position].iter().sum();
+        Some((start..start + self.frames[position], &self.files[position]))
+    }
+
+    /// Write the files stored in `payload` below `dir`, creating it if needed
+    pub fn unpack(&self, payload: &[u8], dir: &Path) -> Result<(), CmprsError> {
+        let total = self
+            .files
+            .iter()
+            .try_fold(0u64, |total, file| total.checked_add(file.size));
+        if total != Some(payload.len() as u64) {
+            return Err(malformed(&format!(
+                "bundle file sizes don't add up to the {} byte payload",
+                payload.len()
+            )));
+        }
+
+        fs::create_dir_all(dir)?;
+        let mut pos = 0;
+        for file in &self.files {
+            let end = pos + file.size as usize;
+            file.unpack(&payload[pos..end], dir)?;
+            pos = end;
+        }
+        Ok(())
+    }
+}
+
+impl BundleFile {
+    /// Write `contents` to this file's path below `dir`, with its permissions
+    pub fn unpack(&self, contents: &[u8], dir: &Path) -> Result<(), CmprsError> {
+        let relative = Path::new(&self.path);
+        if !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(malformed(&format!(
+                "bundle path {} leaves the bundle directory",
+                relative.display()
+            )));
+        }
+        let path = dir.join(relative);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, contents)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(self.mode))?;
+        Ok(())
+    }
+}
+
+/// Metadata stored between the SHA256 hash and the compressed payload
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// Printable ASCII for people looking at the file with `strings`, like
+    /// what the tool is and when it was built. Loaders ignore it.
+    pub comment: Option<String>,
+    /// Unix permission bits of the original file
+    pub mode: Option<u32>,
+    /// Extended attributes of the original file, when packed with
+    /// --preserve-xattr, and its capabilities with --preserve-caps
+    pub xattrs: Vec<(OsString, Vec<u8>)>,
+    /// File name of the original file, after resolving symlinks
+    pub name: Option<OsString>,
+    /// Absolute path the input was packed from, when packed with
+    /// --record-path, after any --path-prefix-map rewrites
+    pub source_path: Option<OsString>,
+    /// Command (program and leading arguments) that runs the payload, which is
+    /// passed as the next argument. Empty when the payload is executed directly.
+    pub launcher: Vec<OsString>,
+    /// Set when the payload isn't a native executable, like a WebAssembly module
+    pub content_type: Option<ContentType>,
+    /// The interpreter named by a script's `#!` line, and the argument it
+    /// passes it if any, to run the script with where it can't be exec'd
+    pub interpreter: Vec<OsString>,
+    /// Arguments passed to the program ahead of the ones it's run with
+    pub entry_args: Vec<OsString>,
+    /// Patterns of environment variables removed before the program runs,
+    /// where `*` matches any run of characters
+    pub strip_env: Vec<String>,
+    /// Environment variables the program gets unless it's run with them set
+    pub env: Vec<(OsString, OsString)>,
+    /// When the payload is checked against its SHA256 at launch
+    pub verify_policy: VerifyPolicy,
+    pub algorithm: Algorithm,
+    /// Applied to the payload before compressing it, so undone after decompressing it
+    pub filter: Option<Filter>,
+    /// Forward `--dcmprs-*` arguments to the program instead of handling them in the stub
+    pub no_stub_flags: bool,
+    /// The payload isn't appended to the file but stored next to it, at [`split_payload_path`]
+    pub split_payload: bool,
+    /// The program only works as root, so dcmprs checks before running it
+    pub needs_root: bool,
+    /// Only extract the bundle's entry at launch, from its frame, and leave
+    /// the other files in the payload
+    pub entry_only: bool,
+    /// The payload is stored twice, one copy after the other, so that a
+    /// corrupted copy can be recovered from the other one
+    pub redundant: bool,
+    /// Expand `@file` arguments into the lines of the file before passing
+    /// them to the program
+    pub argfiles: bool,
+    /// Refuse to run unless the stub in front of the header hashes to
+    /// `stub_sha256`
+    pub verify_stub: bool,
+    /// Length of the decompressed payload
+    pub original_size: Option<u64>,
+    /// Length of the stored payload, both copies of a redundant one, so a
+    /// file cut short in transfer is caught before decompressing it
+    pub payload_size: Option<u64>,
+    /// Set when a directory was packed instead of a single file
+    pub bundle: Option<Bundle>,
+    /// Modification time of the original, in seconds since the Unix epoch
+    pub mtime: Option<u64>,
+    /// Text identifying the file as cmprs-packed to scanners. dcmprs ignores it.
+    pub marker: Option<String>,
+    /// zstd dictionary trained on the input, which the payload needs to decompress
+    pub dictionary: Option<Vec<u8>>,
+    /// Version of cmprs that packed the file, for triaging reports about it
+    pub packer_version: Option<String>,
+    /// Oldest dcmprs that can run the payload, as `MAJOR.MINOR.PATCH`, for
+    /// payloads that rely on something newer stubs added. Stubs from before
+    /// the field was added don't know to check it.
+    pub min_loader_version: Option<String>,
+    /// SHA256 of the stub the file was packed with, to tell a swapped or
+    /// tampered loader from the original. Not recorded for signed outputs,
+    /// since signing changes the stub after the header is written.
+    pub stub_sha256: Option<[u8; SHA256_LEN]>,
+}
+
+impl Header {
+    /// Serialize as `[len u32 LE][checksum u32 LE][fields]`, with the
+    /// checksum covering `sha256` as well so a shifted offset is caught too
+    pub fn to_bytes(&self, sha256: &[u8]) -> Vec<u8> {
+        self.to_version_bytes(FORMAT_VERSION, sha256)
+    }
+
+    /// Serialize for format `version`, which from 3 on has the checksum and
+    /// before it is just `[len u32 LE][fields]`. Version 1 has no header, so
+    /// it's written like version 2.
+    ///
+    /// ```
+    /// use cmprs_format::Header;
+    ///
+    /// let header = Header { mode: Some(0o755), ..Header::default() };
+    /// let bytes = header.to_version_bytes(2, &[0; 32]);
+    /// assert_eq!(bytes.len() + 4, header.to_bytes(&[0; 32]).len());
+    /// let (parsed, len) = Header::parse(&bytes, 2, &[0; 32]).unwrap();
+    /// assert_eq!((parsed.mode, len), (Some(0o755), bytes.len()));
+    /// ```
+    pub fn to_version_bytes(&self, version: u8, sha256: &[u8]) -> Vec<u8> {
+        let mut fields = Vec::new();
+        // First, and on a line of its own, so it's the first text `strings`
+        // finds after the magic, without the length in front of it
+        if let Some(comment) = &self.comment {
+            push_field(
+                &mut fields,
+                TAG_COMMENT,
+                format!("\n{comment}\n").as_bytes(),
+            );
+        }
+        if let Some(mode) = self.mode {
+            push_field(&mut fields, TAG_MODE, &mode.to_le_bytes());
+        }
+        if !self.xattrs.is_empty() {
+            let mut value = Vec::new();
+            for (name, attr) in &self.xattrs {
+                push_bytes(&mut value, name.as_bytes());
+                push_bytes(&mut value, attr);
+            }
+            push_field(&mut fields, TAG_XATTRS, &value);
+        }
+        if let Some(name) = &self.name {
+            push_field(&mut fields, TAG_NAME, name.as_bytes());
+        }
+        if let Some(source_path) = &self.source_path {
+            push_field(&mut fields, TAG_SOURCE_PATH, source_path.as_bytes());
+        }
+        if !self.launcher.is_empty() {
+            let mut value = Vec::new();
+            for arg in &self.launcher {
+                push_bytes(&mut value, arg.as_bytes());
+            }
+            push_field(&mut fields, TAG_LAUNCHER, &value);
+        }
+        if let Some(content_type) = self.content_type {
+            push_field(&mut fields, TAG_CONTENT_TYPE, &[content_type.id()]);
+        }
+        if !self.interpreter.is_empty() {
+            let mut value = Vec::new();
+            for arg in &self.interpreter {
+                push_bytes(&mut value, arg.as_bytes());
+            }
+            push_field(&mut fields, TAG_INTERPRETER, &value);
+        }
+        if !self.entry_args.is_empty() {
+            let mut value = Vec::new();
+            for arg in &self.entry_args {
+                push_bytes(&mut value, arg.as_bytes());
+            }
+            push_field(&mut fields, TAG_ENTRY_ARGS, &value);
+        }
+        if !self.strip_env.is_empty() {
+            let mut value = Vec::new();
+            for pattern in &self.strip_env {
+                push_bytes(&mut value, pattern.as_bytes());
+            }
+            push_field(&mut fields, TAG_STRIP_ENV, &value);
+        }
+        if !self.env.is_empty() {
+            let mut value = Vec::new();
+            for (name, var) in &self.env {
+                push_bytes(&mut value, name.as_bytes());
+                push_bytes(&mut value, var.as_bytes());
+            }
+            push_field(&mut fields, TAG_ENV, &value);
+        }
+        if let Some(filter) = self.filter {
+            push_field(&mut fields, TAG_FILTER, &filter.to_bytes());
+        }
+        if self.verify_policy != VerifyPolicy::Never {
+            push_field(&mut fields, TAG_VERIFY_POLICY, &[self.verify_policy.id()]);
+        }
+        push_field(&mut fields, TAG_ALGORITHM, &[self.algorithm.id()]);
+        let mut options = 0;
+        if self.no_stub_flags {
+            options |= OPTION_NO_STUB_FLAGS;
+        }
+        if self.split_payload {
+            options |= OPTION_SPLIT_PAYLOAD;
+        }
+        if self.needs_root {
+            options |= OPTION_NEEDS_ROOT;
+        }
+        if self.entry_only {
+            options |= OPTION_ENTRY_ONLY;
+        }
+        if self.redundant {
+            options |= OPTION_REDUNDANT;
+        }
+        if self.argfiles {
+            options |= OPTION_ARGFILES;
+        }
+        if self.verify_stub {
+            options |= OPTION_VERIFY_STUB;
+        }
+        if options != 0 {
+            push_field(&mut fields, TAG_OPTIONS, &options.to_le_bytes());
+        }
+        if let Some(original_size) = self.original_size {
+            push_field(&mut fields, TAG_ORIGINAL_SIZE, &original_size.to_le_bytes());
+        }
+        if let Some(stub_sha256) = &self.stub_sha256 {
+            push_field(&mut fields, TAG_STUB_SHA256, stub_sha256);
+        }
+        if let Some(payload_size) = self.payload_size {
+            push_field(&mut fields, TAG_PAYLOAD_SIZE, &payload_size.to_le_bytes());
+        }
+        if let Some(mtime) = self.mtime {
+            push_field(&mut fields, TAG_MTIME, &mtime.to_le_bytes());
+        }
+        if let Some(marker) = &self.marker {
+            push_field(&mut fields, TAG_MARKER, marker.as_bytes());
+        }
+        if let Some(dictionary) = &self.dictionary {
+            push_field(&mut fields, TAG_DICTIONARY, dictionary);
+        }
+        if let Some(packer_version) = &self.packer_version {
+            push_field(&mut fields, TAG_PACKER_VERSION, packer_version.as_bytes());
+        }
+        if let Some(min_loader_version) = &self.min_loader_version {
+            push_field(
+                &mut fields,
+                TAG_MIN_LOADER_VERSION,
+                min_loader_version.as_bytes(),
+            );
+        }
+        if let Some(bundle) = &self.bundle {
+            let mut value = Vec::new();
+            push_bytes(&mut value, bundle.entry.as_bytes());
+            for file in &bundle.files {
+                push_bytes(&mut value, file.path.as_bytes());
+                value.extend_from_slice(&file.size.to_le_bytes());
+                value.extend_from_slice(&file.mode.to_le_bytes());
+            }
+            push_field(&mut fields, TAG_BUNDLE, &value);
+            if !bundle.frames.is_empty() {
+                let index: Vec<u8> = bundle
+                    .frames
+                    .iter()
+                    .flat_map(|len| len.to_le_bytes())
+                    .collect();
+                push_field(&mut fields, TAG_BUNDLE_INDEX, &index);
+            }
+            if !bundle.subcommands.is_empty() {
+                let mut value = Vec::new();
+                for (name, path) in &bundle.subcommands {
+                    push_bytes(&mut value, name.as_bytes());
+                    push_bytes(&mut value, path.as_bytes());
+                }
+                push_field(&mut fields, TAG_SUBCOMMANDS, &value);
+            }
+        }
+
+        let len = (fields.len() as u32).to_le_bytes();
+        let mut bytes = Vec::with_capacity(8 + fields.len());
+        bytes.extend_from_slice(&len);
+        if version >= 3 {
+            bytes.extend_from_slice(&checksum(sha256, &len, &fields).to_le_bytes());
+        }
+        bytes.extend_from_slice(&fields);
+        bytes
+    }
+
+    /// The payload to decompress, and with [`Header::redundant`] the copy of
+    /// it to fall back on
+    ///
+    /// ```
+    /// use cmprs_format::Header;
+    ///
+    /// let header = Header { redundant: true, ..Header::default() };
+    /// assert_eq!(header.payload_copies(b"abcabc"), (&b"abc"[..], Some(&b"abc"[..])));
+    /// assert_eq!(Header::default().payload_copies(b"abcabc"), (&b"abcabc"[..], None));
+    /// ```
+    pub fn payload_copies<'a>(&self, payload: &'a [u8]) -> (&'a [u8], Option<&'a [u8]>) {
+        if self.redundant {
+            let (primary, copy) = payload.split_at(payload.len() / 2);
+            (primary, Some(copy))
+        } else {
+            (payload, None)
+        }
+    }
+
+    /// Check that the stored payload, the `available` bytes from `offset` to
+    /// the end of its file, is all there, failing with [`CmprsError::Truncated`]
+    /// when the file was cut short
+    ///
+    /// ```
+    /// use cmprs_format::{CmprsError, Header};
+    ///
+    /// let header = Header { payload_size: Some(100), ..Header::default() };
+    /// assert!(header.check_payload_size(20, 100).is_ok());
+    /// assert!(matches!(
+    ///     header.check_payload_size(20, 60),
+    ///     Err(CmprsError::Truncated { expected: 120, actual: 80 })
+    /// ));
+    /// // Files packed before the size was recorded can't be checked
+    /// assert!(Header::default().check_payload_size(20, 60).is_ok());
+    /// ```
+    pub fn check_payload_size(&self, offset: u64, available: u64) -> Result<(), CmprsError> {
+        match self.payload_size {
+            Some(size) if available < size => Err(CmprsError::Truncated {
+                expected: offset + size,
+                actual: offset + available,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Parse the header at the start of `data`, returning it and the number of bytes consumed.
+    /// From version 3 on, the checksum over `sha256` and the header is verified first.
+    ///
+    /// Lengths and values are read as little-endian, so this hand-built
+    /// version 2 header parses the same on any host:
+    ///
+    /// ```
+    /// use cmprs_format::Header;
+    ///
+    /// let mut fields = vec![1]; // mode
+    /// fields.extend_from_slice(&4u32.to_le_bytes());
+    /// fields.extend_from_slice(&[0xed, 0x01, 0x00, 0x00]);
+    /// fields.push(7); // original size
+    /// fields.extend_from_slice(&8u32.to_le_bytes());
+    /// fields.extend_from_slice(&[0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01]);
+    /// let mut data = (fields.len() as u32).to_le_bytes().to_vec();
+    /// data.extend_from_slice(&fields);
+    ///
+    /// let (header, len) = Header::parse(&data, 2, &[0; 32]).unwrap();
+    /// assert_eq!(header.mode, Some(0o755));
+    /// assert_eq!(header.original_size, Some(0x0102_0304_0506_0708));
+    /// assert_eq!(len, data.len());
+    /// ```
+    ///
+    /// A version 3 header round-trips, and a corrupted one is rejected as
+    /// malformed rather than misread:
+    ///
+    /// ```
+    /// use cmprs_format::{CmprsError, Header};
+    ///
+    /// let sha256 = [7; 32];
+    /// let header = Header {
+    ///     mode: Some(0o755),
+    ///     original_size: Some(1 << 40),
+    ///     name: Some("tool".into()),
+    ///     ..Header::default()
+    /// };
+    /// let bytes = header.to_bytes(&sha256);
+    /// assert_eq!(Header::parse(&bytes, 3, &sha256).unwrap(), (header, bytes.len()));
+    ///
+    /// let corrupted = |offset: usize, value: u8| {
+    ///     let mut bytes = bytes.clone();
+    ///     bytes[offset] = value;
+    ///     matches!(Header::parse(&bytes, 3, &sha256), Err(CmprsError::Malformed(_)))
+    /// };
+    /// assert!(corrupted(4, bytes[4] ^ 1)); // the checksum
+    /// assert!(corrupted(0, bytes[0] - 1)); // the length, shorter
+    /// assert!(corrupted(0, bytes[0] + 1)); // the length, past the end
+    /// assert!(corrupted(bytes.len() - 1, bytes[bytes.len() - 1] ^ 0x80)); // a field byte
+    /// // The checksum covers the payload's SHA256 too
+    /// assert!(matches!(
+    ///     Header::parse(&bytes, 3, &[8; 32]),
+    ///     Err(CmprsError::Malformed(_))
+    /// ));
+    /// ```
+    pub fn parse(data: &[u8], version: u8, sha256: &[u8]) -> Result<(Header, usize), CmprsError> {
+        let len_bytes = data
+            .get(..4)
+            .ok_or_else(|| malformed("header is truncated"))?;
+        let len = read_u32(len_bytes, 0)? as usize;
+        let fields_start = if version >= 3 { 8 } else { 4 };
+        let fields = data
+            .get(fields_start..fields_start + len)
+            .ok_or_else(|| malformed("header extends past the end of the file"))?;
+
+        if version >= 3 {
+            let expected = read_u32(data, 4)?;
+            let actual = checksum(sha256, len_bytes, fields);
+            if expected != actual {
+                return Err(malformed(&format!(
+                    "checksum mismatch (expected {expected:08x}, got {actual:08x})"
+                )));
+            }
+        }
+
+        let mut header = Header::default();
+        let mut frames = Vec::new();
+        let mut subcommands = Vec::new();
+        let mut pos = 0;
+        while pos < fields.len() {
+            let tag = fields[pos];
+            let value_len = read_u32(fields, pos + 1)? as usize;
+            let value = fields
+                .get(pos + 5..pos + 5 + value_len)
+                .ok_or_else(|| malformed("header field extends past the header"))?;
+
+            match tag {
+                TAG_MODE => header.mode = Some(read_u32(value, 0)?),
+                TAG_XATTRS => {
+                    let mut attr_pos = 0;
+                    while attr_pos < value.len() {
+                        let name = read_bytes(value, &mut attr_pos)?;
+                        let attr = read_bytes(value, &mut attr_pos)?;
+                        header
+                            .xattrs
+                            .push((OsStr::from_bytes(name).to_os_string(), attr.to_vec()));
+                    }
+                }
+                TAG_NAME => header.name = Some(OsStr::from_bytes(value).to_os_string()),
+                TAG_COMMENT => {
+                    let comment = std::str::from_utf8(value)
+                        .map_err(|_| malformed("comment isn't valid UTF-8"))?;
+                    header.comment = Some(comment.trim_matches('\n').to_string());
+                }
+                TAG_SOURCE_PATH => {
+                    header.source_path = Some(OsStr::from_bytes(value).to_os_string())
+                }
+                TAG_ALGORITHM => {
+                    let id = *value
+                        .first()
+                        .ok_or_else(|| malformed("empty algorithm field"))?;
+                    header.algorithm = Algorithm::from_id(id)?;
+                }
+                TAG_FILTER => header.filter = Some(Filter::from_bytes(value)?),
+                TAG_OPTIONS => {
+                    let options = read_u32(value, 0)?;
+                    header.no_stub_flags = options & OPTION_NO_STUB_FLAGS != 0;
+                    header.split_payload = options & OPTION_SPLIT_PAYLOAD != 0;
+                    header.needs_root = options & OPTION_NEEDS_ROOT != 0;
+                    header.entry_only = options & OPTION_ENTRY_ONLY != 0;
+                    header.redundant = options & OPTION_REDUNDANT != 0;
+                    header.argfiles = options & OPTION_ARGFILES != 0;
+                    header.verify_stub = options & OPTION_VERIFY_STUB != 0;
+                }
+                TAG_ORIGINAL_SIZE => header.original_size = Some(read_u64(value, 0)?),
+                TAG_PAYLOAD_SIZE => header.payload_size = Some(read_u64(value, 0)?),
+                TAG_STUB_SHA256 => {
+                    let hash = value
+                        .try_into()
+                        .map_err(|_| malformed("stub hash isn't a SHA256"))?;
+                    header.stub_sha256 = Some(hash);
+                }
+                TAG_MTIME => header.mtime = Some(read_u64(value, 0)?),
+                TAG_MARKER => {
+                    let marker = std::str::from_utf8(value)
+                        .map_err(|_| malformed("marker isn't valid UTF-8"))?;
+                    header.marker = Some(marker.to_string());
+                }
+                TAG_BUNDLE => {
+                    let mut file_pos = 0;
+                    let entry = read_bytes(value, &mut file_pos)?;
+                    let mut bundle = Bundle {
+                        entry: OsStr::from_bytes(entry).to_os_string(),
+                        ..Bundle::default()
+                    };
+                    while file_pos < value.len() {
+                        let path = read_bytes(value, &mut file_pos)?;
+                        let size = read_u64(value, file_pos)?;
+                        let mode = read_u32(value, file_pos + 8)?;
+                        file_pos += 12;
+                        bundle.files.push(BundleFile {
+                            path: OsStr::from_bytes(path).to_os_string(),
+                            size,
+                            mode,
+                        });
+                    }
+                    header.bundle = Some(bundle);
+                }
+                TAG_DICTIONARY => header.dictionary = Some(value.to_vec()),
+                TAG_PACKER_VERSION => {
+                    let packer_version = std::str::from_utf8(value)
+                        .map_err(|_| malformed("packer version isn't valid UTF-8"))?;
+                    header.packer_version = Some(packer_version.to_string());
+                }
+                TAG_MIN_LOADER_VERSION => {
+                    let min_loader_version = std::str::from_utf8(value)
+                        .map_err(|_| malformed("minimum loader version isn't valid UTF-8"))?;
+                    header.min_loader_version = Some(min_loader_version.to_string());
+                }
+                TAG_CONTENT_TYPE => {
+                    let id = *value
+                        .first()
+                        .ok_or_else(|| malformed("empty content type field"))?;
+                    header.content_type = ContentType::from_id(id);
+                }
+                TAG_SUBCOMMANDS => {
+                    let mut subcommand_pos = 0;
+                    while subcommand_pos < value.len() {
+                        let name = std::str::from_utf8(read_bytes(value, &mut subcommand_pos)?)
+                            .map_err(|_| malformed("subcommand name isn't valid UTF-8"))?;
+                        let path = read_bytes(value, &mut subcommand_pos)?;
+                        subcommands
+                            .push((name.to_string(), OsStr::from_bytes(path).to_os_string()));
+                    }
+                }
+                TAG_BUNDLE_INDEX => {
+                    frames = (0..value.len() / 8)
+                        .map(|i| read_u64(value, i * 8))
+                        .collect::<Result<_, _>>()?;
+                }
+                TAG_LAUNCHER => {
+                    let mut arg_pos = 0;
+                    while arg_pos < value.len() {
+                        let arg = read_bytes(value, &mut arg_pos)?;
+                        header.launcher.push(OsStr::from_bytes(arg).to_os_string());
+                    }
+                }
+                TAG_INTERPRETER => {
+                    let mut arg_pos = 0;
+                    while arg_pos < value.len() {
+                        let arg = read_bytes(value, &mut arg_pos)?;
+                        header
+                            .interpreter
+                            .push(OsStr::from_bytes(arg).to_os_string());
+                    }
+                }
+                TAG_ENTRY_ARGS => {
+                    let mut arg_pos = 0;
+                    while arg_pos < value.len() {
+                        let arg = read_bytes(value, &mut arg_pos)?;
+                        header
+                            .entry_args
+                            .push(OsStr::from_bytes(arg).to_os_string());
+                    }
+                }
+                TAG_STRIP_ENV => {
+                    let mut pattern_pos = 0;
+                    while pattern_pos < value.len() {
+                        let pattern = std::str::from_utf8(read_bytes(value, &mut pattern_pos)?)
+                            .map_err(|_| malformed("environment pattern isn't valid UTF-8"))?;
+                        header.strip_env.push(pattern.to_string());
+                    }
+                }
+                TAG_ENV => {
+                    let mut var_pos = 0;
+                    while var_pos < value.len() {
+                        let name = read_bytes(value, &mut var_pos)?;
+                        let var = read_bytes(value, &mut var_pos)?;
+                        header.env.push((
+                            OsStr::from_bytes(name).to_os_string(),
+                            OsStr::from_bytes(var).to_os_string(),
+                        ));
+                    }
+                }
+                TAG_VERIFY_POLICY => {
+                    let id = *value
+                        .first()
+                        .ok_or_else(|| malformed("empty verify policy field"))?;
+                    header.verify_policy = VerifyPolicy::from_id(id);
+                }
+                // Unknown fields come from newer cmprs versions and are safe to skip
+                _ => {}
+            }
+
+            pos += 5 + value_len;
+        }
+
+        // The index may come before or after the file table it describes
+        if !frames.is_empty() {
+            match &mut header.bundle {
+                Some(bundle) if bundle.files.len() == frames.len() => bundle.frames = frames,
+                _ => return Err(malformed("bundle index doesn't match the bundle's files")),
+            }
+        }
+        if !subcommands.is_empty() {
+            match &mut header.bundle {
+                Some(bundle) => bundle.subcommands = subcommands,
+                None => return Err(malformed("subcommands without a bundle to run them from")),
+            }
+        }
+
+        Ok((header, fields_start + len))
+    }
+}
+
+/// A parsed self-extracting executable
+pub struct Packed<'a> {
+    /// Length of the dcmprs stub preceding the magic header
+    pub stub_len: usize,
+    pub version: u8,
+    pub sha256: &'a [u8],
+    pub header: Header,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Packed<'a> {
+    pub fn parse(buffer: &'a [u8]) -> Result<Packed<'a>, CmprsError> {
+        let magic_pos = find_magic_header(buffer).ok_or(CmprsError::MagicNotFound)?;
+        Packed::parse_at(buffer, magic_pos)
+    }
+
+    /// Parse with the magic header at `magic_pos` instead of searching for it.
+    /// The magic bytes themselves aren't checked, only what follows them.
+    pub fn parse_at(buffer: &'a [u8], magic_pos: usize) -> Result<Packed<'a>, CmprsError> {
+        if magic_pos >= buffer.len() {
+            return Err(malformed("magic header offset is past the end of the file"));
+        }
+
+        let version_pos = magic_pos + MAGIC_HEADER.len() + SEPARATOR.len();
+        let version = match buffer.get(version_pos) {
+            Some(&LEGACY_VERSION_BYTE) => 1,
+            Some(&version) => version,
+            None => return Err(malformed("file ends right after the magic header")),
+        };
+        if version > FORMAT_VERSION {
+            return Err(CmprsError::UnsupportedVersion(version));
+        }
+
+        let sha_start = version_pos + 1;
+        let sha256 = buffer
+            .get(sha_start..sha_start + SHA256_LEN)
+            .ok_or_else(|| malformed("no SHA256 hash found after magic header"))?;
+
+        let (header, header_len) = if version == 1 {
+            (Header::default(), 0)
+        } else {
+            Header::parse(&buffer[sha_start + SHA256_LEN..], version, sha256)?
+        };
+
+        // The payload of a file packed with --split is in another file
+        let payload_start = sha_start + SHA256_LEN + header_len;
+        let payload = &buffer[payload_start..];
+        if !header.split_payload {
+            header.check_payload_size(payload_start as u64, payload.len() as u64)?;
+        }
+
+        Ok(Packed {
+            stub_len: magic_pos,
+            version,
+            sha256,
+            header,
+            payload,
+        })
+    }
+}
+
+/// Parse a `MAJOR.MINOR.PATCH` version into numbers that compare in release
+/// order. A missing minor or patch counts as 0, and a pre-release or build
+/// suffix is ignored.
+///
+/// ```
+/// use cmprs_format::parse_version;
+///
+/// assert!(parse_version("0.10.0") > parse_version("0.9.3"));
+/// assert_eq!(parse_version("1.2"), Some([1, 2, 0]));
+/// assert_eq!(parse_version("1.2.3-rc.1"), Some([1, 2, 3]));
+/// assert_eq!(parse_version("latest"), None);
+/// ```
+pub fn parse_version(version: &str) -> Option<[u64; 3]> {
+    let release = version.split(['-', '+']).next()?;
+    let mut parts = release.split('.');
+    let mut parsed = [0; 3];
+    parsed[0] = parts.next()?.parse().ok()?;
+    for part in &mut parsed[1..] {
+        if let Some(value) = parts.next() {
+            *part = value.parse().ok()?;
+        }
+    }
+    parts.next().is_none().then_some(parsed)
+}
+
+/// Where the payload of a file packed with `--split` is stored: `<packed>.data`
+pub fn split_payload_path(packed: &Path) -> PathBuf {
+    let mut path = packed.as_os_str().to_os_string();
+    path.push(".data");
+    PathBuf::from(path)
+}
+
+/// Write everything between the stub and the SHA256 hash
+pub fn write_preamble(output: &mut impl Write) -> io::Result<usize> {
+    write_version_preamble(output, FORMAT_VERSION)
+}
+
+/// Write everything between the stub and the SHA256 hash of a file in format
+/// `version`, for converting files between versions
+pub fn write_version_preamble(output: &mut impl Write, version: u8) -> io::Result<usize> {
+    let version = if version == 1 {
+        LEGACY_VERSION_BYTE
+    } else {
+        version
+    };
+    output.write_all(MAGIC_HEADER)?;
+    output.write_all(SEPARATOR)?;
+    output.write_all(&[version])?;
+    Ok(MAGIC_HEADER.len() + SEPARATOR.len() + 1)
+}
+
+/// Look for our custom magic header
+/// The format is: [dcmprs executable][MAGIC_HEADER][;;][version][32-byte SHA256][header][compressed data]
+/// where the header is [len u32][checksum u32][fields]
+/// Search from the beginning to find the FIRST occurrence, one that has at
+/// least the version byte after it. It runs on every launch over the whole
+/// stub, so it uses a vectorized substring search.
+///
+/// ```
+/// use cmprs_format::{find_magic_header, MAGIC_HEADER, SEPARATOR};
+///
+/// // The byte-by-byte scan this replaced
+/// fn naive(buffer: &[u8]) -> Option<usize> {
+///     let needle = [&MAGIC_HEADER[..], &SEPARATOR[..]].concat();
+///     (0..buffer.len().saturating_sub(needle.len()))
+///         .find(|&i| buffer[i..i + needle.len()] == needle[..])
+/// }
+///
+/// let mut buffer: Vec<u8> = (0..32_000_000u32).map(|i| (i * 31 % 251) as u8).collect();
+/// // Near misses along the way
+/// buffer[1_000..1_016].copy_from_slice(MAGIC_HEADER);
+/// buffer[5_000..5_017].copy_from_slice(&[&MAGIC_HEADER[..], b";"].concat());
+/// assert_eq!(find_magic_header(&buffer), None);
+///
+/// buffer[20_000_000..20_000_018].copy_from_slice(b"DCMPRS_DATA_HERE;;");
+/// buffer[30_000_000..30_000_018].copy_from_slice(b"DCMPRS_DATA_HERE;;");
+/// let start = std::time::Instant::now();
+/// let found = find_magic_header(&buffer);
+/// let fast = start.elapsed();
+/// let start = std::time::Instant::now();
+/// assert_eq!(found, naive(&buffer));
+/// let slow = start.elapsed();
+/// assert_eq!(found, Some(20_000_000));
+/// println!("found the magic in {fast:?}, the naive scan took {slow:?}");
+///
+/// // A match with nothing after it isn't a packed file
+/// assert_eq!(find_magic_header(b"DCMPRS_DATA_HERE;;"), None);
+/// assert_eq!(find_magic_header(b"DCMPRS_DATA_HERE;;\x03"), Some(0));
+/// ```
+pub fn find_magic_header(buffer: &[u8]) -> Option<usize> {
+    // Search for the magic alone and check the separator after it. Searching
+    // for both at once would put them side by side in a constant of the stub
+    // itself, which would be found before the real header.
+    let needle_len = MAGIC_HEADER.len() + SEPARATOR.len();
+    memchr::memmem::find_iter(buffer, MAGIC_HEADER).find(|&i| {
+        i + needle_len < buffer.len()
+            && &buffer[i + MAGIC_HEADER.len()..i + needle_len] == SEPARATOR
+    })
+}
+
+/// CRC-32 (IEEE) over the SHA256 hash and the serialized header
+fn checksum(sha256: &[u8], len: &[u8], fields: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in sha256.iter().chain(len).chain(fields) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn push_field(fields: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    fields.push(tag);
+    fields.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    fields.extend_from_slice(value);
+}
+
+fn push_bytes(value: &mut Vec<u8>, bytes: &[u8]) {
+    value.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    value.extend_from_slice(bytes);
+}
+
+/// Read a length-prefixed byte string at `pos`, advancing it past the string
+fn read_bytes<'a>(data: &'a [u8], pos: &mut usize) -> Result<&'a [u8], CmprsError> {
+    let len = read_u32(data, *pos)? as usize;
+    let bytes = data
+        .get(*pos + 4..*pos + 4 + len)
+        .ok_or_else(|| malformed("header field is truncated"))?;
+    *pos += 4 + len;
+    Ok(bytes)
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, CmprsError> {
+    data.get(pos..pos + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| malformed("header is truncated"))
+}
+
+fn read_u64(data: &[u8], pos: usize) -> Result<u64, CmprsError> {
+    data.get(pos..pos + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| malformed("header is truncated"))
+}
+
+fn malformed(message: &str) -> CmprsError {
+    CmprsError::Malformed(message.to_string())
+}
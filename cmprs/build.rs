@@ -1,16 +1,79 @@
 use std::env;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::Duration;
+use wait_timeout::ChildExt;
+
+// Where the dcmprs stub comes from depends on how cmprs is being built:
+//
+// * Workspace layout (a git checkout): the stub source lives next to us in
+//   `../dcmprs` and is built with `cargo build --release`.
+// * Published layout (`cargo install cmprs`): the sibling directory isn't part
+//   of the crate, so the stub is installed from crates.io with
+//   `cargo install dcmprs` into OUT_DIR, pinned to our own version.
+//
+// `CMPRS_STUB_PATH` short-circuits both by pointing at a prebuilt stub, and
+// `DCMPRS_SRC_DIR` points the workspace build at a different checkout.
+// Either stub build is killed after `CMPRS_STUB_BUILD_TIMEOUT` seconds.
+//
+// `CMPRS_STUB_TARGET` builds the stub for another target triple than the one
+// cmprs is compiled for, so a macOS host can produce Linux self-extractors.
+// `CMPRS_STUB_TARGETS` (comma separated) embeds additional stubs, named after
+// their triple, which `cmprs --stub-target <triple>` picks from at pack time.
+//
+// On macOS, `macos_universal`, `macos_arm64` and `macos_x86_64` stubs are
+// built with cargo zigbuild, selected with `cmprs --stub-variant`. Without
+// zigbuild installed, the thin stubs are built with plain cargo instead and
+// joined into the universal one with lipo.
+//
+// Next to `main`, a `minimal` stub is built with the `minimal` profile and
+// without logging, for `cmprs --minimize-stub`. With `CMPRS_MINIMAL_STUB=1`,
+// `main` and the `CMPRS_STUB_TARGETS` stubs are built that way too, so every
+// output gets the small stub.
+
+// How long a stub build may take before it's considered stuck
+const DEFAULT_STUB_BUILD_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+// The minimal stub exists to cut the fixed per-binary overhead, so fail the
+// build if it ever grows past this
+const MINIMAL_STUB_MAX_SIZE: u64 = 768 * 1024;
+
+// ...and if it stops being meaningfully smaller than the regular stub
+const MINIMAL_STUB_MAX_RATIO: f64 = 0.75;
+
+// zigbuild fails transiently while zig is being installed or another build
+// holds its lock, so a failed run is retried after a delay that doubles each time
+const ZIGBUILD_ATTEMPTS: u32 = 3;
+const ZIGBUILD_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// The thin macOS stubs, by target triple, that make up the universal one
+const MACOS_THIN_STUBS: [(&str, &str); 2] = [
+    ("aarch64-apple-darwin", "macos_arm64"),
+    ("x86_64-apple-darwin", "macos_x86_64"),
+];
 
 fn main() {
+    // Without the `stub` feature only the library is built, which takes its
+    // stub from the caller
+    if env::var_os("CARGO_FEATURE_STUB").is_none() {
+        return;
+    }
+
     let out_dir = env::var("OUT_DIR").unwrap();
     let target = env::var("TARGET").unwrap();
+    let stub_target = env::var("CMPRS_STUB_TARGET").ok().filter(|t| !t.is_empty());
+    let extra_targets: Vec<String> = env::var("CMPRS_STUB_TARGETS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|t| t.trim().to_string())
+        .filter(|t| !t.is_empty())
+        .collect();
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
 
-    // Copy the dcmprs binary to OUT_DIR (must be built first)
-    let dcmprs_name = if target.contains("windows") {
-        "dcmprs.exe"
-    } else {
-        "dcmprs"
-    };
+    // The triple the `main` stub is built for, so `--stub-target` can resolve it
+    let main_target = stub_target.clone().unwrap_or_else(|| target.clone());
+    println!("cargo:rustc-env=CMPRS_MAIN_STUB_TARGET={main_target}");
 
     // Check if we should build universal binary on macOS
     let is_macos = cfg!(target_os = "macos");
@@ -18,59 +81,448 @@ fn main() {
     let dist_dir = Path::new(&out_dir).join("compiled_dcmprs");
     std::fs::create_dir_all(&dist_dir).expect("dist dir");
 
+    println!("cargo:rerun-if-env-changed=BUILD_UNIVERSAL");
+    println!("cargo:rerun-if-env-changed=CMPRS_STUB_PATH");
+    println!("cargo:rerun-if-env-changed=DCMPRS_SRC_DIR");
+    println!("cargo:rerun-if-env-changed=CMPRS_STUB_TARGET");
+    println!("cargo:rerun-if-env-changed=CMPRS_STUB_TARGETS");
+    println!("cargo:rerun-if-env-changed=CMPRS_STUB_BUILD_TIMEOUT");
+    println!("cargo:rerun-if-env-changed=CMPRS_MINIMAL_STUB");
+
+    let main_profile = if env::var("CMPRS_MINIMAL_STUB").is_ok_and(|value| value == "1") {
+        StubProfile::Minimal
+    } else {
+        StubProfile::Release
+    };
+
+    // A prebuilt stub wins over everything else
+    if let Ok(stub_path) = env::var("CMPRS_STUB_PATH") {
+        println!("cargo:rerun-if-changed={stub_path}");
+        std::fs::copy(&stub_path, dist_dir.join("main")).unwrap_or_else(|err| {
+            panic!("CMPRS_STUB_PATH is set to {stub_path}, but it can't be copied: {err}")
+        });
+        return;
+    }
+
+    let Some(source_dir) = dcmprs_source_dir() else {
+        // Published layout: fetch and build the matching dcmprs from crates.io
+        install_published_dcmprs(
+            &cargo,
+            &out_dir,
+            stub_target.as_deref(),
+            main_profile,
+            &dist_dir.join("main"),
+        );
+        install_published_dcmprs(
+            &cargo,
+            &out_dir,
+            stub_target.as_deref(),
+            StubProfile::Minimal,
+            &dist_dir.join("minimal"),
+        );
+        check_minimal_stub_size(
+            &dist_dir.join("minimal"),
+            &dist_dir.join("main"),
+            main_profile,
+        );
+        for extra_target in &extra_targets {
+            install_published_dcmprs(
+                &cargo,
+                &out_dir,
+                Some(extra_target),
+                main_profile,
+                &dist_dir.join(extra_target),
+            );
+        }
+        return;
+    };
+
     // build universal macos
     if is_macos {
-        // Use cargo zigbuild for universal binary on macOS
-        let status = std::process::Command::new("cargo")
-            .current_dir("../dcmprs")
-            .args([
-                "zigbuild",
-                "--target",
-                "universal2-apple-darwin",
-                "--release",
-            ])
-            .status()
-            .expect("Failed to build dcmprs with zigbuild");
-        assert!(status.success());
-        let binary_path = Path::new("../dcmprs/target")
-            .join("universal2-apple-darwin")
-            .join("release")
-            .join(dcmprs_name);
-
-        let dest_path = dist_dir.join("macos_universal");
-        std::fs::copy(binary_path, &dest_path).expect("Failed to copy dcmprs binary");
+        if zigbuild_installed(&cargo) {
+            build_macos_stubs(&cargo, &source_dir, &dist_dir);
+        } else {
+            println!(
+                "cargo:warning=cargo zigbuild isn't installed, building the macOS stubs per \
+                 architecture instead (install it with `cargo install cargo-zigbuild`)"
+            );
+            build_macos_stubs_per_arch(&cargo, &source_dir, &target, &dist_dir);
+        }
     };
 
-    // Standard cargo build
-    let status = std::process::Command::new("cargo")
-        .current_dir("../dcmprs")
-        .args(["build", "--release"])
+    // Standard cargo build, cross compiling when a stub target is requested
+    let dcmprs_path = build_dcmprs(
+        &cargo,
+        &source_dir,
+        stub_target.as_deref(),
+        &target,
+        main_profile,
+    );
+    let dest_path = dist_dir.join("main");
+    std::fs::copy(&dcmprs_path, &dest_path).expect("Failed to copy dcmprs binary");
+
+    let minimal_path = build_dcmprs(
+        &cargo,
+        &source_dir,
+        stub_target.as_deref(),
+        &target,
+        StubProfile::Minimal,
+    );
+    let minimal_dest_path = dist_dir.join("minimal");
+    std::fs::copy(&minimal_path, &minimal_dest_path).expect("Failed to copy dcmprs binary");
+    check_minimal_stub_size(&minimal_dest_path, &dest_path, main_profile);
+
+    // Additional stubs are stored under their target triple
+    for extra_target in &extra_targets {
+        let dcmprs_path = build_dcmprs(
+            &cargo,
+            &source_dir,
+            Some(extra_target),
+            &target,
+            main_profile,
+        );
+        std::fs::copy(&dcmprs_path, dist_dir.join(extra_target))
+            .expect("Failed to copy dcmprs binary");
+    }
+
+    println!(
+        "cargo:rerun-if-changed={}",
+        source_dir.join("src").display()
+    );
+    println!(
+        "cargo:rerun-if-changed={}",
+        source_dir.join("Cargo.toml").display()
+    );
+    println!(
+        "cargo:rerun-if-changed={}",
+        source_dir.join("selftest").display()
+    );
+    // The stub also has to be rebuilt when the shared format crate changes
+    let format_dir = source_dir.join("..").join("cmprs-format");
+    if format_dir.exists() {
+        println!("cargo:rerun-if-changed={}", format_dir.display());
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum StubProfile {
+    /// The regular release build, with DCMPRS_LOG_LEVEL support
+    Release,
+    /// The `minimal` profile without logging
+    Minimal,
+}
+
+impl StubProfile {
+    fn name(self) -> &'static str {
+        match self {
+            StubProfile::Release => "release",
+            StubProfile::Minimal => "minimal",
+        }
+    }
+
+    /// Arguments selecting this profile for `cargo build` and `cargo install`
+    fn add_args(self, command: &mut Command) {
+        command.args(["--profile", self.name()]);
+        if let StubProfile::Minimal = self {
+            command.args(["--no-default-features", "--features", "minimal"]);
+        }
+    }
+}
+
+fn check_minimal_stub_size(path: &Path, main_path: &Path, main_profile: StubProfile) {
+    let size = std::fs::metadata(path)
+        .expect("Failed to read minimal dcmprs metadata")
+        .len();
+    assert!(
+        size <= MINIMAL_STUB_MAX_SIZE,
+        "The minimal dcmprs stub is {size} bytes, over the {MINIMAL_STUB_MAX_SIZE} byte budget"
+    );
+
+    // With CMPRS_MINIMAL_STUB=1 the main stub is the same build
+    if main_profile == StubProfile::Minimal {
+        return;
+    }
+    let main_size = std::fs::metadata(main_path)
+        .expect("Failed to read dcmprs metadata")
+        .len();
+    assert!(
+        size as f64 <= main_size as f64 * MINIMAL_STUB_MAX_RATIO,
+        "The minimal dcmprs stub is {size} bytes, not meaningfully smaller than the \
+         {main_size} byte regular one"
+    );
+}
+
+fn stub_build_timeout() -> Duration {
+    match env::var("CMPRS_STUB_BUILD_TIMEOUT") {
+        Ok(seconds) => seconds
+            .trim()
+            .parse()
+            .map(Duration::from_secs)
+            .unwrap_or_else(|_| {
+                panic!("CMPRS_STUB_BUILD_TIMEOUT must be a number of seconds, got {seconds:?}")
+            }),
+        Err(_) => DEFAULT_STUB_BUILD_TIMEOUT,
+    }
+}
+
+/// Run a cargo command building dcmprs, killing it if it takes longer than
+/// the stub build timeout so a hung build fails instead of blocking forever
+fn run_with_timeout(command: &mut Command, what: &str) -> std::io::Result<ExitStatus> {
+    let timeout = stub_build_timeout();
+    let mut child = command.spawn()?;
+    match child.wait_timeout(timeout)? {
+        Some(status) => Ok(status),
+        None => {
+            let _ = child.kill();
+            let _ = child.wait();
+            panic!(
+                "dcmprs {what} timed out after {}s. Raise CMPRS_STUB_BUILD_TIMEOUT, \
+                 or set CMPRS_STUB_PATH to a prebuilt dcmprs binary.",
+                timeout.as_secs()
+            );
+        }
+    }
+}
+
+fn dcmprs_name(target: &str) -> &'static str {
+    if target.contains("windows") {
+        "dcmprs.exe"
+    } else {
+        "dcmprs"
+    }
+}
+
+/// Whether the cargo zigbuild subcommand exists at all, as opposed to failing
+fn zigbuild_installed(cargo: &str) -> bool {
+    Command::new(cargo)
+        .args(["zigbuild", "--version"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
         .status()
-        .expect("Failed to build dcmprs");
-    assert!(status.success());
+        .is_ok_and(|status| status.success())
+}
 
-    // Try different target directories based on build type
-    let dcmprs_path_specific = Path::new("../dcmprs/target")
-        .join(&target)
-        .join("release")
-        .join(dcmprs_name);
-    let dcmprs_path_general = Path::new("../dcmprs/target")
+/// Build the universal macOS stub with cargo zigbuild, retrying transient
+/// failures, and embed it with the thin stubs it's linked from
+fn build_macos_stubs(cargo: &str, source_dir: &Path, dist_dir: &Path) {
+    let mut zigbuild = Command::new(cargo);
+    zigbuild.current_dir(source_dir).args([
+        "zigbuild",
+        "--target",
+        "universal2-apple-darwin",
+        "--release",
+    ]);
+    for attempt in 1..=ZIGBUILD_ATTEMPTS {
+        let status = run_with_timeout(&mut zigbuild, "zigbuild")
+            .unwrap_or_else(|err| panic!("Failed to run `{cargo} zigbuild` for dcmprs: {err}"));
+        if status.success() {
+            break;
+        }
+        if attempt == ZIGBUILD_ATTEMPTS {
+            panic!(
+                "Building the universal dcmprs stub with cargo zigbuild failed {attempt} times \
+                 ({status}). Fix the errors above, or set CMPRS_STUB_PATH to a prebuilt dcmprs binary."
+            );
+        }
+        let delay = ZIGBUILD_RETRY_DELAY * 2u32.pow(attempt - 1);
+        println!(
+            "cargo:warning=cargo zigbuild failed ({status}), retrying in {}s",
+            delay.as_secs()
+        );
+        thread::sleep(delay);
+    }
+
+    let binary_path = source_dir
+        .join("target")
+        .join("universal2-apple-darwin")
         .join("release")
-        .join(dcmprs_name);
+        .join(dcmprs_name("universal2-apple-darwin"));
+    let dest_path = dist_dir.join("macos_universal");
+    std::fs::copy(binary_path, &dest_path).expect("Failed to copy dcmprs binary");
+
+    // zigbuild links the universal binary from thin ones, which are
+    // embedded too for `cmprs --stub-variant arm64|x86_64`
+    for (triple, name) in MACOS_THIN_STUBS {
+        let thin_path = source_dir
+            .join("target")
+            .join(triple)
+            .join("release")
+            .join(dcmprs_name(triple));
+        std::fs::copy(&thin_path, dist_dir.join(name)).unwrap_or_else(|err| {
+            panic!(
+                "Failed to copy thin dcmprs binary {}: {err}",
+                thin_path.display()
+            )
+        });
+    }
+}
+
+/// Without zigbuild, build each thin macOS stub with plain cargo and join
+/// them with lipo. A stub that can't be built, like one for an architecture
+/// whose Rust target isn't installed, is left out with a warning.
+fn build_macos_stubs_per_arch(cargo: &str, source_dir: &Path, host_target: &str, dist_dir: &Path) {
+    let mut thin_paths = Vec::new();
+    for (triple, name) in MACOS_THIN_STUBS {
+        match try_build_dcmprs(
+            cargo,
+            source_dir,
+            Some(triple),
+            host_target,
+            StubProfile::Release,
+        ) {
+            Ok(thin_path) => {
+                std::fs::copy(&thin_path, dist_dir.join(name))
+                    .expect("Failed to copy dcmprs binary");
+                thin_paths.push(thin_path);
+            }
+            Err(err) => println!("cargo:warning=Leaving out the {name} stub: {err}"),
+        }
+    }
+    if thin_paths.len() < MACOS_THIN_STUBS.len() {
+        println!("cargo:warning=Leaving out the macos_universal stub, it needs both thin stubs");
+        return;
+    }
 
-    let dcmprs_path = if dcmprs_path_specific.exists() {
-        dcmprs_path_specific
-    } else if dcmprs_path_general.exists() {
-        dcmprs_path_general
+    let status = Command::new("lipo")
+        .arg("-create")
+        .arg("-output")
+        .arg(dist_dir.join("macos_universal"))
+        .args(&thin_paths)
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => {
+            println!("cargo:warning=Leaving out the macos_universal stub, lipo failed ({status})")
+        }
+        Err(err) => {
+            println!("cargo:warning=Leaving out the macos_universal stub, lipo couldn't run: {err}")
+        }
+    }
+}
+
+/// Build dcmprs with `profile`, optionally for a specific target, and return the binary path
+fn build_dcmprs(
+    cargo: &str,
+    source_dir: &Path,
+    stub_target: Option<&str>,
+    host_target: &str,
+    profile: StubProfile,
+) -> PathBuf {
+    try_build_dcmprs(cargo, source_dir, stub_target, host_target, profile)
+        .unwrap_or_else(|err| panic!("{err}"))
+}
+
+/// Like [`build_dcmprs`], returning what went wrong instead of panicking
+fn try_build_dcmprs(
+    cargo: &str,
+    source_dir: &Path,
+    stub_target: Option<&str>,
+    host_target: &str,
+    profile: StubProfile,
+) -> Result<PathBuf, String> {
+    let mut build = Command::new(cargo);
+    build.current_dir(source_dir).arg("build");
+    profile.add_args(&mut build);
+    if let Some(stub_target) = stub_target {
+        build.args(["--target", stub_target]);
+    }
+    let status = run_with_timeout(&mut build, "build")
+        .map_err(|err| format!("Failed to run `{cargo} build` for dcmprs: {err}"))?;
+    if !status.success() {
+        return Err(format!(
+            "Building the dcmprs stub in {} for {} failed ({status}). \
+             Fix the errors above, or set CMPRS_STUB_PATH to a prebuilt dcmprs binary.",
+            source_dir.display(),
+            stub_target.unwrap_or(host_target)
+        ));
+    }
+
+    // Try different target directories based on build type
+    let name = dcmprs_name(stub_target.unwrap_or(host_target));
+    let dcmprs_path_specific = source_dir
+        .join("target")
+        .join(stub_target.unwrap_or(host_target))
+        .join(profile.name())
+        .join(name);
+    let dcmprs_path_general = source_dir.join("target").join(profile.name()).join(name);
+
+    // Without --target, cargo only writes to target/<triple>/<profile> when
+    // CARGO_BUILD_TARGET is set, so a leftover cross build there mustn't win
+    if stub_target.is_none() && dcmprs_path_general.exists() {
+        Ok(dcmprs_path_general)
+    } else if dcmprs_path_specific.exists() {
+        Ok(dcmprs_path_specific)
     } else {
-        panic!("dcmprs binary not found at {}, or {}. Please run 'cargo build --package dcmprs --release' first.", 
-               dcmprs_path_specific.display(), dcmprs_path_general.display());
-    };
+        Err(format!(
+            "dcmprs was built but its binary wasn't found at {} or {}. \
+             Set CMPRS_STUB_PATH to a prebuilt dcmprs binary to skip the stub build.",
+            dcmprs_path_specific.display(),
+            dcmprs_path_general.display()
+        ))
+    }
+}
 
-    let dest_path = dist_dir.join("main");
-    std::fs::copy(&dcmprs_path, &dest_path).expect("Failed to copy dcmprs binary");
+/// Locate the dcmprs sources in the workspace layout, if they exist
+fn dcmprs_source_dir() -> Option<PathBuf> {
+    if let Ok(dir) = env::var("DCMPRS_SRC_DIR") {
+        let dir = PathBuf::from(dir);
+        if !dir.join("Cargo.toml").exists() {
+            panic!(
+                "DCMPRS_SRC_DIR is set to {}, but it doesn't contain a Cargo.toml",
+                dir.display()
+            );
+        }
+        return Some(dir);
+    }
 
-    println!("cargo:rerun-if-changed=../dcmprs/src/main.rs");
-    println!("cargo:rerun-if-changed=../dcmprs/Cargo.toml");
-    println!("cargo:rerun-if-env-changed=BUILD_UNIVERSAL");
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let sibling = manifest_dir.join("..").join("dcmprs");
+    sibling.join("Cargo.toml").exists().then_some(sibling)
+}
+
+/// Build the dcmprs release matching this cmprs version from crates.io
+fn install_published_dcmprs(
+    cargo: &str,
+    out_dir: &str,
+    stub_target: Option<&str>,
+    profile: StubProfile,
+    dest_path: &Path,
+) {
+    let version = env::var("CARGO_PKG_VERSION").unwrap();
+    let root = Path::new(out_dir)
+        .join("dcmprs-install")
+        .join(stub_target.unwrap_or("host"))
+        .join(profile.name());
+
+    let mut install = Command::new(cargo);
+    install
+        .args(["install", "dcmprs", "--locked", "--version"])
+        .arg(format!("={version}"))
+        .arg("--root")
+        .arg(&root);
+    profile.add_args(&mut install);
+    if let Some(stub_target) = stub_target {
+        install.args(["--target", stub_target]);
+    }
+    let status = run_with_timeout(&mut install, "install");
+
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => panic!(
+            "The dcmprs sources aren't next to cmprs, and `cargo install dcmprs@{version}` \
+             failed ({status}). Either build from a checkout of the cmprs repository, set \
+             DCMPRS_SRC_DIR to a dcmprs source directory, or set CMPRS_STUB_PATH to a prebuilt \
+             dcmprs binary."
+        ),
+        Err(err) => panic!("Failed to run `{cargo} install dcmprs@{version}`: {err}"),
+    }
+
+    let target = stub_target.map_or_else(|| env::var("TARGET").unwrap(), str::to_string);
+    let binary_path = root.join("bin").join(dcmprs_name(&target));
+    std::fs::copy(&binary_path, dest_path).unwrap_or_else(|err| {
+        panic!(
+            "Failed to copy installed dcmprs from {}: {err}",
+            binary_path.display()
+        )
+    });
 }
@@ -0,0 +1,89 @@
+use clap::Args;
+use cmprs::{
+    Algorithm, CmprsError, GZIP_MAX_LEVEL, MAX_LEVEL, MIN_FAST_LEVEL, MIN_LEVEL, XZ_MAX_PRESET,
+};
+use serde::Serialize;
+use std::io::{self, Write};
+
+#[derive(Args, Clone)]
+pub struct AlgorithmsArgs {
+    #[arg(long, help = "Print a JSON array instead of a table")]
+    json: bool,
+}
+
+/// A compression algorithm this cmprs build can pack with
+#[derive(Serialize)]
+struct AlgorithmInfo {
+    /// Byte identifying the algorithm in the header
+    id: u8,
+    name: &'static str,
+    /// The flag that picks this algorithm, or None for the default one
+    selected_by: Option<&'static str>,
+    /// Levels (presets for xz) it compresses at, None when it doesn't compress
+    levels: Option<String>,
+}
+
+pub fn run(args: AlgorithmsArgs) -> Result<(), CmprsError> {
+    let algorithms: Vec<AlgorithmInfo> = Algorithm::ALL
+        .into_iter()
+        .filter(|&algorithm| cmprs::is_compiled_in(algorithm))
+        .map(describe)
+        .collect();
+    let mut stdout = io::stdout().lock();
+    if args.json {
+        serde_json::to_writer(&mut stdout, &algorithms).map_err(io::Error::from)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+
+    let levels: Vec<String> = algorithms
+        .iter()
+        .map(|algorithm| {
+            algorithm
+                .levels
+                .as_deref()
+                .map_or_else(|| "-".to_string(), |levels| format!("levels {levels}"))
+        })
+        .collect();
+    let name_width = algorithms
+        .iter()
+        .map(|algorithm| algorithm.name.len())
+        .max()
+        .unwrap_or(0);
+    let levels_width = levels.iter().map(String::len).max().unwrap_or(0);
+    for (algorithm, levels) in algorithms.iter().zip(&levels) {
+        writeln!(
+            stdout,
+            "{}  {:name_width$}  {levels:levels_width$}  {}",
+            algorithm.id,
+            algorithm.name,
+            algorithm.selected_by.unwrap_or("(default)")
+        )?;
+    }
+    Ok(())
+}
+
+/// dcmprs decompresses every algorithm, so for one this cmprs was built with
+/// this only has to say how to pick it
+fn describe(algorithm: Algorithm) -> AlgorithmInfo {
+    let (selected_by, levels) = match algorithm {
+        Algorithm::Zstd => (
+            None,
+            Some(format!(
+                "{MIN_LEVEL}-{MAX_LEVEL}, fast {MIN_FAST_LEVEL} to -1"
+            )),
+        ),
+        Algorithm::None => (Some("--store"), None),
+        Algorithm::Xz => (Some("--xz-preset"), Some(format!("0-{XZ_MAX_PRESET}"))),
+        Algorithm::Gzip => (
+            Some("--algorithm gzip"),
+            Some(format!("1-{GZIP_MAX_LEVEL}")),
+        ),
+    };
+    AlgorithmInfo {
+        id: algorithm.id(),
+        name: algorithm.name(),
+        selected_by,
+        levels,
+    }
+}
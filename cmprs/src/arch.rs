@@ -0,0 +1,71 @@
+use cmprs::format::Filter;
+use goblin::elf::header::{machine_to_str, EM_386, EM_AARCH64, EM_ARM, EM_X86_64, ET_DYN, ET_EXEC};
+use goblin::elf::Elf;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+// Mach-O, 32 and 64-bit in either byte order, and universal binaries
+const MACHO_MAGICS: &[[u8; 4]] = &[
+    [0xfe, 0xed, 0xfa, 0xce],
+    [0xfe, 0xed, 0xfa, 0xcf],
+    [0xce, 0xfa, 0xed, 0xfe],
+    [0xcf, 0xfa, 0xed, 0xfe],
+    [0xca, 0xfe, 0xba, 0xbe],
+];
+
+// 32-bit machines whose programs a 64-bit kernel of the second can run, given
+// the 32-bit libraries (multilib). Not every aarch64 CPU can run armv7 code.
+const COMPAT_PAIRS: &[(u16, u16)] = &[(EM_386, EM_X86_64), (EM_ARM, EM_AARCH64)];
+
+/// How a program built for one machine fares started by a stub built for another
+#[derive(Debug, PartialEq, Eq)]
+pub enum Compat {
+    Native,
+    /// A 32-bit program under a 64-bit stub: it runs where the host can run
+    /// 32-bit programs, but not on 32-bit hosts, which can't start the stub
+    Multilib,
+    Incompatible,
+}
+
+/// The ELF machine (`e_machine`) `bytes` is built for, or `None` for
+/// anything that isn't ELF
+pub fn elf_machine(bytes: &[u8]) -> Option<u16> {
+    if !bytes.starts_with(ELF_MAGIC) {
+        return None;
+    }
+    Elf::parse_header(bytes).ok().map(|header| header.e_machine)
+}
+
+/// Whether `bytes` is a program the kernel runs by itself: an ELF executable
+/// (not an object file or core dump) or a Mach-O one
+pub fn is_executable(bytes: &[u8]) -> bool {
+    if bytes.starts_with(ELF_MAGIC) {
+        return Elf::parse_header(bytes)
+            .is_ok_and(|header| matches!(header.e_type, ET_EXEC | ET_DYN));
+    }
+    MACHO_MAGICS.iter().any(|magic| bytes.starts_with(magic))
+}
+
+pub fn compat(program: u16, stub: u16) -> Compat {
+    if program == stub {
+        Compat::Native
+    } else if COMPAT_PAIRS.contains(&(program, stub)) {
+        Compat::Multilib
+    } else {
+        Compat::Incompatible
+    }
+}
+
+/// A short name for `machine`, like x86_64 or 386
+pub fn name(machine: u16) -> String {
+    machine_to_str(machine).to_lowercase()
+}
+
+/// The branch filter for programs built for `machine`, if there is one
+pub fn branch_filter(machine: u16) -> Option<Filter> {
+    match machine {
+        EM_386 | EM_X86_64 => Some(Filter::X86),
+        EM_AARCH64 => Some(Filter::Arm64),
+        _ => None,
+    }
+}
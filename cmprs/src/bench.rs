@@ -0,0 +1,145 @@
+use crate::main_stub;
+use clap::Args;
+use cmprs::{format, Algorithm, CmprsError, PackOptions};
+use log::{debug, info};
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+
+#[derive(Args, Clone)]
+pub struct BenchStartupArgs {
+    #[arg(help = "The program to pack with each algorithm")]
+    input: PathBuf,
+
+    #[arg(
+        long,
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Launches to time per algorithm, after one to warm the page cache"
+    )]
+    runs: u32,
+
+    #[arg(long, help = "Print a JSON array instead of a table")]
+    json: bool,
+}
+
+/// How packing with one algorithm came out
+#[derive(Serialize)]
+struct Bench {
+    algorithm: &'static str,
+    level: i32,
+    /// Size of the packed file, stub included
+    size: u64,
+    /// Size of the compressed payload
+    payload_size: u64,
+    /// Payload size over input size
+    ratio: f64,
+    /// Milliseconds from starting the packed file until it had the program
+    /// extracted, the middle and the fastest of the runs
+    launch_ms_median: f64,
+    launch_ms_min: f64,
+}
+
+pub fn run(args: BenchStartupArgs) -> Result<(), CmprsError> {
+    let input = fs::read(&args.input).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("failed to read {}: {err}", args.input.display()),
+        )
+    })?;
+    let stub = main_stub()?.contents();
+    let header = format::Header {
+        mode: Some(0o755),
+        name: args.input.file_name().map(|name| name.to_os_string()),
+        ..format::Header::default()
+    };
+    let dir = tempfile::Builder::new().prefix("cmprs-bench-").tempdir()?;
+
+    let mut benches = Vec::new();
+    for algorithm in Algorithm::ALL {
+        if !cmprs::is_compiled_in(algorithm) {
+            continue;
+        }
+        info!("Packing {} with {}", args.input.display(), algorithm.name());
+        let options = PackOptions::builder().algorithm(algorithm).build()?;
+        let (packed, report) = cmprs::pack(stub, &input, &header, &options)?;
+        let packed_path = dir.path().join(algorithm.name());
+        fs::write(&packed_path, &packed)?;
+        fs::set_permissions(&packed_path, fs::Permissions::from_mode(0o755))?;
+
+        let extracted_path = dir.path().join(format!("{}.extracted", algorithm.name()));
+        time_launch(&packed_path, &extracted_path)?;
+        let mut launches = (0..args.runs)
+            .map(|_| time_launch(&packed_path, &extracted_path))
+            .collect::<io::Result<Vec<_>>>()?;
+        launches.sort();
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        benches.push(Bench {
+            algorithm: algorithm.name(),
+            level: report.level,
+            size: report.output_size,
+            payload_size: report.compressed_size,
+            ratio: report.ratio(),
+            launch_ms_median: ms(launches[launches.len() / 2]),
+            launch_ms_min: ms(launches[0]),
+        });
+    }
+
+    let mut stdout = io::stdout().lock();
+    if args.json {
+        serde_json::to_writer(&mut stdout, &benches).map_err(io::Error::from)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+    writeln!(
+        stdout,
+        "{:9}  {:>5}  {:>12}  {:>12}  {:>6}  {:>10}  {:>10}",
+        "algorithm", "level", "size", "payload", "ratio", "median", "fastest"
+    )?;
+    for bench in &benches {
+        writeln!(
+            stdout,
+            "{:9}  {:>5}  {:>12}  {:>12}  {:>5.1}%  {:>8.2}ms  {:>8.2}ms",
+            bench.algorithm,
+            bench.level,
+            bench.size,
+            bench.payload_size,
+            bench.ratio * 100.0,
+            bench.launch_ms_median,
+            bench.launch_ms_min
+        )?;
+    }
+    Ok(())
+}
+
+/// Time the packed file at `packed_path` extracting its program to
+/// `extracted_path`: everything a launch does short of running the program
+fn time_launch(packed_path: &Path, extracted_path: &Path) -> io::Result<Duration> {
+    match fs::remove_file(extracted_path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let start = Instant::now();
+    let output = Command::new(packed_path)
+        .arg("--dcmprs-extract")
+        .arg(extracted_path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()?;
+    let elapsed = start.elapsed();
+    if !output.status.success() {
+        return Err(io::Error::other(format!(
+            "{} failed to extract ({}): {}",
+            packed_path.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    debug!("{} launched in {elapsed:?}", packed_path.display());
+    Ok(elapsed)
+}
@@ -0,0 +1,177 @@
+use cmprs::format::{Bundle, BundleFile};
+use cmprs::CmprsError;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use log::{debug, warn};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+/// Paths left out of a bundle with --exclude. Like tar's, a pattern without
+/// a `/` matches a name anywhere in the tree and one with a `/` the path below
+/// the directory, and an excluded directory is skipped with all it holds.
+pub struct Excludes {
+    names: GlobSet,
+    paths: GlobSet,
+}
+
+impl Excludes {
+    pub fn new(patterns: &[String]) -> Result<Self, CmprsError> {
+        let mut names = GlobSetBuilder::new();
+        let mut paths = GlobSetBuilder::new();
+        for pattern in patterns {
+            let invalid = |err: globset::Error| {
+                CmprsError::InvalidOptions(format!("invalid --exclude {pattern:?}: {err}"))
+            };
+            if pattern.contains('/') {
+                let pattern = pattern.trim_matches('/');
+                let glob = GlobBuilder::new(pattern)
+                    .literal_separator(true)
+                    .build()
+                    .map_err(invalid)?;
+                paths.add(glob);
+            } else {
+                names.add(Glob::new(pattern).map_err(invalid)?);
+            }
+        }
+        let build = |set: GlobSetBuilder| {
+            set.build().map_err(|err| {
+                CmprsError::InvalidOptions(format!("invalid --exclude patterns: {err}"))
+            })
+        };
+        Ok(Excludes {
+            names: build(names)?,
+            paths: build(paths)?,
+        })
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        path.file_name()
+            .is_some_and(|name| self.names.is_match(name))
+            || self.paths.is_match(path)
+    }
+}
+
+/// List the files below `dir` for a bundle that runs `entry`, or the program
+/// a subcommand names, leaving out the `excludes`. Symlinks to files are
+/// stored as the file they point to.
+pub fn scan(
+    dir: &Path,
+    entry: Option<&Path>,
+    subcommands: &[(String, PathBuf)],
+    excludes: &Excludes,
+) -> Result<Bundle, CmprsError> {
+    let mut files = Vec::new();
+    let mut excluded = 0;
+    scan_dir(dir, Path::new(""), excludes, &mut files, &mut excluded)?;
+    if excluded > 0 {
+        crate::human::note(format_args!(
+            "bundling {} files from {}, excluded {excluded} files and directories",
+            files.len(),
+            dir.display()
+        ));
+    }
+
+    // Both name a program to run, which has to be one of the files
+    let program = |path: &Path, flag: &str| {
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(CmprsError::InvalidOptions(format!(
+                "{flag} must be a path inside {}, got {}",
+                dir.display(),
+                path.display()
+            )));
+        }
+        if !files.iter().any(|file| file.path == path.as_os_str()) {
+            return Err(CmprsError::InvalidOptions(format!(
+                "{flag}: {} is not a file in {}",
+                path.display(),
+                dir.display()
+            )));
+        }
+        Ok(path.as_os_str().to_os_string())
+    };
+
+    let entry = match entry {
+        Some(entry) => program(entry, "--entry")?,
+        None => OsString::new(),
+    };
+    let mut bundle_subcommands: Vec<(String, OsString)> = Vec::new();
+    for (name, path) in subcommands {
+        if bundle_subcommands.iter().any(|(other, _)| other == name) {
+            return Err(CmprsError::InvalidOptions(format!(
+                "--subcommand {name} is given more than once"
+            )));
+        }
+        let path = program(path, &format!("--subcommand {name}"))?;
+        bundle_subcommands.push((name.clone(), path));
+    }
+
+    debug!("Bundling {} files from {}", files.len(), dir.display());
+    Ok(Bundle {
+        entry,
+        files,
+        frames: Vec::new(),
+        subcommands: bundle_subcommands,
+    })
+}
+
+/// Walk `dir` in name order, so the same tree always produces the same bundle
+fn scan_dir(
+    dir: &Path,
+    relative: &Path,
+    excludes: &Excludes,
+    files: &mut Vec<BundleFile>,
+    excluded: &mut usize,
+) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir.join(relative))?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = relative.join(entry.file_name());
+        if excludes.matches(&path) {
+            debug!("Excluding {}", path.display());
+            *excluded += 1;
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            scan_dir(dir, &path, excludes, files, excluded)?;
+            continue;
+        }
+        let metadata = if file_type.is_symlink() {
+            fs::metadata(entry.path())?
+        } else {
+            entry.metadata()?
+        };
+        if !metadata.is_file() {
+            warn!("Skipping {}, which isn't a regular file", path.display());
+            continue;
+        }
+        files.push(BundleFile {
+            path: path.into_os_string(),
+            size: metadata.len(),
+            mode: metadata.permissions().mode() & 0o7777,
+        });
+    }
+    Ok(())
+}
+
+/// Read the files of `bundle` one after the other, as they're stored in the payload
+pub fn read_payload(dir: &Path, bundle: &Bundle) -> io::Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    for file in &bundle.files {
+        let path = dir.join(&file.path);
+        let read = File::open(&path)?.read_to_end(&mut payload)?;
+        if read as u64 != file.size {
+            return Err(io::Error::other(format!(
+                "{} changed size while it was being packed",
+                path.display()
+            )));
+        }
+    }
+    Ok(payload)
+}
@@ -0,0 +1,90 @@
+use crate::{parent_dir, AlgorithmArg, Args, LevelArg};
+use cmprs::CmprsError;
+use log::debug;
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Looked for in the input's directory, then the current one
+const CONFIG_NAMES: &[&str] = &["cmprs.toml", ".cmprs.toml"];
+
+/// Project defaults from `cmprs.toml`. Flags given on the command line win.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Compression level, or the preset with `algorithm = "xz"`
+    level: Option<i32>,
+    algorithm: Option<ConfigAlgorithm>,
+    suffix: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ConfigAlgorithm {
+    Zstd,
+    Xz,
+    Gzip,
+    None,
+}
+
+/// Fill in whatever `args` leaves unset from the config file found for `input`
+pub fn apply(mut args: Args, input: &Path) -> Result<Args, CmprsError> {
+    let Some((path, config)) = find(input)? else {
+        return Ok(args);
+    };
+    debug!("Using {} for defaults: {config:?}", path.display());
+
+    if args.suffix.is_none() {
+        args.suffix = config.suffix;
+    }
+
+    // Any compression flag on the command line replaces the config's choice as a whole
+    let compression_given = args.compression_level.is_some()
+        || args.store
+        || args.algorithm.is_some()
+        || args.xz_preset.is_some()
+        || args.xz_extreme;
+    if !compression_given {
+        match (
+            config.algorithm.unwrap_or(ConfigAlgorithm::Zstd),
+            config.level,
+        ) {
+            (ConfigAlgorithm::Zstd, level) => args.compression_level = level.map(LevelArg::Number),
+            (ConfigAlgorithm::Xz, preset) => {
+                args.xz_preset = Some(preset.unwrap_or(cmprs::XZ_DEFAULT_PRESET))
+            }
+            (ConfigAlgorithm::Gzip, level) => {
+                args.algorithm = Some(AlgorithmArg::Gzip);
+                args.compression_level = level.map(LevelArg::Number);
+            }
+            (ConfigAlgorithm::None, None) => args.store = true,
+            (ConfigAlgorithm::None, Some(_)) => {
+                return Err(CmprsError::InvalidOptions(format!(
+                    "{}: level doesn't apply to algorithm \"none\"",
+                    path.display()
+                )))
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// The first config file next to `input` or in the current directory, parsed
+fn find(input: &Path) -> Result<Option<(PathBuf, Config)>, CmprsError> {
+    for dir in [parent_dir(input), Path::new(".")] {
+        for name in CONFIG_NAMES {
+            let path = dir.join(name);
+            let text = match fs::read_to_string(&path) {
+                Ok(text) => text,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let config = toml::from_str(&text).map_err(|err| {
+                CmprsError::InvalidOptions(format!("invalid {}: {err}", path.display()))
+            })?;
+            return Ok(Some((path, config)));
+        }
+    }
+    Ok(None)
+}
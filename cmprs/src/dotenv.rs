@@ -0,0 +1,82 @@
+use cmprs::CmprsError;
+use std::fs;
+use std::path::Path;
+
+/// Read the variables of the dotenv file at `path`: `NAME=value` lines, with
+/// an optional `export ` in front, `#` comments and blank lines. Values may be
+/// single-quoted (taken as is) or double-quoted (with `\n`, `\t`, `\"` and
+/// `\\` escapes). Nothing is expanded or run, unlike sourcing it in a shell.
+pub fn read(path: &Path) -> Result<Vec<(String, String)>, CmprsError> {
+    let text = fs::read_to_string(path).map_err(|err| {
+        CmprsError::InvalidOptions(format!("can't read --env-file {}: {err}", path.display()))
+    })?;
+    let mut vars: Vec<(String, String)> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let invalid = |reason: &str| {
+            CmprsError::InvalidOptions(format!("{}:{}: {reason}", path.display(), index + 1))
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((name, value)) = line.split_once('=') else {
+            return Err(invalid("expected NAME=value"));
+        };
+        let name = name.trim();
+        if !is_name(name) {
+            return Err(invalid(&format!("{name:?} isn't a valid variable name")));
+        }
+        let value = parse_value(value.trim_start()).map_err(invalid)?;
+        // A later line sets the variable again, like in a shell
+        vars.retain(|(other, _)| other != name);
+        vars.push((name.to_string(), value));
+    }
+    Ok(vars)
+}
+
+/// Letters, digits and underscores, not starting with a digit
+fn is_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+        && name.chars().all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+fn parse_value(value: &str) -> Result<String, &'static str> {
+    let (quote, rest) = match value.chars().next() {
+        Some(quote @ ('"' | '\'')) => (quote, &value[1..]),
+        // Unquoted values end at a comment
+        _ => {
+            let value = match value.find(" #") {
+                Some(comment) => &value[..comment],
+                None => value,
+            };
+            return Ok(value.trim_end().to_string());
+        }
+    };
+
+    let mut parsed = String::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c == quote => {
+                let trailing = chars.as_str().trim_start();
+                if !trailing.is_empty() && !trailing.starts_with('#') {
+                    return Err("unexpected text after the closing quote");
+                }
+                return Ok(parsed);
+            }
+            '\\' if quote == '"' => match chars.next() {
+                Some('n') => parsed.push('\n'),
+                Some('t') => parsed.push('\t'),
+                Some(escaped @ ('"' | '\\')) => parsed.push(escaped),
+                Some(other) => {
+                    parsed.push('\\');
+                    parsed.push(other);
+                }
+                None => break,
+            },
+            c => parsed.push(c),
+        }
+    }
+    Err("missing the closing quote")
+}
@@ -0,0 +1,211 @@
+use crate::{human, is_same_file, parent_dir, SUFFIX};
+use clap::Args;
+use cmprs::format::{self, Bundle};
+use cmprs::CmprsError;
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+// Mode used for files packed before permissions were recorded in the header
+const DEFAULT_MODE: u32 = 0o755;
+
+#[derive(Args, Clone)]
+pub struct ExtractArgs {
+    #[arg(help = "cmprs-compressed file")]
+    input: PathBuf,
+
+    #[arg(
+        short,
+        long,
+        help = "Output file, or directory for a packed directory. If not specified, defaults to <input> without its .cmprs suffix"
+    )]
+    output: Option<PathBuf>,
+}
+
+pub fn run(args: ExtractArgs) -> Result<(), CmprsError> {
+    let start_time = Instant::now();
+    let output_path = match args.output {
+        Some(output) => output,
+        None => {
+            let output = default_output_path(&args.input);
+            if output.exists() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{} already exists, pass --output to choose where to extract",
+                        output.display()
+                    ),
+                )
+                .into());
+            }
+            output
+        }
+    };
+
+    info!(
+        "Extracting {} to {}",
+        args.input.display(),
+        output_path.display()
+    );
+
+    debug!("Reading packed file: {}", args.input.display());
+    let buffer = cmprs::read_packed(&args.input)?;
+    check_stub(&buffer)?;
+
+    if let Some(bundle) = cmprs::format::Packed::parse(&buffer)?.header.bundle {
+        return extract_bundle(&buffer, &bundle, &output_path);
+    }
+
+    // The input is mapped while it's read, so extracting over it goes to a
+    // temp file next to the file the output names, renamed over it once done,
+    // rather than truncating it under us
+    let in_place = if is_same_file(&args.input, &output_path) {
+        let target = fs::canonicalize(&output_path)?;
+        let temp_path = tempfile::Builder::new()
+            .prefix(".cmprs-extract")
+            .tempfile_in(parent_dir(&target))?
+            .into_temp_path();
+        Some((temp_path, target))
+    } else {
+        None
+    };
+    let write_path = in_place.as_ref().map_or_else(
+        || output_path.clone(),
+        |(temp_path, _)| temp_path.to_path_buf(),
+    );
+
+    debug!("Decompressing into {}", write_path.display());
+    let decompress_start = Instant::now();
+    let mut output = File::create(&write_path)?;
+    let header = match cmprs::unpack_to(&buffer, &mut output) {
+        Ok(header) => header,
+        Err(err) => {
+            // Don't leave a partial or corrupted file behind, but never remove
+            // something like a device node the output was pointed at
+            if in_place.is_none() && output.metadata().is_ok_and(|metadata| metadata.is_file()) {
+                let _ = fs::remove_file(&output_path);
+            }
+            return Err(err);
+        }
+    };
+    let extracted_len = output.metadata()?.len();
+    info!(
+        "Decompressed {} bytes ({}) in {:?}, SHA256 verified",
+        extracted_len,
+        header.algorithm.name(),
+        decompress_start.elapsed()
+    );
+    if let Some(name) = &header.name {
+        info!("Original file name: {}", name.to_string_lossy());
+    }
+    if let Some(source_path) = &header.source_path {
+        info!("Packed from {}", source_path.to_string_lossy());
+    }
+    if let Some(comment) = &header.comment {
+        info!("Comment: {comment}");
+    }
+    if !header.launcher.is_empty() {
+        info!("Payload is run through launcher {:?}", header.launcher);
+    }
+    if !header.entry_args.is_empty() {
+        info!(
+            "Program is run with the arguments {:?} first",
+            header.entry_args
+        );
+    }
+    if let Some(content_type) = header.content_type {
+        info!("Payload is a {} file", content_type.name());
+    }
+    match &header.packer_version {
+        Some(packer_version) => info!("Packed by cmprs {packer_version}"),
+        None => info!("Packed by a cmprs version that didn't record itself"),
+    }
+    if let Some(min_version) = &header.min_loader_version {
+        info!("Needs dcmprs {min_version} or newer to run");
+    }
+
+    let mode = header.mode.unwrap_or_else(|| {
+        warn!("No permissions recorded in the header, using {DEFAULT_MODE:o}");
+        DEFAULT_MODE
+    });
+    output.set_permissions(fs::Permissions::from_mode(mode))?;
+    if let Some(mtime) = header.mtime {
+        output.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+    }
+
+    for (name, value) in &header.xattrs {
+        debug!("Restoring extended attribute {:?}", name);
+        match xattr::set(&write_path, name, value) {
+            Err(err) if name == format::CAPABILITY_XATTR => human::warning(format_args!(
+                "couldn't restore the file capabilities of {} ({err}), that needs root or CAP_SETFCAP",
+                output_path.display()
+            )),
+            Err(err) => warn!("Failed to restore extended attribute {:?}: {}", name, err),
+            Ok(()) => {}
+        }
+    }
+
+    if let Some((temp_path, target)) = in_place {
+        temp_path.persist(target).map_err(io::Error::from)?;
+    }
+
+    info!(
+        "Extracted {} bytes (mode {:o}) in {:?}",
+        extracted_len,
+        mode,
+        start_time.elapsed()
+    );
+    Ok(())
+}
+
+/// Extract a packed directory into `output_path`, with its recorded permissions
+fn extract_bundle(buffer: &[u8], bundle: &Bundle, output_path: &Path) -> Result<(), CmprsError> {
+    let mut payload = Vec::new();
+    let header = cmprs::unpack_to(buffer, &mut payload)?;
+    bundle.unpack(&payload, output_path)?;
+    if let Some(mode) = header.mode {
+        fs::set_permissions(output_path, fs::Permissions::from_mode(mode))?;
+    }
+    info!(
+        "Extracted {} files to {} (entry {})",
+        bundle.files.len(),
+        output_path.display(),
+        if bundle.entry.is_empty() {
+            "none, only subcommands".into()
+        } else {
+            Path::new(&bundle.entry).display().to_string()
+        }
+    );
+    Ok(())
+}
+
+/// Warn when the stub in front of the payload isn't the one it was packed
+/// with. Extracting doesn't run the stub, so it goes ahead anyway.
+fn check_stub(buffer: &[u8]) -> Result<(), CmprsError> {
+    let packed = cmprs::format::Packed::parse(buffer)?;
+    let Some(expected) = packed.header.stub_sha256 else {
+        debug!("No stub hash recorded, can't check the stub");
+        return Ok(());
+    };
+    if Sha256::digest(&buffer[..packed.stub_len])[..] == expected {
+        info!("Stub matches the one the file was packed with");
+    } else {
+        crate::human::warning(
+            "stub/payload mismatch: the stub isn't the one this file was packed with, \
+             it may have been swapped or tampered with",
+        );
+    }
+    Ok(())
+}
+
+fn default_output_path(input: &Path) -> PathBuf {
+    let input_str = input.to_string_lossy();
+    match input_str.strip_suffix(&format!(".{SUFFIX}")) {
+        Some(stripped) if !stripped.is_empty() => PathBuf::from(stripped),
+        _ => PathBuf::from(format!("{input_str}.extracted")),
+    }
+}
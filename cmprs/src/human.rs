@@ -0,0 +1,133 @@
+use crate::stats::PackStats;
+use owo_colors::{OwoColorize, Style};
+use std::env;
+use std::fmt::Display;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Whether stderr output is colored, decided once at startup
+static COLOR: AtomicBool = AtomicBool::new(false);
+// Set while packing outputs nobody sees, whose notes would only repeat
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Color stderr output when it's a terminal (or CLICOLOR_FORCE is set),
+/// unless `no_color` (--no-color) or NO_COLOR says otherwise
+pub fn init(no_color: bool) {
+    let set = |name| env::var_os(name).is_some_and(|value| !value.is_empty() && value != "0");
+    let color =
+        !no_color && !set("NO_COLOR") && (set("CLICOLOR_FORCE") || io::stderr().is_terminal());
+    COLOR.store(color, Ordering::Relaxed);
+}
+
+/// Hold back warnings and notes until `set_quiet(false)`
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+fn paint(text: impl Display, style: Style) -> String {
+    if COLOR.load(Ordering::Relaxed) {
+        text.style(style).to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+/// Print `message` to stderr as a warning, in yellow
+pub fn warning(message: impl Display) {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    eprintln!(
+        "cmprs: {}: {message}",
+        paint("warning", Style::new().yellow().bold())
+    );
+}
+
+/// Print `message` to stderr as a note, which isn't a problem but may surprise
+pub fn note(message: impl Display) {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    eprintln!(
+        "cmprs: {}: {message}",
+        paint("note", Style::new().cyan().bold())
+    );
+}
+
+/// Print the sizes and timings of a pack to stderr, one aligned line each,
+/// with what packing saved in green or what it cost in red
+pub fn print_summary(stats: &PackStats) {
+    let label = |text| paint(format!("{text:<8}"), Style::new().dimmed());
+    let bytes = |size: usize| format!("{size:>12} bytes");
+    let of_input = |size: usize| size as f64 / stats.input_size.max(1) as f64 * 100.0;
+
+    eprintln!(
+        "cmprs: packed {} to {}",
+        stats.input,
+        paint(&stats.output, Style::new().bold())
+    );
+    eprintln!("  {} {}", label("input"), bytes(stats.input_size));
+    if stats.stub_size > 0 {
+        eprintln!("  {} {}", label("stub"), bytes(stats.stub_size));
+    }
+    let algorithm = match stats.algorithm {
+        "none" => "stored uncompressed".to_string(),
+        algorithm => format!("{algorithm} level {}", stats.level),
+    };
+    eprintln!(
+        "  {} {}  {:.1}% of the input, {algorithm}",
+        label("payload"),
+        bytes(stats.compressed_size),
+        of_input(stats.compressed_size),
+    );
+    // Largest first, to show which file dominates the bundle
+    let mut files: Vec<_> = stats.files.iter().collect();
+    files.sort_by_key(|file| std::cmp::Reverse(file.compressed_size));
+    let files_total = files.iter().map(|file| file.compressed_size).sum::<u64>();
+    for file in files {
+        eprintln!(
+            "  {} {}  {:.1}% of the payload, {} from {} bytes",
+            label("file"),
+            bytes(file.compressed_size as usize),
+            file.compressed_size as f64 / files_total.max(1) as f64 * 100.0,
+            paint(&file.path, Style::new().bold()),
+            file.size,
+        );
+    }
+    eprintln!("  {} {}", label("output"), bytes(stats.output_size));
+
+    let (difference, style) = if stats.output_size < stats.input_size {
+        let saved = stats.input_size - stats.output_size;
+        let text = format!(
+            "saved {saved} bytes ({:.1}%)",
+            100.0 - of_input(stats.output_size)
+        );
+        (text, Style::new().green())
+    } else {
+        let grown = stats.output_size - stats.input_size;
+        let text = format!(
+            "grew by {grown} bytes (+{:.1}%)",
+            of_input(stats.output_size) - 100.0
+        );
+        (text, Style::new().red())
+    };
+    eprintln!("  {} {}", label("net"), paint(difference, style));
+
+    let timings = &stats.timings_ms;
+    eprintln!(
+        "  {} {:.1}ms (read {:.1}ms, hash {:.1}ms, compress {:.1}ms, write {:.1}ms)",
+        label("took"),
+        timings.total,
+        timings.read,
+        timings.hash,
+        timings.compress,
+        timings.write
+    );
+    if let Some(peak_rss) = stats.peak_rss {
+        eprintln!(
+            "  {} {:.1} MiB peak resident",
+            label("memory"),
+            peak_rss as f64 / 1_048_576.0
+        );
+    }
+}
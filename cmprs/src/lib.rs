@@ -0,0 +1,914 @@
+//! Building blocks of the `cmprs` packer, for embedding it in other tools.
+//!
+//! The on-disk layout itself lives in the `cmprs-format` crate, re-exported
+//! here as [`format`].
+
+#[cfg(feature = "gzip")]
+use flate2::write::GzEncoder;
+use log::debug;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+#[cfg(feature = "xz")]
+use xz2::write::XzEncoder;
+use zstd::stream::write::Encoder as ZstdEncoder;
+use zstd::zstd_safe::CParameter;
+
+pub use cmprs_format as format;
+pub use cmprs_format::{Algorithm, CmprsError};
+pub use pack::{pack, recompress, upgrade_format, PackReport};
+pub use unpack::{read_packed, unpack_to, PackedFile, PayloadReader};
+
+mod pack;
+mod unpack;
+
+/// Whether this build packs and unpacks `algorithm`. xz and gzip are behind
+/// the `xz` and `gzip` cargo features, on by default, and the others are
+/// always there.
+///
+/// ```
+/// use cmprs::{Algorithm, CmprsError, PackOptions};
+///
+/// assert!(cmprs::is_compiled_in(Algorithm::Zstd));
+/// let gzip = PackOptions::builder().algorithm(Algorithm::Gzip).build();
+/// if cmprs::is_compiled_in(Algorithm::Gzip) {
+///     assert!(gzip.is_ok());
+/// } else {
+///     assert!(matches!(gzip, Err(CmprsError::AlgorithmNotCompiled(Algorithm::Gzip))));
+/// }
+/// ```
+pub fn is_compiled_in(algorithm: Algorithm) -> bool {
+    match algorithm {
+        Algorithm::Zstd | Algorithm::None => true,
+        Algorithm::Xz => cfg!(feature = "xz"),
+        Algorithm::Gzip => cfg!(feature = "gzip"),
+    }
+}
+
+/// This version of cmprs, recorded in the header of every file it packs
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub const DEFAULT_LEVEL: i32 = 3;
+pub const MIN_LEVEL: i32 = 1;
+pub const MAX_LEVEL: i32 = 22;
+/// Highest zstd level before the ones `zstd --ultra` unlocks, whose larger
+/// windows make dcmprs need that much more memory to decompress
+pub const BEST_LEVEL: i32 = 19;
+/// Negative levels, down to this one, are zstd's fast mode (`zstd --fast=N`):
+/// faster than level 1 and compressing less the lower they go
+pub const MIN_FAST_LEVEL: i32 = -22;
+
+/// xz presets, used as the level with [`Algorithm::Xz`]
+pub const XZ_DEFAULT_PRESET: i32 = 6;
+pub const XZ_MAX_PRESET: i32 = 9;
+
+/// gzip levels, used as the level with [`Algorithm::Gzip`]
+pub const GZIP_DEFAULT_LEVEL: i32 = 6;
+pub const GZIP_MAX_LEVEL: i32 = 9;
+
+/// A level named for what it's for rather than by number, which each
+/// algorithm maps to its own
+///
+/// ```
+/// use cmprs::{Algorithm, LevelAlias, PackOptions};
+///
+/// assert_eq!(LevelAlias::Best.level(Algorithm::Zstd), Some(cmprs::BEST_LEVEL));
+/// assert_eq!(LevelAlias::Best.level(Algorithm::Gzip), Some(cmprs::GZIP_MAX_LEVEL));
+/// assert_eq!(LevelAlias::Fast.level(Algorithm::Xz), Some(0));
+/// assert_eq!(LevelAlias::Default.level(Algorithm::None), None);
+///
+/// for algorithm in [Algorithm::Zstd, Algorithm::Xz, Algorithm::Gzip, Algorithm::None] {
+///     # if !cmprs::is_compiled_in(algorithm) { continue; }
+///     for alias in [LevelAlias::Fast, LevelAlias::Default, LevelAlias::Best] {
+///         let options = PackOptions::builder().algorithm(algorithm).level_alias(alias).build().unwrap();
+///         assert_eq!(options.level(), alias.level(algorithm).unwrap_or(0));
+///     }
+/// }
+/// assert_eq!("best".parse::<LevelAlias>(), Ok(LevelAlias::Best));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LevelAlias {
+    /// The quickest level that still compresses
+    Fast,
+    /// The algorithm's own default
+    Default,
+    /// The strongest level, short of zstd's ultra ones
+    Best,
+}
+
+impl LevelAlias {
+    /// The level this stands for with `algorithm`, or `None` for a stored
+    /// payload, which has no level
+    pub fn level(self, algorithm: Algorithm) -> Option<i32> {
+        match (algorithm, self) {
+            (Algorithm::None, _) => None,
+            (Algorithm::Zstd, LevelAlias::Fast) => Some(MIN_LEVEL),
+            (Algorithm::Zstd, LevelAlias::Default) => Some(DEFAULT_LEVEL),
+            (Algorithm::Zstd, LevelAlias::Best) => Some(BEST_LEVEL),
+            (Algorithm::Xz, LevelAlias::Fast) => Some(0),
+            (Algorithm::Xz, LevelAlias::Default) => Some(XZ_DEFAULT_PRESET),
+            (Algorithm::Xz, LevelAlias::Best) => Some(XZ_MAX_PRESET),
+            (Algorithm::Gzip, LevelAlias::Fast) => Some(1),
+            (Algorithm::Gzip, LevelAlias::Default) => Some(GZIP_DEFAULT_LEVEL),
+            (Algorithm::Gzip, LevelAlias::Best) => Some(GZIP_MAX_LEVEL),
+        }
+    }
+}
+
+impl std::str::FromStr for LevelAlias {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, String> {
+        match name {
+            "fast" => Ok(LevelAlias::Fast),
+            "default" => Ok(LevelAlias::Default),
+            "best" => Ok(LevelAlias::Best),
+            _ => Err(format!(
+                "'{name}' is not a level alias, expected fast, default or best"
+            )),
+        }
+    }
+}
+
+// liblzma's LZMA_PRESET_EXTREME, or'ed into the preset
+#[cfg(feature = "xz")]
+const XZ_PRESET_EXTREME: u32 = 1 << 31;
+
+/// How much input is fed to the encoder at a time unless
+/// [`PackOptionsBuilder::chunk_size`] says otherwise. Progress is reported
+/// and the time limit checked after each chunk. Packing an 84 MB binary at
+/// zstd levels 3 and 19, 16 KiB to 256 KiB chunks were within noise of each
+/// other and 1 MiB and up a few percent slower, the encoder buffers
+/// internally anyway, so this stays small.
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Smallest zstd target block size [`PackOptionsBuilder::block_size`] takes,
+/// zstd's own minimum, which fits a compressed block in a network packet
+pub const MIN_BLOCK_SIZE: u32 = 1340;
+
+/// Largest zstd target block size, the size of a whole zstd block
+pub const MAX_BLOCK_SIZE: u32 = 128 * 1024;
+
+/// Largest dictionary [`train_dictionary`] trains, zstd's own default
+pub const MAX_DICTIONARY_SIZE: usize = 112_640;
+
+// Size of the blocks of input a dictionary is trained on, and how much of the
+// input to sample at most, as zstd suggests, in multiples of the dictionary size
+const DICTIONARY_SAMPLE_SIZE: usize = 4096;
+const DICTIONARY_SAMPLES_PER_SIZE: usize = 100;
+
+/// How an input gets packed. Created with [`PackOptions::builder`].
+///
+/// ```
+/// use cmprs::{Algorithm, PackOptions};
+///
+/// let options = PackOptions::builder().level(19).build().unwrap();
+/// assert_eq!(options.level(), 19);
+///
+/// // Defaults: zstd at level 3, setuid/setgid bits kept
+/// let defaults = PackOptions::default();
+/// assert_eq!(defaults.algorithm(), Algorithm::Zstd);
+/// assert_eq!(defaults.level(), 3);
+/// assert!(defaults.preserve_suid());
+///
+/// assert!(PackOptions::builder().level(23).build().is_err());
+///
+/// // Negative levels are zstd's fast mode, like `zstd --fast=5`
+/// assert_eq!(PackOptions::builder().level(-5).build().unwrap().level(), -5);
+/// assert!(PackOptions::builder().level(-23).build().is_err());
+///
+/// // xz takes a preset, 0-9, as its level, like `xz -9e`
+/// # #[cfg(feature = "xz")] {
+/// let xz = PackOptions::builder()
+///     .algorithm(Algorithm::Xz)
+///     .level(9)
+///     .xz_extreme(true)
+///     .build()
+///     .unwrap();
+/// assert_eq!(xz.level(), 9);
+/// assert!(PackOptions::builder().algorithm(Algorithm::Xz).level(10).build().is_err());
+/// # }
+///
+/// // Each algorithm checks the level against its own range, and says so
+/// # #[cfg(feature = "gzip")] {
+/// let err = PackOptions::builder().algorithm(Algorithm::Gzip).level(22).build().unwrap_err();
+/// assert_eq!(err.to_string(), "gzip level must be between 1 and 9, got 22");
+/// # }
+/// let err = PackOptions::builder().level(23).build().unwrap_err();
+/// assert!(err.to_string().starts_with("zstd level must be between 1 and 22"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackOptions {
+    level: i32,
+    algorithm: Algorithm,
+    preserve_suid: bool,
+    xz_extreme: bool,
+    time_limit: Option<Duration>,
+    dictionary: Option<Vec<u8>>,
+    chunk_size: usize,
+    workers: u32,
+    block_size: Option<u32>,
+}
+
+impl PackOptions {
+    pub fn builder() -> PackOptionsBuilder {
+        PackOptionsBuilder::default()
+    }
+
+    /// Compression level (the preset for xz), or 0 when the payload is stored uncompressed
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Whether the setuid and setgid bits of the input are stored in the header
+    pub fn preserve_suid(&self) -> bool {
+        self.preserve_suid
+    }
+
+    /// Whether xz uses the extreme variant of its preset
+    pub fn xz_extreme(&self) -> bool {
+        self.xz_extreme
+    }
+
+    /// How long compressing may take before it's abandoned
+    pub fn time_limit(&self) -> Option<Duration> {
+        self.time_limit
+    }
+
+    /// zstd dictionary the payload is compressed with
+    pub fn dictionary(&self) -> Option<&[u8]> {
+        self.dictionary.as_deref()
+    }
+
+    /// How much input is fed to the encoder at a time
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// How many threads zstd compresses on, or 0 for the calling thread alone
+    pub fn workers(&self) -> u32 {
+        self.workers
+    }
+
+    /// Size zstd aims to keep each compressed block under, if any
+    pub fn block_size(&self) -> Option<u32> {
+        self.block_size
+    }
+
+    /// The permission bits to record for an input with `mode`
+    pub fn stored_mode(&self, mode: u32) -> u32 {
+        let mode = mode & 0o7777;
+        if self.preserve_suid {
+            mode
+        } else {
+            mode & !0o6000
+        }
+    }
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        PackOptions::builder()
+            .build()
+            .expect("default pack options are valid")
+    }
+}
+
+/// Builder for [`PackOptions`], validated by [`PackOptionsBuilder::build`]
+#[derive(Debug, Clone)]
+pub struct PackOptionsBuilder {
+    level: Option<i32>,
+    level_alias: Option<LevelAlias>,
+    algorithm: Algorithm,
+    preserve_suid: bool,
+    xz_extreme: bool,
+    time_limit: Option<Duration>,
+    dictionary: Option<Vec<u8>>,
+    chunk_size: usize,
+    workers: u32,
+    block_size: Option<u32>,
+}
+
+impl Default for PackOptionsBuilder {
+    fn default() -> Self {
+        PackOptionsBuilder {
+            level: None,
+            level_alias: None,
+            algorithm: Algorithm::Zstd,
+            preserve_suid: true,
+            xz_extreme: false,
+            time_limit: None,
+            dictionary: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            workers: 0,
+            block_size: None,
+        }
+    }
+}
+
+impl PackOptionsBuilder {
+    /// Compression level, [`MIN_LEVEL`] to [`MAX_LEVEL`], or [`MIN_FAST_LEVEL`]
+    /// to -1 for zstd's fast mode. Defaults to [`DEFAULT_LEVEL`].
+    /// For xz it's the preset, 0 to [`XZ_MAX_PRESET`], defaulting to [`XZ_DEFAULT_PRESET`].
+    pub fn level(mut self, level: i32) -> Self {
+        self.level = Some(level);
+        self.level_alias = None;
+        self
+    }
+
+    /// Compress at the level `alias` stands for with the algorithm, see
+    /// [`LevelAlias::level`]. Replaces [`PackOptionsBuilder::level`] and is
+    /// replaced by it, whichever comes last.
+    pub fn level_alias(mut self, alias: LevelAlias) -> Self {
+        self.level = None;
+        self.level_alias = Some(alias);
+        self
+    }
+
+    /// Defaults to [`Algorithm::Zstd`]
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Defaults to `true`
+    pub fn preserve_suid(mut self, preserve_suid: bool) -> Self {
+        self.preserve_suid = preserve_suid;
+        self
+    }
+
+    /// Use the extreme variant of the xz preset (`xz -e`), slower for a
+    /// slightly smaller payload. Defaults to `false`.
+    pub fn xz_extreme(mut self, xz_extreme: bool) -> Self {
+        self.xz_extreme = xz_extreme;
+        self
+    }
+
+    /// Make compressing fail with [`io::ErrorKind::TimedOut`] once it has taken
+    /// longer than `time_limit`. It's checked between chunks of input, see
+    /// [`PackOptionsBuilder::chunk_size`].
+    ///
+    /// ```
+    /// use cmprs::PackOptions;
+    /// use std::io::ErrorKind;
+    /// use std::time::Duration;
+    ///
+    /// let options = PackOptions::builder().time_limit(Duration::ZERO).build().unwrap();
+    /// let err = cmprs::compress(&vec![7u8; 1_000_000], &options).unwrap_err();
+    /// assert_eq!(err.kind(), ErrorKind::TimedOut);
+    /// ```
+    pub fn time_limit(mut self, time_limit: Duration) -> Self {
+        self.time_limit = Some(time_limit);
+        self
+    }
+
+    /// Compress with this zstd dictionary, like one from [`train_dictionary`].
+    /// The dictionary is needed to decompress, so it's stored in the header.
+    pub fn dictionary(mut self, dictionary: Vec<u8>) -> Self {
+        self.dictionary = Some(dictionary);
+        self
+    }
+
+    /// Feed the encoder `chunk_size` bytes of input at a time. Larger chunks
+    /// mean fewer calls into the encoder, but less frequent progress reports
+    /// and time limit checks. The payload is the same either way. Defaults
+    /// to [`DEFAULT_CHUNK_SIZE`].
+    ///
+    /// ```
+    /// use cmprs::{Algorithm, PackOptions};
+    ///
+    /// let input: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251 ^ i / 4093) as u8).collect();
+    /// # let algorithms = [Algorithm::Zstd, Algorithm::Xz].into_iter().filter(|&a| cmprs::is_compiled_in(a));
+    /// for algorithm in algorithms {
+    ///     let options = |chunk_size| {
+    ///         PackOptions::builder().algorithm(algorithm).chunk_size(chunk_size).build().unwrap()
+    ///     };
+    ///     let payload = cmprs::compress(&input, &options(cmprs::DEFAULT_CHUNK_SIZE)).unwrap();
+    ///     for chunk_size in [1, 4096, 1 << 20, 16 << 20] {
+    ///         assert_eq!(cmprs::compress(&input, &options(chunk_size)).unwrap(), payload);
+    ///     }
+    /// }
+    /// assert!(PackOptions::builder().chunk_size(0).build().is_err());
+    /// ```
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Compress with zstd on `workers` threads, each taking a job of a few
+    /// MiB of input at a time. zstd splits the input into jobs the same way
+    /// whatever the number of workers, so the payload is the same for any
+    /// count above 0, though not the same as with 0, the default, which
+    /// compresses on the calling thread as a single job.
+    ///
+    /// ```
+    /// use cmprs::PackOptions;
+    ///
+    /// let input: Vec<u8> = (0..12_000_000u32).map(|i| (i % 251 ^ i / 4093) as u8).collect();
+    /// let options = |workers| PackOptions::builder().workers(workers).build().unwrap();
+    /// let payload = cmprs::compress(&input, &options(1)).unwrap();
+    /// for workers in [4, 8] {
+    ///     assert_eq!(cmprs::compress(&input, &options(workers)).unwrap(), payload);
+    /// }
+    /// assert_eq!(zstd::decode_all(&payload[..]).unwrap(), input);
+    /// ```
+    pub fn workers(mut self, workers: u32) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Have zstd aim to keep each compressed block under `block_size` bytes,
+    /// [`MIN_BLOCK_SIZE`] to [`MAX_BLOCK_SIZE`], instead of leaving blocks
+    /// at up to 128 KiB of input. A decoder can hand out the start of the
+    /// input once the first block is in, so smaller blocks get to the first
+    /// byte sooner, for a loader that streams the program as it decompresses.
+    /// Each block starts its entropy tables over, which costs some ratio:
+    /// packing cmprs itself (8.5 MB), 1% at the smallest size and next to
+    /// nothing at 32 KiB. Decoders read the blocks as they
+    /// are, so nothing about it is recorded.
+    ///
+    /// ```
+    /// use cmprs::{PackOptions, MIN_BLOCK_SIZE};
+    ///
+    /// let input: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251 ^ i / 4093) as u8).collect();
+    /// let options = PackOptions::builder().block_size(MIN_BLOCK_SIZE).build().unwrap();
+    /// let small_blocks = cmprs::compress(&input, &options).unwrap();
+    /// assert_eq!(zstd::decode_all(&small_blocks[..]).unwrap(), input);
+    ///
+    /// // Smaller blocks trade some ratio for latency
+    /// let default = cmprs::compress(&input, &PackOptions::default()).unwrap();
+    /// assert!(small_blocks.len() > default.len());
+    ///
+    /// assert!(PackOptions::builder().block_size(512).build().is_err());
+    /// ```
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.block_size = Some(block_size);
+        self
+    }
+
+    pub fn build(self) -> Result<PackOptions, CmprsError> {
+        if !is_compiled_in(self.algorithm) {
+            return Err(CmprsError::AlgorithmNotCompiled(self.algorithm));
+        }
+        if self.chunk_size == 0 {
+            return Err(invalid_options(
+                "the chunk size must be at least 1 byte".to_string(),
+            ));
+        }
+        if self.dictionary.is_some() && self.algorithm != Algorithm::Zstd {
+            return Err(invalid_options(
+                "a dictionary only applies to zstd".to_string(),
+            ));
+        }
+        if self.workers > 0 && self.algorithm != Algorithm::Zstd {
+            return Err(invalid_options(
+                "compression threads only apply to zstd".to_string(),
+            ));
+        }
+        if let Some(block_size) = self.block_size {
+            if self.algorithm != Algorithm::Zstd {
+                return Err(invalid_options(
+                    "a block size only applies to zstd".to_string(),
+                ));
+            }
+            if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+                return Err(invalid_options(format!(
+                    "the block size must be between {MIN_BLOCK_SIZE} and {MAX_BLOCK_SIZE} bytes, got {block_size}"
+                )));
+            }
+        }
+        if self.xz_extreme && self.algorithm != Algorithm::Xz {
+            return Err(invalid_options(
+                "the extreme preset only applies to xz".to_string(),
+            ));
+        }
+        let level = self.level.or_else(|| {
+            self.level_alias
+                .and_then(|alias| alias.level(self.algorithm))
+        });
+        let level = match (self.algorithm, level) {
+            (Algorithm::None, None) => 0,
+            (Algorithm::None, Some(level)) => {
+                return Err(invalid_options(format!(
+                    "compression level {level} doesn't apply to a stored payload"
+                )))
+            }
+            (Algorithm::Zstd, level) => {
+                let level = level.unwrap_or(DEFAULT_LEVEL);
+                if !(MIN_LEVEL..=MAX_LEVEL).contains(&level)
+                    && !(MIN_FAST_LEVEL..=-1).contains(&level)
+                {
+                    return Err(invalid_options(format!(
+                        "zstd level must be between {MIN_LEVEL} and {MAX_LEVEL}, \
+                         or {MIN_FAST_LEVEL} to -1 for its fast mode, got {level}"
+                    )));
+                }
+                level
+            }
+            (Algorithm::Gzip, level) => {
+                let level = level.unwrap_or(GZIP_DEFAULT_LEVEL);
+                if !(1..=GZIP_MAX_LEVEL).contains(&level) {
+                    return Err(invalid_options(format!(
+                        "gzip level must be between 1 and {GZIP_MAX_LEVEL}, got {level}"
+                    )));
+                }
+                level
+            }
+            (Algorithm::Xz, preset) => {
+                let preset = preset.unwrap_or(XZ_DEFAULT_PRESET);
+                if !(0..=XZ_MAX_PRESET).contains(&preset) {
+                    return Err(invalid_options(format!(
+                        "xz preset must be between 0 and {XZ_MAX_PRESET}, got {preset}"
+                    )));
+                }
+                preset
+            }
+        };
+
+        Ok(PackOptions {
+            level,
+            algorithm: self.algorithm,
+            preserve_suid: self.preserve_suid,
+            xz_extreme: self.xz_extreme,
+            time_limit: self.time_limit,
+            dictionary: self.dictionary,
+            chunk_size: self.chunk_size,
+            workers: self.workers,
+            block_size: self.block_size,
+        })
+    }
+}
+
+/// Compress `input` into the payload stored after the header. zstd frames
+/// record their decompressed size and end with a checksum of their content.
+///
+/// ```
+/// use cmprs::PackOptions;
+///
+/// let input = b"#!/bin/sh\necho hi\n".repeat(1000);
+/// let payload = cmprs::compress(&input, &PackOptions::default()).unwrap();
+/// let content_size = zstd::zstd_safe::get_frame_content_size(&payload).unwrap();
+/// assert_eq!(content_size, Some(input.len() as u64));
+///
+/// // The checksum catches corruption that still decodes
+/// let mut corrupted = payload.clone();
+/// let last = corrupted.len() - 1;
+/// corrupted[last] ^= 0xff;
+/// assert!(zstd::decode_all(&corrupted[..]).is_err());
+/// ```
+///
+/// A gzip payload is a standard gzip stream, as `gunzip` and any other gzip
+/// reader expect:
+///
+/// ```
+/// # #[cfg(feature = "gzip")] {
+/// use cmprs::{Algorithm, PackOptions};
+/// use flate2::read::MultiGzDecoder;
+/// use std::io::Read;
+///
+/// let input = b"#!/bin/sh\necho hi\n".repeat(1000);
+/// let options = PackOptions::builder().algorithm(Algorithm::Gzip).level(9).build().unwrap();
+/// let payload = cmprs::compress(&input, &options).unwrap();
+/// assert_eq!(&payload[..2], b"\x1f\x8b");
+/// let mut decompressed = Vec::new();
+/// MultiGzDecoder::new(&payload[..]).read_to_end(&mut decompressed).unwrap();
+/// assert_eq!(decompressed, input);
+/// # }
+/// ```
+pub fn compress(input: &[u8], options: &PackOptions) -> io::Result<Vec<u8>> {
+    compress_with_progress(input, options, |_, _| {})
+}
+
+/// Like [`compress`], calling `progress(bytes_done, bytes_total)` each time a
+/// chunk of `input` has been fed to the encoder
+///
+/// ```
+/// use cmprs::PackOptions;
+///
+/// let input = vec![7u8; 1_000_000];
+/// let mut calls = Vec::new();
+/// cmprs::compress_with_progress(&input, &PackOptions::default(), |done, total| {
+///     calls.push((done, total));
+/// })
+/// .unwrap();
+///
+/// assert!(calls.windows(2).all(|pair| pair[0].0 < pair[1].0));
+/// assert!(calls.iter().all(|&(_, total)| total == input.len() as u64));
+/// assert_eq!(calls.last().unwrap().0, input.len() as u64);
+/// ```
+pub fn compress_with_progress(
+    input: &[u8],
+    options: &PackOptions,
+    progress: impl FnMut(u64, u64),
+) -> io::Result<Vec<u8>> {
+    let mut compressed = Vec::new();
+    compress_to(input, options, &mut compressed, progress)?;
+    Ok(compressed)
+}
+
+/// Moving average of the rate bytes are processed at, over the last `window`
+/// of progress, to estimate how long the rest will take. The rate of a long
+/// compression changes with the input, so only recent progress is counted.
+///
+/// ```
+/// use cmprs::Throughput;
+/// use std::time::{Duration, Instant};
+///
+/// // A feed that slows from 4MB/s to 1MB/s, one sample a second
+/// let start = Instant::now();
+/// let mut throughput = Throughput::new(Duration::from_secs(3));
+/// assert_eq!(throughput.bytes_per_sec(), None);
+/// let mut done = 0;
+/// for second in 0..=10u64 {
+///     throughput.record(done, start + Duration::from_secs(second));
+///     done += if second < 5 { 4_000_000 } else { 1_000_000 };
+/// }
+///
+/// // Only the slow seconds are in the window
+/// let rate = throughput.bytes_per_sec().unwrap();
+/// assert!((rate - 1_000_000.0).abs() < 1.0);
+/// let eta = throughput.eta(done - 1_000_000 + 30_000_000).unwrap();
+/// assert_eq!(eta.as_secs(), 30);
+/// assert_eq!(throughput.eta(0), Some(Duration::ZERO));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Throughput {
+    window: Duration,
+    /// (when, bytes done by then), oldest first
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Throughput {
+    pub fn new(window: Duration) -> Self {
+        Throughput {
+            window,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Record that `done` bytes had been processed `at` that time
+    pub fn record(&mut self, done: u64, at: Instant) {
+        self.samples.push_back((at, done));
+        // Keep the newest sample at least `window` old, so the average spans all of it
+        while self.samples.len() > 2
+            && at.saturating_duration_since(self.samples[1].0) >= self.window
+        {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes processed per second over the window, once there's been progress to measure
+    pub fn bytes_per_sec(&self) -> Option<f64> {
+        let (first_at, first_done) = *self.samples.front()?;
+        let (last_at, last_done) = *self.samples.back()?;
+        let elapsed = last_at.saturating_duration_since(first_at).as_secs_f64();
+        if elapsed <= 0.0 || last_done <= first_done {
+            return None;
+        }
+        Some((last_done - first_done) as f64 / elapsed)
+    }
+
+    /// How long the bytes up to `total` will take at the current rate
+    pub fn eta(&self, total: u64) -> Option<Duration> {
+        let (_, done) = *self.samples.back()?;
+        let remaining = total.saturating_sub(done);
+        if remaining == 0 {
+            return Some(Duration::ZERO);
+        }
+        Some(Duration::from_secs_f64(
+            remaining as f64 / self.bytes_per_sec()?,
+        ))
+    }
+}
+
+/// Stream the payload for `input` into `out` as it's compressed, instead of
+/// holding all of it in memory, and return its length
+///
+/// ```
+/// use cmprs::PackOptions;
+///
+/// let input = b"#!/bin/sh\necho hi\n".repeat(100);
+/// let mut out = Vec::new();
+/// let len = cmprs::compress_to(&input, &PackOptions::default(), &mut out, |_, _| {}).unwrap();
+/// assert_eq!(len, out.len() as u64);
+/// assert_eq!(out, cmprs::compress(&input, &PackOptions::default()).unwrap());
+/// ```
+pub fn compress_to(
+    input: &[u8],
+    options: &PackOptions,
+    out: &mut impl Write,
+    progress: impl FnMut(u64, u64),
+) -> io::Result<u64> {
+    let frames = compress_frames_to(input, &[input.len() as u64], options, out, progress)?;
+    Ok(frames[0])
+}
+
+/// Like [`compress_to`], but compress each of the consecutive `frame_sizes`
+/// byte ranges of `input` as a frame of its own, which decompresses without
+/// the ones before it. Returns the compressed length of each frame. One after
+/// the other, the frames decompress to `input` like a single frame would.
+///
+/// ```
+/// use cmprs::PackOptions;
+/// use std::io::Read;
+///
+/// let input = [b"first file\n".repeat(10), b"second file\n".repeat(10)].concat();
+/// let mut out = Vec::new();
+/// let frames =
+///     cmprs::compress_frames_to(&input, &[110, 120], &PackOptions::default(), &mut out, |_, _| {})
+///         .unwrap();
+/// assert_eq!(frames.iter().sum::<u64>(), out.len() as u64);
+///
+/// let mut second = Vec::new();
+/// zstd::Decoder::new(&out[frames[0] as usize..]).unwrap().read_to_end(&mut second).unwrap();
+/// assert_eq!(second, &input[110..]);
+/// ```
+pub fn compress_frames_to(
+    input: &[u8],
+    frame_sizes: &[u64],
+    options: &PackOptions,
+    out: &mut impl Write,
+    mut progress: impl FnMut(u64, u64),
+) -> io::Result<Vec<u64>> {
+    let total = input.len() as u64;
+    if frame_sizes.iter().sum::<u64>() != total {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame sizes don't add up to the {total} byte input"),
+        ));
+    }
+    let mut out = CountingWriter {
+        inner: out,
+        written: 0,
+    };
+    let deadline = Deadline {
+        start: Instant::now(),
+        time_limit: options.time_limit,
+    };
+    let chunk_size = options.chunk_size;
+
+    let mut frames = Vec::with_capacity(frame_sizes.len());
+    let mut start = 0;
+    for &size in frame_sizes {
+        let frame = &input[start as usize..(start + size) as usize];
+        let mut progress = |done, _| progress(start + done, total);
+        let frame_start = out.written;
+        match options.algorithm {
+            Algorithm::None => {
+                out.write_all(frame)?;
+                progress(size, size);
+            }
+            Algorithm::Zstd => {
+                let dictionary = options.dictionary.as_deref().unwrap_or_default();
+                let mut encoder =
+                    ZstdEncoder::with_dictionary(&mut out, options.level, dictionary)?;
+                // Make the frame self-describing: `zstd -l` and decoders see
+                // its size up front, and a corrupted frame fails to decode
+                encoder.set_pledged_src_size(Some(size))?;
+                encoder.include_contentsize(true)?;
+                encoder.include_checksum(true)?;
+                if options.workers > 0 {
+                    encoder.multithread(options.workers)?;
+                }
+                if let Some(block_size) = options.block_size {
+                    encoder.set_parameter(CParameter::TargetCBlockSize(block_size))?;
+                }
+                write_chunks(&mut encoder, frame, chunk_size, &mut progress, &deadline)?;
+                encoder.finish()?;
+            }
+            #[cfg(feature = "xz")]
+            Algorithm::Xz => {
+                let mut preset = options.level as u32;
+                if options.xz_extreme {
+                    preset |= XZ_PRESET_EXTREME;
+                }
+                let mut encoder = XzEncoder::new(&mut out, preset);
+                write_chunks(&mut encoder, frame, chunk_size, &mut progress, &deadline)?;
+                encoder.finish()?;
+            }
+            // A gzip member per frame, which gunzip reads one after the other
+            // like `cat a.gz b.gz`. No name or mtime, so the output is reproducible.
+            #[cfg(feature = "gzip")]
+            Algorithm::Gzip => {
+                let level = flate2::Compression::new(options.level as u32);
+                let mut encoder = GzEncoder::new(&mut out, level);
+                write_chunks(&mut encoder, frame, chunk_size, &mut progress, &deadline)?;
+                encoder.finish()?;
+            }
+            // PackOptions::build refuses them
+            #[allow(unreachable_patterns)]
+            algorithm => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    CmprsError::AlgorithmNotCompiled(algorithm),
+                ))
+            }
+        }
+        frames.push(out.written - frame_start);
+        start += size;
+    }
+    Ok(frames)
+}
+
+/// Train a zstd dictionary of at most [`MAX_DICTIONARY_SIZE`] bytes on blocks
+/// sampled evenly across `input`. Fails when the input is too small or too
+/// uniform to train on.
+///
+/// ```
+/// use cmprs::PackOptions;
+///
+/// // Repetitive, but not the same block over and over
+/// let input: Vec<u8> = (0..20_000u32)
+///     .flat_map(|i| format!("entry {} of the table: {}\n", i % 97, i * 7 % 13).into_bytes())
+///     .collect();
+/// let dictionary = cmprs::train_dictionary(&input).unwrap();
+/// assert!(dictionary.len() <= cmprs::MAX_DICTIONARY_SIZE);
+///
+/// let options = PackOptions::builder().dictionary(dictionary).build().unwrap();
+/// let header = cmprs::format::Header::default();
+/// let (packed, _) = cmprs::pack(b"stub", &input, &header, &options).unwrap();
+/// let mut unpacked = Vec::new();
+/// let header = cmprs::unpack_to(&packed, &mut unpacked).unwrap();
+/// assert!(header.dictionary.is_some());
+/// assert_eq!(unpacked, input);
+/// ```
+pub fn train_dictionary(input: &[u8]) -> io::Result<Vec<u8>> {
+    let blocks = input.len() / DICTIONARY_SAMPLE_SIZE;
+    let max_samples = MAX_DICTIONARY_SIZE * DICTIONARY_SAMPLES_PER_SIZE / DICTIONARY_SAMPLE_SIZE;
+    let step = blocks.div_ceil(max_samples).max(1);
+    let samples: Vec<&[u8]> = input
+        .chunks_exact(DICTIONARY_SAMPLE_SIZE)
+        .step_by(step)
+        .collect();
+    debug!(
+        "Training a dictionary on {} samples of {} bytes",
+        samples.len(),
+        DICTIONARY_SAMPLE_SIZE
+    );
+    zstd::dict::from_samples(&samples, MAX_DICTIONARY_SIZE)
+}
+
+/// When compressing started and how long it may take
+struct Deadline {
+    start: Instant,
+    time_limit: Option<Duration>,
+}
+
+/// Feed `input` to `encoder` in chunks, reporting progress after each one and
+/// giving up once the deadline has passed
+fn write_chunks(
+    encoder: &mut impl Write,
+    input: &[u8],
+    chunk_size: usize,
+    progress: &mut impl FnMut(u64, u64),
+    deadline: &Deadline,
+) -> io::Result<()> {
+    let total = input.len() as u64;
+    let mut done = 0;
+    for (i, chunk) in input.chunks(chunk_size).enumerate() {
+        encoder.write_all(chunk)?;
+        done += chunk.len() as u64;
+        progress(done, total);
+        if let Some(time_limit) = deadline.time_limit {
+            if deadline.start.elapsed() > time_limit {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "compression took longer than the {}s time limit",
+                        time_limit.as_secs_f64()
+                    ),
+                ));
+            }
+        }
+        if i % 100 == 0 {
+            debug!("Compression: processed {} MB", done / 1_048_576);
+        }
+    }
+    Ok(())
+}
+
+/// Counts the bytes written through it
+struct CountingWriter<'a, W> {
+    inner: &'a mut W,
+    written: u64,
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn invalid_options(message: String) -> CmprsError {
+    CmprsError::InvalidOptions(message)
+}
@@ -0,0 +1,77 @@
+use goblin::elf::Elf;
+use goblin::mach::{Mach, MachO, SingleArch};
+use log::{debug, info};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+// Every macOS install has these, so they aren't worth pointing out
+const MACOS_SYSTEM_PREFIXES: &[&str] = &["/usr/lib/", "/System/Library/"];
+
+/// How an executable input finds its code at runtime
+#[derive(Debug, PartialEq, Eq)]
+enum Linkage {
+    Static,
+    /// Shared libraries the machine it runs on has to provide
+    Dynamic(Vec<String>),
+}
+
+/// The linkage of `input` if it's an ELF or Mach-O executable, or `None` for
+/// anything else, like a script
+fn linkage(input: &[u8]) -> Option<Linkage> {
+    if input.starts_with(ELF_MAGIC) {
+        let elf = Elf::parse(input)
+            .inspect_err(|err| debug!("Can't parse the input as ELF: {err}"))
+            .ok()?;
+        if elf.interpreter.is_none() && elf.libraries.is_empty() {
+            return Some(Linkage::Static);
+        }
+        let libraries = elf.libraries.iter().map(|lib| lib.to_string()).collect();
+        return Some(Linkage::Dynamic(libraries));
+    }
+    let libraries = match Mach::parse(input) {
+        Ok(Mach::Binary(macho)) => macho_libraries(&macho),
+        // The architectures of a universal binary link the same libraries
+        Ok(Mach::Fat(multi)) => match multi.get(0) {
+            Ok(SingleArch::MachO(macho)) => macho_libraries(&macho),
+            _ => return None,
+        },
+        Err(err) => {
+            debug!("Input isn't an executable we can inspect: {err}");
+            return None;
+        }
+    };
+    Some(Linkage::Dynamic(libraries))
+}
+
+/// The dylibs `macho` loads that don't ship with macOS itself
+fn macho_libraries(macho: &MachO) -> Vec<String> {
+    macho
+        .libs
+        .iter()
+        // goblin lists the binary itself first
+        .filter(|lib| **lib != "self")
+        .filter(|lib| {
+            !MACOS_SYSTEM_PREFIXES
+                .iter()
+                .any(|prefix| lib.starts_with(prefix))
+        })
+        .map(|lib| lib.to_string())
+        .collect()
+}
+
+/// Point out what the packed program needs from the machine it runs on,
+/// since a missing library only shows up there, far from cmprs
+pub fn report(input_name: &str, input: &[u8]) {
+    match linkage(input) {
+        Some(Linkage::Static) => info!("{input_name} is statically linked"),
+        Some(Linkage::Dynamic(libraries)) if libraries.is_empty() => {
+            info!("{input_name} only links system libraries")
+        }
+        Some(Linkage::Dynamic(libraries)) => crate::human::note(format_args!(
+            "{input_name} is dynamically linked, machines running it need {}. \
+             Build it statically to run anywhere",
+            libraries.join(", ")
+        )),
+        None => {}
+    }
+}
@@ -0,0 +1,55 @@
+use log::kv::{self, Key, Value, VisitSource};
+use log::Record;
+use serde_json::{Map, Number};
+use std::env;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Set up `RUST_LOG` logging, as JSON lines when `CMPRS_LOG_FORMAT=json`
+pub fn init() {
+    let mut builder = env_logger::Builder::from_default_env();
+    if env::var("CMPRS_LOG_FORMAT").is_ok_and(|format| format == "json") {
+        builder.format(|out, record| {
+            serde_json::to_writer(&mut *out, &json_line(record)).map_err(io::Error::from)?;
+            writeln!(out)
+        });
+    }
+    builder.init();
+}
+
+/// A log record as one JSON object: when, how severe, where from, the message,
+/// then any fields logged with it, like `stage`, `bytes` and `duration_ms`
+fn json_line(record: &Record) -> Map<String, serde_json::Value> {
+    let mut line = Map::new();
+    let ts_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    line.insert("ts_ms".into(), ts_ms.into());
+    line.insert("level".into(), record.level().as_str().into());
+    line.insert("target".into(), record.target().into());
+    line.insert("message".into(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut Fields(&mut line));
+    line
+}
+
+struct Fields<'a>(&'a mut Map<String, serde_json::Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        // Numbers stay numbers, for aggregators to sum and chart
+        let value = if let Some(number) = value.to_u64() {
+            number.into()
+        } else if let Some(number) = value.to_i64() {
+            number.into()
+        } else if let Some(number) = value.to_f64().and_then(Number::from_f64) {
+            number.into()
+        } else if let Some(flag) = value.to_bool() {
+            flag.into()
+        } else {
+            value.to_string().into()
+        };
+        self.0.insert(key.as_str().to_string(), value);
+        Ok(())
+    }
+}
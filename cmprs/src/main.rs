@@ -1,32 +1,246 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
+use cmprs::format::VerifyPolicy;
+use cmprs::{format, Algorithm, CmprsError, LevelAlias, PackOptions, Throughput};
 use include_dir::{include_dir, Dir};
+use indicatif::{FormattedDuration, HumanBytes, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use log::{debug, info, warn};
 use sha2::{Digest, Sha256};
-use std::fs::File;
-use std::io::{self, Read, Write};
-use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::sync::Arc;
 use std::thread;
-use std::time::Instant;
-use zstd::stream::write::Encoder as ZstdEncoder;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-static DIST_DIR: Dir = include_dir!("$OUT_DIR/compiled_dcmprs");
+mod algorithms;
+mod arch;
+mod bench;
+mod bundle;
+mod config;
+mod dotenv;
+mod extract;
+mod human;
+mod linkage;
+mod logging;
+mod oci;
+mod progress;
+mod repack;
+mod shell;
+mod split;
+mod stats;
+mod strip;
+mod stubs;
+mod watch;
 
-// Custom magic header to mark the boundary between dcmprs executable and compressed data
-// Using a unique 16-byte sequence that's unlikely to appear in binaries
-const MAGIC_HEADER: &[u8; 16] = b"DCMPRS_DATA_HERE";
+static DIST_DIR: Dir = include_dir!("$OUT_DIR/compiled_dcmprs");
 
 #[cfg(not(windows))]
 const SUFFIX: &str = "cmprs";
 #[cfg(windows)]
 const SUFFIX: &str = "cmprs.exe";
+const SHELL_SUFFIX: &str = "cmprs.sh";
+const NO_STUB_SUFFIX: &str = "cmprz";
+
+// Level 0 skips zstd and stores the payload as-is
+const STORE_LEVEL: i32 = 0;
+
+// Mode of the output when the input isn't executable and --chmod isn't given
+const DEFAULT_OUTPUT_MODE: u32 = 0o755;
+
+// Mode of a --no-stub output when --chmod isn't given, since it can't be run
+const NO_STUB_OUTPUT_MODE: u32 = 0o644;
+
+// The input named `-` is read from stdin
+const STDIN_INPUT: &str = "-";
+
+// Mode of an input read from stdin, which has no permissions of its own. Like
+// a file's, it's what the output and the extracted program get without --chmod.
+const STDIN_MODE: u32 = 0o755;
+
+// How much recent progress the compression ETA is estimated from
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(5);
+
+// Hex digits of the input's SHA256 used for --name-by-hash output names
+const HASH_NAME_LEN: usize = 16;
+
+// Text stored by a bare --marker
+const DEFAULT_MARKER: &str = concat!(
+    "packed with cmprs ",
+    env!("CARGO_PKG_VERSION"),
+    " https://github.com/Schniz/cmprs"
+);
+
+// Scanners look for short printable markers, keep them that way
+const MAX_MARKER_LEN: usize = 256;
+
+// A --comment is a line for people to read, not a document
+const MAX_COMMENT_LEN: usize = 1024;
+
+// Payloads that compression can't shrink below this fraction of the input are stored
+// uncompressed instead, since decompressing them would only cost launch time.
+// --store-if-ratio-above overrides it.
+const AUTO_STORE_MAX_RATIO: f64 = 0.98;
+
+// zstd levels --max-output-size tries, in order, above the chosen one. Each
+// costs a full pack, so it skips ahead rather than trying every level.
+const BUDGET_ZSTD_LEVELS: &[i32] = &[6, 9, 12, 15, 19, 22];
+
+// Inputs smaller than this are hashed and compressed inline, where starting
+// threads would cost more than it saves. Packing them doesn't pay off either,
+// the stub alone is far larger.
+const SMALL_INPUT_SIZE: usize = 16 * 1024;
+
+// What every WebAssembly module starts with
+const WASM_MAGIC: &[u8] = b"\0asm";
+
+// The whole input is held in memory alongside its compressed copy, so refuse
+// inputs larger than this fraction of the available memory
+const MAX_INPUT_MEMORY_FRACTION: f64 = 0.5;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// Prepend the embedded dcmprs stub
+    Binary,
+    /// Emit a POSIX shell script that decompresses with the system `zstd`
+    Shell,
+}
+
+/// Compression algorithms that can be picked by name with --algorithm
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AlgorithmArg {
+    Zstd,
+    Xz,
+    /// A standard gzip stream, which gunzip reads
+    Gzip,
+    /// Store the payload uncompressed
+    None,
+}
+
+impl From<AlgorithmArg> for Algorithm {
+    fn from(algorithm: AlgorithmArg) -> Self {
+        match algorithm {
+            AlgorithmArg::Zstd => Algorithm::Zstd,
+            AlgorithmArg::Xz => Algorithm::Xz,
+            AlgorithmArg::Gzip => Algorithm::Gzip,
+            AlgorithmArg::None => Algorithm::None,
+        }
+    }
+}
+
+/// When the packed program checks its payload's SHA256, with --verify-policy
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum VerifyPolicyArg {
+    /// Only when run with DCMPRS_VERIFY_ONCE=1
+    Never,
+    /// On the first run, like DCMPRS_VERIFY_ONCE=1 always being set
+    Once,
+    /// On every run, keeping the packed file instead of replacing it with the program
+    Always,
+}
+
+impl From<VerifyPolicyArg> for VerifyPolicy {
+    fn from(policy: VerifyPolicyArg) -> Self {
+        match policy {
+            VerifyPolicyArg::Never => VerifyPolicy::Never,
+            VerifyPolicyArg::Once => VerifyPolicy::Once,
+            VerifyPolicyArg::Always => VerifyPolicy::Always,
+        }
+    }
+}
+
+impl From<VerifyPolicy> for VerifyPolicyArg {
+    fn from(policy: VerifyPolicy) -> Self {
+        match policy {
+            VerifyPolicy::Never => VerifyPolicyArg::Never,
+            VerifyPolicy::Once => VerifyPolicyArg::Once,
+            VerifyPolicy::Always => VerifyPolicyArg::Always,
+        }
+    }
+}
+
+/// What --filter applies to the payload before compressing it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FilterArg {
+    /// The branch filter for the input's machine
+    Bcj,
+    Delta(u8),
+    None,
+}
+
+impl From<Option<format::Filter>> for FilterArg {
+    fn from(filter: Option<format::Filter>) -> Self {
+        match filter {
+            Some(format::Filter::X86 | format::Filter::Arm64) => FilterArg::Bcj,
+            Some(format::Filter::Delta { distance }) => FilterArg::Delta(distance),
+            None => FilterArg::None,
+        }
+    }
+}
+
+/// What --level asks for: a level of the algorithm's own, or an alias that
+/// each algorithm maps to its own
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LevelArg {
+    Number(i32),
+    Alias(LevelAlias),
+}
+
+impl LevelArg {
+    fn apply(self, builder: cmprs::PackOptionsBuilder) -> cmprs::PackOptionsBuilder {
+        match self {
+            LevelArg::Number(level) => builder.level(level),
+            LevelArg::Alias(alias) => builder.level_alias(alias),
+        }
+    }
+}
+
+/// macOS stubs embedded next to `main` when cmprs is built on macOS
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum StubVariant {
+    /// Thin stub for Apple silicon
+    Arm64,
+    /// Thin stub for Intel Macs
+    #[value(name = "x86_64")]
+    X86_64,
+    /// Fat stub running on both
+    Universal,
+}
+
+impl StubVariant {
+    /// Name of the stub under DIST_DIR, as written by build.rs
+    fn file_name(self) -> &'static str {
+        match self {
+            StubVariant::Arm64 => "macos_arm64",
+            StubVariant::X86_64 => "macos_x86_64",
+            StubVariant::Universal => "macos_universal",
+        }
+    }
+
+    /// Target triple the stub runs on, or both architectures for the fat one
+    fn target(self) -> &'static str {
+        match self {
+            StubVariant::Arm64 => "aarch64-apple-darwin",
+            StubVariant::X86_64 => "x86_64-apple-darwin",
+            StubVariant::Universal => "universal-apple-darwin",
+        }
+    }
+}
 
-#[derive(Parser)]
+#[derive(Clone, Parser)]
 #[command(name = "cmprs")]
 #[command(about = "Creates self-extracting zstd compressed executables")]
 #[command(version)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(
         short,
         long,
@@ -34,229 +248,2820 @@ struct Args {
     )]
     output: Option<PathBuf>,
 
-    #[arg(help = "Input file")]
-    input: PathBuf,
+    #[arg(
+        long,
+        value_name = "EXT",
+        help = "Suffix used to derive the output name when --output isn't given. An empty suffix packs in place (requires --force)"
+    )]
+    suffix: Option<String>,
 
     #[arg(
-        short = 'l',
-        long = "level",
-        default_value = "3",
-        help = "Compression level (1-22, higher = better compression but slower)"
+        long,
+        value_name = "DIR",
+        conflicts_with_all = ["output", "name_by_hash"],
+        help = "Write each output into DIR, created if missing, as <input name>.<suffix> instead of next to its input"
     )]
-    compression_level: i32,
+    output_dir: Option<PathBuf>,
 
     #[arg(
         long,
-        default_value = "false",
-        help = "Build universal macOS binary",
-        hide = cfg!(not(target_os = "macos")),
+        help = "Allow the output to overwrite the input file, or a different file of the same --name-by-hash name"
     )]
-    build_universal_macos: bool,
-}
+    force: bool,
 
-fn main() -> io::Result<()> {
-    env_logger::init();
-    let start_time = Instant::now();
+    #[arg(
+        long,
+        conflicts_with = "output",
+        help = "Name the output after a prefix of the input's SHA256 (<hash>.<suffix>), next to the input"
+    )]
+    name_by_hash: bool,
 
-    let args = Args::parse();
-    let output_path = args
-        .output
-        .unwrap_or_else(|| PathBuf::from(format!("{}.{SUFFIX}", args.input.display())));
+    #[arg(
+        long,
+        value_name = "HEX",
+        value_parser = parse_sha256,
+        conflicts_with_all = ["strip_input", "entry", "subcommands"],
+        help = "The input's SHA256, computed upstream, to embed instead of hashing the input again"
+    )]
+    sha256: Option<[u8; format::SHA256_LEN]>,
 
-    info!(
-        "Starting compression of {} to {}",
-        args.input.display(),
-        output_path.display(),
-    );
+    #[arg(
+        long,
+        requires = "sha256",
+        help = "Hash the input anyway and fail if it doesn't match --sha256"
+    )]
+    verify_hash: bool,
 
-    // Read input file and check permissions
-    debug!("Reading input file: {}", args.input.display());
-    let read_start = Instant::now();
-    let mut input = Vec::new();
-    let input_file = File::open(&args.input)?;
-    let input_metadata = input_file.metadata()?;
-    let input_permissions = input_metadata.permissions();
-    let is_executable = input_permissions.mode() & 0o111 != 0;
+    #[arg(
+        help = "Input files, or directories to bundle with --entry, each packed to its own output. Symlinks are resolved, and the target's name and permissions are stored. - reads a single input from stdin, which needs --output",
+        required_unless_present_any = ["stub_only", "files_from"]
+    )]
+    input: Vec<PathBuf>,
 
-    if !is_executable {
-        warn!("Input file '{}' is not executable", args.input.display());
-    }
+    #[arg(
+        long,
+        value_name = "LISTFILE",
+        help = "Also pack each file listed in LISTFILE, one path per line, like tar -T. Blank lines and lines starting with # are skipped. - reads the list from stdin"
+    )]
+    files_from: Option<PathBuf>,
 
-    let mut input_file = input_file;
-    input_file.read_to_end(&mut input)?;
-    info!("Read {} bytes in {:?}", input.len(), read_start.elapsed());
+    #[arg(
+        long,
+        help = "With several inputs, stop at the first one that fails instead of packing the rest"
+    )]
+    fail_fast: bool,
 
-    let input_len = input.len();
-    info!("Input size: {:.2} MB", input_len as f64 / 1_048_576.0);
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "When the input is a directory, the program inside it to run (e.g. bin/mytool)"
+    )]
+    entry: Option<PathBuf>,
 
-    // Share input data between threads using Arc
-    debug!("Creating Arc for input data ({} bytes)", input_len);
-    let arc_start = Instant::now();
-    let input_data = Arc::new(input);
-    info!("Arc creation took {:?}", arc_start.elapsed());
+    #[arg(
+        long = "subcommand",
+        value_name = "NAME=PATH",
+        value_parser = parse_subcommand,
+        help = "When the input is a directory, run the program at PATH inside it instead when the first argument is NAME, passing it the rest (e.g. build=bin/build). Repeat for each program. On any other first argument --entry runs, or without one the packed file prints its subcommands"
+    )]
+    subcommands: Vec<(String, PathBuf)>,
 
-    // Start SHA256 calculation in a separate thread
-    debug!("Starting SHA256 calculation thread");
-    let input_for_hash = Arc::clone(&input_data);
-    let hash_thread = thread::spawn(move || {
-        let hash_start = Instant::now();
-        debug!("SHA256 thread: starting hash calculation");
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "When the input is a directory, leave out the files and directories matching GLOB, by name (e.g. .git or '*.o') or, with a '/', by path inside it (e.g. target/debug). Repeatable"
+    )]
+    exclude: Vec<String>,
 
-        let mut hasher = Sha256::new();
-        let chunk_size = 1024 * 1024; // 1MB chunks
-        let data = &*input_for_hash;
+    #[arg(
+        long,
+        requires = "entry",
+        help = "Only extract the --entry at launch, from its own compressed frame, leaving the other files in the packed file. For programs that don't read the files next to them"
+    )]
+    extract_entry_only: bool,
 
-        for (i, chunk) in data.chunks(chunk_size).enumerate() {
-            hasher.update(chunk);
-            if i % 10 == 0 {
-                debug!(
-                    "SHA256 thread: processed {} MB",
-                    (i + 1) * chunk_size / 1_048_576
-                );
-            }
-        }
+    #[arg(
+        short = 'l',
+        long = "level",
+        env = "CMPRS_LEVEL",
+        value_parser = parse_level,
+        allow_negative_numbers = true,
+        help = "Compression level (1-22, higher = better compression but slower, 0 = store uncompressed, -1 to -22 for zstd's fast mode like zstd --fast=N), or fast, default or best, which each algorithm maps to its own (zstd 1, 3 and 19, xz 0, 6 and 9, gzip 1, 6 and 9). With --algorithm, numbers are the algorithm's own levels: xz presets 0-9, gzip 1-9 [default: 3]"
+    )]
+    compression_level: Option<LevelArg>,
 
-        let sha256_hash = hasher.finalize();
-        let elapsed = hash_start.elapsed();
-        let throughput = data.len() as f64 / elapsed.as_secs_f64() / 1_048_576.0;
-        info!(
-            "SHA256 calculated in {:?} ({:.1} MB/s): {}",
-            elapsed,
-            throughput,
-            hex::encode(sha256_hash)
-        );
-        (sha256_hash, elapsed)
-    });
+    #[arg(
+        long,
+        help = "Store the payload uncompressed for the fastest launch (same as --level 0, and overrides it)"
+    )]
+    store: bool,
 
-    // Start compression in a separate thread
-    debug!("Starting compression thread");
-    let input_for_compress = Arc::clone(&input_data);
-    let compression_level = args.compression_level;
+    #[arg(
+        long,
+        value_enum,
+        conflicts_with_all = ["store", "xz_preset"],
+        help = "Compression algorithm, with --level as its level (the preset for xz, 1-9 for gzip) [default: zstd]. With --split, a gzip payload is a .data file any gzip tool reads"
+    )]
+    algorithm: Option<AlgorithmArg>,
 
-    let compress_thread = thread::spawn(move || {
-        let compress_start = Instant::now();
-        debug!(
-            "Compression thread: starting Zstd compression (level {})",
-            compression_level
-        );
+    #[arg(
+        long,
+        conflicts_with = "prefer_speed",
+        help = "Pack as small as cmprs can, whatever the time: xz where the output can hold it (zstd otherwise) at its best level, xz's extreme variant, and the bcj filter for x86 and arm64 programs, on one thread. Explicit flags override each of these"
+    )]
+    prefer_ratio: bool,
 
-        let mut compressed = Vec::new();
-        {
-            let mut encoder = ZstdEncoder::new(&mut compressed, compression_level)
-                .expect("Failed to create Zstd encoder");
+    #[arg(
+        long,
+        help = "Pack as fast as cmprs can: zstd at its fast level, on a thread per core. Explicit flags override each of these"
+    )]
+    prefer_speed: bool,
 
-            let data = &*input_for_compress;
-            let chunk_size = 64 * 1024; // 64KB chunks for compression
+    #[arg(
+        long,
+        value_name = "PRESET",
+        value_parser = clap::value_parser!(i32).range(0..=9),
+        conflicts_with = "store",
+        help = "Compress with xz at this preset (0-9) instead of zstd: much slower, usually smaller (overrides --level)"
+    )]
+    xz_preset: Option<i32>,
 
-            for (i, chunk) in data.chunks(chunk_size).enumerate() {
-                encoder
-                    .write_all(chunk)
-                    .expect("Failed to write to encoder");
-                if i % 100 == 0 {
-                    debug!(
-                        "Compression thread: processed {} MB",
-                        (i + 1) * chunk_size / 1_048_576
-                    );
-                }
-            }
+    #[arg(
+        long,
+        conflicts_with = "store",
+        help = "Use the extreme variant of the xz preset, like xz -9e (implies xz, at preset 6 without --xz-preset)"
+    )]
+    xz_extreme: bool,
 
-            encoder.finish().expect("Failed to finish compression");
-        }
+    #[arg(
+        long,
+        conflicts_with_all = ["store", "xz_preset", "xz_extreme"],
+        help = "Train a zstd dictionary on samples of the input and compress with it, storing it in the header. Can help large inputs with repetitive sections"
+    )]
+    train_dict: bool,
 
-        let elapsed = compress_start.elapsed();
-        let compression_ratio = compressed.len() as f64 / input_for_compress.len() as f64;
-        let throughput = input_for_compress.len() as f64 / elapsed.as_secs_f64() / 1_048_576.0;
-        info!(
-            "Compressed {} bytes to {} bytes ({:.1}%) in {:?} ({:.1} MB/s)",
-            input_for_compress.len(),
-            compressed.len(),
-            compression_ratio * 100.0,
-            elapsed,
-            throughput
-        );
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = parse_time_limit,
+        help = "Give up compressing after this many seconds and store the payload uncompressed instead"
+    )]
+    time_limit: Option<Duration>,
 
-        if elapsed.as_secs() > 5 {
-            warn!(
-                "Compression took longer than 5 seconds - consider using a lower compression level"
-            );
-        }
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = parse_size,
+        conflicts_with_all = ["store", "time_limit"],
+        help = "Make the output fit in this many bytes (e.g. 1500000, 512K or 2M): if it doesn't at the chosen level, try higher ones, then xz -9e, and pack with the first that fits. Fails when none does"
+    )]
+    max_output_size: Option<usize>,
 
-        (compressed, elapsed)
-    });
+    #[arg(
+        long,
+        help = "Fail when the output is larger than the input, for a CI gate against packing that doesn't pay off (small or incompressible inputs). The output is still written"
+    )]
+    report_only_larger: bool,
 
-    // Meanwhile, start writing the output file with dcmprs executable
-    debug!("Loading embedded dcmprs executable");
-    let embed_start = Instant::now();
-    let dcmprs_file = if args.build_universal_macos {
-        DIST_DIR.get_file("macos_universal").or_else(|| {
-            log::error!("Universal macOS binary not found, falling back to main dcmprs");
-            DIST_DIR.get_file("main")
-        })
-    } else {
-        DIST_DIR.get_file("main")
-    };
-    let dcmprs_data = dcmprs_file.unwrap().contents();
-    info!(
-        "Loaded {} byte dcmprs executable in {:?}",
-        dcmprs_data.len(),
-        embed_start.elapsed()
-    );
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = parse_size,
+        help = "Feed the encoder this much input at a time (e.g. 65536, 256K or 4M), for experimenting with throughput. The output is the same for any size [default: 64K]"
+    )]
+    chunk_size: Option<usize>,
 
-    debug!(
-        "Creating output file and writing dcmprs executable: {}",
-        output_path.display()
-    );
-    let write_start = Instant::now();
-    let mut output = File::create(&output_path)?;
-    output.write_all(dcmprs_data)?;
-    output.write_all(MAGIC_HEADER)?;
-    output.write_all(b";;;")?;
-    let dcmprs_write_time = write_start.elapsed();
-    info!(
-        "Wrote {} byte dcmprs executable + magic header in {:?}",
-        dcmprs_data.len() + MAGIC_HEADER.len() + 3,
-        dcmprs_write_time
-    );
+    #[arg(
+        long,
+        value_name = "THREADS",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with_all = ["store", "xz_preset", "xz_extreme", "max_output_size"],
+        help = "Compress with zstd on this many threads. The output is the same for any number of them, so it stays reproducible across machines, but differs from the default single-threaded output"
+    )]
+    deterministic_threads: Option<u32>,
 
-    // Wait for SHA256 calculation to complete and write it
-    debug!("Waiting for SHA256 calculation to complete");
-    let (sha256_hash, hash_duration) = hash_thread.join().expect("SHA256 thread panicked");
-    let sha_write_start = Instant::now();
-    output.write_all(&sha256_hash)?;
-    let sha_write_time = sha_write_start.elapsed();
-    info!("Wrote 32-byte SHA256 hash in {:?}", sha_write_time);
-
-    // Wait for compression to complete and write it
-    debug!("Waiting for compression to complete");
-    let (compressed, compress_duration) =
-        compress_thread.join().expect("Compression thread panicked");
-    let compress_write_start = Instant::now();
-    output.write_all(&compressed)?;
-    let compress_write_time = compress_write_start.elapsed();
-    info!(
-        "Wrote {} byte compressed data in {:?}",
-        compressed.len(),
-        compress_write_time
-    );
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = parse_block_size,
+        conflicts_with_all = ["store", "xz_preset", "xz_extreme", "max_output_size"],
+        help = "Have zstd keep each compressed block under this size (1340 to 128K), so decompression gets to the first bytes sooner, for about 1% of ratio at the smallest size"
+    )]
+    block_size: Option<u32>,
 
-    // Copy permissions from the original file to the compressed file
-    debug!("Copying permissions from input to output file");
-    let perm_start = Instant::now();
-    output.set_permissions(input_permissions)?;
-    info!("Set permissions in {:?}", perm_start.elapsed());
+    #[arg(
+        long,
+        default_value = "false",
+        help = "Build universal macOS binary (same as --stub-variant universal)",
+        hide = cfg!(not(target_os = "macos")),
+    )]
+    build_universal_macos: bool,
 
-    let total_size = dcmprs_data.len() + MAGIC_HEADER.len() + sha256_hash.len() + compressed.len();
-    let total_write_time = dcmprs_write_time + sha_write_time + compress_write_time;
-    info!(
-        "Total output: {} bytes written in {:?}",
-        total_size, total_write_time
-    );
+    #[arg(
+        long,
+        value_enum,
+        conflicts_with_all = ["stub_target", "build_universal_macos"],
+        help = "Prepend one of the macOS stubs embedded when cmprs was built on macOS",
+        hide = cfg!(not(target_os = "macos")),
+    )]
+    stub_variant: Option<StubVariant>,
 
-    let parallel_time = hash_duration.max(compress_duration);
-    info!(
-        "Parallel processing completed in {:?} (hash: {:?}, compress: {:?})",
-        parallel_time, hash_duration, compress_duration
-    );
-    info!("Total compression completed in {:?}", start_time.elapsed());
-    Ok(())
+    #[arg(
+        long,
+        value_enum,
+        default_value = "binary",
+        help = "Self-extractor format: a native dcmprs stub or a POSIX shell script"
+    )]
+    format: Format,
+
+    #[arg(
+        long,
+        value_name = "TRIPLE",
+        help = "Prepend the dcmprs stub built for this target triple (see CMPRS_STUB_TARGETS)"
+    )]
+    stub_target: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["stub_target", "build_universal_macos", "stub_variant"],
+        help = "Prepend the smallest dcmprs stub: built with the minimal profile and without DCMPRS_LOG_LEVEL support"
+    )]
+    minimize_stub: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["format", "stub_target", "build_universal_macos", "stub_variant", "minimize_stub", "no_stub"],
+        help = "Prepend this executable instead of an embedded dcmprs stub, e.g. a signed or customized loader that reads the same format"
+    )]
+    stub_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        visible_alias = "exclude-stub",
+        conflicts_with_all = ["format", "stub_target", "build_universal_macos", "stub_variant", "minimize_stub", "split", "codesign"],
+        help = "Write only the magic, SHA256, header and payload, without the dcmprs stub, to <input>.cmprz for a custom loader. The output can't be run directly"
+    )]
+    no_stub: bool,
+
+    #[arg(
+        long,
+        requires = "output",
+        conflicts_with_all = ["input", "files_from", "stub_file", "no_stub", "format"],
+        help = "Write only the dcmprs stub the stub options pick, with no payload, to --output, to inspect it or put a payload of your own after it"
+    )]
+    stub_only: bool,
+
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        conflicts_with = "format",
+        help = "Run the payload through this command (e.g. \"python3\") instead of executing it directly. Arguments are split on whitespace"
+    )]
+    launcher: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "ARGS",
+        allow_hyphen_values = true,
+        conflicts_with = "format",
+        help = "Arguments the program always gets ahead of the ones it's run with (e.g. \"--config /etc/foo.toml\"). Split like a shell would, quotes included. The packed file isn't replaced by the program, which would run without them"
+    )]
+    entry_args: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PATTERNS",
+        conflicts_with = "format",
+        help = "Remove the environment variables matching these comma-separated patterns (e.g. \"LD_PRELOAD,DYLD_*\", where * matches anything) before the program starts. DCMPRS_STRIP_ENV replaces them at launch. The packed file isn't replaced by the program, which would run it unstripped"
+    )]
+    strip_env: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with = "format",
+        help = "Embed the variables of this dotenv file (NAME=value lines), which the program gets unless it's run with them set"
+    )]
+    env_file: Option<PathBuf>,
+
+    /// Variables to embed without an --env-file, carried over by repack
+    #[arg(skip)]
+    embedded_env: Vec<(OsString, OsString)>,
+
+    #[arg(
+        long = "chmod",
+        value_name = "OCTAL",
+        value_parser = parse_mode,
+        help = "Permissions of the output (e.g. 0700). Defaults to the input's, or 0755 when the input isn't executable"
+    )]
+    output_mode: Option<u32>,
+
+    #[arg(
+        long,
+        help = "Store the input's extended attributes and restore them on extraction and launch, all but its file capabilities, which need --preserve-caps"
+    )]
+    preserve_xattr: bool,
+
+    #[arg(
+        long,
+        help = "Store the input's file capabilities, as setcap sets them (e.g. cap_net_bind_service), and restore them on extraction and launch where that's permitted (as root, or with CAP_SETFCAP)"
+    )]
+    preserve_caps: bool,
+
+    #[arg(
+        long,
+        help = "Fail instead of warning when the input file is not executable"
+    )]
+    require_executable: bool,
+
+    #[arg(
+        long,
+        help = "Print a JSON object with sizes, hash and timings to stdout when done"
+    )]
+    json_stats: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        num_args = 0..=1,
+        require_equals = true,
+        help = "Write compression progress as JSON lines, {\"stage\":\"compress\",\"bytes_done\":N,\"bytes_total\":M}, to stderr or to PATH (e.g. /dev/fd/3), for frontends drawing their own progress. The last event of each input has bytes_done equal to bytes_total"
+    )]
+    progress_json: Option<Option<PathBuf>>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Don't color the output, like setting NO_COLOR"
+    )]
+    no_color: bool,
+
+    /// Leave out the summary on stderr, for packs that aren't the user's output
+    #[arg(skip)]
+    no_summary: bool,
+
+    /// The input was read from stdin into a temp file, whose name isn't recorded
+    #[arg(skip)]
+    from_stdin: bool,
+
+    #[arg(
+        long,
+        help = "Strip symbols from a copy of the input (with strip or llvm-strip) before compressing it"
+    )]
+    strip_input: bool,
+
+    #[arg(
+        long,
+        help = "Forward --dcmprs-* arguments (like --dcmprs-extract) to the packed program instead of handling them in the stub"
+    )]
+    no_stub_flags: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "format",
+        help = "Make the packed program expand @file arguments into the lines of the file, one argument each, for argument lists too long or too sensitive for the command line. Keeps the packed file in place instead of replacing it with the program"
+    )]
+    argfiles: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["format", "no_stub", "codesign"],
+        help = "Make the stub hash itself before doing anything and refuse to run when it isn't the one the file was packed with, a loader swapped or tampered with"
+    )]
+    verify_stub: bool,
+
+    #[arg(
+        long,
+        value_name = "TEXT",
+        num_args = 0..=1,
+        default_missing_value = DEFAULT_MARKER,
+        value_parser = parse_marker,
+        help = "Store a printable ASCII note in the header identifying the file as cmprs-packed, for scanners and forensics [default text: packed with cmprs <version> <url>]"
+    )]
+    marker: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "TEXT",
+        value_parser = parse_comment,
+        conflicts_with = "format",
+        help = "Store a line of printable ASCII right after the stub, like the tool's name, version and build date, for people inspecting the file with strings or a hex dump. dcmprs ignores it"
+    )]
+    comment: Option<String>,
+
+    #[arg(
+        long,
+        help = "Make the packed program refuse to start unless run as root, or re-run itself through DCMPRS_ESCALATE (e.g. sudo). The packed file isn't replaced by the program, which would drop the check"
+    )]
+    needs_root: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        conflicts_with = "format",
+        help = "When the packed program checks its payload against the SHA256 before running it. The environment can make this stricter (DCMPRS_VERIFY_ONCE=1) but never weaker [default: never]"
+    )]
+    verify_policy: Option<VerifyPolicyArg>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["format", "verify_policy"],
+        help = "Same as --verify-policy always"
+    )]
+    verify_on_every_run: bool,
+
+    #[arg(
+        long,
+        value_name = "VERSION",
+        value_parser = parse_min_loader_version,
+        conflicts_with = "format",
+        help = "Make the packed program refuse to start under a dcmprs stub older than VERSION (e.g. 0.2.0), for payloads that rely on something newer stubs added"
+    )]
+    min_loader_version: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "format",
+        help = "Record the input's absolute path in the header, shown by cmprs extract and --dcmprs-info, to trace a packed file back to where it was built. It can reveal build machine paths, see --path-prefix-map"
+    )]
+    record_path: bool,
+
+    #[arg(
+        long,
+        value_name = "OLD=NEW",
+        value_parser = parse_path_prefix_map,
+        requires = "record_path",
+        help = "With --record-path, record paths under OLD as under NEW instead (e.g. /home/ci/build=/src), like the compilers' -fdebug-prefix-map, for reproducible outputs. Repeatable, the last matching one wins"
+    )]
+    path_prefix_map: Vec<(PathBuf, PathBuf)>,
+
+    // The path repack found in the header, recorded again as it was
+    #[arg(skip)]
+    recorded_path: Option<OsString>,
+
+    #[arg(
+        long,
+        conflicts_with = "format",
+        help = "Store the compressed payload twice, so the packed program still starts when one copy gets corrupted, e.g. on unreliable storage. Doubles the payload's size"
+    )]
+    redundant: bool,
+
+    #[arg(
+        long,
+        help = "Keep the compressed payload even when it's barely smaller than the input, instead of storing it uncompressed"
+    )]
+    no_auto_store: bool,
+
+    #[arg(
+        long,
+        value_name = "PERCENT",
+        value_parser = parse_store_ratio,
+        help = "Store the payload uncompressed when compressing leaves more than PERCENT of the input, 98 by default. Lower it to keep only compression that pays off in launch time, or set 100 to store only what compression grows"
+    )]
+    store_if_ratio_above: Option<f64>,
+
+    #[arg(
+        long,
+        value_parser = parse_filter,
+        conflicts_with = "format",
+        help = "Transform the payload before compressing it, so it compresses better: bcj for the calls and jumps of an x86 or arm64 program, delta (or delta:N, N from 1 to 255) for tables of N-byte records. dcmprs undoes it after decompressing [default: none]"
+    )]
+    filter: Option<FilterArg>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["name_by_hash", "check_reproducible"],
+        help = "Write the compressed payload to <output>.data instead of appending it, leaving a small launcher that reads it from there"
+    )]
+    split: bool,
+
+    #[arg(
+        long,
+        help = "Pack inputs too large to safely hold in the available memory"
+    )]
+    allow_large: bool,
+
+    #[arg(
+        long,
+        value_name = "IDENTITY",
+        help = "Sign the output with `codesign --sign <IDENTITY>` (\"-\" for ad-hoc signing)",
+        hide = cfg!(not(target_os = "macos")),
+    )]
+    codesign: Option<String>,
+
+    #[arg(
+        long,
+        help = "Pack twice and fail if the two outputs aren't byte-identical"
+    )]
+    check_reproducible: bool,
+
+    #[arg(
+        long,
+        help = "Hash and compress one after the other on the main thread, for comparable timings and simpler backtraces. The output is the same"
+    )]
+    single_threaded: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "format",
+        help = "Read the output back once it's written, decompress it and check its SHA256, failing if it wouldn't launch"
+    )]
+    verify: bool,
+
+    #[arg(
+        long,
+        help = "Write the output's SHA256 to <output>.sha256 in sha256sum's format, for checking downloads with `sha256sum -c` before running them (with --split, the payload file's too)"
+    )]
+    emit_checksum: bool,
+
+    #[arg(
+        long,
+        value_name = "path=PATH",
+        value_parser = oci::parse_layer,
+        conflicts_with_all = ["format", "split", "train_dict", "no_stub", "launcher", "entry_args", "strip_env", "env_file", "marker", "comment", "needs_root", "verify_policy", "verify_on_every_run", "min_loader_version", "filter", "record_path", "argfiles", "verify_stub", "verify"],
+        help = "Instead of a self-extractor, write a container image layer: a tar with the input at PATH (e.g. path=/usr/local/bin/tool), compressed with zstd, gzip or nothing as --algorithm says. --chmod sets the program's mode in it"
+    )]
+    oci_layer: Option<oci::OciLayer>,
+}
+
+#[derive(Clone, Subcommand)]
+enum Command {
+    /// Extract the original executable from a cmprs-compressed file without running it
+    Extract(extract::ExtractArgs),
+    /// Pack the payload of a cmprs-compressed file again with a different level or stub
+    Repack(repack::RepackArgs),
+    /// List the dcmprs stubs embedded in this build, with their sizes and targets
+    Stubs(stubs::StubsArgs),
+    /// Parse the embedded dcmprs stubs and report their format, architecture, stripping and version
+    InspectStub(stubs::InspectStubArgs),
+    /// Write the dcmprs stub and the payload of a cmprs-compressed file to <file>.stub and <file>.payload
+    Split(split::SplitArgs),
+    /// List the compression algorithms this build can pack with, with their header ids
+    Algorithms(algorithms::AlgorithmsArgs),
+    /// Pack a program with each compression algorithm and time how long each takes to launch
+    BenchStartup(bench::BenchStartupArgs),
+    /// Pack a program, then again each time it changes, for development loops
+    Watch(watch::WatchArgs),
+}
+
+fn main() {
+    logging::init();
+
+    let args = Args::parse();
+    human::init(args.no_color);
+    let result = match args.command {
+        Some(Command::Extract(extract_args)) => extract::run(extract_args),
+        Some(Command::Repack(repack_args)) => repack::run(repack_args),
+        Some(Command::Stubs(stubs_args)) => stubs::run(stubs_args),
+        Some(Command::InspectStub(inspect_args)) => stubs::inspect(inspect_args),
+        Some(Command::Split(split_args)) => split::run(split_args),
+        Some(Command::Algorithms(algorithms_args)) => algorithms::run(algorithms_args),
+        Some(Command::BenchStartup(bench_args)) => bench::run(bench_args),
+        Some(Command::Watch(watch_args)) => watch::run(watch_args),
+        None => pack_inputs(args),
+    };
+
+    // Report failures as a single line and a nonzero exit code so scripts can rely on it
+    if let Err(err) = result {
+        eprintln!("cmprs: {err}");
+        process::exit(err.exit_code());
+    }
+}
+
+/// Pack each input to its own output. A failing input is reported and the
+/// rest are still packed, unless --fail-fast is given.
+fn pack_inputs(mut args: Args) -> Result<(), CmprsError> {
+    if args.stub_only {
+        return write_stub_only(&args);
+    }
+    if let Some(path) = &args.progress_json {
+        progress::init(path.as_deref())?;
+    }
+    if let Some(list) = &args.files_from {
+        if list == Path::new(STDIN_INPUT) && args.input.iter().any(|input| input == STDIN_INPUT) {
+            return Err(CmprsError::InvalidOptions(
+                "--files-from - and the input - can't both read stdin".to_string(),
+            ));
+        }
+        let listed = read_file_list(list)?;
+        if listed.is_empty() && args.input.is_empty() {
+            let list = if list == Path::new(STDIN_INPUT) {
+                "stdin".to_string()
+            } else {
+                list.display().to_string()
+            };
+            return Err(CmprsError::InvalidOptions(format!(
+                "{list} lists no files to pack"
+            )));
+        }
+        args.input.extend(listed);
+    }
+    if let Some(dir) = &args.output_dir {
+        check_output_names(&args.input, dir)?;
+        fs::create_dir_all(dir)?;
+    }
+    if let [input_path] = args.input.as_slice() {
+        let input_path = input_path.clone();
+        return pack_one(args, input_path).map(drop);
+    }
+    if args.output.is_some() || args.sha256.is_some() {
+        return Err(CmprsError::InvalidOptions(
+            "--output and --sha256 can only be used with a single input; use --suffix to name the outputs"
+                .to_string(),
+        ));
+    }
+
+    let mut first_error = None;
+    let (mut packed, mut input_size, mut output_size) = (0, 0, 0);
+    for input_path in &args.input {
+        match pack_one(args.clone(), input_path.clone()) {
+            Ok(output) => {
+                packed += 1;
+                input_size += output.input_size;
+                output_size += output.output_size;
+            }
+            Err(err) => {
+                eprintln!("cmprs: {}: {err}", input_path.display());
+                first_error.get_or_insert(err.exit_code());
+                if args.fail_fast {
+                    break;
+                }
+            }
+        }
+    }
+
+    eprintln!(
+        "cmprs: packed {packed} of {} inputs, {input_size} bytes to {output_size} bytes ({:.1}%)",
+        args.input.len(),
+        output_size as f64 / input_size.max(1) as f64 * 100.0
+    );
+    if let Some(exit_code) = first_error {
+        process::exit(exit_code);
+    }
+    Ok(())
+}
+
+/// The paths listed in `list`, one per line, skipping blank lines and
+/// comments. `-` reads the list from stdin.
+fn read_file_list(list: &Path) -> Result<Vec<PathBuf>, CmprsError> {
+    let contents = if list == Path::new(STDIN_INPUT) {
+        let mut contents = Vec::new();
+        io::stdin().lock().read_to_end(&mut contents)?;
+        contents
+    } else {
+        fs::read(list).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("failed to read the file list {}: {err}", list.display()),
+            )
+        })?
+    };
+    // Paths are bytes, and needn't be UTF-8
+    Ok(contents
+        .split(|&byte| byte == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.trim_ascii().is_empty() && !line.trim_ascii_start().starts_with(b"#"))
+        .map(|line| PathBuf::from(OsStr::from_bytes(line)))
+        .collect())
+}
+
+/// Refuse inputs that --output-dir would write to the same output, since
+/// they're named after the input's file name alone
+fn check_output_names(inputs: &[PathBuf], dir: &Path) -> Result<(), CmprsError> {
+    let mut names = HashMap::new();
+    for input in inputs {
+        let Some(name) = input.file_name() else {
+            continue;
+        };
+        if let Some(other) = names.insert(name, input) {
+            return Err(CmprsError::InvalidOptions(format!(
+                "{} and {} are both named {}, so they'd overwrite each other in {}",
+                other.display(),
+                input.display(),
+                name.to_string_lossy(),
+                dir.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn pack_one(args: Args, input_path: PathBuf) -> Result<PackedOutput, CmprsError> {
+    if input_path == Path::new(STDIN_INPUT) {
+        return pack_stdin(args);
+    }
+    // Input from stdin comes from the current directory as far as config goes
+    let config_input = if args.from_stdin {
+        Path::new(STDIN_INPUT)
+    } else {
+        &input_path
+    };
+    let args = apply_profile(config::apply(args, config_input)?);
+    if (args.verify || args.check_reproducible || args.emit_checksum)
+        && args.output.as_deref().is_some_and(is_special_file)
+    {
+        return Err(CmprsError::InvalidOptions(
+            "--verify, --check-reproducible and --emit-checksum read the output back, which needs it to be a regular file"
+                .to_string(),
+        ));
+    }
+    let args = match args.max_output_size {
+        Some(budget) => fit_output_size(args, &input_path, budget as u64)?,
+        None => args,
+    };
+    let verify = args.verify;
+    let checksum = args.emit_checksum.then_some(args.split);
+    let report_larger = args.report_only_larger;
+    let packed = if args.check_reproducible {
+        pack_reproducibly(args, input_path)?
+    } else {
+        pack(args, input_path)?
+    };
+    if verify {
+        verify_output(&packed.path)?;
+    }
+    if let Some(split) = checksum {
+        write_checksum(&packed.path, split)?;
+    }
+    if report_larger && packed.output_size > packed.input_size {
+        return Err(io::Error::other(format!(
+            "{} is larger than its input, {} to {} bytes (+{:.1}%), so packing doesn't pay off",
+            packed.path.display(),
+            packed.input_size,
+            packed.output_size,
+            (packed.output_size - packed.input_size) as f64 / packed.input_size.max(1) as f64
+                * 100.0
+        ))
+        .into());
+    }
+    Ok(packed)
+}
+
+/// Fill in what --prefer-ratio or --prefer-speed set, wherever the flags or
+/// the config didn't choose for themselves. --prefer-ratio's bcj filter is
+/// added once the input is known to be a program it applies to.
+fn apply_profile(mut args: Args) -> Args {
+    if !args.prefer_ratio && !args.prefer_speed {
+        return args;
+    }
+    let codec_chosen = args.store
+        || args.algorithm.is_some()
+        || args.xz_preset.is_some()
+        || args.xz_extreme
+        || args.compression_level == Some(LevelArg::Number(STORE_LEVEL));
+    // --xz-preset and --store set the level themselves
+    let level_chosen =
+        (codec_chosen && args.algorithm.is_none()) || args.compression_level.is_some();
+
+    if args.prefer_ratio {
+        let xz_fits = args.format == Format::Binary && args.oci_layer.is_none() && !args.train_dict;
+        if !codec_chosen {
+            args.algorithm = Some(if xz_fits && cmprs::is_compiled_in(Algorithm::Xz) {
+                AlgorithmArg::Xz
+            } else {
+                AlgorithmArg::Zstd
+            });
+        }
+        if !level_chosen {
+            args.compression_level = Some(LevelArg::Alias(LevelAlias::Best));
+        }
+        args.xz_extreme |= args.algorithm == Some(AlgorithmArg::Xz) || args.xz_preset.is_some();
+    } else {
+        if !codec_chosen {
+            args.algorithm = Some(AlgorithmArg::Zstd);
+        }
+        if !level_chosen {
+            args.compression_level = Some(LevelArg::Alias(LevelAlias::Fast));
+        }
+        if args.algorithm == Some(AlgorithmArg::Zstd)
+            && args.deterministic_threads.is_none()
+            && args.max_output_size.is_none()
+        {
+            let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+            args.deterministic_threads = Some(cores as u32);
+        }
+    }
+    args
+}
+
+/// Pack what's read from stdin, spooled to a temp file with STDIN_MODE
+fn pack_stdin(mut args: Args) -> Result<PackedOutput, CmprsError> {
+    if args.output.is_none() {
+        return Err(CmprsError::InvalidOptions(
+            "reading the input from stdin needs --output, there's no file name to derive it from"
+                .to_string(),
+        ));
+    }
+    let mut spooled = tempfile::Builder::new().prefix(".cmprs-stdin").tempfile()?;
+    io::copy(&mut io::stdin().lock(), &mut spooled)?;
+    spooled
+        .as_file()
+        .set_permissions(fs::Permissions::from_mode(STDIN_MODE))?;
+    let spooled = spooled.into_temp_path();
+    debug!("Read the input from stdin into {}", spooled.display());
+    args.from_stdin = true;
+    pack_one(args, spooled.to_path_buf())
+}
+
+/// Write `<output>.sha256` next to the output, with the SHA256 of the packed
+/// file as it's distributed (not the payload's, which the header records)
+fn write_checksum(output: &Path, split: bool) -> io::Result<()> {
+    let mut files = vec![output.to_path_buf()];
+    if split {
+        files.push(format::split_payload_path(output));
+    }
+    // sha256sum's format, with names relative to the checksum file
+    let mut lines = String::new();
+    for file in &files {
+        let mut hasher = Sha256::new();
+        io::copy(&mut File::open(file)?, &mut hasher)?;
+        let name = file.file_name().unwrap_or(file.as_os_str());
+        lines += &format!(
+            "{}  {}\n",
+            hex::encode(hasher.finalize()),
+            name.to_string_lossy()
+        );
+    }
+    let mut checksum_path = output.as_os_str().to_os_string();
+    checksum_path.push(".sha256");
+    fs::write(&checksum_path, lines)?;
+    info!(
+        "Wrote the output's SHA256 to {}",
+        Path::new(&checksum_path).display()
+    );
+    Ok(())
+}
+
+/// Unpack the output just written at `path` the way `cmprs extract` would,
+/// so a file that can't launch fails the pack instead of the user's run
+fn verify_output(path: &Path) -> Result<(), CmprsError> {
+    let verify_start = Instant::now();
+    let result =
+        cmprs::read_packed(path).and_then(|packed| cmprs::unpack_to(&packed, &mut io::sink()));
+    if result.is_err() {
+        eprintln!(
+            "cmprs: {} failed its self-check, it won't launch",
+            path.display()
+        );
+    }
+    result?;
+    info!(
+        "Verified {} decompresses to its SHA256 in {:?}",
+        path.display(),
+        verify_start.elapsed()
+    );
+    Ok(())
+}
+
+/// Pack once to the real output and once to a temp file next to it, and fail
+/// if the two differ
+fn pack_reproducibly(args: Args, input_path: PathBuf) -> Result<PackedOutput, CmprsError> {
+    let mut second_args = args.clone();
+    let packed = pack(args, input_path.clone())?;
+    let output_path = &packed.path;
+
+    let second_path = tempfile::Builder::new()
+        .prefix(".cmprs-reproducible")
+        .tempfile_in(parent_dir(output_path))?
+        .into_temp_path();
+    second_args.output = Some(second_path.to_path_buf());
+    second_args.json_stats = false;
+    second_args.no_summary = true;
+    second_args.name_by_hash = false;
+    info!("Packing a second time to check the output is reproducible");
+    pack(second_args, input_path)?;
+
+    let first = fs::read(output_path)?;
+    let second = fs::read(&second_path)?;
+    if first != second {
+        let offset = first
+            .iter()
+            .zip(&second)
+            .position(|(a, b)| a != b)
+            .unwrap_or(first.len().min(second.len()));
+        return Err(io::Error::other(format!(
+            "output is not reproducible: two packs of the same input differ at byte {offset} \
+             ({} vs {} bytes)",
+            first.len(),
+            second.len()
+        ))
+        .into());
+    }
+    info!("Output is reproducible");
+    Ok(packed)
+}
+
+/// `args` with the weakest compression setting, starting from the chosen one,
+/// whose output fits in `budget` bytes. Each setting is tried with a pack to a
+/// temp directory, and the one that fits is packed again for real.
+fn fit_output_size(args: Args, input_path: &Path, budget: u64) -> Result<Args, CmprsError> {
+    let attempts_dir = tempfile::tempdir()?;
+    let options = pack_options(&args)?;
+    let steps = budget_steps(&args, &options);
+    let mut best = None;
+    for step in std::iter::once(None).chain(steps.into_iter().map(Some)) {
+        let mut fitted = args.clone();
+        if let Some((algorithm, level, extreme)) = step {
+            fitted.algorithm = Some(algorithm);
+            fitted.compression_level = Some(LevelArg::Number(level));
+            fitted.xz_preset = None;
+            fitted.xz_extreme = extreme;
+        }
+        let setting = describe_setting(&pack_options(&fitted)?);
+
+        let mut attempt = fitted.clone();
+        attempt.output = Some(attempts_dir.path().join("attempt"));
+        attempt.json_stats = false;
+        attempt.no_summary = true;
+        attempt.name_by_hash = false;
+        // The real pack says what there is to say about the input
+        human::set_quiet(true);
+        let packed = pack(attempt, input_path.to_path_buf());
+        human::set_quiet(false);
+        let size = packed?.output_size;
+        info!("With {setting}, the output is {size} bytes");
+        if size <= budget {
+            human::note(format_args!(
+                "{setting} packs {} to {size} bytes, within --max-output-size {budget}",
+                input_path.display()
+            ));
+            return Ok(fitted);
+        }
+        if best.as_ref().is_none_or(|(best_size, _)| size < *best_size) {
+            best = Some((size, setting));
+        }
+    }
+    let (size, setting) = best.expect("the chosen setting is always tried");
+    Err(io::Error::other(format!(
+        "{} doesn't fit in --max-output-size {budget} bytes, {setting} packs it smallest, to {size} bytes",
+        input_path.display()
+    ))
+    .into())
+}
+
+/// The settings --max-output-size tries after `options`, each compressing
+/// harder than the one before: higher levels of the same algorithm, then xz -9e
+/// where the output can hold xz
+fn budget_steps(args: &Args, options: &PackOptions) -> Vec<(AlgorithmArg, i32, bool)> {
+    let level = options.level();
+    let zstd_levels = |from: i32| {
+        BUDGET_ZSTD_LEVELS
+            .iter()
+            .filter(move |&&zstd_level| zstd_level > from)
+            .map(|&zstd_level| (AlgorithmArg::Zstd, zstd_level, false))
+    };
+    let mut steps: Vec<_> = match options.algorithm() {
+        Algorithm::Zstd => zstd_levels(level).collect(),
+        Algorithm::Xz if options.xz_extreme() => Vec::new(),
+        Algorithm::Xz => (level + 1..=9)
+            .map(|preset| (AlgorithmArg::Xz, preset, false))
+            .collect(),
+        Algorithm::Gzip => (level + 1..=9)
+            .map(|gzip_level| (AlgorithmArg::Gzip, gzip_level, false))
+            .chain(zstd_levels(15))
+            .collect(),
+        Algorithm::None => zstd_levels(0).collect(),
+    };
+    let xz_fits = args.format == Format::Binary && args.oci_layer.is_none() && !args.train_dict;
+    if xz_fits && cmprs::is_compiled_in(Algorithm::Xz) && !options.xz_extreme() {
+        steps.push((AlgorithmArg::Xz, 9, true));
+    }
+    steps
+}
+
+/// Like "zstd level 19" or "xz preset 9e"
+fn describe_setting(options: &PackOptions) -> String {
+    match options.algorithm() {
+        Algorithm::None => "storing uncompressed".to_string(),
+        Algorithm::Xz => format!(
+            "xz preset {}{}",
+            options.level(),
+            if options.xz_extreme() { "e" } else { "" }
+        ),
+        algorithm => format!("{} level {}", algorithm.name(), options.level()),
+    }
+}
+
+/// Where an input was packed to, and the sizes going in and out
+struct PackedOutput {
+    path: PathBuf,
+    input_size: u64,
+    output_size: u64,
+}
+
+/// Pack `input_path` with the options in `args`
+fn pack(args: Args, input_path: PathBuf) -> Result<PackedOutput, CmprsError> {
+    let start_time = Instant::now();
+    // What messages call the input, which is a temp file for stdin
+    let shown_input = if args.from_stdin {
+        PathBuf::from("stdin")
+    } else {
+        input_path.clone()
+    };
+    let options = pack_options(&args)?;
+    if options.algorithm() != Algorithm::Zstd && args.format == Format::Shell {
+        return Err(CmprsError::InvalidOptions(
+            "--format shell always decompresses with zstd and can't store the payload uncompressed or use xz or gzip"
+                .to_string(),
+        ));
+    }
+    if args.oci_layer.is_some() {
+        oci::media_type(options.algorithm())?;
+    }
+    if options.algorithm() == Algorithm::Zstd && options.level() < 0 {
+        human::warning(format_args!(
+            "level {} is zstd's fast mode, which packs quickly but compresses poorly, \
+             better kept to development builds",
+            options.level()
+        ));
+    }
+    if options.algorithm() == Algorithm::Xz {
+        human::warning(format_args!(
+            "xz compresses many times slower than zstd, expect this to take a while"
+        ));
+    }
+    if let Some(min_loader_version) = &args.min_loader_version {
+        // Every embedded stub is built along with this cmprs
+        if args.stub_file.is_none()
+            && format::parse_version(min_loader_version) > format::parse_version(cmprs::VERSION)
+        {
+            human::warning(format_args!(
+                "--min-loader-version {min_loader_version} is newer than the {} stub this packs \
+                 with, so the output won't start until it's restubbed",
+                cmprs::VERSION
+            ));
+        }
+    }
+    if (args.split || args.train_dict) && args.format == Format::Shell {
+        return Err(CmprsError::InvalidOptions(
+            "--split and --train-dict only apply to --format binary".to_string(),
+        ));
+    }
+    if (args.marker.is_some() || args.needs_root) && args.format == Format::Shell {
+        return Err(CmprsError::InvalidOptions(
+            "--marker and --needs-root only apply to --format binary".to_string(),
+        ));
+    }
+    let suffix = match (&args.suffix, args.format) {
+        (Some(suffix), _) => suffix.as_str(),
+        (None, _) if args.oci_layer.is_some() => oci::suffix(options.algorithm()),
+        (None, Format::Binary) if args.no_stub => NO_STUB_SUFFIX,
+        (None, Format::Binary) => SUFFIX,
+        (None, Format::Shell) => SHELL_SUFFIX,
+    };
+    // With --name-by-hash the name is only known once the input is hashed, so
+    // pack to a temp file next to the input and rename it when done
+    let hash_named = if args.name_by_hash {
+        Some(
+            tempfile::Builder::new()
+                .prefix(".cmprs-hash-named")
+                .tempfile_in(parent_dir(&input_path))?
+                .into_temp_path(),
+        )
+    } else {
+        None
+    };
+    let derived_path = match &args.output_dir {
+        Some(dir) => dir.join(input_path.file_name().unwrap_or(input_path.as_os_str())),
+        None => input_path.clone(),
+    };
+    let output_path = match (&hash_named, &args.output) {
+        (Some(temp_path), _) => temp_path.to_path_buf(),
+        (None, Some(output)) => output.clone(),
+        (None, None) if suffix.is_empty() => derived_path,
+        (None, None) => PathBuf::from(format!("{}.{suffix}", derived_path.display())),
+    };
+
+    // A FIFO or a device like /dev/stdout is written to as it is, and can't be the input
+    let special_output = is_special_file(&output_path);
+    if special_output {
+        debug!(
+            "{} isn't a regular file, the output is copied into it once complete",
+            output_path.display()
+        );
+    }
+    if !special_output && is_same_file(&input_path, &output_path) && args.check_reproducible {
+        return Err(CmprsError::InvalidOptions(
+            "--check-reproducible can't repack an input that is packed in place".to_string(),
+        ));
+    }
+    if !special_output && is_same_file(&input_path, &output_path) && !args.force {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "output {} would overwrite the input; pass --force to pack in place",
+                output_path.display()
+            ),
+        )
+        .into());
+    }
+
+    info!(
+        "Starting compression of {} to {}",
+        input_path.display(),
+        output_path.display(),
+    );
+
+    // Write to a temp file next to the output and rename it into place once
+    // it's complete, so an interrupted pack never leaves a truncated output
+    let staged_output = match hash_named {
+        Some(_) => None,
+        None => stage_output(&output_path),
+    };
+    let write_path = staged_output
+        .as_ref()
+        .map_or_else(|| output_path.clone(), |temp_path| temp_path.to_path_buf());
+
+    // Operate on the real file when the input is a symlink. canonicalize fails
+    // with ELOOP on symlink loops instead of hanging.
+    let resolved_input = fs::canonicalize(&input_path).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("failed to resolve input {}: {err}", input_path.display()),
+        )
+    })?;
+    if resolved_input != input_path {
+        debug!(
+            "Resolved input {} to {}",
+            input_path.display(),
+            resolved_input.display()
+        );
+    }
+
+    // Read input file and check permissions
+    debug!("Reading input file: {}", resolved_input.display());
+    let read_start = Instant::now();
+    let mut input = Vec::new();
+    let input_file = File::open(&resolved_input)?;
+    let input_metadata = input_file.metadata()?;
+    let input_permissions = input_metadata.permissions();
+    let mtime = recorded_mtime(&input_metadata)?;
+
+    // A directory is packed as a bundle of all its files, running --entry or
+    // the program a --subcommand names
+    let runs_bundle = args.entry.is_some() || !args.subcommands.is_empty();
+    let bundle = match (input_metadata.is_dir(), runs_bundle) {
+        (true, true) => {
+            if args.format == Format::Shell
+                || args.oci_layer.is_some()
+                || args.strip_input
+                || args.preserve_xattr
+                || args.preserve_caps
+            {
+                return Err(CmprsError::InvalidOptions(
+                    "--format shell, --oci-layer, --strip-input, --preserve-xattr and --preserve-caps can't be used when packing a directory"
+                        .to_string(),
+                ));
+            }
+            Some(bundle::scan(
+                &resolved_input,
+                args.entry.as_deref(),
+                &args.subcommands,
+                &bundle::Excludes::new(&args.exclude)?,
+            )?)
+        }
+        (true, false) => {
+            return Err(CmprsError::InvalidOptions(format!(
+                "{} is a directory; pass --entry with the program inside it to run",
+                input_path.display()
+            )))
+        }
+        (false, _) if runs_bundle || !args.exclude.is_empty() => {
+            return Err(CmprsError::InvalidOptions(
+                "--entry, --subcommand and --exclude only apply when the input is a directory"
+                    .to_string(),
+            ))
+        }
+        (false, _) => None,
+    };
+
+    // The file that ends up being run: the input itself or the bundle's entry
+    let (program_path, program_mode) = match &bundle {
+        Some(bundle) => {
+            // A multi-tool without an entry stands in its first subcommand
+            let program = match bundle.subcommands.first() {
+                Some((_, path)) if bundle.entry.is_empty() => path,
+                _ => &bundle.entry,
+            };
+            let entry = bundle
+                .files
+                .iter()
+                .find(|file| file.path == *program)
+                .expect("bundle::scan checks the programs exist");
+            (input_path.join(&entry.path), entry.mode)
+        }
+        None => (input_path.clone(), input_permissions.mode()),
+    };
+    let is_executable = program_mode & 0o111 != 0;
+
+    match &bundle {
+        Some(bundle) => {
+            let size = bundle.files.iter().map(|file| file.size).sum();
+            check_available_memory(size, args.allow_large)?;
+            input = bundle::read_payload(&resolved_input, bundle)?;
+        }
+        None => {
+            check_available_memory(input_metadata.len(), args.allow_large)?;
+            let mut input_file = input_file;
+            input_file.read_to_end(&mut input)?;
+        }
+    }
+    let read_time = read_start.elapsed();
+    info!(
+        stage = "read",
+        bytes = input.len(),
+        duration_ms = stats::millis(read_time);
+        "Read {} bytes in {:?}", input.len(), read_time
+    );
+
+    let content_type = match &bundle {
+        Some(_) => None,
+        None => content_type(&input_path, &input, args.launcher.as_deref())?,
+    };
+    let interpreter = match content_type {
+        Some(format::ContentType::Script) => shebang(&input),
+        _ => None,
+    };
+    if let Some(launcher) = &args.launcher {
+        if launcher.trim().is_empty() {
+            return Err(CmprsError::InvalidOptions(
+                "--launcher must name a command".to_string(),
+            ));
+        }
+        // The payload is an argument to the launcher, so it needn't be executable
+        debug!("Payload will be run through launcher: {launcher}");
+    } else if let Some(interpreter) = &interpreter {
+        // dcmprs marks the extracted script executable, or runs the interpreter itself
+        debug!("Payload is a script run by {interpreter:?}");
+    } else if !is_executable {
+        if args.require_executable {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "input file '{}' is not executable (mode {:o}); chmod +x it or drop --require-executable",
+                    program_path.display(),
+                    program_mode & 0o7777
+                ),
+            )
+            .into());
+        }
+        warn!("Input file '{}' is not executable", program_path.display());
+    }
+
+    // Only a native program that runs by itself loads shared libraries, and
+    // has to be built for a machine the stub runs on
+    let native_program = bundle.is_none() && args.launcher.is_none() && content_type.is_none();
+    if native_program {
+        linkage::report(&shown_input.display().to_string(), &input);
+    }
+    let program_machine = native_program.then(|| arch::elf_machine(&input)).flatten();
+    let filter = match args.filter {
+        // Where it applies, and nowhere else
+        None if args.prefer_ratio && bundle.is_none() => {
+            program_machine.and_then(arch::branch_filter)
+        }
+        None | Some(FilterArg::None) => None,
+        // Filtered as a whole, the payload couldn't be decompressed a file at a time
+        Some(_) if bundle.is_some() => {
+            return Err(CmprsError::InvalidOptions(
+                "--filter applies to a single program, not a directory".to_string(),
+            ))
+        }
+        Some(FilterArg::Delta(distance)) => Some(format::Filter::Delta { distance }),
+        Some(FilterArg::Bcj) => Some(program_machine.and_then(arch::branch_filter).ok_or_else(
+            || {
+                CmprsError::InvalidOptions(format!(
+                    "--filter bcj needs an x86 or arm64 ELF program, which {} isn't",
+                    shown_input.display()
+                ))
+            },
+        )?),
+    };
+
+    // Everything from here on, including the SHA256, covers the bytes that get shipped
+    if args.strip_input && interpreter.is_some() {
+        human::note(format_args!(
+            "{} is a script, there's nothing to strip",
+            shown_input.display()
+        ));
+    } else if args.strip_input {
+        if let Some(stripped) = strip::strip_payload(&input)? {
+            input = stripped;
+        }
+    }
+
+    // --chmod wins. Otherwise the output keeps the input's permissions, which
+    // are STDIN_MODE for stdin, but has to be runnable even when the input
+    // isn't, like a script run through a launcher.
+    let output_mode = args.output_mode.unwrap_or_else(|| {
+        if args.no_stub {
+            return NO_STUB_OUTPUT_MODE;
+        }
+        let input_mode = input_permissions.mode() & 0o7777;
+        if input_mode & 0o111 != 0 {
+            input_mode
+        } else {
+            DEFAULT_OUTPUT_MODE
+        }
+    });
+
+    let input_len = input.len();
+    info!("Input size: {:.2} MB", input_len as f64 / 1_048_576.0);
+
+    let options = if args.train_dict {
+        with_trained_dictionary(&args, options, &input)?
+    } else {
+        options
+    };
+
+    let small_input = input_len < SMALL_INPUT_SIZE;
+    if small_input {
+        human::note(format_args!(
+            "{} is only {input_len} bytes, packing it makes it larger rather than smaller",
+            shown_input.display()
+        ));
+    }
+    let inline = small_input || args.single_threaded;
+    if inline {
+        debug!("Hashing and compressing the input inline");
+    }
+
+    // Share input data between threads using Arc
+    debug!("Creating Arc for input data ({} bytes)", input_len);
+    let arc_start = Instant::now();
+    let input_data = Arc::new(input);
+    info!("Arc creation took {:?}", arc_start.elapsed());
+
+    // Start SHA256 calculation in a separate thread, unless the hash is given
+    let input_for_hash = Arc::clone(&input_data);
+    let given_sha256 = args.sha256.map(sha2::digest::Output::<Sha256>::from);
+    let hash_thread = match given_sha256 {
+        Some(sha256) if !args.verify_hash => {
+            debug!("Using the SHA256 from --sha256, skipping hashing");
+            Worker::Inline((sha256, Duration::ZERO))
+        }
+        _ => Worker::spawn(inline, "SHA256", move || {
+            let hash_start = Instant::now();
+            debug!("SHA256 thread: starting hash calculation");
+
+            let mut hasher = Sha256::new();
+            let chunk_size = 1024 * 1024; // 1MB chunks
+            let data = &*input_for_hash;
+
+            for (i, chunk) in data.chunks(chunk_size).enumerate() {
+                hasher.update(chunk);
+                if i % 10 == 0 {
+                    debug!(
+                        "SHA256 thread: processed {} MB",
+                        (i + 1) * chunk_size / 1_048_576
+                    );
+                }
+            }
+
+            let sha256_hash = hasher.finalize();
+            let elapsed = hash_start.elapsed();
+            let throughput = data.len() as f64 / elapsed.as_secs_f64() / 1_048_576.0;
+            info!(
+                stage = "hash",
+                bytes = data.len(),
+                duration_ms = stats::millis(elapsed);
+                "SHA256 calculated in {:?} ({:.1} MB/s): {}",
+                elapsed,
+                throughput,
+                hex::encode(sha256_hash)
+            );
+            (sha256_hash, elapsed)
+        }),
+    };
+
+    // A shell script and an OCI layer are written in one piece once the
+    // payload is compressed, with no stub or header around it
+    let single_piece = SinglePieceOutput {
+        args: &args,
+        options: &options,
+        input_path: &input_path,
+        shown_input: &shown_input,
+        input_len,
+        hash_thread,
+        given_sha256,
+        write_path,
+        output_path,
+        hash_named,
+        staged_output,
+        suffix,
+        read_time,
+        start_time,
+    };
+    if let Some(layer) = &args.oci_layer {
+        let tar = oci::layer_tar(&layer.path, &input_data, output_mode, mtime.unwrap_or(0))?;
+        let diff_id = Sha256::digest(&tar);
+        let tar_len = tar.len();
+        let compress_thread = spawn_compression(
+            inline,
+            Arc::new(tar),
+            vec![tar_len as u64],
+            options.clone(),
+            Vec::new(),
+        );
+        let mut digest = None;
+        // A layer is data for image tools, not something to run
+        let mode = args.output_mode.unwrap_or(0o644);
+        let (packed, stats) = single_piece.finish(
+            compress_thread,
+            mode,
+            "oci-layer",
+            |output, _, compressed| {
+                digest = Some(Sha256::digest(compressed));
+                output.write_all(compressed)?;
+                Ok(compressed.len())
+            },
+        )?;
+        // What an image manifest and config refer to the layer by
+        human::note(format_args!(
+            "{} layer digest sha256:{}, diff_id sha256:{}",
+            oci::media_type(options.algorithm())?,
+            hex::encode(digest.unwrap_or_default()),
+            hex::encode(diff_id)
+        ));
+        print_stats(&args, &stats)?;
+        return Ok(packed);
+    }
+
+    if args.format == Format::Shell {
+        let compress_thread = spawn_compression(
+            inline,
+            Arc::clone(&input_data),
+            vec![input_len as u64],
+            options.clone(),
+            Vec::new(),
+        );
+        let (packed, stats) =
+            single_piece.finish(compress_thread, output_mode, "shell", shell::write_script)?;
+        print_stats(&args, &stats)?;
+        return Ok(packed);
+    }
+    let SinglePieceOutput {
+        hash_thread,
+        write_path,
+        output_path,
+        hash_named,
+        staged_output,
+        ..
+    } = single_piece;
+
+    // Meanwhile, start writing the output file with dcmprs executable
+    debug!("Loading embedded dcmprs executable");
+    let embed_start = Instant::now();
+    let custom_stub = args.stub_file.as_deref().map(read_stub_file).transpose()?;
+    let dcmprs_file = if args.no_stub || custom_stub.is_some() {
+        None
+    } else {
+        Some(embedded_stub(&args, program_machine)?)
+    };
+    // With --no-stub everything below is written as usual, just without a stub in front
+    let dcmprs_data = match &custom_stub {
+        Some(stub) => &stub[..],
+        None => dcmprs_file.map_or(&[][..], |file| file.contents()),
+    };
+    if dcmprs_file.is_some() && dcmprs_data.is_empty() {
+        return Err(missing_stub_error().into());
+    }
+    info!(
+        "Loaded {} byte dcmprs executable in {:?}",
+        dcmprs_data.len(),
+        embed_start.elapsed()
+    );
+    if let (Some(program), Some(stub)) = (program_machine, arch::elf_machine(dcmprs_data)) {
+        match arch::compat(program, stub) {
+            arch::Compat::Native => {}
+            arch::Compat::Multilib => human::note(format_args!(
+                "{} is a 32-bit {} program and the stub is built for {}, so the output runs \
+                 on {2} hosts that can run 32-bit programs, but not on 32-bit ones. \
+                 --stub-target picks a 32-bit stub for those",
+                shown_input.display(),
+                arch::name(program),
+                arch::name(stub)
+            )),
+            arch::Compat::Incompatible => {
+                return Err(CmprsError::InvalidOptions(format!(
+                    "{} is built for {}, but the stub is built for {}, so the output couldn't \
+                     start it. --stub-target or --stub-file picks a stub for its machine",
+                    shown_input.display(),
+                    arch::name(program),
+                    arch::name(stub)
+                )))
+            }
+        }
+    }
+
+    let xattrs = if args.preserve_xattr || args.preserve_caps {
+        read_xattrs(&resolved_input, args.preserve_xattr, args.preserve_caps)?
+    } else {
+        Vec::new()
+    };
+    let entry_args = match &args.entry_args {
+        Some(entry_args) => shell_words::split(entry_args).map_err(|err| {
+            CmprsError::InvalidOptions(format!("can't split --entry-args {entry_args:?}: {err}"))
+        })?,
+        None => Vec::new(),
+    };
+    let env = match &args.env_file {
+        Some(env_file) => dotenv::read(env_file)?
+            .into_iter()
+            .map(|(name, value)| (OsString::from(name), OsString::from(value)))
+            .collect(),
+        None => args.embedded_env.clone(),
+    };
+    let mut header = format::Header {
+        mode: Some(options.stored_mode(input_permissions.mode())),
+        xattrs,
+        name: resolved_input
+            .file_name()
+            .filter(|_| !args.from_stdin)
+            .map(|name| name.to_os_string()),
+        source_path: match &args.recorded_path {
+            Some(path) => Some(path.clone()),
+            None if args.record_path && !args.from_stdin => {
+                Some(recorded_path(&input_path, &args.path_prefix_map)?.into_os_string())
+            }
+            None => None,
+        },
+        launcher: args
+            .launcher
+            .iter()
+            .flat_map(|launcher| launcher.split_whitespace())
+            .map(OsString::from)
+            .collect(),
+        content_type,
+        interpreter: interpreter.unwrap_or_default(),
+        entry_args: entry_args.into_iter().map(OsString::from).collect(),
+        strip_env: args
+            .strip_env
+            .iter()
+            .flat_map(|patterns| patterns.split(','))
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .map(String::from)
+            .collect(),
+        env,
+        verify_policy: match args.verify_policy {
+            _ if args.verify_on_every_run => VerifyPolicy::Always,
+            Some(policy) => policy.into(),
+            None => VerifyPolicy::Never,
+        },
+        algorithm: options.algorithm(),
+        no_stub_flags: args.no_stub_flags,
+        argfiles: args.argfiles,
+        verify_stub: args.verify_stub,
+        split_payload: args.split,
+        needs_root: args.needs_root,
+        entry_only: args.extract_entry_only,
+        redundant: args.redundant,
+        original_size: Some(input_len as u64),
+        // Filled in once the payload is written, the same size either way
+        payload_size: Some(0),
+        bundle,
+        mtime,
+        marker: args.marker.clone(),
+        comment: args.comment.clone(),
+        dictionary: options.dictionary().map(<[u8]>::to_vec),
+        packer_version: Some(cmprs::VERSION.to_string()),
+        // Older stubs would skip the filter and run the filtered program
+        min_loader_version: match (&args.min_loader_version, filter) {
+            (Some(version), Some(_))
+                if format::parse_version(version) < format::parse_version(cmprs::VERSION) =>
+            {
+                Some(cmprs::VERSION.to_string())
+            }
+            (None, Some(_)) => Some(cmprs::VERSION.to_string()),
+            (version, _) => version.clone(),
+        },
+        filter,
+        // Signing rewrites the stub, so its hash would never match
+        stub_sha256: (!dcmprs_data.is_empty() && args.codesign.is_none())
+            .then(|| Sha256::digest(dcmprs_data).into()),
+    };
+
+    // The bundle index is filled in once its files are compressed
+    if let Some(bundle) = &mut header.bundle {
+        bundle.frames = vec![0; bundle.files.len()];
+    }
+
+    // The SHA256 and the header go before the payload but aren't final until
+    // hashing and compression are done. Their size is known up front though,
+    // so write placeholders and let the payload stream in behind them.
+    debug!(
+        "Creating output file and writing dcmprs executable: {}",
+        output_path.display()
+    );
+    let write_start = Instant::now();
+    let mut output = create_readable(&write_path)?;
+    output.write_all(dcmprs_data)?;
+    let preamble_len = format::write_preamble(&mut output)?;
+    let sha256_offset = (dcmprs_data.len() + preamble_len) as u64;
+    let placeholder = [0u8; format::SHA256_LEN];
+    let header_len = header.to_bytes(&placeholder).len();
+    output.write_all(&placeholder)?;
+    output.write_all(&vec![0; header_len])?;
+    let payload_offset = sha256_offset + (format::SHA256_LEN + header_len) as u64;
+    let dcmprs_write_time = write_start.elapsed();
+    info!(
+        "Wrote {} byte dcmprs executable + magic header in {:?}",
+        dcmprs_data.len() + preamble_len,
+        dcmprs_write_time
+    );
+
+    // With --split the payload goes to its own file, otherwise it follows the header
+    let (mut payload_file, payload_start) = if args.split {
+        let data_path = format::split_payload_path(&output_path);
+        debug!("Writing the payload to {}", data_path.display());
+        (create_readable(&data_path)?, 0)
+    } else {
+        (output.try_clone()?, payload_offset)
+    };
+    // A bundle's files get a frame each, so dcmprs can decompress any one of them alone
+    let frame_sizes = match &header.bundle {
+        Some(bundle) => bundle.files.iter().map(|file| file.size).collect(),
+        None => vec![input_len as u64],
+    };
+    // The SHA256 is of the input, what's compressed is filtered
+    let payload_data = match header.filter {
+        Some(filter) => {
+            let mut filtered = input_data.to_vec();
+            filter.encode(&mut filtered);
+            Arc::new(filtered)
+        }
+        None => Arc::clone(&input_data),
+    };
+    let compress_start = Instant::now();
+    let compress_thread = spawn_compression(
+        inline,
+        Arc::clone(&payload_data),
+        frame_sizes.clone(),
+        options.clone(),
+        BufWriter::new(payload_file.try_clone()?),
+    );
+
+    debug!("Waiting for SHA256 calculation and compression to complete");
+    let (sha256_hash, hash_duration) = join_thread(hash_thread, "SHA256")?;
+    check_given_sha256(given_sha256, &sha256_hash)?;
+    let (frames, compress_duration, timed_out) = match join_thread(compress_thread, "compression")?
+    {
+        Ok((_, frames, compress_duration)) => (frames, compress_duration, false),
+        Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+            human::warning(format_args!(
+                "{err}, storing the payload uncompressed instead"
+            ));
+            (Vec::new(), compress_start.elapsed(), true)
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let compressed_len = frames.iter().sum::<u64>();
+
+    let finish_start = Instant::now();
+    let (payload_len, level) = if timed_out
+        || options.algorithm() != Algorithm::None
+            && !args.no_auto_store
+            && compressed_len as f64
+                > input_len as f64 * args.store_if_ratio_above.unwrap_or(AUTO_STORE_MAX_RATIO)
+    {
+        if !timed_out {
+            info!(
+                "Compression only got {} bytes down to {}, storing the payload uncompressed \
+                 (pass --no-auto-store to keep it compressed)",
+                input_len, compressed_len
+            );
+        }
+        payload_file.seek(SeekFrom::Start(payload_start))?;
+        payload_file.write_all(&payload_data)?;
+        payload_file.set_len(payload_start + input_len as u64)?;
+        header.algorithm = Algorithm::None;
+        if let Some(bundle) = &mut header.bundle {
+            bundle.frames = frame_sizes;
+        }
+        (input_len as u64, STORE_LEVEL)
+    } else {
+        if let Some(bundle) = &mut header.bundle {
+            bundle.frames = frames;
+        }
+        (compressed_len, options.level())
+    };
+
+    // The copy goes right after the payload, which dcmprs splits in half
+    let stored_len = if args.redundant {
+        debug!("Writing a redundant copy of the {payload_len} byte payload");
+        let mut payload = vec![0; payload_len as usize];
+        payload_file.read_exact_at(&mut payload, payload_start)?;
+        payload_file.write_all_at(&payload, payload_start + payload_len)?;
+        payload_len * 2
+    } else {
+        payload_len
+    };
+
+    header.payload_size = Some(stored_len);
+    let header_bytes = header.to_bytes(&sha256_hash);
+    assert_eq!(
+        header_bytes.len(),
+        header_len,
+        "the header's size doesn't depend on the hash or algorithm"
+    );
+    output.seek(SeekFrom::Start(sha256_offset))?;
+    output.write_all(&sha256_hash)?;
+    output.write_all(&header_bytes)?;
+    let finish_write_time = finish_start.elapsed();
+    info!(
+        "Wrote SHA256 hash and {} byte header in {:?}",
+        header_len, finish_write_time
+    );
+
+    debug!("Setting output permissions to {:o}", output_mode);
+    let perm_start = Instant::now();
+    set_output_mode_or_warn(&output, &output_path, output_mode);
+    info!("Set permissions in {:?}", perm_start.elapsed());
+
+    // Appending the payload invalidates a signed stub's signature, so sign the
+    // final file instead
+    if let Some(identity) = &args.codesign {
+        drop(output);
+        codesign(&write_path, identity)?;
+    }
+    // Signing grows the file past what we wrote. Measured before it's moved
+    // into place, where it may be a FIFO.
+    let output_size = fs::metadata(&write_path)?.len();
+    let output_path = match (hash_named, staged_output) {
+        (Some(temp_path), _) => {
+            place_by_hash(temp_path, &input_path, &sha256_hash, suffix, args.force)?
+        }
+        (None, Some(temp_path)) => persist_output(temp_path, output_path)?,
+        (None, None) => output_path,
+    };
+
+    // Sizes as written, across both files with --split
+    let report = cmprs::PackReport {
+        input_size: input_len as u64,
+        output_size: payload_offset + stored_len,
+        stub_size: dcmprs_data.len() as u64,
+        header_size: payload_offset - dcmprs_data.len() as u64,
+        compressed_size: stored_len,
+        sha256: hex::encode(sha256_hash),
+        algorithm: header.algorithm,
+        level,
+        hash_time: hash_duration,
+        compress_time: compress_duration,
+        write_time: dcmprs_write_time + finish_write_time,
+    };
+    info!("{report}");
+    if let (Some(dictionary), Algorithm::Zstd) = (options.dictionary(), header.algorithm) {
+        report_dictionary(&args, &input_data, dictionary.len(), payload_len)?;
+    }
+    info!("Total compression completed in {:?}", start_time.elapsed());
+    let packed = PackedOutput {
+        output_size,
+        path: output_path,
+        input_size: input_len as u64,
+    };
+
+    let mut stats = stats::PackStats::from_report(
+        &shown_input,
+        if args.no_stub { "payload" } else { "binary" },
+        &packed,
+        &report,
+        read_time,
+        start_time.elapsed(),
+    );
+    if let Some(bundle) = &header.bundle {
+        stats.files = stats::FileStats::from_bundle(bundle);
+    }
+    print_stats(&args, &stats)?;
+    Ok(packed)
+}
+
+/// A pack into a format written in one piece from the compressed payload, a
+/// shell script or an OCI layer, once its hashing and compression are started
+struct SinglePieceOutput<'a> {
+    args: &'a Args,
+    options: &'a PackOptions,
+    input_path: &'a Path,
+    shown_input: &'a Path,
+    input_len: usize,
+    hash_thread: Worker<(sha2::digest::Output<Sha256>, Duration)>,
+    given_sha256: Option<sha2::digest::Output<Sha256>>,
+    write_path: PathBuf,
+    output_path: PathBuf,
+    hash_named: Option<tempfile::TempPath>,
+    staged_output: Option<tempfile::TempPath>,
+    suffix: &'a str,
+    read_time: Duration,
+    start_time: Instant,
+}
+
+impl SinglePieceOutput<'_> {
+    /// Wait for the hash and `compress_thread`, write the output with `write`,
+    /// given the input's SHA256 and the compressed payload and returning the
+    /// bytes written, and put it in place with `mode`
+    fn finish(
+        self,
+        compress_thread: Compression<Vec<u8>>,
+        mode: u32,
+        format: &'static str,
+        write: impl FnOnce(&mut File, &[u8], &[u8]) -> io::Result<usize>,
+    ) -> Result<(PackedOutput, stats::PackStats), CmprsError> {
+        debug!("Waiting for SHA256 calculation and compression to complete");
+        let (sha256_hash, hash_duration) = join_thread(self.hash_thread, "SHA256")?;
+        check_given_sha256(self.given_sha256, &sha256_hash)?;
+        let (compressed, _, compress_duration) = join_thread(compress_thread, "compression")??;
+
+        debug!("Writing {format} output: {}", self.output_path.display());
+        let write_start = Instant::now();
+        let mut output = File::create(&self.write_path)?;
+        let written = write(&mut output, &sha256_hash, &compressed)?;
+        let write_time = write_start.elapsed();
+        info!("Wrote {written} byte {format} output in {write_time:?}");
+
+        set_output_mode_or_warn(&output, &self.output_path, mode);
+        let output_path = match (self.hash_named, self.staged_output) {
+            (Some(temp_path), _) => place_by_hash(
+                temp_path,
+                self.input_path,
+                &sha256_hash,
+                self.suffix,
+                self.args.force,
+            )?,
+            (None, Some(temp_path)) => persist_output(temp_path, self.output_path)?,
+            (None, None) => self.output_path,
+        };
+        info!(
+            "Total compression completed in {:?}",
+            self.start_time.elapsed()
+        );
+        let packed = PackedOutput {
+            path: output_path,
+            input_size: self.input_len as u64,
+            output_size: written as u64,
+        };
+
+        let stats = stats::PackStats {
+            input: self.shown_input.display().to_string(),
+            output: packed.path.display().to_string(),
+            format,
+            algorithm: self.options.algorithm().name(),
+            level: self.options.level(),
+            sha256: hex::encode(sha256_hash),
+            input_size: self.input_len,
+            stub_size: 0,
+            compressed_size: compressed.len(),
+            output_size: written,
+            ratio: compressed.len() as f64 / self.input_len as f64,
+            timings_ms: stats::Timings::new(
+                self.read_time,
+                hash_duration,
+                compress_duration,
+                write_time,
+                self.start_time.elapsed(),
+            ),
+            peak_rss: stats::peak_rss(),
+            files: Vec::new(),
+        };
+        Ok((packed, stats))
+    }
+}
+
+/// Print the stats as JSON with --json-stats, or else as a summary for people
+fn print_stats(args: &Args, stats: &stats::PackStats) -> io::Result<()> {
+    if args.json_stats {
+        stats.print()
+    } else {
+        if !args.no_summary {
+            human::print_summary(stats);
+        }
+        Ok(())
+    }
+}
+
+/// What a single-file input is, when it isn't a native executable. A
+/// WebAssembly module only runs through a WASI runtime, given with --launcher.
+fn content_type(
+    input_path: &Path,
+    input: &[u8],
+    launcher: Option<&str>,
+) -> Result<Option<format::ContentType>, CmprsError> {
+    if shebang(input).is_some() {
+        debug!("Input is a script");
+        return Ok(Some(format::ContentType::Script));
+    }
+    if !input.starts_with(WASM_MAGIC) {
+        if input_path
+            .extension()
+            .is_some_and(|extension| extension == "wasm")
+        {
+            return Err(CmprsError::InvalidOptions(format!(
+                "{} isn't a WebAssembly module, it doesn't start with \\0asm",
+                input_path.display()
+            )));
+        }
+        if launcher.is_none() && !arch::is_executable(input) {
+            human::warning(format_args!(
+                "{} isn't an executable or a #! script, the packed file will refuse to run \
+                 without --launcher naming a program to open it with",
+                input_path.display()
+            ));
+            return Ok(Some(format::ContentType::Data));
+        }
+        return Ok(None);
+    }
+    if launcher.is_none() {
+        return Err(CmprsError::InvalidOptions(format!(
+            "{} is a WebAssembly module; pass --launcher with the WASI runtime to run it, e.g. --launcher wasmtime",
+            input_path.display()
+        )));
+    }
+    debug!("Input is a WebAssembly module");
+    Ok(Some(format::ContentType::Wasm))
+}
+
+/// The interpreter of a script starting with a `#!` line, split the way the
+/// kernel does: the program, then the rest of the line as a single argument
+fn shebang(input: &[u8]) -> Option<Vec<OsString>> {
+    let line = input.strip_prefix(b"#!")?;
+    let line = &line[..line.iter().position(|&byte| byte == b'\n')?];
+    let line = line.trim_ascii();
+    let (program, arg) = match line.iter().position(|byte| matches!(byte, b' ' | b'\t')) {
+        Some(end) => (&line[..end], line[end..].trim_ascii()),
+        None => (line, &b""[..]),
+    };
+    if program.is_empty() {
+        return None;
+    }
+    let mut interpreter = vec![OsStr::from_bytes(program).to_os_string()];
+    if !arg.is_empty() {
+        interpreter.push(OsStr::from_bytes(arg).to_os_string());
+    }
+    Some(interpreter)
+}
+
+/// The modification time stored for the input. Reproducible builds set
+/// SOURCE_DATE_EPOCH, which wins over the input's own mtime.
+fn recorded_mtime(metadata: &fs::Metadata) -> Result<Option<u64>, CmprsError> {
+    if let Some(epoch) = env::var_os("SOURCE_DATE_EPOCH") {
+        let epoch = epoch.to_string_lossy();
+        return epoch.trim().parse().map(Some).map_err(|_| {
+            CmprsError::InvalidOptions(format!(
+                "SOURCE_DATE_EPOCH must be a number of seconds, got {epoch:?}"
+            ))
+        });
+    }
+    Ok(metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map(|since_epoch| since_epoch.as_secs()))
+}
+
+/// A temp file next to `output_path` to write the output to, or None when
+/// one can't be created there and the output has to be written in place
+fn stage_output(output_path: &Path) -> Option<tempfile::TempPath> {
+    // The output is written with seeks, which a FIFO or a device can't take,
+    // and next to a device (in /dev) is no place for a temp file
+    let dir = if is_special_file(output_path) {
+        env::temp_dir()
+    } else {
+        parent_dir(output_path).to_path_buf()
+    };
+    match tempfile::Builder::new()
+        .prefix(".cmprs-output")
+        .tempfile_in(dir)
+    {
+        Ok(temp_file) => Some(temp_file.into_temp_path()),
+        Err(err) => {
+            human::warning(format_args!(
+                "can't create a temp file next to {} ({err}), writing it directly",
+                output_path.display()
+            ));
+            None
+        }
+    }
+}
+
+/// Rename the finished output from `temp_path` to `output_path`. When the
+/// output is on another filesystem, like a bind-mounted file, it's copied over
+/// instead, which isn't atomic.
+fn persist_output(temp_path: tempfile::TempPath, output_path: PathBuf) -> io::Result<PathBuf> {
+    // Renaming over a FIFO or a device would replace it with a regular file
+    if is_special_file(&output_path) {
+        debug!(
+            "Copying the output into {}, leaving its permissions alone",
+            output_path.display()
+        );
+        let mut output = fs::OpenOptions::new().write(true).open(&output_path)?;
+        io::copy(&mut File::open(&temp_path)?, &mut output)?;
+        return Ok(output_path);
+    }
+    match temp_path.persist(&output_path) {
+        Ok(()) => Ok(output_path),
+        Err(err)
+            if matches!(
+                err.error.kind(),
+                io::ErrorKind::CrossesDevices | io::ErrorKind::ResourceBusy
+            ) =>
+        {
+            human::warning(format_args!(
+                "can't rename the output into {} ({}), writing it directly",
+                output_path.display(),
+                err.error
+            ));
+            fs::copy(&err.path, &output_path)?;
+            Ok(output_path)
+        }
+        Err(err) => Err(err.error),
+    }
+}
+
+/// Fail when --verify-hash found the input's SHA256 to differ from --sha256.
+/// Without --verify-hash, `actual` is the given hash and always matches.
+fn check_given_sha256(
+    given: Option<sha2::digest::Output<Sha256>>,
+    actual: &[u8],
+) -> Result<(), CmprsError> {
+    match given {
+        Some(given) if given.as_slice() != actual => Err(CmprsError::InvalidOptions(format!(
+            "--sha256 {} doesn't match the input, whose SHA256 is {}",
+            hex::encode(given),
+            hex::encode(actual)
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Move an output packed with --name-by-hash to its final name next to `input`.
+/// An existing file with the same content is kept, a different one needs `force`.
+fn place_by_hash(
+    temp_path: tempfile::TempPath,
+    input: &Path,
+    sha256: &[u8],
+    suffix: &str,
+    force: bool,
+) -> io::Result<PathBuf> {
+    let prefix = &hex::encode(sha256)[..HASH_NAME_LEN];
+    let name = if suffix.is_empty() {
+        prefix.to_string()
+    } else {
+        format!("{prefix}.{suffix}")
+    };
+    let path = parent_dir(input).join(name);
+
+    if path.exists() && !force {
+        if fs::read(&path)? == fs::read(&temp_path)? {
+            info!(
+                "{} already exists with the same content, leaving it as is",
+                path.display()
+            );
+            return Ok(path);
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{} already exists with different content (packed with other options?); \
+                 pass --force to replace it",
+                path.display()
+            ),
+        ));
+    }
+    temp_path.persist(&path).map_err(|err| err.error)?;
+    Ok(path)
+}
+
+/// Create (or truncate) `path` for writing the output, readable as well so
+/// that --redundant can copy the payload it wrote
+fn create_readable(path: &Path) -> io::Result<File> {
+    fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+}
+
+/// The absolute path of `input_path` as --record-path records it, rewritten
+/// by the last of `prefix_map` it's under. Relative OLD prefixes are relative
+/// to the current directory, like the input.
+fn recorded_path(input_path: &Path, prefix_map: &[(PathBuf, PathBuf)]) -> io::Result<PathBuf> {
+    let path = std::path::absolute(input_path)?;
+    for (old, new) in prefix_map.iter().rev() {
+        if let Ok(rest) = path.strip_prefix(std::path::absolute(old)?) {
+            // Joining nothing would add a trailing slash
+            return Ok(if rest.as_os_str().is_empty() {
+                new.clone()
+            } else {
+                new.join(rest)
+            });
+        }
+    }
+    Ok(path)
+}
+
+/// The directory `path` is in, which is "." for a bare file name
+fn parent_dir(path: &Path) -> &Path {
+    path.parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."))
+}
+
+/// A running compression: its output, the compressed size of each frame and
+/// how long it took
+type Compression<W> = Worker<io::Result<(W, Vec<u64>, Duration)>>;
+
+/// Compress `input` into `out` on its own thread (unless `inline`), a frame for
+/// each of `frame_sizes`, returning `out`, the compressed size of each frame
+/// and how long compressing took
+fn spawn_compression<W: Write + Send + 'static>(
+    inline: bool,
+    input: Arc<Vec<u8>>,
+    frame_sizes: Vec<u64>,
+    options: PackOptions,
+    mut out: W,
+) -> Compression<W> {
+    Worker::spawn(inline, "compression", move || {
+        let compress_start = Instant::now();
+        debug!(
+            "Compression thread: starting {} compression (level {})",
+            options.algorithm().name(),
+            options.level()
+        );
+        let progress_bar = compression_progress_bar(input.len() as u64);
+        let mut stage = progress::Stage::new("compress", input.len() as u64);
+        let mut throughput = Throughput::new(THROUGHPUT_WINDOW);
+        let result =
+            cmprs::compress_frames_to(&input, &frame_sizes, &options, &mut out, |done, total| {
+                progress_bar.set_position(done);
+                stage.update(done);
+                throughput.record(done, Instant::now());
+                if let (Some(rate), Some(eta)) = (throughput.bytes_per_sec(), throughput.eta(total))
+                {
+                    progress_bar.set_message(format!(
+                        "{}/s, {} left",
+                        HumanBytes(rate as u64),
+                        FormattedDuration(eta)
+                    ));
+                }
+            });
+        progress_bar.finish_and_clear();
+        let frames = result?;
+        stage.finish();
+        let compressed_len = frames.iter().sum::<u64>();
+        out.flush()?;
+
+        let elapsed = compress_start.elapsed();
+        let compression_ratio = compressed_len as f64 / input.len() as f64;
+        let throughput = input.len() as f64 / elapsed.as_secs_f64() / 1_048_576.0;
+        info!(
+            stage = "compress",
+            bytes = input.len(),
+            compressed_bytes = compressed_len,
+            duration_ms = stats::millis(elapsed);
+            "Compressed {} bytes to {} bytes ({:.1}%) in {:?} ({:.1} MB/s)",
+            input.len(),
+            compressed_len,
+            compression_ratio * 100.0,
+            elapsed,
+            throughput
+        );
+
+        if elapsed.as_secs() > 5 {
+            warn!(
+                "Compression took longer than 5 seconds - consider using a lower compression level"
+            );
+        }
+
+        Ok((out, frames, elapsed))
+    })
+}
+
+/// Sign `path` with the macOS codesign tool, replacing any existing signature
+fn codesign(path: &Path, identity: &str) -> io::Result<()> {
+    debug!("Signing {} as {identity}", path.display());
+    let status = process::Command::new("codesign")
+        .args(["--force", "--sign", identity])
+        .arg(path)
+        .status()
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("failed to run codesign (--codesign needs macOS's codesign tool): {err}"),
+            )
+        })?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "codesign failed to sign {} ({status})",
+            path.display()
+        )));
+    }
+    info!("Signed {} as {identity}", path.display());
+    Ok(())
+}
+
+/// Progress bar for the compression thread, drawn on stderr only when it's a terminal,
+/// with the recent throughput and how long the rest will take at it
+fn compression_progress_bar(total: u64) -> ProgressBar {
+    let progress_bar = ProgressBar::new(total);
+    progress_bar.set_style(
+        ProgressStyle::with_template(
+            "{spinner} Compressing [{bar:40}] {bytes}/{total_bytes} {msg}",
+        )
+        .expect("valid progress template")
+        .progress_chars("=> "),
+    );
+    // --progress-json lines on stderr would be drawn over
+    if progress::on_stderr() {
+        progress_bar.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    progress_bar
+}
+
+/// Work done on a thread of its own, or right away for a small input
+enum Worker<T> {
+    Thread(thread::JoinHandle<T>),
+    Inline(T),
+}
+
+impl<T: Send + 'static> Worker<T> {
+    fn spawn(inline: bool, name: &str, work: impl FnOnce() -> T + Send + 'static) -> Self {
+        if inline {
+            Worker::Inline(work())
+        } else {
+            debug!("Starting {name} thread");
+            Worker::Thread(thread::spawn(work))
+        }
+    }
+}
+
+/// Wait for a worker thread, turning a panic into an error instead of propagating it
+fn join_thread<T>(worker: Worker<T>, name: &str) -> io::Result<T> {
+    match worker {
+        Worker::Thread(handle) => handle
+            .join()
+            .map_err(|_| io::Error::other(format!("the {name} thread panicked"))),
+        Worker::Inline(result) => Ok(result),
+    }
+}
+
+/// Find the embedded macOS stub for `variant`
+fn select_stub_variant(variant: StubVariant) -> io::Result<&'static include_dir::File<'static>> {
+    DIST_DIR.get_file(variant.file_name()).ok_or_else(|| {
+        let available: Vec<String> = StubVariant::value_variants()
+            .iter()
+            .filter(|variant| DIST_DIR.get_file(variant.file_name()).is_some())
+            .filter_map(|variant| variant.to_possible_value())
+            .map(|value| value.get_name().to_string())
+            .collect();
+        let name = variant
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default();
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "no {name} dcmprs stub was embedded (available: {}). \
+                 macOS stub variants are only built when cmprs is built on macOS",
+                if available.is_empty() {
+                    "none".to_string()
+                } else {
+                    available.join(", ")
+                }
+            ),
+        )
+    })
+}
+
+/// Read the stub given with --stub-file. One that's packed itself would be
+/// found instead of our payload, so it's refused. One without the magic header
+/// most likely doesn't look for a payload at all, which only gets a warning
+/// since custom loaders may find it another way.
+fn read_stub_file(path: &Path) -> Result<Vec<u8>, CmprsError> {
+    let stub = fs::read(path).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("failed to read --stub-file {}: {err}", path.display()),
+        )
+    })?;
+    if stub.is_empty() {
+        return Err(CmprsError::InvalidOptions(format!(
+            "--stub-file {} is empty",
+            path.display()
+        )));
+    }
+    if format::find_magic_header(&stub).is_some() {
+        return Err(CmprsError::InvalidOptions(format!(
+            "--stub-file {} already has a payload packed into it; use the bare stub",
+            path.display()
+        )));
+    }
+    if !stub
+        .windows(format::MAGIC_HEADER.len())
+        .any(|window| window == format::MAGIC_HEADER)
+    {
+        human::warning(format_args!(
+            "--stub-file {} doesn't look like a dcmprs stub, it never mentions the magic header \
+             it would have to search for, so the output may not run",
+            path.display()
+        ));
+    }
+    Ok(stub)
+}
+
+/// The stub built for the target cmprs was compiled for (or CMPRS_STUB_TARGET)
+fn main_stub() -> io::Result<&'static include_dir::File<'static>> {
+    DIST_DIR.get_file("main").ok_or_else(missing_stub_error)
+}
+
+/// The embedded stub `args` pick, or the default one for `program`
+fn embedded_stub(
+    args: &Args,
+    program: Option<u16>,
+) -> io::Result<&'static include_dir::File<'static>> {
+    if let Some(stub_target) = &args.stub_target {
+        select_stub_for_target(stub_target)
+    } else if args.minimize_stub {
+        DIST_DIR.get_file("minimal").ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "no minimal dcmprs stub was embedded (cmprs was built with CMPRS_STUB_PATH)",
+            )
+        })
+    } else if let Some(variant) = args
+        // --build-universal-macos predates --stub-variant
+        .stub_variant
+        .or(args.build_universal_macos.then_some(StubVariant::Universal))
+    {
+        select_stub_variant(variant)
+    } else {
+        default_stub(program)
+    }
+}
+
+/// Write the stub `args` pick, with no payload, to their --output
+fn write_stub_only(args: &Args) -> Result<(), CmprsError> {
+    let stub = embedded_stub(args, None)?;
+    if stub.contents().is_empty() {
+        return Err(missing_stub_error().into());
+    }
+    let output_path = args
+        .output
+        .as_deref()
+        .expect("clap requires --output with --stub-only");
+    let mut output = File::create(output_path)?;
+    output.write_all(stub.contents())?;
+    set_output_mode_or_warn(&output, output_path, args.output_mode.unwrap_or(0o755));
+    eprintln!(
+        "cmprs: wrote the {} dcmprs stub ({} bytes) to {}",
+        stub.path().display(),
+        stub.contents().len(),
+        output_path.display()
+    );
+    Ok(())
+}
+
+/// The stub to use when none was asked for: the main one, unless `program` is
+/// for another machine and a stub built for that one was embedded as well
+fn default_stub(program: Option<u16>) -> io::Result<&'static include_dir::File<'static>> {
+    let main = main_stub()?;
+    let Some(program) = program else {
+        return Ok(main);
+    };
+    if arch::elf_machine(main.contents()).is_none_or(|stub| stub == program) {
+        return Ok(main);
+    }
+    let native = DIST_DIR
+        .files()
+        .filter(|file| !matches!(file.path().to_str(), Some("main" | "minimal")))
+        .find(|file| arch::elf_machine(file.contents()) == Some(program));
+    if let Some(native) = native {
+        info!(
+            "Using the embedded {} stub, built for the input's machine",
+            native.path().display()
+        );
+        return Ok(native);
+    }
+    Ok(main)
+}
+
+fn missing_stub_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        "embedded dcmprs stub missing; rebuild cmprs",
+    )
+}
+
+/// Find the embedded stub built for `target`.
+/// The `main` stub is stored under "main", additional ones under their triple.
+fn select_stub_for_target(target: &str) -> io::Result<&'static include_dir::File<'static>> {
+    let name = if target == env!("CMPRS_MAIN_STUB_TARGET") {
+        "main"
+    } else {
+        target
+    };
+
+    DIST_DIR.get_file(name).ok_or_else(|| {
+        let mut available: Vec<&str> = vec![env!("CMPRS_MAIN_STUB_TARGET")];
+        available.extend(
+            DIST_DIR
+                .files()
+                .filter_map(|file| file.path().to_str())
+                .filter(|name| !matches!(*name, "main" | "minimal"))
+                .filter(|name| !name.starts_with("macos_")),
+        );
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "no dcmprs stub was embedded for target {target} (available: {}). \
+                 Rebuild cmprs with CMPRS_STUB_TARGETS={target}",
+                available.join(", ")
+            ),
+        )
+    })
+}
+
+/// Read the extended attributes of `path` so they can be stored in the
+/// header: all of them with `all`, but the file capabilities only with `caps`
+fn read_xattrs(path: &Path, all: bool, caps: bool) -> io::Result<Vec<(OsString, Vec<u8>)>> {
+    let mut xattrs = Vec::new();
+    for name in xattr::list(path)? {
+        let is_caps = name == format::CAPABILITY_XATTR;
+        if is_caps && !caps {
+            human::note(format_args!(
+                "{} has file capabilities, which aren't stored without --preserve-caps",
+                path.display()
+            ));
+            continue;
+        }
+        if !is_caps && !all {
+            continue;
+        }
+        match xattr::get(path, &name)? {
+            Some(value) => {
+                debug!(
+                    "Preserving extended attribute {:?} ({} bytes)",
+                    name,
+                    value.len()
+                );
+                xattrs.push((name, value));
+            }
+            None => debug!("Extended attribute {:?} vanished while reading", name),
+        }
+    }
+    if caps
+        && !xattrs
+            .iter()
+            .any(|(name, _)| name == format::CAPABILITY_XATTR)
+    {
+        human::warning(format_args!(
+            "--preserve-caps: {} has no file capabilities to store",
+            path.display()
+        ));
+    }
+    info!("Preserving {} extended attributes", xattrs.len());
+    Ok(xattrs)
+}
+
+/// Refuse inputs that wouldn't fit in memory, since they're read in full
+fn check_available_memory(input_len: u64, allow_large: bool) -> io::Result<()> {
+    let mut system = sysinfo::System::new();
+    system.refresh_memory();
+    let available = system.available_memory();
+    debug!("{available} bytes of memory available");
+    check_memory(input_len, available, allow_large)
+}
+
+/// The decision behind check_available_memory, given what's `available`. An
+/// `available` of 0 means sysinfo couldn't tell, which isn't worth refusing over.
+fn check_memory(input_len: u64, available: u64, allow_large: bool) -> io::Result<()> {
+    if available == 0 || fits_in_memory(input_len, available) {
+        return Ok(());
+    }
+    if allow_large {
+        warn!(
+            "Input is {:.1} MB with only {:.1} MB of memory available, packing anyway",
+            input_len as f64 / 1_048_576.0,
+            available as f64 / 1_048_576.0
+        );
+        return Ok(());
+    }
+    Err(io::Error::new(
+        io::ErrorKind::OutOfMemory,
+        format!(
+            "input is {:.1} MB, more than {:.0}% of the {:.1} MB of available memory, and cmprs \
+             reads the whole input into memory. Pass --allow-large to pack it anyway",
+            input_len as f64 / 1_048_576.0,
+            MAX_INPUT_MEMORY_FRACTION * 100.0,
+            available as f64 / 1_048_576.0
+        ),
+    ))
+}
+
+/// Whether an input of `input_len` bytes can be packed with `available` bytes of memory
+fn fits_in_memory(input_len: u64, available: u64) -> bool {
+    input_len as f64 <= available as f64 * MAX_INPUT_MEMORY_FRACTION
+}
+
+/// Turn the compression flags into validated pack options
+fn pack_options(args: &Args) -> Result<PackOptions, CmprsError> {
+    pack_options_builder(args).build()
+}
+
+fn pack_options_builder(args: &Args) -> cmprs::PackOptionsBuilder {
+    let builder = PackOptions::builder();
+    let builder = match (args.algorithm, args.compression_level) {
+        _ if args.store => builder.algorithm(Algorithm::None),
+        // --level is the named algorithm's own, where 0 doesn't mean storing
+        (Some(algorithm), level) => {
+            let builder = builder
+                .algorithm(algorithm.into())
+                .xz_extreme(args.xz_extreme);
+            match level {
+                Some(level) => level.apply(builder),
+                None => builder,
+            }
+        }
+        _ if args.xz_preset.is_some() || args.xz_extreme => {
+            let builder = builder.algorithm(Algorithm::Xz).xz_extreme(args.xz_extreme);
+            match args.xz_preset {
+                Some(preset) => builder.level(preset),
+                None => builder,
+            }
+        }
+        (None, Some(LevelArg::Number(STORE_LEVEL))) => builder.algorithm(Algorithm::None),
+        (None, Some(level)) => level.apply(builder),
+        (None, None) => builder,
+    };
+    let builder = match args.chunk_size {
+        Some(chunk_size) => builder.chunk_size(chunk_size),
+        None => builder,
+    };
+    let builder = match args.deterministic_threads {
+        Some(threads) => builder.workers(threads),
+        None => builder,
+    };
+    let builder = match args.block_size {
+        Some(block_size) => builder.block_size(block_size),
+        None => builder,
+    };
+    match args.time_limit {
+        Some(time_limit) => builder.time_limit(time_limit),
+        None => builder,
+    }
+}
+
+/// `options` with a dictionary trained on `input`, or as they are when the
+/// input is too small to train on
+fn with_trained_dictionary(
+    args: &Args,
+    options: PackOptions,
+    input: &[u8],
+) -> Result<PackOptions, CmprsError> {
+    let train_start = Instant::now();
+    match cmprs::train_dictionary(input) {
+        Ok(dictionary) => {
+            info!(
+                "Trained a {} byte dictionary in {:?}",
+                dictionary.len(),
+                train_start.elapsed()
+            );
+            pack_options_builder(args).dictionary(dictionary).build()
+        }
+        Err(err) => {
+            human::warning(format_args!(
+                "can't train a dictionary on {} bytes of input ({err}), compressing without one",
+                input.len()
+            ));
+            Ok(options)
+        }
+    }
+}
+
+/// Compress `input` again without the dictionary and print how the two compare,
+/// counting the dictionary stored in the header against it
+fn report_dictionary(
+    args: &Args,
+    input: &[u8],
+    dictionary_len: usize,
+    payload_len: u64,
+) -> Result<(), CmprsError> {
+    let baseline = cmprs::compress_to(input, &pack_options(args)?, &mut io::sink(), |_, _| {})?;
+    let with_dictionary = payload_len + dictionary_len as u64;
+    eprintln!(
+        "cmprs: {payload_len} byte payload + {dictionary_len} byte dictionary, vs {baseline} bytes without one ({:+.1}%)",
+        (with_dictionary as f64 / baseline as f64 - 1.0) * 100.0
+    );
+    Ok(())
+}
+
+/// Parse a compression level from `--level` or `CMPRS_LEVEL`, a number or
+/// an alias. The range is checked when building the pack options.
+fn parse_level(value: &str) -> Result<LevelArg, String> {
+    let value = value.trim();
+    match value.parse() {
+        Ok(level) => Ok(LevelArg::Number(level)),
+        Err(_) if value.starts_with(|c: char| c == '-' || c.is_ascii_digit()) => {
+            Err(format!("'{value}' is not a number"))
+        }
+        Err(_) => value.parse().map(LevelArg::Alias),
+    }
+}
+
+fn parse_marker(value: &str) -> Result<String, String> {
+    if value.len() > MAX_MARKER_LEN {
+        return Err(format!("markers are limited to {MAX_MARKER_LEN} bytes"));
+    }
+    if !value
+        .bytes()
+        .all(|byte| byte == b' ' || byte.is_ascii_graphic())
+    {
+        return Err("markers must be printable ASCII".to_string());
+    }
+    Ok(value.to_string())
+}
+
+fn parse_comment(value: &str) -> Result<String, String> {
+    if value.len() > MAX_COMMENT_LEN {
+        return Err(format!("comments are limited to {MAX_COMMENT_LEN} bytes"));
+    }
+    if !value
+        .bytes()
+        .all(|byte| byte == b' ' || byte.is_ascii_graphic())
+    {
+        return Err("comments must be a single line of printable ASCII".to_string());
+    }
+    Ok(value.to_string())
+}
+
+fn parse_path_prefix_map(value: &str) -> Result<(PathBuf, PathBuf), String> {
+    match value.split_once('=') {
+        Some((old, new)) if !old.is_empty() => Ok((PathBuf::from(old), PathBuf::from(new))),
+        _ => Err(format!("expected OLD=NEW, got '{value}'")),
+    }
+}
+
+fn parse_subcommand(value: &str) -> Result<(String, PathBuf), String> {
+    let Some((name, path)) = value.split_once('=') else {
+        return Err(format!("expected NAME=PATH, got '{value}'"));
+    };
+    if name.is_empty() || name.starts_with('-') {
+        return Err(format!(
+            "subcommand names can't be empty or start with '-', got '{name}'"
+        ));
+    }
+    Ok((name.to_string(), PathBuf::from(path)))
+}
+
+fn parse_filter(value: &str) -> Result<FilterArg, String> {
+    match value.trim() {
+        "bcj" => Ok(FilterArg::Bcj),
+        "delta" => Ok(FilterArg::Delta(1)),
+        "none" => Ok(FilterArg::None),
+        other => other
+            .strip_prefix("delta:")
+            .and_then(|distance| distance.parse().ok())
+            .filter(|&distance| distance > 0)
+            .map(FilterArg::Delta)
+            .ok_or_else(|| {
+                format!("'{value}' is not bcj, delta, delta:N (N from 1 to 255) or none")
+            }),
+    }
+}
+
+fn parse_min_loader_version(value: &str) -> Result<String, String> {
+    let version = value.trim();
+    format::parse_version(version)
+        .map(|_| version.to_string())
+        .ok_or_else(|| format!("'{value}' is not a version like 0.2.0"))
+}
+
+/// A percentage from 0 to 100, as the fraction of the input it stands for
+fn parse_store_ratio(value: &str) -> Result<f64, String> {
+    value
+        .trim()
+        .trim_end_matches('%')
+        .parse::<f64>()
+        .ok()
+        .filter(|percent| (0.0..=100.0).contains(percent))
+        .map(|percent| percent / 100.0)
+        .ok_or_else(|| format!("'{value}' is not a percentage from 0 to 100"))
+}
+
+fn parse_time_limit(value: &str) -> Result<Duration, String> {
+    value
+        .trim()
+        .parse()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("'{value}' is not a number of seconds"))
+}
+
+fn parse_size(value: &str) -> Result<usize, String> {
+    let digits = value.trim();
+    let (digits, multiplier) = match digits.strip_suffix(['K', 'k']) {
+        Some(digits) => (digits, 1024),
+        None => match digits.strip_suffix(['M', 'm']) {
+            Some(digits) => (digits, 1024 * 1024),
+            None => (digits, 1),
+        },
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|size| size.checked_mul(multiplier))
+        .filter(|&size| size > 0)
+        .ok_or_else(|| format!("'{value}' is not a size in bytes like 65536, 256K or 4M"))
+}
+
+/// A zstd target block size, as a size in bytes within zstd's range
+fn parse_block_size(value: &str) -> Result<u32, String> {
+    let size = parse_size(value)?;
+    u32::try_from(size)
+        .ok()
+        .filter(|size| (cmprs::MIN_BLOCK_SIZE..=cmprs::MAX_BLOCK_SIZE).contains(size))
+        .ok_or_else(|| {
+            format!(
+                "the block size must be between {} and {} bytes (128K), got {size}",
+                cmprs::MIN_BLOCK_SIZE,
+                cmprs::MAX_BLOCK_SIZE
+            )
+        })
+}
+
+fn parse_sha256(value: &str) -> Result<[u8; format::SHA256_LEN], String> {
+    let mut sha256 = [0; format::SHA256_LEN];
+    hex::decode_to_slice(value.trim(), &mut sha256)
+        .map_err(|_| format!("'{value}' is not a SHA256 of 64 hex digits"))?;
+    Ok(sha256)
+}
+
+fn parse_mode(value: &str) -> Result<u32, String> {
+    let digits = value.trim();
+    let digits = digits.strip_prefix("0o").unwrap_or(digits);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("'{value}' is not an octal file mode like 0755")),
+    }
+}
+
+/// Set the permission bits of the output, only warning when that fails. Some
+/// filesystems (FAT, some network mounts) have no mode bits, and an output
+/// missing them is easily fixed, unlike a pack that's thrown away.
+fn set_output_mode_or_warn(output: &File, output_path: &Path, mode: u32) {
+    if let Err(err) = set_output_mode(output, mode) {
+        human::warning(format_args!(
+            "can't set the permissions of {} to {mode:o} ({err}), its filesystem may not \
+             support them. The output is complete otherwise; run `chmod {mode:o}` on it where it runs",
+            output_path.display()
+        ));
+    }
+}
+
+/// Set the permission bits of the output
+fn set_output_mode(output: &File, mode: u32) -> io::Result<()> {
+    output.set_permissions(fs::Permissions::from_mode(mode))
+}
+
+/// Whether `path` exists as something other than a regular file, like a
+/// FIFO or a character device, which the output is streamed into
+fn is_special_file(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| !metadata.is_file() && !metadata.is_dir())
+}
+
+/// Whether `input` and `output` are the same file on disk, through a symlink
+/// or a hard link too
+fn is_same_file(input: &Path, output: &Path) -> bool {
+    match (fs::metadata(input), fs::metadata(output)) {
+        (Ok(input), Ok(output)) => input.dev() == output.dev() && input.ino() == output.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1 << 30;
+
+    #[test]
+    fn fits_in_memory_up_to_the_fraction() {
+        let limit = (8.0 * GIB as f64 * MAX_INPUT_MEMORY_FRACTION) as u64;
+        assert!(fits_in_memory(limit - 1, 8 * GIB));
+        assert!(fits_in_memory(limit, 8 * GIB));
+        assert!(!fits_in_memory(limit + 1, 8 * GIB));
+    }
+
+    #[test]
+    fn check_memory_refuses_large_inputs_unless_allowed() {
+        let err = check_memory(5 * GIB, 8 * GIB, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+        assert!(check_memory(5 * GIB, 8 * GIB, true).is_ok());
+        assert!(check_memory(3 * GIB, 8 * GIB, false).is_ok());
+    }
+
+    #[test]
+    fn check_memory_passes_when_available_memory_is_unknown() {
+        assert!(check_memory(u64::MAX, 0, false).is_ok());
+    }
 }
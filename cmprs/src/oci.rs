@@ -0,0 +1,156 @@
+use cmprs::{Algorithm, CmprsError};
+use std::path::{Component, Path, PathBuf};
+
+const BLOCK_LEN: usize = 512;
+
+// The directories leading to the program, which everyone can search
+const DIR_MODE: u32 = 0o755;
+
+/// Where --oci-layer puts the program in the image's filesystem
+#[derive(Clone, Debug)]
+pub struct OciLayer {
+    pub path: PathBuf,
+}
+
+/// Parse `path=/usr/local/bin/tool` for --oci-layer
+pub fn parse_layer(value: &str) -> Result<OciLayer, String> {
+    let mut path = None;
+    for option in value.split(',') {
+        match option.split_once('=') {
+            Some(("path", value)) => path = Some(PathBuf::from(value)),
+            Some((key, _)) => return Err(format!("unknown layer option {key:?}, expected path")),
+            None => return Err(format!("expected path=/usr/local/bin/tool, got {option:?}")),
+        }
+    }
+    let path = path.ok_or("expected path=/usr/local/bin/tool")?;
+    let normal = path
+        .components()
+        .skip(1)
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !path.has_root() || !normal || path.parent().is_none() || value.ends_with('/') {
+        return Err(format!(
+            "{} must be the absolute path of a file, without . or .. in it",
+            path.display()
+        ));
+    }
+    Ok(OciLayer { path })
+}
+
+/// The media type of a layer compressed with `algorithm`, from the OCI image
+/// spec, which has no xz layers
+pub fn media_type(algorithm: Algorithm) -> Result<&'static str, CmprsError> {
+    match algorithm {
+        Algorithm::Zstd => Ok("application/vnd.oci.image.layer.v1.tar+zstd"),
+        Algorithm::Gzip => Ok("application/vnd.oci.image.layer.v1.tar+gzip"),
+        Algorithm::None => Ok("application/vnd.oci.image.layer.v1.tar"),
+        Algorithm::Xz => Err(CmprsError::InvalidOptions(
+            "--oci-layer compresses with zstd or gzip (or --store), OCI layers can't be xz"
+                .to_string(),
+        )),
+    }
+}
+
+/// The suffix of a layer compressed with `algorithm`
+pub fn suffix(algorithm: Algorithm) -> &'static str {
+    match algorithm {
+        Algorithm::Zstd => "tar.zst",
+        Algorithm::Gzip => "tar.gz",
+        Algorithm::None | Algorithm::Xz => "tar",
+    }
+}
+
+/// A ustar archive of `program` at `path`, with each directory above it, all
+/// owned by root. Nothing but `mode` and `mtime` comes from the host, so the
+/// same input makes the same layer.
+pub fn layer_tar(
+    path: &Path,
+    program: &[u8],
+    mode: u32,
+    mtime: u64,
+) -> Result<Vec<u8>, CmprsError> {
+    let relative = path.strip_prefix("/").unwrap_or(path);
+    let mut tar = Vec::with_capacity(program.len() + 8 * BLOCK_LEN);
+    let mut dir = PathBuf::new();
+    for ancestor in relative.parent().into_iter().flat_map(Path::components) {
+        dir.push(ancestor);
+        let name = format!("{}/", dir.display());
+        append_header(&mut tar, &name, DIR_MODE, 0, mtime, b'5')?;
+    }
+    append_header(
+        &mut tar,
+        &relative.display().to_string(),
+        mode & 0o7777,
+        program.len() as u64,
+        mtime,
+        b'0',
+    )?;
+    tar.extend_from_slice(program);
+    tar.resize(tar.len().next_multiple_of(BLOCK_LEN), 0);
+    // Two zero blocks end the archive
+    tar.resize(tar.len() + 2 * BLOCK_LEN, 0);
+    Ok(tar)
+}
+
+fn append_header(
+    tar: &mut Vec<u8>,
+    name: &str,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    kind: u8,
+) -> Result<(), CmprsError> {
+    let (prefix, name) = split_name(name).ok_or_else(|| {
+        CmprsError::InvalidOptions(format!("{name} is too long a path for a layer"))
+    })?;
+    // Eleven octal digits, which size and mtime both fit in
+    if size >= 1 << 33 {
+        return Err(CmprsError::InvalidOptions(format!(
+            "{name} is over 8 GiB, more than a ustar layer can hold"
+        )));
+    }
+    if mtime >= 1 << 33 {
+        return Err(CmprsError::InvalidOptions(format!(
+            "an mtime of {mtime} is past the year 2242, later than a ustar layer can record; \
+             set SOURCE_DATE_EPOCH to an earlier time"
+        )));
+    }
+    let mut header = [0u8; BLOCK_LEN];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], mode.into());
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    write_octal(&mut header[136..148], mtime);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[265..269].copy_from_slice(b"root");
+    header[297..301].copy_from_slice(b"root");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    // The checksum is summed with its own field as spaces
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    write_octal(&mut header[148..155], checksum.into());
+    tar.extend_from_slice(&header);
+    Ok(())
+}
+
+/// Split `name` into ustar's 155 byte prefix and 100 byte name at a '/'
+fn split_name(name: &str) -> Option<(&str, &str)> {
+    if name.len() <= 100 {
+        return Some(("", name));
+    }
+    // A directory's trailing '/' isn't a place to split
+    let trimmed = name.trim_end_matches('/');
+    trimmed
+        .match_indices('/')
+        .map(|(at, _)| (&name[..at], &name[at + 1..]))
+        .find(|(prefix, rest)| prefix.len() <= 155 && rest.len() <= 100)
+}
+
+/// Write `value` as zero-padded octal digits ending in a NUL, as ustar does
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{value:0width$o}", width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()..].fill(0);
+}
@@ -0,0 +1,323 @@
+use crate::format::{self, Filter, Header, Packed};
+use crate::{compress_frames_to, Algorithm, CmprsError, PackOptions};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What went into a packed file and where the time went
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackReport {
+    pub input_size: u64,
+    /// `stub_size + header_size + compressed_size`
+    pub output_size: u64,
+    pub stub_size: u64,
+    /// Magic, version, SHA256 and header, between the stub and the payload
+    pub header_size: u64,
+    pub compressed_size: u64,
+    /// SHA256 of the input, hex encoded
+    pub sha256: String,
+    pub algorithm: Algorithm,
+    /// Compression level, or the xz preset, or 0 when stored
+    pub level: i32,
+    pub hash_time: Duration,
+    pub compress_time: Duration,
+    pub write_time: Duration,
+}
+
+impl PackReport {
+    /// compressed_size / input_size
+    pub fn ratio(&self) -> f64 {
+        self.compressed_size as f64 / self.input_size as f64
+    }
+}
+
+impl fmt::Display for PackReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Packed {} bytes into {} bytes ({} stub + {} header + {} payload, {} level {}, {:.1}%); \
+             hash {:?}, compress {:?}, write {:?}",
+            self.input_size,
+            self.output_size,
+            self.stub_size,
+            self.header_size,
+            self.compressed_size,
+            self.algorithm.name(),
+            self.level,
+            self.ratio() * 100.0,
+            self.hash_time,
+            self.compress_time,
+            self.write_time
+        )
+    }
+}
+
+/// Build a complete self-extractor from `stub` and `input` in memory. The
+/// input is hashed while it's compressed, and `header`'s algorithm and
+/// original size (and dictionary) are filled in from `options` and `input`, as is the index
+/// of a bundle, whose files are compressed one frame each, and the hash of `stub`.
+///
+/// ```
+/// use cmprs::{format, PackOptions};
+/// use sha2::Digest;
+///
+/// let input = b"#!/bin/sh\necho hi\n".repeat(100);
+/// let header = format::Header::default();
+/// let (packed, report) = cmprs::pack(b"stub", &input, &header, &PackOptions::default()).unwrap();
+///
+/// assert_eq!(report.output_size, packed.len() as u64);
+/// assert_eq!(
+///     report.output_size,
+///     report.stub_size + report.header_size + report.compressed_size
+/// );
+/// assert_eq!(report.input_size, input.len() as u64);
+/// assert_eq!(report.sha256.len(), 64);
+/// assert_eq!(report.level, 3);
+///
+/// let mut unpacked = Vec::new();
+/// cmprs::unpack_to(&packed, &mut unpacked).unwrap();
+/// assert_eq!(unpacked, input);
+///
+/// // Without a stub, what's left is the bare payload a custom loader reads
+/// let (bare, _) = cmprs::pack(b"", &input, &header, &PackOptions::default()).unwrap();
+/// assert!(bare.starts_with(format::MAGIC_HEADER));
+/// // The same payload, behind a header that has no stub hash to record
+/// let payload = &packed[packed.len() - report.compressed_size as usize..];
+/// assert!(bare.ends_with(payload));
+/// let stub_sha256 = format::Packed::parse(&packed).unwrap().header.stub_sha256;
+/// assert_eq!(stub_sha256, Some(sha2::Sha256::digest(b"stub").into()));
+/// assert_eq!(format::Packed::parse(&bare).unwrap().header.stub_sha256, None);
+///
+/// // A redundant payload survives damage to one of its copies
+/// let header = format::Header { redundant: true, ..header };
+/// let (mut packed, report) = cmprs::pack(b"stub", &input, &header, &PackOptions::default()).unwrap();
+/// let payload_start = (report.stub_size + report.header_size) as usize;
+/// packed[payload_start + 10..payload_start + 20].fill(0xff);
+/// let mut unpacked = Vec::new();
+/// cmprs::unpack_to(&packed, &mut unpacked).unwrap();
+/// assert_eq!(unpacked, input);
+/// ```
+pub fn pack(
+    stub: &[u8],
+    input: &[u8],
+    header: &Header,
+    options: &PackOptions,
+) -> io::Result<(Vec<u8>, PackReport)> {
+    let frame_sizes = match &header.bundle {
+        Some(bundle) => bundle.files.iter().map(|file| file.size).collect(),
+        None => vec![input.len() as u64],
+    };
+    let ((sha256, hash_time), compressed) = thread::scope(|scope| {
+        let hash_thread = scope.spawn(|| {
+            let hash_start = Instant::now();
+            (Sha256::digest(input), hash_start.elapsed())
+        });
+        let compress_start = Instant::now();
+        let filtered = filtered(input, header.filter);
+        let mut payload = Vec::new();
+        let compressed =
+            compress_frames_to(&filtered, &frame_sizes, options, &mut payload, |_, _| {})
+                .map(|frames| (payload, frames, compress_start.elapsed()));
+        (
+            hash_thread.join().expect("hashing doesn't panic"),
+            compressed,
+        )
+    });
+    let (compressed, frames, compress_time) = compressed?;
+
+    let header = Header {
+        algorithm: options.algorithm(),
+        original_size: Some(input.len() as u64),
+        payload_size: Some(compressed.len() as u64 * if header.redundant { 2 } else { 1 }),
+        dictionary: options.dictionary().map(<[u8]>::to_vec),
+        packer_version: Some(crate::VERSION.to_string()),
+        stub_sha256: (!stub.is_empty()).then(|| Sha256::digest(stub).into()),
+        ..header.clone()
+    };
+    let (packed, report) = assemble(stub, &sha256, header, &compressed, frames, options)?;
+    Ok((
+        packed,
+        PackReport {
+            input_size: input.len() as u64,
+            hash_time,
+            compress_time,
+            ..report
+        },
+    ))
+}
+
+/// Compress the payload of `packed` again with `options`, once it's checked
+/// against its SHA256. Everything else stays as it was: the stub, the hash and
+/// every header field but the ones describing the compressed payload (its
+/// algorithm, size, dictionary and the bundle's frame sizes).
+///
+/// ```
+/// use cmprs::{format, PackOptions};
+///
+/// let input = b"#!/bin/sh\necho hi\n".repeat(1000);
+/// let header = format::Header { name: Some("hi".into()), mtime: Some(1), ..Default::default() };
+/// let (packed, _) = cmprs::pack(b"stub", &input, &header, &PackOptions::default()).unwrap();
+///
+/// let options = PackOptions::builder().level(19).build().unwrap();
+/// let (recompressed, report) = cmprs::recompress(&packed, &options).unwrap();
+/// assert_eq!(report.level, 19);
+/// let before = format::Packed::parse(&packed).unwrap();
+/// let after = format::Packed::parse(&recompressed).unwrap();
+/// assert_eq!(after.sha256, before.sha256);
+/// assert_eq!(after.header, format::Header { payload_size: after.header.payload_size, ..before.header });
+///
+/// let mut unpacked = Vec::new();
+/// cmprs::unpack_to(&recompressed, &mut unpacked).unwrap();
+/// assert_eq!(unpacked, input);
+/// ```
+pub fn recompress(
+    packed: &[u8],
+    options: &PackOptions,
+) -> Result<(Vec<u8>, PackReport), CmprsError> {
+    let mut input = Vec::new();
+    crate::unpack_to(packed, &mut input)?;
+    let original = Packed::parse(packed)?;
+
+    let frame_sizes = match &original.header.bundle {
+        Some(bundle) => bundle.files.iter().map(|file| file.size).collect(),
+        None => vec![input.len() as u64],
+    };
+    let compress_start = Instant::now();
+    let input = filtered(&input, original.header.filter);
+    let mut compressed = Vec::new();
+    let frames = compress_frames_to(&input, &frame_sizes, options, &mut compressed, |_, _| {})?;
+    let compress_time = compress_start.elapsed();
+
+    let header = Header {
+        algorithm: options.algorithm(),
+        payload_size: Some(compressed.len() as u64 * if original.header.redundant { 2 } else { 1 }),
+        dictionary: options.dictionary().map(<[u8]>::to_vec),
+        ..original.header.clone()
+    };
+    let stub = &packed[..original.stub_len];
+    let (packed, report) = assemble(stub, original.sha256, header, &compressed, frames, options)?;
+    Ok((
+        packed,
+        PackReport {
+            input_size: input.len() as u64,
+            compress_time,
+            ..report
+        },
+    ))
+}
+
+/// Convert `packed` from its older format to `target_version`, once its
+/// payload is checked against its SHA256. The stub, the hash, the compressed
+/// payload and every header field are kept; a file from before the header
+/// gets its sizes recorded, the only fields that can be recovered from it.
+/// The stub is kept too, so one older than `target_version` can't run the
+/// result, swap in a newer one to run it.
+///
+/// ```
+/// use cmprs::{format, PackOptions};
+/// use sha2::{Digest, Sha256};
+///
+/// // A file packed before the header: the magic, `;;;` and the hash
+/// let original = b"#!/bin/sh\necho hi\n";
+/// let mut v1 = b"stub".to_vec();
+/// format::write_version_preamble(&mut v1, 1).unwrap();
+/// v1.extend_from_slice(&Sha256::digest(original));
+/// v1.extend_from_slice(&cmprs::compress(original, &PackOptions::default()).unwrap());
+/// assert_eq!(format::Packed::parse(&v1).unwrap().version, 1);
+///
+/// for version in 2..=format::FORMAT_VERSION {
+///     let upgraded = cmprs::upgrade_format(&v1, version).unwrap();
+///     let parsed = format::Packed::parse(&upgraded).unwrap();
+///     assert_eq!(parsed.version, version);
+///     assert_eq!(parsed.header.original_size, Some(original.len() as u64));
+///     let mut unpacked = Vec::new();
+///     cmprs::unpack_to(&upgraded, &mut unpacked).unwrap();
+///     assert_eq!(unpacked, original);
+/// }
+/// // Only ever to a newer version this cmprs knows
+/// assert!(cmprs::upgrade_format(&v1, 1).is_err());
+/// assert!(cmprs::upgrade_format(&v1, format::FORMAT_VERSION + 1).is_err());
+/// ```
+pub fn upgrade_format(packed: &[u8], target_version: u8) -> Result<Vec<u8>, CmprsError> {
+    let original = Packed::parse(packed)?;
+    if target_version > format::FORMAT_VERSION {
+        return Err(CmprsError::UnsupportedVersion(target_version));
+    }
+    if target_version <= original.version {
+        return Err(CmprsError::InvalidOptions(format!(
+            "the file is already in format version {}, it can only be upgraded to a newer one",
+            original.version
+        )));
+    }
+    let mut unpacked = Vec::new();
+    crate::unpack_to(packed, &mut unpacked)?;
+
+    let mut header = original.header.clone();
+    header.original_size.get_or_insert(unpacked.len() as u64);
+    header
+        .payload_size
+        .get_or_insert(original.payload.len() as u64);
+
+    let mut upgraded = packed[..original.stub_len].to_vec();
+    format::write_version_preamble(&mut upgraded, target_version)?;
+    upgraded.extend_from_slice(original.sha256);
+    upgraded.extend_from_slice(&header.to_version_bytes(target_version, original.sha256));
+    upgraded.extend_from_slice(original.payload);
+    Ok(upgraded)
+}
+
+/// `input` with `filter` applied, ready to compress
+fn filtered(input: &[u8], filter: Option<Filter>) -> Cow<'_, [u8]> {
+    match filter {
+        Some(filter) => {
+            let mut filtered = input.to_vec();
+            filter.encode(&mut filtered);
+            Cow::Owned(filtered)
+        }
+        None => Cow::Borrowed(input),
+    }
+}
+
+/// Lay out `stub`, the preamble, `sha256`, `header` (with the bundle's frame
+/// sizes) and the `compressed` payload, which is stored twice when redundant.
+/// The report's input size and hash and compress times are left at zero.
+fn assemble(
+    stub: &[u8],
+    sha256: &[u8],
+    mut header: Header,
+    compressed: &[u8],
+    frames: Vec<u64>,
+    options: &PackOptions,
+) -> io::Result<(Vec<u8>, PackReport)> {
+    let write_start = Instant::now();
+    if let Some(bundle) = &mut header.bundle {
+        bundle.frames = frames;
+    }
+    let mut packed = stub.to_vec();
+    format::write_preamble(&mut packed)?;
+    packed.extend_from_slice(sha256);
+    packed.extend_from_slice(&header.to_bytes(sha256));
+    let header_size = (packed.len() - stub.len()) as u64;
+    packed.extend_from_slice(compressed);
+    if header.redundant {
+        packed.extend_from_slice(compressed);
+    }
+
+    let report = PackReport {
+        input_size: 0,
+        output_size: packed.len() as u64,
+        stub_size: stub.len() as u64,
+        header_size,
+        compressed_size: packed.len() as u64 - stub.len() as u64 - header_size,
+        sha256: hex::encode(sha256),
+        algorithm: options.algorithm(),
+        level: options.level(),
+        hash_time: Duration::ZERO,
+        compress_time: Duration::ZERO,
+        write_time: write_start.elapsed(),
+    };
+    Ok((packed, report))
+}
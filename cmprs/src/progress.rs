@@ -0,0 +1,98 @@
+use log::debug;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+// Where --progress-json events go, set once at startup
+static EVENTS: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+static ON_STDERR: AtomicBool = AtomicBool::new(false);
+
+// How often a stage reports, which is plenty to draw a bar from
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// One line of --progress-json
+#[derive(Serialize)]
+struct Event<'a> {
+    stage: &'a str,
+    bytes_done: u64,
+    bytes_total: u64,
+}
+
+/// Write progress events to `path`, or to stderr when it's `None`
+pub fn init(path: Option<&Path>) -> io::Result<()> {
+    let out: Box<dyn Write + Send> = match path {
+        Some(path) => Box::new(File::create(path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("can't open --progress-json {}: {err}", path.display()),
+            )
+        })?),
+        None => {
+            ON_STDERR.store(true, Ordering::Relaxed);
+            Box::new(io::stderr())
+        }
+    };
+    let _ = EVENTS.set(Mutex::new(out));
+    Ok(())
+}
+
+/// Whether events go to stderr, where a progress bar would garble them
+pub fn on_stderr() -> bool {
+    ON_STDERR.load(Ordering::Relaxed)
+}
+
+/// The progress of one stage over `total` bytes, reported as it changes but
+/// at most every `INTERVAL`, except for its end
+pub struct Stage {
+    name: &'static str,
+    total: u64,
+    last: Option<(u64, Instant)>,
+}
+
+impl Stage {
+    pub fn new(name: &'static str, total: u64) -> Self {
+        Stage {
+            name,
+            total,
+            last: None,
+        }
+    }
+
+    pub fn update(&mut self, done: u64) {
+        let Some(events) = EVENTS.get() else {
+            return;
+        };
+        let now = Instant::now();
+        match self.last {
+            Some((last, _)) if last == done => return,
+            Some((_, at)) if done < self.total && now - at < INTERVAL => return,
+            _ => {}
+        }
+        self.last = Some((done, now));
+        let event = Event {
+            stage: self.name,
+            bytes_done: done,
+            bytes_total: self.total,
+        };
+        let mut out = events
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let result = serde_json::to_writer(&mut *out, &event)
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(out))
+            .and_then(|()| out.flush());
+        // Whoever reads the events going away shouldn't fail the pack
+        if let Err(err) = result {
+            debug!("Failed to write a progress event: {err}");
+        }
+    }
+
+    /// Report the stage done, which an empty input never gets to otherwise
+    pub fn finish(&mut self) {
+        self.update(self.total);
+    }
+}
@@ -0,0 +1,297 @@
+use crate::{
+    arch, default_stub, is_same_file, missing_stub_error, pack, parent_dir, parse_level, Args,
+    LevelArg, STORE_LEVEL,
+};
+use clap::{Args as ClapArgs, Parser};
+use cmprs::{format, Algorithm, CmprsError, PackOptions};
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+// Mode used for files packed before permissions were recorded in the header
+const DEFAULT_MODE: u32 = 0o755;
+
+#[derive(ClapArgs, Clone)]
+pub struct RepackArgs {
+    #[arg(help = "cmprs-compressed file")]
+    input: PathBuf,
+
+    #[arg(
+        short,
+        long,
+        help = "Output file. If not specified, the input is replaced"
+    )]
+    output: Option<PathBuf>,
+
+    #[arg(
+        short = 'l',
+        long = "level",
+        value_parser = parse_level,
+        allow_negative_numbers = true,
+        help = "New compression level (1-22, 0 = store uncompressed, -1 to -22 for zstd's fast mode), or fast, default or best for the algorithm's own [default: 3]"
+    )]
+    compression_level: Option<LevelArg>,
+
+    #[arg(long, help = "Store the payload uncompressed (same as --level 0)")]
+    store: bool,
+
+    #[arg(long, help = "Prepend the minimal dcmprs stub")]
+    minimize_stub: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "minimize_stub",
+        help = "Only compress the payload again, with the same algorithm at the new level, keeping the stub, the hash and every other header field exactly as they are"
+    )]
+    recompress_only: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["compression_level", "store", "minimize_stub", "recompress_only"],
+        help = "Convert a file packed in an older format to the current one, keeping its payload and header as they are, with the current stub for its machine since older ones can't read the new format"
+    )]
+    upgrade: bool,
+}
+
+/// Extract and verify the payload of a packed file, then pack it again with
+/// new options, keeping everything its header recorded
+pub fn run(args: RepackArgs) -> Result<(), CmprsError> {
+    debug!("Reading packed file: {}", args.input.display());
+    let packed = cmprs::read_packed(&args.input)?;
+    if args.recompress_only {
+        return recompress_only(&args, &packed);
+    }
+    if args.upgrade {
+        return upgrade(&args, &packed);
+    }
+
+    let mut payload = Vec::new();
+    let header = cmprs::unpack_to(&packed, &mut payload)?;
+    info!(
+        "Extracted {} bytes from {} ({} byte packed file)",
+        payload.len(),
+        args.input.display(),
+        packed.len()
+    );
+
+    // Recreate the original file or directory, under its original name, for pack to read
+    let work_dir = tempfile::tempdir()?;
+    let name = header
+        .name
+        .clone()
+        .unwrap_or_else(|| OsString::from("payload"));
+    let payload_path = work_dir.path().join(name);
+    match &header.bundle {
+        Some(bundle) => bundle.unpack(&payload, &payload_path)?,
+        None => fs::write(&payload_path, &payload)?,
+    }
+    let mode = header.mode.unwrap_or_else(|| {
+        warn!("No permissions recorded in the header, using {DEFAULT_MODE:o}");
+        DEFAULT_MODE
+    });
+    fs::set_permissions(&payload_path, fs::Permissions::from_mode(mode))?;
+    for (name, value) in &header.xattrs {
+        xattr::set(&payload_path, name, value)?;
+    }
+
+    let (output_path, in_place) = output_path(&args)?;
+
+    let mut pack_args = Args::parse_from([OsString::from("cmprs"), payload_path.clone().into()]);
+    pack_args.output = Some(output_path.clone());
+    pack_args.compression_level = args.compression_level;
+    pack_args.store = args.store;
+    // Train a new dictionary for the new level
+    pack_args.train_dict = header.dictionary.is_some() && !args.store;
+    pack_args.minimize_stub = args.minimize_stub;
+    let is_caps = |(name, _): &(OsString, Vec<u8>)| name == format::CAPABILITY_XATTR;
+    pack_args.preserve_xattr = !header.xattrs.iter().all(is_caps);
+    pack_args.preserve_caps = header.xattrs.iter().any(is_caps);
+    pack_args.no_stub_flags = header.no_stub_flags;
+    pack_args.argfiles = header.argfiles;
+    pack_args.verify_stub = header.verify_stub;
+    pack_args.split = header.split_payload;
+    pack_args.marker = header.marker.clone();
+    pack_args.comment = header.comment.clone();
+    if !header.strip_env.is_empty() {
+        pack_args.strip_env = Some(header.strip_env.join(","));
+    }
+    pack_args.embedded_env = header.env.clone();
+    pack_args.needs_root = header.needs_root;
+    pack_args.verify_policy = Some(header.verify_policy.into());
+    pack_args.min_loader_version = header.min_loader_version.clone();
+    pack_args.recorded_path = header.source_path.clone();
+    pack_args.filter = Some(header.filter.into());
+    pack_args.redundant = header.redundant;
+    pack_args.extract_entry_only = header.entry_only;
+    if let Some(bundle) = header.bundle {
+        pack_args.entry = (!bundle.entry.is_empty()).then(|| PathBuf::from(bundle.entry));
+        pack_args.subcommands = bundle
+            .subcommands
+            .into_iter()
+            .map(|(name, path)| (name, PathBuf::from(path)))
+            .collect();
+    }
+    if !header.launcher.is_empty() {
+        let launcher: Vec<_> = header
+            .launcher
+            .iter()
+            .map(|arg| arg.to_string_lossy())
+            .collect();
+        pack_args.launcher = Some(launcher.join(" "));
+    }
+    if !header.entry_args.is_empty() {
+        let entry_args: Vec<_> = header
+            .entry_args
+            .iter()
+            .map(|arg| arg.to_string_lossy())
+            .collect();
+        pack_args.entry_args = Some(shell_words::join(entry_args));
+    }
+    pack(pack_args, payload_path)?;
+
+    let repacked_len = fs::metadata(&output_path)?.len();
+    if let Some((temp_path, target)) = in_place {
+        replace_input(&target, temp_path, header.split_payload)?;
+    }
+    info!(
+        "Repacked {} from {} to {} bytes",
+        args.input.display(),
+        packed.len(),
+        repacked_len
+    );
+    Ok(())
+}
+
+/// Compress the payload of `packed` again, with its algorithm at the new
+/// level, and write it out with the rest of the file as it was
+fn recompress_only(args: &RepackArgs, packed: &[u8]) -> Result<(), CmprsError> {
+    let original = format::Packed::parse(packed)?;
+    let header = &original.header;
+    let algorithm = match (header.algorithm, args.compression_level) {
+        _ if args.store => Algorithm::None,
+        // An xz preset of 0 still compresses
+        (Algorithm::Xz, _) => Algorithm::Xz,
+        (_, Some(LevelArg::Number(STORE_LEVEL))) => Algorithm::None,
+        // A stored payload has no level of its own, a new one compresses it
+        (Algorithm::None, Some(_)) => Algorithm::Zstd,
+        (algorithm, _) => algorithm,
+    };
+    let mut builder = PackOptions::builder().algorithm(algorithm);
+    if let Some(level) = args
+        .compression_level
+        .filter(|_| algorithm != Algorithm::None)
+    {
+        builder = level.apply(builder);
+    }
+    // The dictionary the payload was compressed with works at any level
+    if let (Some(dictionary), Algorithm::Zstd) = (&header.dictionary, algorithm) {
+        builder = builder.dictionary(dictionary.clone());
+    }
+    let (recompressed, report) = cmprs::recompress(packed, &builder.build()?)?;
+
+    let (output_path, in_place) = output_path(args)?;
+    let payload_start = (report.stub_size + report.header_size) as usize;
+    if header.split_payload {
+        fs::write(&output_path, &recompressed[..payload_start])?;
+        fs::write(
+            format::split_payload_path(&output_path),
+            &recompressed[payload_start..],
+        )?;
+    } else {
+        fs::write(&output_path, &recompressed)?;
+    }
+    fs::set_permissions(&output_path, fs::metadata(&args.input)?.permissions())?;
+    if let Some((temp_path, target)) = in_place {
+        replace_input(&target, temp_path, header.split_payload)?;
+    }
+    info!(
+        "Recompressed the payload of {} from {} to {} bytes ({} level {})",
+        args.input.display(),
+        original.payload.len(),
+        report.compressed_size,
+        report.algorithm.name(),
+        report.level
+    );
+    Ok(())
+}
+
+/// Convert `packed` to the current format with [`cmprs::upgrade_format`],
+/// behind the current stub for the machine its stub was built for
+fn upgrade(args: &RepackArgs, packed: &[u8]) -> Result<(), CmprsError> {
+    let version = format::Packed::parse(packed)?.version;
+    let upgraded = cmprs::upgrade_format(packed, format::FORMAT_VERSION)?;
+    let upgraded = format::Packed::parse(&upgraded)?;
+    let old_stub = &packed[..upgraded.stub_len];
+    let stub = default_stub(arch::elf_machine(old_stub))?.contents();
+    if stub.is_empty() {
+        return Err(missing_stub_error().into());
+    }
+
+    // The recorded stub hash is of the old stub, which is replaced
+    let mut header = upgraded.header.clone();
+    if header.stub_sha256.is_some() {
+        header.stub_sha256 = Some(Sha256::digest(stub).into());
+    }
+    let mut output = stub.to_vec();
+    format::write_preamble(&mut output)?;
+    output.extend_from_slice(upgraded.sha256);
+    output.extend_from_slice(&header.to_bytes(upgraded.sha256));
+    let payload_start = output.len();
+    output.extend_from_slice(upgraded.payload);
+
+    let (output_path, in_place) = output_path(args)?;
+    if header.split_payload {
+        fs::write(&output_path, &output[..payload_start])?;
+        fs::write(
+            format::split_payload_path(&output_path),
+            &output[payload_start..],
+        )?;
+    } else {
+        fs::write(&output_path, &output)?;
+    }
+    fs::set_permissions(&output_path, fs::metadata(&args.input)?.permissions())?;
+    if let Some((temp_path, target)) = in_place {
+        replace_input(&target, temp_path, header.split_payload)?;
+    }
+    info!(
+        "Upgraded {} from format version {} to {}",
+        args.input.display(),
+        version,
+        format::FORMAT_VERSION
+    );
+    Ok(())
+}
+
+/// Where to write the repacked file: --output, or without it a temp file next
+/// to the input, to rename over it once done. An --output that is the input
+/// under another name is written the same way, and renamed over the file it
+/// names, since the input is mapped while it's read and writing over it would
+/// truncate it under us.
+fn output_path(args: &RepackArgs) -> io::Result<(PathBuf, Option<(tempfile::TempPath, PathBuf)>)> {
+    let target = match &args.output {
+        Some(output) if is_same_file(&args.input, output) => fs::canonicalize(output)?,
+        Some(output) => return Ok((output.clone(), None)),
+        None => args.input.clone(),
+    };
+    let temp_path = tempfile::Builder::new()
+        .prefix(".cmprs-repack")
+        .tempfile_in(parent_dir(&target))?
+        .into_temp_path();
+    Ok((temp_path.to_path_buf(), Some((temp_path, target))))
+}
+
+/// Move the file repacked at `temp_path`, and its payload file when `split`,
+/// over `target`, the input
+fn replace_input(target: &Path, temp_path: tempfile::TempPath, split: bool) -> io::Result<()> {
+    if split {
+        fs::rename(
+            format::split_payload_path(&temp_path),
+            format::split_payload_path(target),
+        )?;
+    }
+    temp_path.persist(target).map_err(|err| err.error)
+}
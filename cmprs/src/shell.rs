@@ -0,0 +1,72 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::io::{self, Write};
+
+// Line that separates the script from the base64 encoded payload
+const PAYLOAD_MARKER: &str = "__CMPRS_PAYLOAD_BELOW__";
+
+// Keep base64 lines short so `sed` and `base64 -d` never choke on them
+const BASE64_LINE_LEN: usize = 76;
+
+/// POSIX shell header that decodes, decompresses and runs the appended payload.
+/// Only relies on `sed`, `base64`, `mktemp`, `chmod` and a zstd decompressor.
+/// `@SHA256@` is replaced with the hex hash of the original file.
+const SCRIPT_HEADER: &str = r#"#!/bin/sh
+# Self-extracting executable created by cmprs
+set -eu
+
+decompress() {
+  if command -v zstd >/dev/null 2>&1; then
+    zstd -dcq
+  elif command -v unzstd >/dev/null 2>&1; then
+    unzstd -cq
+  else
+    echo "cmprs: zstd is required to run this program" >&2
+    exit 127
+  fi
+}
+
+tmp=$(mktemp "${TMPDIR:-/tmp}/cmprs.XXXXXX")
+trap 'rm -f "$tmp"' EXIT
+trap 'exit 129' HUP
+trap 'exit 130' INT
+trap 'exit 143' TERM
+
+sed '1,/^__CMPRS_PAYLOAD_BELOW__$/d' "$0" | base64 -d | decompress >"$tmp"
+if command -v sha256sum >/dev/null 2>&1; then
+  if ! echo "@SHA256@  $tmp" | sha256sum -c --status; then
+    echo "cmprs: SHA256 mismatch, the script may be corrupted" >&2
+    exit 1
+  fi
+fi
+chmod 700 "$tmp"
+
+status=0
+"$tmp" "$@" || status=$?
+exit "$status"
+"#;
+
+/// Write a shell script self-extractor with the compressed payload appended as base64.
+/// The format is: [SCRIPT_HEADER][PAYLOAD_MARKER\n][base64 zstd compressed data]
+pub fn write_script(
+    output: &mut impl Write,
+    sha256_hash: &[u8],
+    compressed: &[u8],
+) -> io::Result<usize> {
+    let header = SCRIPT_HEADER.replace("@SHA256@", &hex::encode(sha256_hash));
+    let encoded = BASE64.encode(compressed);
+    let mut written = 0;
+
+    output.write_all(header.as_bytes())?;
+    written += header.len();
+    writeln!(output, "{PAYLOAD_MARKER}")?;
+    written += PAYLOAD_MARKER.len() + 1;
+
+    for line in encoded.as_bytes().chunks(BASE64_LINE_LEN) {
+        output.write_all(line)?;
+        output.write_all(b"\n")?;
+        written += line.len() + 1;
+    }
+
+    Ok(written)
+}
@@ -0,0 +1,61 @@
+use clap::Args;
+use cmprs::format::Packed;
+use cmprs::CmprsError;
+use log::{debug, info};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Args, Clone)]
+pub struct SplitArgs {
+    #[arg(help = "cmprs-compressed file")]
+    input: PathBuf,
+}
+
+/// Write the dcmprs stub of a packed file to `<input>.stub` and everything
+/// from the magic header on to `<input>.payload`, so that concatenating the
+/// two gives back the packed file
+pub fn run(args: SplitArgs) -> Result<(), CmprsError> {
+    debug!("Reading packed file: {}", args.input.display());
+    let buffer = fs::read(&args.input)?;
+    let packed = Packed::parse(&buffer)?;
+    let (stub, payload) = buffer.split_at(packed.stub_len);
+
+    let stub_path = with_suffix(&args.input, ".stub");
+    let payload_path = with_suffix(&args.input, ".payload");
+    for path in [&stub_path, &payload_path] {
+        if path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists, not overwriting it", path.display()),
+            )
+            .into());
+        }
+    }
+
+    fs::write(&stub_path, stub)?;
+    fs::write(&payload_path, payload)?;
+    info!(
+        "Wrote the {} byte stub to {} and the {} byte payload ({}, {} bytes compressed) to {}",
+        stub.len(),
+        stub_path.display(),
+        payload.len(),
+        packed.header.algorithm.name(),
+        packed.payload.len(),
+        payload_path.display()
+    );
+    if packed.header.split_payload {
+        info!(
+            "The compressed data itself is in {}",
+            cmprs::format::split_payload_path(&args.input).display()
+        );
+    }
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    PathBuf::from(path)
+}
@@ -0,0 +1,146 @@
+use crate::PackedOutput;
+use cmprs::format::Bundle;
+use cmprs::PackReport;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Summary of a pack, printed as a single JSON object with --json-stats
+#[derive(Serialize)]
+pub struct PackStats {
+    pub input: String,
+    pub output: String,
+    pub format: &'static str,
+    pub algorithm: &'static str,
+    pub level: i32,
+    pub sha256: String,
+    pub input_size: usize,
+    pub stub_size: usize,
+    pub compressed_size: usize,
+    pub output_size: usize,
+    /// compressed_size / input_size
+    pub ratio: f64,
+    pub timings_ms: Timings,
+    /// Most memory cmprs had resident at once while packing, in bytes
+    pub peak_rss: Option<u64>,
+    /// What each file of a bundle contributes, in bundle order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<FileStats>,
+}
+
+#[derive(Serialize)]
+pub struct FileStats {
+    pub path: String,
+    pub size: u64,
+    /// Size of the file's own frame in the payload
+    pub compressed_size: u64,
+}
+
+impl FileStats {
+    /// The breakdown of `bundle`, whose files are compressed a frame each so
+    /// their compressed sizes are exact rather than estimated
+    pub fn from_bundle(bundle: &Bundle) -> Vec<Self> {
+        bundle
+            .files
+            .iter()
+            .zip(&bundle.frames)
+            .map(|(file, &compressed_size)| FileStats {
+                path: file.path.to_string_lossy().into_owned(),
+                size: file.size,
+                compressed_size,
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize)]
+pub struct Timings {
+    pub read: f64,
+    pub hash: f64,
+    pub compress: f64,
+    pub write: f64,
+    pub total: f64,
+}
+
+impl Timings {
+    pub fn new(
+        read: Duration,
+        hash: Duration,
+        compress: Duration,
+        write: Duration,
+        total: Duration,
+    ) -> Self {
+        Timings {
+            read: millis(read),
+            hash: millis(hash),
+            compress: millis(compress),
+            write: millis(write),
+            total: millis(total),
+        }
+    }
+}
+
+impl PackStats {
+    /// Stats for a binary pack, or a bare payload with `--no-stub`. The output size is taken from the file, which
+    /// signing grows past what the report accounts for.
+    pub fn from_report(
+        input: &Path,
+        format: &'static str,
+        packed: &PackedOutput,
+        report: &PackReport,
+        read: Duration,
+        total: Duration,
+    ) -> Self {
+        PackStats {
+            input: input.display().to_string(),
+            output: packed.path.display().to_string(),
+            format,
+            algorithm: report.algorithm.name(),
+            level: report.level,
+            sha256: report.sha256.clone(),
+            input_size: report.input_size as usize,
+            stub_size: report.stub_size as usize,
+            compressed_size: report.compressed_size as usize,
+            output_size: packed.output_size as usize,
+            ratio: report.ratio(),
+            timings_ms: Timings::new(
+                read,
+                report.hash_time,
+                report.compress_time,
+                report.write_time,
+                total,
+            ),
+            peak_rss: peak_rss(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Write the stats to stdout, keeping stderr for the logs
+    pub fn print(&self) -> io::Result<()> {
+        let mut stdout = io::stdout().lock();
+        serde_json::to_writer(&mut stdout, self)?;
+        writeln!(stdout)
+    }
+}
+
+/// The peak resident set size of this process so far, in bytes
+pub fn peak_rss() -> Option<u64> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage fills in `usage` when it returns 0
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    // SAFETY: initialized by the successful call above
+    let max_rss = unsafe { usage.assume_init() }.ru_maxrss as u64;
+    // Linux reports kilobytes, macOS bytes
+    if cfg!(target_os = "macos") {
+        Some(max_rss)
+    } else {
+        Some(max_rss * 1024)
+    }
+}
+
+pub fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,47 @@
+use log::{debug, info, warn};
+use std::io::{self, Write};
+use std::process::Command;
+use tempfile::NamedTempFile;
+
+// Tried in order, the first one that can be spawned wins
+const STRIP_TOOLS: &[&str] = &["strip", "llvm-strip"];
+
+/// Strip symbols from a copy of `input`, returning the stripped bytes.
+/// The original file is never touched. Returns `None` with a warning when no
+/// strip tool is available or stripping fails, so the caller ships `input` as-is.
+pub fn strip_payload(input: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    let mut copy = NamedTempFile::new()?;
+    copy.write_all(input)?;
+    copy.as_file().sync_all()?;
+    // strip replaces the file, so don't keep a handle to the old inode around
+    let copy = copy.into_temp_path();
+
+    for tool in STRIP_TOOLS {
+        debug!("Running {tool} on {}", copy.display());
+        match Command::new(tool).arg(&copy).status() {
+            Ok(status) if status.success() => {
+                let stripped = std::fs::read(&copy)?;
+                info!(
+                    "Stripped input with {tool}: {} bytes -> {} bytes",
+                    input.len(),
+                    stripped.len()
+                );
+                return Ok(Some(stripped));
+            }
+            Ok(status) => {
+                warn!("{tool} failed ({status}), packing the input unstripped");
+                return Ok(None);
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                debug!("{tool} is not installed");
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    warn!(
+        "--strip-input needs one of {} on PATH, packing the input unstripped",
+        STRIP_TOOLS.join(", ")
+    );
+    Ok(None)
+}
@@ -0,0 +1,241 @@
+use crate::{arch, StubVariant, DIST_DIR};
+use clap::{Args, ValueEnum};
+use cmprs::format::STUB_VERSION_TAG;
+use cmprs::CmprsError;
+use goblin::elf::Elf;
+use goblin::mach::constants::cputype::get_arch_name_from_types;
+use goblin::mach::{Mach, MachO, SingleArch};
+use serde::Serialize;
+use std::io::{self, Write};
+
+#[derive(Args, Clone)]
+pub struct StubsArgs {
+    #[arg(long, help = "Print a JSON array instead of a table")]
+    json: bool,
+}
+
+#[derive(Args, Clone)]
+pub struct InspectStubArgs {
+    #[arg(long, help = "Print a JSON array instead of a table")]
+    json: bool,
+}
+
+/// A dcmprs stub embedded in this cmprs build
+#[derive(Serialize)]
+struct Stub {
+    name: String,
+    size: usize,
+    /// Target triple the stub runs on
+    target: String,
+    /// The flag that prepends this stub, or None for the default one
+    selected_by: Option<String>,
+}
+
+pub fn run(args: StubsArgs) -> Result<(), CmprsError> {
+    let stubs = embedded_stubs();
+    let mut stdout = io::stdout().lock();
+    if args.json {
+        serde_json::to_writer(&mut stdout, &stubs).map_err(io::Error::from)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+
+    let name_width = stubs.iter().map(|stub| stub.name.len()).max().unwrap_or(0);
+    let target_width = stubs
+        .iter()
+        .map(|stub| stub.target.len())
+        .max()
+        .unwrap_or(0);
+    for stub in &stubs {
+        writeln!(
+            stdout,
+            "{:name_width$}  {:>9} bytes  {:target_width$}  {}",
+            stub.name,
+            stub.size,
+            stub.target,
+            stub.selected_by.as_deref().unwrap_or("(default)")
+        )?;
+    }
+    Ok(())
+}
+
+/// What parsing an embedded stub tells about it
+#[derive(Serialize)]
+struct Inspection {
+    name: String,
+    size: usize,
+    /// ELF or Mach-O, with the word size for ELF, or None if it's neither
+    format: Option<String>,
+    /// The machine it runs on, or the architectures of a universal binary
+    arch: Option<String>,
+    /// Whether its symbol table was stripped, or None where we can't tell
+    stripped: Option<bool>,
+    /// The dcmprs version it embeds, or None for stubs from before it did
+    version: Option<String>,
+}
+
+pub fn inspect(args: InspectStubArgs) -> Result<(), CmprsError> {
+    let inspections: Vec<Inspection> = embedded_stubs()
+        .into_iter()
+        .filter_map(|stub| {
+            let bytes = DIST_DIR.get_file(&stub.name)?.contents();
+            let (format, arch, stripped) = parse_binary(bytes);
+            Some(Inspection {
+                name: stub.name,
+                size: stub.size,
+                format,
+                arch,
+                stripped,
+                version: stub_version(bytes),
+            })
+        })
+        .collect();
+    let mut stdout = io::stdout().lock();
+    if args.json {
+        serde_json::to_writer(&mut stdout, &inspections).map_err(io::Error::from)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+
+    let unknown = || "unknown".to_string();
+    let rows: Vec<[String; 6]> = inspections
+        .into_iter()
+        .map(|inspection| {
+            [
+                inspection.name,
+                format!("{} bytes", inspection.size),
+                inspection.format.unwrap_or_else(unknown),
+                inspection.arch.unwrap_or_else(unknown),
+                match inspection.stripped {
+                    Some(true) => "stripped".to_string(),
+                    Some(false) => "not stripped".to_string(),
+                    None => unknown(),
+                },
+                inspection
+                    .version
+                    .map_or_else(unknown, |version| format!("dcmprs {version}")),
+            ]
+        })
+        .collect();
+    let widths: Vec<usize> = (0..6)
+        .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0))
+        .collect();
+    for row in &rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .enumerate()
+            .map(|(column, (cell, &width))| match column {
+                1 => format!("{cell:>width$}"),
+                _ => format!("{cell:width$}"),
+            })
+            .collect::<Vec<_>>()
+            .join("  ");
+        writeln!(stdout, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+/// The format, architecture and whether `bytes` is stripped, each `None`
+/// where goblin can't tell
+fn parse_binary(bytes: &[u8]) -> (Option<String>, Option<String>, Option<bool>) {
+    if let Some(machine) = arch::elf_machine(bytes) {
+        let elf = Elf::parse(bytes).ok();
+        let format = elf
+            .as_ref()
+            .map(|elf| format!("ELF {}-bit", if elf.is_64 { 64 } else { 32 }));
+        let stripped = elf.map(|elf| elf.syms.is_empty());
+        return (format, Some(arch::name(machine)), stripped);
+    }
+    match Mach::parse(bytes) {
+        Ok(Mach::Binary(macho)) => (
+            Some("Mach-O".to_string()),
+            macho_arch(macho.header.cputype(), macho.header.cpusubtype()),
+            Some(macho_stripped(&macho)),
+        ),
+        Ok(Mach::Fat(multi)) => {
+            let arches = multi.arches().ok().map(|arches| {
+                arches
+                    .iter()
+                    .map(|fat| {
+                        macho_arch(fat.cputype(), fat.cpusubtype())
+                            .unwrap_or_else(|| "unknown".to_string())
+                    })
+                    .collect::<Vec<_>>()
+                    .join("+")
+            });
+            // The architectures are built from the same source and profile
+            let stripped = match multi.get(0) {
+                Ok(SingleArch::MachO(macho)) => Some(macho_stripped(&macho)),
+                _ => None,
+            };
+            (Some("Mach-O universal".to_string()), arches, stripped)
+        }
+        Err(_) => (None, None, None),
+    }
+}
+
+fn macho_arch(cputype: u32, cpusubtype: u32) -> Option<String> {
+    get_arch_name_from_types(cputype, cpusubtype).map(str::to_string)
+}
+
+/// `strip` leaves a Mach-O only the global symbols dyld needs, so any local
+/// one means it wasn't stripped
+fn macho_stripped(macho: &MachO) -> bool {
+    !macho
+        .symbols()
+        .flatten()
+        .any(|(_, nlist)| !nlist.is_global() && !nlist.is_undefined() && !nlist.is_stab())
+}
+
+/// The version after STUB_VERSION_TAG in `bytes`, as dcmprs embeds it
+fn stub_version(bytes: &[u8]) -> Option<String> {
+    let start = bytes
+        .windows(STUB_VERSION_TAG.len())
+        .position(|window| window == STUB_VERSION_TAG)?
+        + STUB_VERSION_TAG.len();
+    let len = bytes[start..].iter().position(|&byte| byte == 0)?;
+    let version = std::str::from_utf8(&bytes[start..start + len]).ok()?;
+    cmprs::format::parse_version(version).map(|_| version.to_string())
+}
+
+/// Describe the stubs in DIST_DIR from their names, as build.rs writes them:
+/// `main` and `minimal` first, then the rest by name
+fn embedded_stubs() -> Vec<Stub> {
+    let main_target = env!("CMPRS_MAIN_STUB_TARGET");
+    let mut stubs: Vec<Stub> = DIST_DIR
+        .files()
+        .filter_map(|file| {
+            let name = file.path().to_str()?;
+            let (target, selected_by) = match name {
+                "main" => (main_target.to_string(), None),
+                "minimal" => (main_target.to_string(), Some("--minimize-stub".to_string())),
+                _ => match StubVariant::value_variants()
+                    .iter()
+                    .find(|variant| variant.file_name() == name)
+                {
+                    Some(variant) => (
+                        variant.target().to_string(),
+                        Some(format!(
+                            "--stub-variant {}",
+                            variant.to_possible_value()?.get_name()
+                        )),
+                    ),
+                    None => (name.to_string(), Some(format!("--stub-target {name}"))),
+                },
+            };
+            Some(Stub {
+                name: name.to_string(),
+                size: file.contents().len(),
+                target,
+                selected_by,
+            })
+        })
+        .collect();
+    stubs.sort_by_key(|stub| match stub.name.as_str() {
+        "main" => (0, String::new()),
+        "minimal" => (1, String::new()),
+        name => (2, name.to_string()),
+    });
+    stubs
+}
@@ -0,0 +1,519 @@
+use crate::format::{self, Header, Packed};
+use crate::{Algorithm, CmprsError};
+#[cfg(feature = "gzip")]
+use flate2::read::MultiGzDecoder;
+use log::warn;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+#[cfg(feature = "xz")]
+use xz2::read::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// Stream the original file stored in `packed` into `out`, returning its header.
+///
+/// The length and SHA256 are checked once the whole payload has been written,
+/// so on [`CmprsError::SizeMismatch`] or [`CmprsError::HashMismatch`] `out`
+/// has already received the bad bytes. A payload stored twice with
+/// [`Header::redundant`] is unpacked in memory instead, and recovered from
+/// its second copy when the first one fails those checks.
+///
+/// ```
+/// use cmprs::{format, PackOptions};
+/// use sha2::{Digest, Sha256};
+///
+/// let original = b"#!/bin/sh\necho hi\n";
+/// let sha256 = Sha256::digest(original);
+/// let mut packed = b"stub".to_vec();
+/// format::write_preamble(&mut packed).unwrap();
+/// packed.extend_from_slice(&sha256);
+/// packed.extend_from_slice(&format::Header::default().to_bytes(&sha256));
+/// packed.extend_from_slice(&cmprs::compress(original, &PackOptions::default()).unwrap());
+///
+/// let mut unpacked = Vec::new();
+/// cmprs::unpack_to(&packed, &mut unpacked).unwrap();
+/// assert_eq!(unpacked, original);
+/// ```
+pub fn unpack_to(packed: &[u8], out: &mut impl Write) -> Result<Header, CmprsError> {
+    let packed = Packed::parse(packed)?;
+    let (primary, copy) = packed.header.payload_copies(packed.payload);
+    let Some(copy) = copy else {
+        unpack_payload(&packed, primary, out)?;
+        return Ok(packed.header);
+    };
+
+    // Decompress in memory first, so only a good copy reaches `out`
+    let mut unpacked = Vec::new();
+    if let Err(err) = unpack_payload(&packed, primary, &mut unpacked) {
+        warn!("The payload is corrupted ({err}), recovering it from its redundant copy");
+        unpacked.clear();
+        unpack_payload(&packed, copy, &mut unpacked)?;
+    }
+    out.write_all(&unpacked)?;
+    Ok(packed.header)
+}
+
+/// Decompress `payload`, one copy of the payload of `packed`, into `out`, and
+/// check its length and SHA256
+fn unpack_payload(packed: &Packed, payload: &[u8], out: &mut impl Write) -> Result<(), CmprsError> {
+    let mut writer = HashingWriter {
+        inner: out,
+        hasher: Sha256::new(),
+        written: 0,
+        failed: false,
+    };
+    if !crate::is_compiled_in(packed.header.algorithm) {
+        return Err(CmprsError::AlgorithmNotCompiled(packed.header.algorithm));
+    }
+    // A filter is undone over the whole payload, so it's decompressed in memory first
+    let result = match packed.header.filter {
+        Some(filter) => {
+            let mut filtered = Vec::new();
+            decompress_to(&packed.header, payload, &mut filtered).and_then(|()| {
+                filter.decode(&mut filtered);
+                writer.write_all(&filtered)
+            })
+        }
+        None => decompress_to(&packed.header, payload, &mut writer),
+    };
+    // io::copy doesn't tell read errors from write errors, the writer does
+    result.map_err(|err| {
+        if writer.failed {
+            CmprsError::Io(err)
+        } else {
+            CmprsError::Decode(err)
+        }
+    })?;
+
+    if let Some(expected) = packed.header.original_size {
+        if writer.written != expected {
+            return Err(CmprsError::SizeMismatch {
+                expected,
+                actual: writer.written,
+            });
+        }
+    }
+
+    let actual = writer.hasher.finalize();
+    if actual.as_slice() != packed.sha256 {
+        return Err(CmprsError::HashMismatch {
+            expected: hex::encode(packed.sha256),
+            actual: hex::encode(actual),
+        });
+    }
+    Ok(())
+}
+
+/// Decompress `payload` with the header's algorithm into `out`
+fn decompress_to(header: &Header, payload: &[u8], out: &mut impl Write) -> io::Result<()> {
+    match header.algorithm {
+        Algorithm::Zstd => {
+            ZstdDecoder::with_dictionary(payload, header.dictionary.as_deref().unwrap_or_default())
+                .and_then(|mut decoder| io::copy(&mut decoder, out))
+                .map(drop)
+        }
+        // Bundles hold one xz stream per file
+        #[cfg(feature = "xz")]
+        Algorithm::Xz => io::copy(&mut XzDecoder::new_multi_decoder(payload), out).map(drop),
+        #[cfg(feature = "gzip")]
+        Algorithm::Gzip => io::copy(&mut MultiGzDecoder::new(payload), out).map(drop),
+        Algorithm::None => out.write_all(payload),
+        // Checked by the caller
+        #[allow(unreachable_patterns)]
+        algorithm => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            CmprsError::AlgorithmNotCompiled(algorithm).to_string(),
+        )),
+    }
+}
+
+/// Read the packed file at `path`. The payload of a file packed with `--split`
+/// is read from its `.data` file and appended, so the result unpacks like any other.
+///
+/// On unix the file is memory-mapped rather than read, so parsing and
+/// unpacking it never copy the payload onto the heap: only what it
+/// decompresses to is allocated. The file must not be truncated while the
+/// result is alive. A split file is still read, to join its two halves.
+///
+/// ```
+/// use cmprs::format::{self, Algorithm, Header};
+/// use sha2::{Digest, Sha256};
+/// use std::alloc::{GlobalAlloc, Layout, System};
+/// use std::io;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// // Counts the bytes allocated at once, and the most there ever were
+/// struct Counting;
+/// static CURRENT: AtomicUsize = AtomicUsize::new(0);
+/// static PEAK: AtomicUsize = AtomicUsize::new(0);
+///
+/// unsafe impl GlobalAlloc for Counting {
+///     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+///         let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+///         PEAK.fetch_max(current, Ordering::Relaxed);
+///         System.alloc(layout)
+///     }
+///
+///     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+///         CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+///         System.dealloc(ptr, layout)
+///     }
+/// }
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Counting = Counting;
+///
+/// fn main() {
+///     // A stored 32 MiB payload, so the packed file is as large as it
+///     let original: Vec<u8> = (0..32u32 << 20).map(|i| (i % 251) as u8).collect();
+///     let sha256 = Sha256::digest(&original);
+///     let header = Header {
+///         algorithm: Algorithm::None,
+///         ..Header::default()
+///     };
+///     let mut packed = b"stub".to_vec();
+///     format::write_preamble(&mut packed).unwrap();
+///     packed.extend_from_slice(&sha256);
+///     packed.extend_from_slice(&header.to_bytes(&sha256));
+///     packed.extend_from_slice(&original);
+///     let dir = tempfile::tempdir().unwrap();
+///     let path = dir.path().join("packed");
+///     std::fs::write(&path, &packed).unwrap();
+///     drop((original, packed));
+///
+///     let before = CURRENT.load(Ordering::Relaxed);
+///     PEAK.store(before, Ordering::Relaxed);
+///     let packed = cmprs::read_packed(&path).unwrap();
+///     cmprs::unpack_to(&packed, &mut io::sink()).unwrap();
+///     let extra = PEAK.load(Ordering::Relaxed) - before;
+///     if cfg!(unix) {
+///         assert!(extra < 1 << 20, "unpacking allocated {extra} bytes");
+///     }
+/// }
+/// ```
+pub fn read_packed(path: &Path) -> Result<PackedFile, CmprsError> {
+    let file = File::open(path)?;
+    let packed = PackedFile::map(&file)?;
+    let header = Packed::parse(&packed)?.header;
+    if !header.split_payload {
+        return Ok(packed);
+    }
+
+    let mut packed = packed.to_vec();
+    let offset = packed.len() as u64;
+    let data_path = format::split_payload_path(path);
+    let mut data = File::open(&data_path).map_err(|err| {
+        io::Error::new(
+            err.kind(),
+            format!("failed to open the payload {}: {err}", data_path.display()),
+        )
+    })?;
+    let available = data.read_to_end(&mut packed)?;
+    header.check_payload_size(offset, available as u64)?;
+    Ok(PackedFile(Bytes::Read(packed)))
+}
+
+/// The bytes of a packed file, as [`read_packed`] got them. Derefs to the
+/// whole file, for [`unpack_to`] and [`Packed::parse`].
+pub struct PackedFile(Bytes);
+
+enum Bytes {
+    /// Mapped read-only, and unmapped when dropped
+    Mapped {
+        ptr: *const u8,
+        len: usize,
+    },
+    Read(Vec<u8>),
+}
+
+impl PackedFile {
+    fn map(file: &File) -> io::Result<PackedFile> {
+        use std::os::fd::AsRawFd;
+
+        let len = usize::try_from(file.metadata()?.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::OutOfMemory, "file too large to map"))?;
+        // mmap refuses empty mappings, and there's nothing to map anyway
+        if len == 0 {
+            return Ok(PackedFile(Bytes::Read(Vec::new())));
+        }
+        // SAFETY: a fresh private read-only mapping of an open file, which
+        // the kernel places and only this value unmaps
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(PackedFile(Bytes::Mapped {
+            ptr: ptr as *const u8,
+            len,
+        }))
+    }
+}
+
+impl std::ops::Deref for PackedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            // SAFETY: mapped readable for `len` bytes until dropped
+            Bytes::Mapped { ptr, len } => unsafe { std::slice::from_raw_parts(*ptr, *len) },
+            Bytes::Read(packed) => packed,
+        }
+    }
+}
+
+impl Drop for PackedFile {
+    fn drop(&mut self) {
+        if let Bytes::Mapped { ptr, len } = self.0 {
+            // SAFETY: mapped by `map`, and nothing borrows it past this point
+            unsafe { libc::munmap(ptr as *mut libc::c_void, len) };
+        }
+    }
+}
+
+// SAFETY: the mapping is read-only and owned by the value, like a Vec<u8>
+unsafe impl Send for PackedFile {}
+unsafe impl Sync for PackedFile {}
+
+/// The original file stored in a packed file, decompressed as it's read,
+/// for feeding it to something else without holding all of it in memory.
+/// Only the decoder's own window is buffered, except for payloads packed
+/// with a filter, which is undone over the whole payload, so those are
+/// decompressed in full up front. A redundant payload is read from its first
+/// copy alone, since what it held has been handed out by the time a check fails.
+///
+/// The length and SHA256 are checked once the end is reached: the read that
+/// would return 0 fails instead, with an [`io::ErrorKind::InvalidData`] error
+/// holding the [`CmprsError`], after the bad bytes have been read.
+///
+/// ```
+/// use cmprs::{format, PackOptions, PayloadReader};
+/// use sha2::{Digest, Sha256};
+/// use std::io::Read;
+///
+/// let original = b"#!/bin/sh\necho hi\n".repeat(1000);
+/// let options = PackOptions::default();
+/// let (packed, _) = cmprs::pack(b"stub", &original, &format::Header::default(), &options).unwrap();
+///
+/// // A few bytes at a time
+/// let mut reader = PayloadReader::new(&packed).unwrap();
+/// let mut read = Vec::new();
+/// let mut chunk = [0; 7];
+/// loop {
+///     match reader.read(&mut chunk).unwrap() {
+///         0 => break,
+///         n => read.extend_from_slice(&chunk[..n]),
+///     }
+/// }
+/// assert_eq!(read, original);
+///
+/// // Or from a file, mapped rather than read
+/// let file = tempfile::NamedTempFile::new().unwrap();
+/// std::fs::write(file.path(), &packed).unwrap();
+/// let mut read = Vec::new();
+/// PayloadReader::open(file.path()).unwrap().read_to_end(&mut read).unwrap();
+/// assert_eq!(read, original);
+///
+/// // A payload that decompresses fine but isn't the one hashed fails at the end
+/// let other = Sha256::digest(b"something else");
+/// let mut mismatched = b"stub".to_vec();
+/// format::write_preamble(&mut mismatched).unwrap();
+/// mismatched.extend_from_slice(&other);
+/// mismatched.extend_from_slice(&format::Header::default().to_bytes(&other));
+/// mismatched.extend_from_slice(&cmprs::compress(&original, &options).unwrap());
+///
+/// let mut read = Vec::new();
+/// let err = PayloadReader::new(&mismatched).unwrap().read_to_end(&mut read).unwrap_err();
+/// assert_eq!(read, original);
+/// assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+/// let err = err.into_inner().unwrap().downcast::<cmprs::CmprsError>().unwrap();
+/// assert!(matches!(*err, cmprs::CmprsError::HashMismatch { .. }));
+/// ```
+pub struct PayloadReader<'a> {
+    header: Header,
+    decoder: Box<dyn Read + 'a>,
+    sha256: Vec<u8>,
+    hasher: Sha256,
+    read: u64,
+    checked: bool,
+}
+
+/// The compressed payload a [`PayloadReader`] reads, borrowed, or in a
+/// packed file the reader owns
+enum PayloadBytes<'a> {
+    Borrowed(&'a [u8]),
+    Owned(PackedFile, Range<usize>),
+}
+
+impl AsRef<[u8]> for PayloadBytes<'_> {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            PayloadBytes::Borrowed(payload) => payload,
+            PayloadBytes::Owned(packed, range) => &packed[range.clone()],
+        }
+    }
+}
+
+impl<'a> PayloadReader<'a> {
+    /// Read the payload of the packed file `packed`
+    pub fn new(packed: &'a [u8]) -> Result<PayloadReader<'a>, CmprsError> {
+        let parsed = Packed::parse(packed)?;
+        let (payload, _) = parsed.header.payload_copies(parsed.payload);
+        PayloadReader::from_payload(
+            parsed.header,
+            parsed.sha256.to_vec(),
+            PayloadBytes::Borrowed(payload),
+        )
+    }
+
+    /// The header of the packed file
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    fn from_payload(
+        header: Header,
+        sha256: Vec<u8>,
+        payload: PayloadBytes<'a>,
+    ) -> Result<PayloadReader<'a>, CmprsError> {
+        if !crate::is_compiled_in(header.algorithm) {
+            return Err(CmprsError::AlgorithmNotCompiled(header.algorithm));
+        }
+        let payload = io::Cursor::new(payload);
+        let mut decoder: Box<dyn Read + 'a> = match header.algorithm {
+            Algorithm::Zstd => Box::new(
+                ZstdDecoder::with_dictionary(
+                    payload,
+                    header.dictionary.as_deref().unwrap_or_default(),
+                )
+                .map_err(CmprsError::Decode)?,
+            ),
+            #[cfg(feature = "xz")]
+            Algorithm::Xz => Box::new(XzDecoder::new_multi_decoder(payload)),
+            #[cfg(feature = "gzip")]
+            Algorithm::Gzip => Box::new(MultiGzDecoder::new(payload)),
+            Algorithm::None => Box::new(payload),
+            #[allow(unreachable_patterns)]
+            algorithm => return Err(CmprsError::AlgorithmNotCompiled(algorithm)),
+        };
+        if let Some(filter) = header.filter {
+            let mut filtered = Vec::new();
+            decoder
+                .read_to_end(&mut filtered)
+                .map_err(CmprsError::Decode)?;
+            filter.decode(&mut filtered);
+            decoder = Box::new(io::Cursor::new(filtered));
+        }
+        Ok(PayloadReader {
+            header,
+            decoder,
+            sha256,
+            hasher: Sha256::new(),
+            read: 0,
+            checked: false,
+        })
+    }
+
+    /// The length and SHA256 of what was read, against the header's
+    fn check(&self) -> Result<(), CmprsError> {
+        if let Some(expected) = self.header.original_size {
+            if self.read != expected {
+                return Err(CmprsError::SizeMismatch {
+                    expected,
+                    actual: self.read,
+                });
+            }
+        }
+        let actual = self.hasher.clone().finalize();
+        if actual.as_slice() != self.sha256 {
+            return Err(CmprsError::HashMismatch {
+                expected: hex::encode(&self.sha256),
+                actual: hex::encode(actual),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl PayloadReader<'static> {
+    /// Read the payload of the packed file at `path`, mapped like
+    /// [`read_packed`] maps it, and from its `.data` file when it's split
+    pub fn open(path: &Path) -> Result<PayloadReader<'static>, CmprsError> {
+        PayloadReader::owning(read_packed(path)?)
+    }
+
+    /// Read the payload of the packed file open as `file`, mapped like
+    /// [`read_packed`] maps it. The payload of a split file is in another
+    /// file, so those are read with [`PayloadReader::open`].
+    pub fn from_file(file: &File) -> Result<PayloadReader<'static>, CmprsError> {
+        let packed = PackedFile::map(file)?;
+        if Packed::parse(&packed)?.header.split_payload {
+            return Err(CmprsError::InvalidOptions(
+                "the payload of a file packed with --split is in its .data file, open it by path"
+                    .to_string(),
+            ));
+        }
+        PayloadReader::owning(packed)
+    }
+
+    fn owning(packed: PackedFile) -> Result<PayloadReader<'static>, CmprsError> {
+        let (header, sha256, range) = {
+            let parsed = Packed::parse(&packed)?;
+            let (payload, _) = parsed.header.payload_copies(parsed.payload);
+            // Where the payload is in the file, to find it again once the file moves in
+            let start = payload.as_ptr() as usize - packed.as_ptr() as usize;
+            let range = start..start + payload.len();
+            (parsed.header, parsed.sha256.to_vec(), range)
+        };
+        PayloadReader::from_payload(header, sha256, PayloadBytes::Owned(packed, range))
+    }
+}
+
+impl Read for PayloadReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.checked {
+            return Ok(0);
+        }
+        let read = self.decoder.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            self.check()
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            self.checked = true;
+            return Ok(0);
+        }
+        self.hasher.update(&buf[..read]);
+        self.read += read as u64;
+        Ok(read)
+    }
+}
+
+/// Hashes and counts everything written through it, and remembers whether writing failed
+struct HashingWriter<'a, W> {
+    inner: &'a mut W,
+    hasher: Sha256,
+    written: u64,
+    failed: bool,
+}
+
+impl<W: Write> Write for HashingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf).inspect_err(|_| self.failed = true)?;
+        self.hasher.update(&buf[..written]);
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().inspect_err(|_| self.failed = true)
+    }
+}
@@ -0,0 +1,128 @@
+use crate::{human, pack_one, parent_dir, parse_level, Args, LevelArg};
+use clap::{Args as ClapArgs, Parser};
+use cmprs::CmprsError;
+use log::{debug, info, warn};
+use notify::event::{AccessKind, AccessMode};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+#[derive(ClapArgs, Clone)]
+pub struct WatchArgs {
+    #[arg(help = "The program to pack, and pack again each time it changes")]
+    input: PathBuf,
+
+    #[arg(short, long, help = "Output file, replaced by each pack")]
+    output: PathBuf,
+
+    #[arg(
+        short = 'l',
+        long = "level",
+        value_parser = parse_level,
+        allow_negative_numbers = true,
+        help = "Compression level, like cmprs --level. Negative levels pack fastest, for the tightest loops [default: 3]"
+    )]
+    compression_level: Option<LevelArg>,
+
+    #[arg(
+        long,
+        value_name = "MS",
+        default_value_t = 200,
+        help = "Pack once the input has been left alone for this many milliseconds, so a build writing it in several steps is packed once"
+    )]
+    debounce_ms: u64,
+}
+
+/// Pack the input, then again each time it changes, until interrupted
+pub fn run(args: WatchArgs) -> Result<(), CmprsError> {
+    // Editors and linkers often write a new file and rename it over the old
+    // one, which a watch on the file itself would lose track of
+    let Some(name) = args.input.file_name() else {
+        return Err(CmprsError::InvalidOptions(format!(
+            "{} doesn't name a file to watch",
+            args.input.display()
+        )));
+    };
+    let dir = parent_dir(&args.input).canonicalize()?;
+    let watched = dir.join(name);
+    let (sender, events) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(watch_error)?;
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .map_err(watch_error)?;
+
+    pack(&args);
+    human::note(format_args!(
+        "watching {} for changes, press Ctrl-C to stop",
+        args.input.display()
+    ));
+    let debounce = Duration::from_millis(args.debounce_ms);
+    loop {
+        // Block until it changes, then until it's done changing
+        while !changes(events.recv().map_err(watch_error)?, &watched) {}
+        while let Ok(event) = events.recv_timeout(debounce) {
+            changes(event, &watched);
+        }
+        if !watched.exists() {
+            debug!("{} is gone, waiting for it to come back", watched.display());
+            continue;
+        }
+        pack(&args);
+    }
+}
+
+/// Whether `event` is a change to `watched`. Reads, like the ones packing
+/// does, aren't.
+fn changes(event: notify::Result<Event>, watched: &Path) -> bool {
+    let event = match event {
+        Ok(event) => event,
+        Err(err) => {
+            warn!("Error watching {}: {}", watched.display(), err);
+            return false;
+        }
+    };
+    let change = matches!(
+        event.kind,
+        EventKind::Create(_)
+            | EventKind::Modify(_)
+            | EventKind::Remove(_)
+            | EventKind::Access(AccessKind::Close(AccessMode::Write))
+    );
+    change && event.paths.iter().any(|path| path == watched)
+}
+
+/// Pack the input to the output, reporting how it went on one line. A
+/// failed pack is reported and the next change tries again.
+fn pack(args: &WatchArgs) {
+    let mut pack_args = Args::parse_from([OsString::from("cmprs"), args.input.clone().into()]);
+    pack_args.output = Some(args.output.clone());
+    pack_args.compression_level = args.compression_level;
+    pack_args.no_summary = true;
+
+    let start = Instant::now();
+    match pack_one(pack_args, args.input.clone()) {
+        Ok(output) => {
+            let elapsed = start.elapsed();
+            info!("Packed {} in {:?}", args.input.display(), elapsed);
+            eprintln!(
+                "cmprs: packed {} to {} in {:.1?}, {} to {} bytes ({:.1}% of the input)",
+                args.input.display(),
+                output.path.display(),
+                elapsed,
+                output.input_size,
+                output.output_size,
+                output.output_size as f64 / output.input_size.max(1) as f64 * 100.0
+            );
+        }
+        Err(err) => eprintln!("cmprs: {}: {err}", args.input.display()),
+    }
+}
+
+fn watch_error(err: impl std::fmt::Display) -> CmprsError {
+    CmprsError::Io(io::Error::other(format!(
+        "failed to watch for changes: {err}"
+    )))
+}
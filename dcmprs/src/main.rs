@@ -1,21 +1,88 @@
+use cmprs_format::{
+    find_magic_header, split_payload_path, Algorithm, CmprsError, ContentType, Filter, Header,
+    Packed, VerifyPolicy, CAPABILITY_XATTR,
+};
+use flate2::read::MultiGzDecoder;
+use fs2::FileExt;
 use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
 use std::env;
-use std::fs::{File, OpenOptions};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
 use std::io::{self, Read, Write};
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
 use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process::{self, Command};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use tempfile::NamedTempFile;
+use xz2::read::XzDecoder;
 use zstd::stream::read::Decoder as ZstdDecoder;
 
-// Same magic header as in cmprs+boundary
-const MAGIC_HEADER: &[u8; 16] = b"DCMPRS_DATA_HERE";
+// Launch overhead above which we warn that packing is hurting startup time
+const SLOW_LAUNCH_THRESHOLD: Duration = Duration::from_millis(500);
+
+// When passed as the first argument, write the original program to the
+// following path instead of running it. Disabled with `cmprs --no-stub-flags`.
+const EXTRACT_FLAG: &str = "--dcmprs-extract";
+
+// When passed as the first argument, write the original program to stdout
+// instead of running it. Also disabled with `cmprs --no-stub-flags`.
+const CAT_FLAG: &str = "--dcmprs-cat";
+
+// When passed as the first argument, print what the header says about the
+// original program instead of running it. Also disabled with `cmprs --no-stub-flags`.
+const INFO_FLAG: &str = "--dcmprs-info";
+
+// When passed as the first argument, replace the payload with the one packed
+// in the file at the following path, keeping this stub, instead of running
+// the program. Also disabled with `cmprs --no-stub-flags`.
+const UPDATE_FLAG: &str = "--dcmprs-update";
+
+// When passed as the first argument, decompress the payload in memory and
+// check it against its SHA256, reporting OK or FAIL, instead of running the
+// program. Also disabled with `cmprs --no-stub-flags`.
+const CHECK_FLAG: &str = "--dcmprs-check";
+
+// When passed as the first argument, decompress a small sample with every
+// algorithm and filter this stub knows, through the same code as a launch,
+// reporting OK or FAIL, instead of running the program. Vouches for the stub
+// on this host regardless of the payload. Also disabled with `cmprs --no-stub-flags`.
+const SELFTEST_FLAG: &str = "--dcmprs-selftest";
+
+// The self-test sample as each algorithm compresses it, made with `cmprs --split`
+// from `selftest_sample()`
+const SELFTEST_PAYLOADS: &[(Algorithm, &[u8])] = &[
+    (Algorithm::Zstd, include_bytes!("../selftest/sample.zst")),
+    (Algorithm::Xz, include_bytes!("../selftest/sample.xz")),
+    (Algorithm::Gzip, include_bytes!("../selftest/sample.gz")),
+];
+
+// Largest payload dcmprs will decompress unless DCMPRS_MAX_SIZE says otherwise,
+// so a crafted payload can't exhaust memory at launch
+const DEFAULT_MAX_SIZE: u64 = 4 << 30;
+
+// Mode used for files packed before permissions were recorded in the header
+const DEFAULT_MODE: u32 = 0o755;
+
+// Mode of the extracted temp file unless DCMPRS_TEMP_MODE says otherwise. Only
+// we exec it, so other users have no reason to read it.
+const DEFAULT_TEMP_MODE: u32 = 0o700;
+
+// Packed-inside-packed payloads are unwrapped in memory up to this many
+// layers deep, so a payload that contains itself can't loop forever
+const MAX_NESTED_LAYERS: usize = 8;
+
+// Our version after cmprs_format::STUB_VERSION_TAG, kept through LTO and
+// stripping so `cmprs inspect-stub` can read it without running us
+#[used]
+static VERSION_TAG: &[u8] = concat!("DCMPRS_VERSION=", env!("CARGO_PKG_VERSION"), "\0").as_bytes();
 
 fn main() -> io::Result<()> {
-    // Initialize logger with custom environment variable
-    env_logger::Builder::from_env(env_logger::Env::new().filter("DCMPRS_LOG_LEVEL")).init();
+    #[cfg(feature = "logging")]
+    init_logging();
 
     let start_time = Instant::now();
     let current_exe = env::current_exe()?;
@@ -24,106 +91,536 @@ fn main() -> io::Result<()> {
         current_exe.display()
     );
 
-    debug!("Opening current executable file");
-    let mut file = File::open(&current_exe)?;
-
     debug!("Reading executable file into memory");
     let read_start = Instant::now();
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-    info!("Read {} bytes in {:?}", buffer.len(), read_start.elapsed());
+    let payload_offset = payload_offset();
+    let (buffer, at_current_exe) = read_own_executable(&current_exe, payload_offset);
+    let read_time = read_start.elapsed();
+    info!(
+        stage = "read",
+        bytes = buffer.len(),
+        duration_ms = millis(read_time);
+        "Read {} bytes in {:?}", buffer.len(), read_time
+    );
+
+    // Find the boundary between the dcmprs executable and the magic header,
+    // unless DCMPRS_PAYLOAD_OFFSET says where it is
+    let scan_start = Instant::now();
+    let parsed = match payload_offset {
+        Some(offset) if offset >= buffer.len() => exit_with(CmprsError::InvalidOptions(format!(
+            "DCMPRS_PAYLOAD_OFFSET {offset} is past the end of the {} byte file",
+            buffer.len()
+        ))),
+        Some(offset) => {
+            debug!("Using the magic header offset {offset} from DCMPRS_PAYLOAD_OFFSET");
+            Packed::parse_at(&buffer, offset)
+        }
+        None => {
+            debug!("Searching for magic header in {} byte buffer", buffer.len());
+            Packed::parse(&buffer)
+        }
+    };
+    let packed = match parsed {
+        Ok(packed) => packed,
+        Err(err) => exit_with(err),
+    };
+    let scan_time = scan_start.elapsed();
+    info!(
+        "Found magic header at position {} (format v{})",
+        packed.stub_len, packed.version
+    );
 
-    // Find the boundary between the dcmprs executable and the magic header
-    debug!("Searching for magic header in {} byte buffer", buffer.len());
-    let magic_pos = find_magic_header(&buffer);
+    debug!("shasum is {:x?}", packed.sha256);
+    debug!("header is {:?}", packed.header);
+    if let Some(packer_version) = &packed.header.packer_version {
+        debug!("Packed by cmprs {packer_version}");
+        if newer_release(packer_version, env!("CARGO_PKG_VERSION")) {
+            warn!(
+                "Packed by cmprs {packer_version}, which is newer than this {} stub, \
+                 fields it added may be ignored",
+                env!("CARGO_PKG_VERSION")
+            );
+        }
+    }
 
-    if magic_pos.is_none() {
-        warn!("No magic header found - this may not be a dcmprs-compressed file");
-        process::exit(1);
+    // Collect command line arguments (excluding the program name)
+    let args: Vec<String> = env::args().skip(1).collect();
+    debug!("Command line arguments: {:?}", args);
+
+    let stub_flag = args
+        .first()
+        .map(String::as_str)
+        .filter(|_| !packed.header.no_stub_flags);
+
+    // Only the header is needed, so there's nothing to decompress or run as root
+    if stub_flag == Some(INFO_FLAG) {
+        print_info(&packed, &buffer[..packed.stub_len], &current_exe);
+        process::exit(0);
     }
 
-    let magic_pos = magic_pos.unwrap();
-    info!("Found magic header at position {}", magic_pos);
-    let data_start = magic_pos + MAGIC_HEADER.len() + 3;
+    if stub_flag == Some(SELFTEST_FLAG) {
+        selftest();
+    }
 
-    if data_start + 32 >= buffer.len() {
-        warn!("No SHA256 hash or compressed data found after magic header");
-        process::exit(1);
+    // Before the stub does anything on the payload's behalf
+    if packed.header.verify_stub {
+        verify_stub(&packed.header, &buffer[..packed.stub_len]);
     }
 
-    debug!(
-        "Data starts at position {} (after magic header + SHA256)",
-        data_start + 32
-    );
+    if stub_flag == Some(UPDATE_FLAG) {
+        let Some(new_path) = args.get(1) else {
+            eprintln!("dcmprs: {UPDATE_FLAG} needs the path of a packed file to update to");
+            process::exit(2);
+        };
+        if !at_current_exe {
+            eprintln!(
+                "dcmprs: {} was replaced by its program, there's no payload left in it to update",
+                current_exe.display()
+            );
+            process::exit(1);
+        }
+        update(
+            &current_exe,
+            &buffer[..packed.stub_len],
+            &packed.header,
+            Path::new(new_path),
+        );
+    }
 
-    debug!(
-        "shasum is {:x?}",
-        &buffer[magic_pos + MAGIC_HEADER.len()..data_start]
-    );
+    require_loader_version(&packed.header);
+
+    // exec would only fail with "Exec format error"
+    let extracting = matches!(stub_flag, Some(EXTRACT_FLAG | CAT_FLAG | CHECK_FLAG));
+    if packed.header.content_type == Some(ContentType::Data)
+        && packed.header.launcher.is_empty()
+        && !extracting
+    {
+        eprintln!(
+            "dcmprs: the packed file is not an executable and no launcher was configured, \
+             repack it with cmprs --launcher to open it with a program, or get it out with \
+             {EXTRACT_FLAG}"
+        );
+        process::exit(126);
+    }
+
+    // Extracting doesn't need root, only running does
+    if packed.header.needs_root && !extracting && !is_root() {
+        escalate(&current_exe, &args);
+    }
 
-    // Skip the SHA256 hash (32 bytes after magic header) and get compressed data
-    let compressed_data = &buffer[data_start + 32..];
+    // A bundle packed with --subcommand runs the program its first argument
+    // names, passing it the rest, and otherwise its entry
+    let mut dispatched = 0;
+    let program = match &packed.header.bundle {
+        Some(bundle) if !extracting => match bundle.select(args.first().map(String::as_str)) {
+            Some((program, matched)) => {
+                dispatched = usize::from(matched);
+                Some(program.to_os_string())
+            }
+            None => {
+                let names: Vec<&str> = bundle
+                    .subcommands
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect();
+                eprintln!(
+                    "dcmprs: usage: {} <command> [args...], where <command> is one of: {}",
+                    current_exe
+                        .file_name()
+                        .unwrap_or(current_exe.as_os_str())
+                        .to_string_lossy(),
+                    names.join(", ")
+                );
+                process::exit(2);
+            }
+        },
+        _ => None,
+    };
+
+    // With DCMPRS_INSTALL_DIR, a program an earlier run installed is run from
+    // there, without reading or decompressing the payload again
+    let verify_policy = verify_policy(packed.header.verify_policy);
+    let exec_wrapper = exec_wrapper();
+    let install_path = install_dir()
+        .filter(|_| packed.header.bundle.is_none() && !extracting)
+        .map(|dir| install_path(&dir, packed.sha256, &packed.header, &current_exe));
+    if let Some(path) = &install_path {
+        if is_installed(path, &packed.header, packed.sha256, verify_policy) {
+            info!("Running {}, installed by an earlier run", path.display());
+            let argfiles = packed.header.argfiles;
+            let program_args = program_args(&packed.header, argfiles, &args, dispatched);
+            let mut cmd = command(&packed.header, path, program_args, &exec_wrapper);
+            return Err(exec_failed(cmd.exec(), &packed.header, &exec_wrapper));
+        }
+    }
+
+    // Files packed with --split keep their payload in a file next to them
+    let split_payload;
+    let compressed_data = if packed.header.split_payload {
+        let data_path = split_payload_path(&current_exe);
+        debug!("Reading the payload from {}", data_path.display());
+        split_payload = match fs::read(&data_path) {
+            Ok(data) => data,
+            Err(err) => exit_with(CmprsError::Io(io::Error::new(
+                err.kind(),
+                format!("failed to read the payload {}: {err}", data_path.display()),
+            ))),
+        };
+        if let Err(err) = packed
+            .header
+            .check_payload_size(0, split_payload.len() as u64)
+        {
+            exit_with(err);
+        }
+        &split_payload[..]
+    } else {
+        packed.payload
+    };
+    if compressed_data.is_empty() {
+        warn!("No compressed data found after magic header");
+        process::exit(1);
+    }
     info!("Found {} bytes of compressed data", compressed_data.len());
 
-    // Decompress the data
-    debug!("Starting zstd decompression");
+    // Refuse up front when the header says the payload is over the limit
+    let max_size = max_decompressed_size();
+    if packed
+        .header
+        .original_size
+        .is_some_and(|size| size > max_size)
+    {
+        exit_with(CmprsError::TooLarge { limit: max_size });
+    }
+
+    if stub_flag == Some(CHECK_FLAG) {
+        check(&packed, compressed_data, max_size);
+    }
+
+    // A bundle packed with --extract-entry-only runs with nothing but its entry
+    // extracted, decompressed from the entry's own frame. Checking the payload
+    // against its SHA256 needs all of it though, and so does telling whether
+    // a redundant payload needs recovering.
+    let verify = verify_policy != VerifyPolicy::Never;
+    let (compressed_data, redundant_copy) = packed.header.payload_copies(compressed_data);
+    let entry_frame = match &packed.header.bundle {
+        Some(bundle)
+            if packed.header.entry_only
+                && !extracting
+                && !verify
+                && redundant_copy.is_none()
+                && packed.header.filter.is_none() =>
+        {
+            program
+                .as_deref()
+                .and_then(|program| bundle.frame_of(program))
+        }
+        _ => None,
+    };
+
+    // Decompress the data. Stored payloads are used as-is.
     let decompress_start = Instant::now();
-    let mut decoder = ZstdDecoder::new(compressed_data)?;
-    let mut decompressed_data = Vec::new();
-    decoder.read_to_end(&mut decompressed_data)?;
+    let (decompressed_data, expected_size) = match &entry_frame {
+        Some((range, entry)) => {
+            debug!("Decompressing only the entry, from bytes {range:?} of the payload");
+            let frame = usize::try_from(range.start)
+                .ok()
+                .zip(usize::try_from(range.end).ok())
+                .and_then(|(start, end)| compressed_data.get(start..end))
+                .unwrap_or_else(|| {
+                    exit_with(CmprsError::Malformed(
+                        "bundle index points past the end of the payload".to_string(),
+                    ))
+                });
+            (
+                decompress(&packed.header, frame, Some(entry.size), max_size),
+                Some(entry.size),
+            )
+        }
+        None => {
+            let decompressed_data = match redundant_copy {
+                Some(copy) => decompress_redundant(&packed, compressed_data, copy, max_size),
+                None => decompress(
+                    &packed.header,
+                    compressed_data,
+                    packed.header.original_size,
+                    max_size,
+                ),
+            };
+            (decompressed_data, packed.header.original_size)
+        }
+    };
     let decompress_time = decompress_start.elapsed();
+    if decompressed_data.len() as u64 > max_size {
+        exit_with(CmprsError::TooLarge { limit: max_size });
+    }
+    if let Some(expected) = expected_size {
+        let actual = decompressed_data.len() as u64;
+        if actual != expected {
+            exit_with(CmprsError::SizeMismatch { expected, actual });
+        }
+    }
     info!(
-        "Decompressed {} bytes to {} bytes in {:?}",
+        stage = "decompress",
+        bytes = decompressed_data.len(),
+        compressed_bytes = compressed_data.len(),
+        duration_ms = millis(decompress_time);
+        "Decompressed {} bytes to {} bytes ({}) in {:?}",
         compressed_data.len(),
         decompressed_data.len(),
+        packed.header.algorithm.name(),
         decompress_time
     );
+    info!(
+        "Decompression throughput: {:.1} MB/s",
+        throughput_mb_per_sec(decompressed_data.len(), decompress_time)
+    );
 
-    // Create a temporary file to write the decompressed content
-    debug!("Creating temporary file for decompressed content");
-    let temp_start = Instant::now();
-    let mut temp_file = NamedTempFile::new()?;
-    temp_file.write_all(&decompressed_data)?;
+    match verify_policy {
+        VerifyPolicy::Never => {}
+        VerifyPolicy::Once => verify_once(&current_exe, packed.sha256, &decompressed_data),
+        VerifyPolicy::Always => verify_payload(packed.sha256, &decompressed_data),
+    }
 
-    // Make sure the temp file is executable
-    let metadata = temp_file.as_file().metadata()?;
-    let mut permissions = metadata.permissions();
-    permissions.set_mode(0o755);
-    temp_file.as_file().set_permissions(permissions)?;
+    // A payload that is packed itself is unwrapped here instead of being
+    // exec'd only to decompress itself. Extracting gets the payload as stored.
+    let unwrap_start = Instant::now();
+    let (decompressed_data, header, sha256) = if extracting {
+        (
+            decompressed_data,
+            packed.header.clone(),
+            packed.sha256.to_vec(),
+        )
+    } else {
+        unwrap_nested(decompressed_data, &packed.header, packed.sha256, max_size)
+    };
+    let decompress_time = decompress_time + unwrap_start.elapsed();
 
-    let temp_path = temp_file.path().to_path_buf();
+    // A copy of exactly what is about to run, for debugging deployments
+    if let Some(dump_path) = env::var_os("DCMPRS_DUMP") {
+        match fs::write(&dump_path, &decompressed_data) {
+            Ok(()) => info!("Dumped the decompressed payload to {:?}", dump_path),
+            Err(err) => warn!(
+                "Failed to dump the decompressed payload to {:?}: {}",
+                dump_path, err
+            ),
+        }
+    }
+
+    if stub_flag == Some(CAT_FLAG) {
+        if header.bundle.is_some() {
+            eprintln!(
+                "dcmprs: this is a packed directory, use {EXTRACT_FLAG} instead of {CAT_FLAG}"
+            );
+            process::exit(2);
+        }
+        let mut stdout = io::stdout().lock();
+        if let Err(err) = stdout
+            .write_all(&decompressed_data)
+            .and_then(|()| stdout.flush())
+        {
+            eprintln!("dcmprs: failed to write to stdout: {err}");
+            process::exit(1);
+        }
+        process::exit(0);
+    }
+
+    if stub_flag == Some(EXTRACT_FLAG) {
+        let Some(output_path) = args.get(1) else {
+            eprintln!("dcmprs: {EXTRACT_FLAG} needs an output path");
+            process::exit(2);
+        };
+        // Whatever the policy, a payload that doesn't match never reaches the output
+        if verify_policy != VerifyPolicy::Always {
+            verify_payload(&sha256, &decompressed_data);
+        }
+        let result = match &header.bundle {
+            Some(bundle) => bundle.unpack(&decompressed_data, Path::new(output_path)),
+            None => extract_to(Path::new(output_path), &decompressed_data, &header)
+                .map_err(CmprsError::from),
+        };
+        if let Err(err) = result {
+            eprintln!("dcmprs: failed to extract to {output_path}: {err}");
+            process::exit(err.exit_code());
+        }
+        info!("Extracted original program to {output_path}");
+        process::exit(0);
+    }
+
+    let temp_mode = temp_mode();
+    let preserve_mtime = env::var_os("DCMPRS_PRESERVE_MTIME").is_some_and(|value| value == "1");
+    let ephemeral = env::var_os("DCMPRS_EPHEMERAL").is_some_and(|value| value == "1");
+    let temp_start = Instant::now();
+    let (temp_path, temp_file) = match &header.bundle {
+        Some(bundle) => {
+            debug!("Unpacking {} bundled files", bundle.files.len());
+            let temp_dir = create_temp(&current_exe, |builder, dir| builder.tempdir_in(dir))?;
+            let result = match &entry_frame {
+                Some((_, entry)) => entry.unpack(&decompressed_data, temp_dir.path()),
+                None => bundle.unpack(&decompressed_data, temp_dir.path()),
+            };
+            if let Err(err) = result {
+                exit_with(err);
+            }
+            // Like a single extracted file, the directory outlives the exec
+            let program = program.as_deref().unwrap_or(&bundle.entry);
+            (temp_dir.keep().join(program), None)
+        }
+        None => {
+            // WASI runtimes go by the extension as well as the contents
+            let suffix = match header.content_type {
+                Some(ContentType::Wasm) => ".wasm",
+                Some(ContentType::Script | ContentType::Data) | None => "",
+            };
+            let extracted = Extracted {
+                content: &decompressed_data,
+                header: &header,
+                mode: temp_mode,
+                preserve_mtime,
+            };
+            // Only a payload that isn't packed itself, as the installed copy is
+            // run with the header of the packed file
+            let installed = install_path
+                .clone()
+                .filter(|_| sha256[..] == packed.sha256[..])
+                .filter(|path| match install(path, &extracted) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        warn!(
+                            "Can't install to {} ({}), running a fresh temp file instead",
+                            path.display(),
+                            err
+                        );
+                        false
+                    }
+                });
+            if let Some(path) = installed {
+                info!("Installed the program to {}", path.display());
+                (path, None)
+            } else if ephemeral {
+                // Left behind for the next run, or the next stub to run it
+                let path = cache_path(&sha256, suffix);
+                if holds_extracted(&path, &decompressed_data) {
+                    info!("Reusing {}, extracted by an earlier run", path.display());
+                    (path, None)
+                } else {
+                    debug!("Extracting to {}", path.display());
+                    let temp_file = tempfile::Builder::new()
+                        .suffix(suffix)
+                        .tempfile_in(env::temp_dir())?;
+                    match extracted.write_to(temp_file)?.persist(&path) {
+                        Ok(_) => (path, None),
+                        Err(err) => {
+                            warn!(
+                                "Can't extract to {} ({}), running a fresh temp file instead",
+                                path.display(),
+                                err.error
+                            );
+                            (err.file.path().to_path_buf(), Some(err.file))
+                        }
+                    }
+                }
+            } else {
+                // Create a temporary file to write the decompressed content
+                debug!("Creating temporary file for decompressed content");
+                let temp_file = create_temp(&current_exe, |builder, dir| {
+                    builder.clone().suffix(suffix).tempfile_in(dir)
+                })?;
+                let temp_file = extracted.write_to(temp_file)?;
+                (temp_file.path().to_path_buf(), Some(temp_file))
+            }
+        }
+    };
+    let temp_write_time = temp_start.elapsed();
     info!(
+        stage = "temp_write",
+        duration_ms = millis(temp_write_time);
         "Created executable temp file at {} in {:?}",
         temp_path.display(),
-        temp_start.elapsed()
+        temp_write_time
     );
+    let exec_prep_start = Instant::now();
 
-    // Collect command line arguments (excluding the program name)
-    let args: Vec<String> = env::args().skip(1).collect();
-    debug!("Command line arguments: {:?}", args);
+    let launch_overhead = start_time.elapsed();
+    if launch_overhead > SLOW_LAUNCH_THRESHOLD {
+        warn!(
+            "Reading, decompressing and writing the payload took {:?} (over {:?}), \
+             packing this binary is noticeably slowing down its startup",
+            launch_overhead, SLOW_LAUNCH_THRESHOLD
+        );
+    }
 
     // Clone data needed for the replacement thread
+    let packed_len = buffer.len() as u64;
     let current_exe_clone = current_exe.clone();
     let decompressed_data_clone = decompressed_data.clone();
+    let launcher = header.launcher.clone();
+    let is_bundle = header.bundle.is_some();
+    let has_env = !header.env.is_empty();
+    let strips_env = !header.strip_env.is_empty();
+    let has_entry_args = !header.entry_args.is_empty();
+    let needs_root = header.needs_root;
+    let argfiles = packed.header.argfiles;
+    let installing = install_path.is_some();
+    let verify_always = verify_policy.max(header.verify_policy) == VerifyPolicy::Always;
 
-    // Start replacement in parallel
+    // Start replacement in parallel. Payloads run through a launcher aren't
+    // executables themselves, bundles are whole directories and only the stub
+    // sets packed variables, strips variables, checks for root and passes
+    // baked-in arguments or expands argfiles, so for those the packed file has
+    // to stay in place.
     debug!("Starting parallel file replacement thread");
     let replacement_handle = thread::spawn(move || {
+        if !at_current_exe {
+            debug!("The packed file isn't at its original path anymore, leaving it alone");
+            return;
+        }
+        if !launcher.is_empty() {
+            debug!("Payload runs through a launcher, keeping the packed file");
+            return;
+        }
+        if is_bundle {
+            debug!("Payload is a bundled directory, keeping the packed file");
+            return;
+        }
+        if has_env {
+            debug!("Payload runs with packed variables, keeping the packed file");
+            return;
+        }
+        if strips_env {
+            debug!("Variables are stripped from the environment, keeping the packed file");
+            return;
+        }
+        if has_entry_args {
+            debug!("Payload runs with baked-in arguments, keeping the packed file");
+            return;
+        }
+        if needs_root {
+            debug!("Payload only runs as root, keeping the packed file");
+            return;
+        }
+        if argfiles {
+            debug!("Arguments are expanded from argfiles, keeping the packed file");
+            return;
+        }
+        if ephemeral {
+            debug!("DCMPRS_EPHEMERAL is set, keeping the packed file");
+            return;
+        }
+        if installing {
+            debug!("DCMPRS_INSTALL_DIR is set, keeping the packed file");
+            return;
+        }
+        if verify_always {
+            debug!("Payload is verified on every run, keeping the packed file");
+            return;
+        }
         let replace_start = Instant::now();
-        // Write decompressed content directly to original file
-        if let Ok(mut output_file) = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(&current_exe_clone)
-        {
-            let _ = output_file.write_all(&decompressed_data_clone);
-            let _ = output_file.sync_all();
-            debug!(
+        match replace_original(&current_exe_clone, packed_len, &decompressed_data_clone) {
+            Ok(()) => debug!(
                 "File replacement completed in {:?}",
                 replace_start.elapsed()
-            );
-        } else {
-            warn!("Failed to open original file for replacement");
+            ),
+            Err(err) => warn!("Failed to replace original file: {}", err),
         }
     });
 
@@ -133,42 +630,1476 @@ fn main() -> io::Result<()> {
         "Preparing to exec decompressed program: {}",
         temp_path.display()
     );
-    let mut cmd = Command::new(&temp_path);
-    cmd.args(&args);
-
-    // Preserve all environment variables
-    let env_count = env::vars().count();
-    debug!("Preserving {} environment variables", env_count);
-    for (key, value) in env::vars() {
-        cmd.env(key, value);
-    }
+    let program_args = program_args(&header, packed.header.argfiles, &args, dispatched);
+    let mut cmd = command(&header, &temp_path, program_args, &exec_wrapper);
 
     // Wait for replacement to complete before exec
     debug!("Waiting for file replacement to complete");
     let _ = replacement_handle.join();
 
     info!("Total dcmprs processing time: {:?}", start_time.elapsed());
+    if let Some(trace_path) = env::var_os("DCMPRS_TRACE_FILE") {
+        let trace = LaunchTrace {
+            read: read_time,
+            magic_scan: scan_time,
+            decompress: decompress_time,
+            temp_write: temp_write_time,
+            exec_prep: exec_prep_start.elapsed(),
+            total: start_time.elapsed(),
+            packed_size: packed_len,
+            compressed_size: compressed_data.len() as u64,
+            decompressed_size: decompressed_data.len() as u64,
+            algorithm: header.algorithm.name(),
+        };
+        trace.append_to(Path::new(&trace_path));
+    }
     info!("Executing decompressed program with exec()");
 
-    // Keep temp file alive until exec
-    let _temp_file_guard = temp_file;
+    // Keep temp file alive until exec, but close our writable handle to it:
+    // exec'ing a file that is open for writing fails with ETXTBSY
+    let temp_file_guard = temp_file.map(NamedTempFile::into_temp_path);
+
+    // exec leaves the temp file behind anyway, but with DCMPRS_KEEP_TEMP=1 it
+    // also survives a failed exec, and says where it is for post-mortem debugging
+    let _temp_file_guard = if env::var_os("DCMPRS_KEEP_TEMP").is_some_and(|value| value == "1") {
+        eprintln!(
+            "dcmprs: keeping the extracted program at {}",
+            temp_path.display()
+        );
+        match temp_file_guard.map(tempfile::TempPath::keep).transpose() {
+            Ok(_) => None,
+            Err(err) => {
+                warn!("Failed to keep {}: {}", err.path.display(), err.error);
+                Some(err.path)
+            }
+        }
+    } else {
+        temp_file_guard
+    };
 
     // Replace current process with the decompressed executable
     // This never returns if successful
-    let err = cmd.exec();
+    Err(exec_failed(cmd.exec(), &header, &exec_wrapper))
+}
+
+/// The arguments to run the program with: those baked in with --entry-args,
+/// which are the entry's alone rather than a subcommand's, then the ones we
+/// got, with `argfiles` expanded. Those go by the packed file's header, even
+/// when `header` is that of a payload packed itself.
+fn program_args(
+    header: &Header,
+    argfiles: bool,
+    args: &[String],
+    dispatched: usize,
+) -> Vec<OsString> {
+    let mut program_args = Vec::new();
+    if dispatched == 0 {
+        program_args.extend(header.entry_args.iter().cloned());
+    }
+    if argfiles {
+        program_args.extend(expand_argfiles(&args[dispatched..]));
+    } else {
+        program_args.extend(args[dispatched..].iter().map(OsString::from));
+    }
+    program_args
+}
+
+/// The command running the extracted `program` as the header says: through its
+/// launcher or interpreter, under DCMPRS_EXEC_WRAPPER, with the packed variables
+fn command(
+    header: &Header,
+    program: &Path,
+    args: Vec<OsString>,
+    exec_wrapper: &[String],
+) -> Command {
+    let mut cmd = match header.launcher.split_first() {
+        Some((launcher, launcher_args)) => {
+            info!("Running payload through launcher {:?}", header.launcher);
+            let mut cmd = Command::new(launcher);
+            cmd.args(launcher_args).arg(program);
+            cmd
+        }
+        // The kernel runs a script through its #! line, unless the temp dir
+        // is mounted noexec or DCMPRS_TEMP_MODE left out the exec bit
+        None if !header.interpreter.is_empty() && !is_executable(program) => {
+            info!(
+                "Can't exec the script, running it through {:?}",
+                header.interpreter
+            );
+            let mut cmd = Command::new(&header.interpreter[0]);
+            cmd.args(&header.interpreter[1..]).arg(program);
+            cmd
+        }
+        None => Command::new(program),
+    };
+    cmd.args(args);
+    let mut cmd = match exec_wrapper.split_first() {
+        Some((wrapper, wrapper_args)) => {
+            info!("Running the program under {:?}", exec_wrapper);
+            let mut wrapped = Command::new(wrapper);
+            wrapped
+                .args(wrapper_args)
+                .arg(cmd.get_program())
+                .args(cmd.get_args());
+            wrapped
+        }
+        None => cmd,
+    };
+
+    // Command passes our environment on by itself, only the variables packed
+    // with --env-file and restrictions need applying
+    for (name, value) in &header.env {
+        if env::var_os(name).is_none() {
+            cmd.env(name, value);
+        }
+    }
+    restrict_env(&mut cmd, &header.strip_env);
+    cmd
+}
 
-    // If we get here, exec failed
+/// Explain why exec failed where there's more to say than the error, exiting
+/// for those, and otherwise hand the error back
+fn exec_failed(err: io::Error, header: &Header, exec_wrapper: &[String]) -> io::Error {
     warn!("exec() failed: {}", err);
-    Err(err)
+    if let Some(wrapper) = exec_wrapper.first() {
+        eprintln!("dcmprs: failed to run the DCMPRS_EXEC_WRAPPER {wrapper}: {err}");
+        process::exit(1);
+    }
+    if let (Some(ContentType::Script), Some(interpreter), true) = (
+        header.content_type,
+        header.interpreter.first(),
+        header.launcher.is_empty(),
+    ) {
+        if err.kind() == io::ErrorKind::NotFound {
+            eprintln!(
+                "dcmprs: this is a script run by {}, which isn't installed",
+                interpreter.to_string_lossy()
+            );
+            process::exit(127);
+        }
+    }
+    if let (Some(ContentType::Wasm), Some(runtime)) = (header.content_type, header.launcher.first())
+    {
+        if err.kind() == io::ErrorKind::NotFound {
+            eprintln!(
+                "dcmprs: this is a WebAssembly module, install {} to run it",
+                runtime.to_string_lossy()
+            );
+            process::exit(127);
+        }
+    }
+    err
+}
+
+/// Set up DCMPRS_LOG_LEVEL logging, as JSON lines when DCMPRS_LOG_FORMAT=json:
+/// when, how severe, where from, the message, then any fields logged with it
+#[cfg(feature = "logging")]
+fn init_logging() {
+    use log::kv::{self, Key, Value, VisitSource};
+
+    struct Fields<'a>(&'a mut String);
+
+    impl<'kvs> VisitSource<'kvs> for Fields<'_> {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+            self.0.push_str(&format!(",{}:", json_string(key.as_str())));
+            // Numbers stay numbers, for aggregators to sum and chart
+            match (value.to_u64(), value.to_f64()) {
+                (Some(number), _) => self.0.push_str(&number.to_string()),
+                (None, Some(number)) if number.is_finite() => self.0.push_str(&number.to_string()),
+                _ => self.0.push_str(&json_string(&value.to_string())),
+            }
+            Ok(())
+        }
+    }
+
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::new().filter("DCMPRS_LOG_LEVEL"));
+    if env::var("DCMPRS_LOG_FORMAT").is_ok_and(|format| format == "json") {
+        builder.format(|out, record| {
+            let ts_ms = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            let mut line = format!(
+                "{{\"ts_ms\":{ts_ms},\"level\":\"{}\",\"target\":{},\"message\":{}",
+                record.level(),
+                json_string(record.target()),
+                json_string(&record.args().to_string())
+            );
+            let _ = record.key_values().visit(&mut Fields(&mut line));
+            line.push_str("}\n");
+            out.write_all(line.as_bytes())
+        });
+    }
+    builder.init();
+}
+
+/// `value` as a quoted JSON string
+#[cfg(feature = "logging")]
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if u32::from(c) < 0x20 => quoted.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// How long each stage of a launch took, written as a JSON line to
+/// DCMPRS_TRACE_FILE to collect launch latency from many machines
+struct LaunchTrace {
+    read: Duration,
+    magic_scan: Duration,
+    decompress: Duration,
+    temp_write: Duration,
+    exec_prep: Duration,
+    total: Duration,
+    packed_size: u64,
+    compressed_size: u64,
+    decompressed_size: u64,
+    algorithm: &'static str,
+}
+
+impl LaunchTrace {
+    /// Append the trace to `path` as one line, so concurrent launches don't
+    /// interleave. Failing to write it never stops the launch.
+    fn append_to(&self, path: &Path) {
+        let line = format!(
+            "{{\"read_us\":{},\"magic_scan_us\":{},\"decompress_us\":{},\"temp_write_us\":{},\
+             \"exec_prep_us\":{},\"total_us\":{},\"packed_size\":{},\"compressed_size\":{},\
+             \"decompressed_size\":{},\"algorithm\":\"{}\"}}\n",
+            self.read.as_micros(),
+            self.magic_scan.as_micros(),
+            self.decompress.as_micros(),
+            self.temp_write.as_micros(),
+            self.exec_prep.as_micros(),
+            self.total.as_micros(),
+            self.packed_size,
+            self.compressed_size,
+            self.decompressed_size,
+            self.algorithm,
+        );
+        let result = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        match result {
+            Ok(()) => debug!("Wrote the launch trace to {}", path.display()),
+            Err(err) => warn!(
+                "Failed to write the launch trace to {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+}
+
+/// Read the running binary, and whether it was found at `current_exe`.
+///
+/// `current_exe` is only the path the binary was started from, which holds
+/// something else once the binary has been replaced or deleted. On Linux,
+/// /proc/self/exe still refers to the running file in that case. With an
+/// explicit `payload_offset` there's no magic header to recognize it by, so
+/// the file at `current_exe` is taken as is.
+fn read_own_executable(current_exe: &Path, payload_offset: Option<usize>) -> (Vec<u8>, bool) {
+    let from_path = match fs::read(current_exe) {
+        Ok(buffer) if payload_offset.is_some() || find_magic_header(&buffer).is_some() => {
+            return (buffer, true)
+        }
+        result => result,
+    };
+    debug!(
+        "{} doesn't contain the packed payload",
+        current_exe.display()
+    );
+
+    #[cfg(target_os = "linux")]
+    match fs::read("/proc/self/exe") {
+        Ok(buffer) if find_magic_header(&buffer).is_some() => {
+            info!(
+                "{} no longer contains the packed payload, read it from /proc/self/exe",
+                current_exe.display()
+            );
+            return (buffer, false);
+        }
+        // The running binary is a stub without a payload
+        Ok(_) => exit_with(CmprsError::MagicNotFound),
+        Err(err) => debug!("Failed to read /proc/self/exe: {}", err),
+    }
+
+    let (reason, code) = match from_path {
+        Ok(_) => (
+            "no magic header found".to_string(),
+            CmprsError::MagicNotFound.exit_code(),
+        ),
+        Err(err) => {
+            let err = CmprsError::from(err);
+            (err.to_string(), err.exit_code())
+        }
+    };
+    eprintln!(
+        "dcmprs: running binary no longer contains packed payload ({}: {reason})",
+        current_exe.display()
+    );
+    process::exit(code);
+}
+
+/// The program inherits our environment, unless DCMPRS_CLEAR_ENV=1 starts
+/// it with an empty one or DCMPRS_KEEP_ENV=VAR1,VAR2 with only those variables
+/// (leaving out the ones set on `cmd` from the header too). Variables matching
+/// the `strip_env` patterns from the header, or the ones in
+/// DCMPRS_STRIP_ENV=LD_PRELOAD,DYLD_* instead, are removed either way.
+fn restrict_env(cmd: &mut Command, strip_env: &[String]) {
+    if let Some(keep) = env::var_os("DCMPRS_KEEP_ENV") {
+        let keep = keep.to_string_lossy().into_owned();
+        let names: Vec<&str> = keep
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect();
+        debug!("Only passing {:?} to the program", names);
+        cmd.env_clear();
+        for name in names {
+            if let Some(value) = env::var_os(name) {
+                cmd.env(name, value);
+            }
+        }
+    } else if env::var_os("DCMPRS_CLEAR_ENV").is_some_and(|value| value == "1") {
+        debug!("Running the program with an empty environment");
+        cmd.env_clear();
+    }
+
+    let strip_override = env::var("DCMPRS_STRIP_ENV").ok();
+    let patterns: Vec<&str> = match &strip_override {
+        Some(patterns) => patterns
+            .split(',')
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .collect(),
+        None => strip_env.iter().map(String::as_str).collect(),
+    };
+    for (name, _) in env::vars_os() {
+        let name_bytes = name.as_bytes();
+        if patterns
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), name_bytes))
+        {
+            debug!("Removing {:?} from the program's environment", name);
+            cmd.env_remove(name);
+        }
+    }
+}
+
+/// Whether `name` matches `pattern`, in which `*` stands for any run of bytes
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was and how much of the name it has taken up to now
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&byte) if byte == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            // Let the last `*` take one more byte and try again from there
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
+
+/// The program about to run, and how its file should look
+struct Extracted<'a> {
+    content: &'a [u8],
+    header: &'a Header,
+    mode: u32,
+    preserve_mtime: bool,
+}
+
+impl Extracted<'_> {
+    /// Write the program to `temp_file`, executable and with its recorded
+    /// extended attributes (and mtime, when asked for)
+    fn write_to(&self, mut temp_file: NamedTempFile) -> io::Result<NamedTempFile> {
+        temp_file.write_all(self.content)?;
+
+        // Make sure the temp file is executable
+        let metadata = temp_file.as_file().metadata()?;
+        let mut permissions = metadata.permissions();
+        permissions.set_mode(self.mode);
+        temp_file.as_file().set_permissions(permissions)?;
+
+        // Restore extended attributes captured with --preserve-xattr and --preserve-caps
+        restore_xattrs(temp_file.path(), self.header);
+
+        // Off by default, most programs expect their file to be as fresh as it is
+        if let Some(mtime) = self.header.mtime.filter(|_| self.preserve_mtime) {
+            debug!("Setting the temp file's mtime to the original's, {mtime}");
+            temp_file
+                .as_file()
+                .set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+        }
+        Ok(temp_file)
+    }
+}
+
+/// Set the extended attributes recorded in `header` on `path`. Failing to is
+/// only logged, except for file capabilities: setting them takes root or
+/// CAP_SETFCAP, and a program that needs them would fail without saying why.
+fn restore_xattrs(path: &Path, header: &Header) {
+    for (name, value) in &header.xattrs {
+        match xattr::set(path, name, value) {
+            Err(err) if name == CAPABILITY_XATTR => eprintln!(
+                "dcmprs: couldn't restore the program's file capabilities ({err}), it runs without \
+                 them; that needs root or CAP_SETFCAP"
+            ),
+            Err(err) => warn!("Failed to restore extended attribute {:?}: {}", name, err),
+            Ok(()) => {}
+        }
+    }
+}
+
+/// Where DCMPRS_EPHEMERAL extracts the program whose SHA256 is `sha256`. The
+/// hash is of the program itself, so a new stub, or the same program packed
+/// with another algorithm, level or dictionary, extracts to the same path.
+fn cache_path(sha256: &[u8], suffix: &str) -> PathBuf {
+    env::temp_dir().join(format!("dcmprs-{}{suffix}", to_hex(sha256)))
 }
 
-/// Look for our custom magic header
-/// The format is: [dcmprs executable][MAGIC_HEADER][32-byte SHA256][zstd compressed data]
-/// Search from the beginning to find the FIRST occurrence
-fn find_magic_header(buffer: &[u8]) -> Option<usize> {
-    (0..buffer.len().saturating_sub(MAGIC_HEADER.len())).find(|&i| {
-        &buffer[i..i + MAGIC_HEADER.len()] == MAGIC_HEADER
-            && &buffer[i + MAGIC_HEADER.len()..i + MAGIC_HEADER.len() + 3] == b";;;"
+/// Whether `path` holds `content`, extracted there by an earlier run as this
+/// user. Anything else, like a file another user planted at the predictable
+/// path in a shared temp dir, isn't run.
+fn holds_extracted(path: &Path, content: &[u8]) -> bool {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return false;
+    };
+    // SAFETY: geteuid has no preconditions and can't fail
+    let euid = unsafe { libc::geteuid() };
+    metadata.is_file()
+        && metadata.uid() == euid
+        && metadata.mode() & 0o022 == 0
+        && metadata.len() == content.len() as u64
+        && fs::read(path).is_ok_and(|existing| existing == content)
+}
+
+/// Where DCMPRS_INSTALL_DIR says to install programs: the directory it names,
+/// or with `1`, `cmprs` in `$XDG_DATA_HOME`, by default `~/.local/share/cmprs`
+fn install_dir() -> Option<PathBuf> {
+    let dir = env::var_os("DCMPRS_INSTALL_DIR").filter(|dir| !dir.is_empty())?;
+    if dir != "1" {
+        return Some(PathBuf::from(dir));
+    }
+    let data_home = env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")));
+    if data_home.is_none() {
+        warn!("DCMPRS_INSTALL_DIR=1 but neither XDG_DATA_HOME nor HOME is set, not installing");
+    }
+    Some(data_home?.join("cmprs"))
+}
+
+/// `<dir>/<sha256>/<name>`, named like the original program, so each version
+/// of it gets its own directory and nothing is ever evicted
+fn install_path(dir: &Path, sha256: &[u8], header: &Header, current_exe: &Path) -> PathBuf {
+    let mut name = header
+        .name
+        .clone()
+        .or_else(|| current_exe.file_name().map(OsStr::to_os_string))
+        .unwrap_or_else(|| "program".into());
+    // WASI runtimes go by the extension as well as the contents
+    if header.content_type == Some(ContentType::Wasm) && !name.as_bytes().ends_with(b".wasm") {
+        name.push(".wasm");
+    }
+    dir.join(to_hex(sha256)).join(name)
+}
+
+/// Whether `path` holds the program, installed there by an earlier run as
+/// this user. The installed copy is only hashed again when the payload is
+/// to be verified on every run; otherwise a file of ours, of the right size
+/// and that nobody else can write to, is taken as it is.
+fn is_installed(path: &Path, header: &Header, sha256: &[u8], policy: VerifyPolicy) -> bool {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return false;
+    };
+    // SAFETY: geteuid has no preconditions and can't fail
+    let euid = unsafe { libc::geteuid() };
+    let ours = metadata.is_file()
+        && metadata.uid() == euid
+        && metadata.mode() & 0o022 == 0
+        && header
+            .original_size
+            .is_none_or(|size| size == metadata.len());
+    if !ours {
+        warn!(
+            "{} isn't a program dcmprs installed, installing over it",
+            path.display()
+        );
+        return false;
+    }
+    let intact = policy.max(header.verify_policy) != VerifyPolicy::Always
+        || fs::read(path).is_ok_and(|content| Sha256::digest(content)[..] == *sha256);
+    if !intact {
+        warn!(
+            "{} doesn't match the payload's SHA256, installing it again",
+            path.display()
+        );
+    }
+    intact
+}
+
+/// Write the program to `path`, through a temp file next to it, so a run
+/// finding it there never finds it partially written
+fn install(path: &Path, extracted: &Extracted) -> io::Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)?;
+    let temp_file = tempfile::Builder::new()
+        .prefix(".dcmprs-")
+        .tempfile_in(dir)?;
+    extracted.write_to(temp_file)?.persist(path)?;
+    Ok(())
+}
+
+/// Create the file (or a bundle's directory) the program is extracted to,
+/// with `create` in the temp dir. With DCMPRS_EXTRACT_BESIDE=1 it's a dotfile
+/// next to the packed binary, for systems where the temp dir is mounted noexec.
+fn create_temp<T>(
+    current_exe: &Path,
+    create: impl Fn(&tempfile::Builder, &Path) -> io::Result<T>,
+) -> io::Result<T> {
+    if env::var_os("DCMPRS_EXTRACT_BESIDE").is_some_and(|value| value == "1") {
+        let dir = current_exe.parent().unwrap_or_else(|| Path::new("."));
+        match create(tempfile::Builder::new().prefix(".dcmprs-"), dir) {
+            Ok(temp) => return Ok(temp),
+            Err(err) => warn!(
+                "Can't extract next to {} ({}), using the temp dir instead",
+                current_exe.display(),
+                err
+            ),
+        }
+    }
+    create(&tempfile::Builder::new(), &env::temp_dir())
+}
+
+/// Where the magic header starts in our own file, from DCMPRS_PAYLOAD_OFFSET,
+/// for loaders that strip or move the magic and for debugging offset bugs
+fn payload_offset() -> Option<usize> {
+    let value = env::var_os("DCMPRS_PAYLOAD_OFFSET")?;
+    match value.to_str().and_then(|value| value.parse().ok()) {
+        Some(offset) => Some(offset),
+        None => exit_with(CmprsError::InvalidOptions(format!(
+            "DCMPRS_PAYLOAD_OFFSET must be a byte offset, got {value:?}"
+        ))),
+    }
+}
+
+/// The command to run the program under from DCMPRS_EXEC_WRAPPER (e.g.
+/// `gdb --args` or `strace -f`), split like a shell would but never run by one
+fn exec_wrapper() -> Vec<String> {
+    let Some(value) = env::var_os("DCMPRS_EXEC_WRAPPER") else {
+        return Vec::new();
+    };
+    match value.to_str().map(shell_words::split) {
+        Some(Ok(words)) => words,
+        _ => exit_with(CmprsError::InvalidOptions(format!(
+            "DCMPRS_EXEC_WRAPPER must be a command like `strace -f`, got {value:?}"
+        ))),
+    }
+}
+
+/// The mode of the extracted temp file from DCMPRS_TEMP_MODE (octal), or the default
+fn temp_mode() -> u32 {
+    let Some(value) = env::var_os("DCMPRS_TEMP_MODE") else {
+        return DEFAULT_TEMP_MODE;
+    };
+    match value
+        .to_str()
+        .map(|value| value.trim().trim_start_matches("0o"))
+        .and_then(|digits| u32::from_str_radix(digits, 8).ok())
+        .filter(|mode| *mode <= 0o7777)
+    {
+        Some(mode) => mode,
+        None => exit_with(CmprsError::InvalidOptions(format!(
+            "DCMPRS_TEMP_MODE must be an octal mode like 0700, got {value:?}"
+        ))),
+    }
+}
+
+/// When `data`, the payload described by `header`, is a packed binary itself,
+/// decompress its payload in turn, and so on, returning the innermost payload
+/// with its header and SHA256. Layers that need their own stub to run, with a
+/// split payload, a bundle, a root check or a newer --min-loader-version than
+/// ours, are left packed.
+fn unwrap_nested(
+    mut data: Vec<u8>,
+    header: &Header,
+    sha256: &[u8],
+    max_size: u64,
+) -> (Vec<u8>, Header, Vec<u8>) {
+    let mut header = header.clone();
+    let mut sha256 = sha256.to_vec();
+    let mut depth = 0;
+    while header.launcher.is_empty() && header.bundle.is_none() {
+        let Ok(inner) = Packed::parse(&data) else {
+            break;
+        };
+        if inner.header.split_payload
+            || inner.header.bundle.is_some()
+            || inner.header.needs_root
+            || inner.header.redundant
+            || too_old_for(&inner.header)
+        {
+            debug!("Payload is packed itself, but needs its own stub to run");
+            break;
+        }
+        if depth == MAX_NESTED_LAYERS {
+            warn!("Payload is packed over {MAX_NESTED_LAYERS} layers deep, running it as is");
+            break;
+        }
+        depth += 1;
+        debug!("Payload is packed itself, unwrapping layer {depth} in memory");
+        let inner_data = decompress(
+            &inner.header,
+            inner.payload,
+            inner.header.original_size,
+            max_size,
+        );
+        if inner_data.len() as u64 > max_size {
+            exit_with(CmprsError::TooLarge { limit: max_size });
+        }
+        if let Some(expected) = inner.header.original_size {
+            let actual = inner_data.len() as u64;
+            if actual != expected {
+                exit_with(CmprsError::SizeMismatch { expected, actual });
+            }
+        }
+        // The layer would have checked its own payload when run
+        if inner.header.verify_policy != VerifyPolicy::Never {
+            verify_payload(inner.sha256, &inner_data);
+        }
+        // The outer layer would have passed its arguments on to this one, and
+        // removed its variables from the environment this one sees
+        let mut inner_header = inner.header;
+        inner_header.entry_args.append(&mut header.entry_args);
+        inner_header.strip_env.append(&mut header.strip_env);
+        inner_header
+            .env
+            .retain(|(name, _)| !header.env.iter().any(|(outer, _)| outer == name));
+        inner_header.env.append(&mut header.env);
+        inner_header.verify_policy = inner_header.verify_policy.max(header.verify_policy);
+        header = inner_header;
+        sha256 = inner.sha256.to_vec();
+        data = inner_data;
+    }
+    if depth > 0 {
+        info!(
+            "Payload was packed {depth} more times, unwrapped it to {} bytes",
+            data.len()
+        );
+    }
+    (data, header, sha256)
+}
+
+/// Decompress `data` as described by `header`, or exit with a decode error.
+/// Stored data is used as-is.
+fn decompress(header: &Header, data: &[u8], expected_size: Option<u64>, max_size: u64) -> Vec<u8> {
+    try_decompress(header, data, expected_size, max_size)
+        .unwrap_or_else(|err| exit_with(CmprsError::Decode(err)))
+}
+
+/// Decompress the first copy of a payload stored twice with `cmprs
+/// --redundant`, or the second when the first one doesn't decompress to the
+/// recorded size and SHA256
+fn decompress_redundant(packed: &Packed, primary: &[u8], copy: &[u8], max_size: u64) -> Vec<u8> {
+    let intact = |data: &[u8]| -> Result<Vec<u8>, CmprsError> {
+        let decompressed =
+            try_decompress(&packed.header, data, packed.header.original_size, max_size)
+                .map_err(CmprsError::Decode)?;
+        if decompressed.len() as u64 > max_size {
+            return Err(CmprsError::TooLarge { limit: max_size });
+        }
+        if let Some(expected) = packed.header.original_size {
+            let actual = decompressed.len() as u64;
+            if actual != expected {
+                return Err(CmprsError::SizeMismatch { expected, actual });
+            }
+        }
+        let actual = to_hex(&Sha256::digest(&decompressed));
+        let expected = to_hex(packed.sha256);
+        if actual != expected {
+            return Err(CmprsError::HashMismatch { expected, actual });
+        }
+        Ok(decompressed)
+    };
+    intact(primary).unwrap_or_else(|err| {
+        eprintln!(
+            "dcmprs: the payload is corrupted ({err}), recovering it from its redundant copy"
+        );
+        intact(copy).unwrap_or_else(|err| exit_with(err))
+    })
+}
+
+/// Decompress `data` with the header's algorithm, stopping one byte past
+/// `max_size`, into a buffer sized for `expected_size` bytes, and undo the
+/// header's filter
+fn try_decompress(
+    header: &Header,
+    data: &[u8],
+    expected_size: Option<u64>,
+    max_size: u64,
+) -> io::Result<Vec<u8>> {
+    // Knowing the size up front saves growing the buffer as it fills: from the
+    // header, or from the zstd frame for files packed before it recorded one
+    let size_hint = expected_size.or_else(|| match header.algorithm {
+        Algorithm::Zstd => zstd::zstd_safe::get_frame_content_size(data).ok().flatten(),
+        _ => None,
+    });
+    let mut decompressed_data = if header.algorithm == Algorithm::None {
+        debug!("Payload is stored uncompressed, skipping the decoder");
+        data.to_vec()
+    } else {
+        let mut decompressed_data =
+            Vec::with_capacity(size_hint.unwrap_or(0).min(max_size) as usize);
+        // One byte past the limit tells an exactly-at-limit payload from an oversized one
+        decoder(header, data)?
+            .take(max_size.saturating_add(1))
+            .read_to_end(&mut decompressed_data)?;
+        decompressed_data
+    };
+    if let Some(filter) = header.filter {
+        let filter_start = Instant::now();
+        filter.decode(&mut decompressed_data);
+        debug!(
+            "Undid the {} filter in {:?}",
+            filter.name(),
+            filter_start.elapsed()
+        );
+    }
+    Ok(decompressed_data)
+}
+
+/// A reader of `data` decompressed with the header's algorithm
+fn decoder<'a>(header: &'a Header, data: &'a [u8]) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match header.algorithm {
+        Algorithm::Zstd => {
+            debug!("Starting zstd decompression");
+            let dictionary = header.dictionary.as_deref().unwrap_or_default();
+            Box::new(ZstdDecoder::with_dictionary(data, dictionary)?)
+        }
+        Algorithm::Xz => {
+            debug!("Starting xz decompression");
+            // The dictionary size comes from the stream header, with no memory
+            // limit. Bundles hold one stream per file.
+            Box::new(XzDecoder::new_multi_decoder(data))
+        }
+        Algorithm::Gzip => {
+            debug!("Starting gzip decompression");
+            // Bundles hold one gzip member per file
+            Box::new(MultiGzDecoder::new(data))
+        }
+        Algorithm::None => Box::new(data),
     })
 }
 
+/// Decompress the payload, `compressed_data`, without keeping it (unless it
+/// has a filter to undo), and check it against the recorded size and SHA256.
+/// Prints OK or FAIL and exits, with the exit code of what went wrong on a FAIL.
+fn check(packed: &Packed, compressed_data: &[u8], max_size: u64) -> ! {
+    let check_start = Instant::now();
+    let sha256 = to_hex(packed.sha256);
+    let intact = |data: &[u8]| -> Result<u64, CmprsError> {
+        let mut hasher = HashingWriter {
+            hasher: Sha256::new(),
+            written: 0,
+        };
+        // A filter is undone over the whole payload, which has to be in memory for
+        // it, so it gets the same DCMPRS_MAX_SIZE cap as a launch
+        let decoded = match packed.header.filter {
+            Some(_) => match try_decompress(&packed.header, data, None, max_size) {
+                Ok(decompressed) if decompressed.len() as u64 > max_size => {
+                    return Err(CmprsError::TooLarge { limit: max_size })
+                }
+                decompressed => {
+                    decompressed.and_then(|decompressed| hasher.write_all(&decompressed))
+                }
+            },
+            None => decoder(&packed.header, data)
+                .and_then(|mut decoder| io::copy(&mut decoder, &mut hasher))
+                .map(drop),
+        };
+        decoded.map_err(CmprsError::Decode)?;
+        if let Some(expected) = packed.header.original_size {
+            if hasher.written != expected {
+                return Err(CmprsError::SizeMismatch {
+                    expected,
+                    actual: hasher.written,
+                });
+            }
+        }
+        let actual = to_hex(&hasher.hasher.finalize());
+        if actual != sha256 {
+            return Err(CmprsError::HashMismatch {
+                expected: sha256.clone(),
+                actual,
+            });
+        }
+        Ok(hasher.written)
+    };
+
+    let (primary, redundant_copy) = packed.header.payload_copies(compressed_data);
+    let result = match (intact(primary), redundant_copy) {
+        (Err(err), Some(copy)) => {
+            eprintln!("dcmprs: the payload's first copy is corrupted ({err}), checking its redundant copy");
+            intact(copy)
+        }
+        (result, _) => result,
+    };
+    match result {
+        Ok(size) => {
+            println!(
+                "OK: {size} bytes from {} bytes of {}, sha256 {sha256}, in {:?}",
+                compressed_data.len(),
+                packed.header.algorithm.name(),
+                check_start.elapsed()
+            );
+            process::exit(0);
+        }
+        Err(err) => {
+            println!("FAIL: {err}");
+            process::exit(err.exit_code());
+        }
+    }
+}
+
+/// The text the self-test payloads decompress to: a line repeated over
+/// 16 KiB, changed every KiB so it isn't all one match
+fn selftest_sample() -> Vec<u8> {
+    let line = b"dcmprs self-test sample, decoded by the loader on this host\n";
+    (0..16384)
+        .map(|i| line[i % line.len()] ^ (i / 1024) as u8)
+        .collect()
+}
+
+/// Decompress the embedded sample with each algorithm, and undo each filter
+/// over it, checking every result against the sample and its SHA256
+fn selftest() -> ! {
+    let selftest_start = Instant::now();
+    let sample = selftest_sample();
+    let expected = Sha256::digest(&sample);
+
+    let mut cases = vec![(Algorithm::None, None, sample.clone())];
+    for &(algorithm, payload) in SELFTEST_PAYLOADS {
+        cases.push((algorithm, None, payload.to_vec()));
+    }
+    for filter in [Filter::X86, Filter::Arm64, Filter::Delta { distance: 4 }] {
+        let mut filtered = sample.clone();
+        filter.encode(&mut filtered);
+        cases.push((Algorithm::None, Some(filter), filtered));
+    }
+
+    let mut names = Vec::new();
+    for (algorithm, filter, payload) in cases {
+        let header = Header {
+            algorithm,
+            filter,
+            original_size: Some(sample.len() as u64),
+            ..Header::default()
+        };
+        let name = match (filter, algorithm) {
+            (Some(filter), _) => format!("{} filter", filter.name()),
+            (None, Algorithm::None) => "stored".to_string(),
+            (None, algorithm) => algorithm.name().to_string(),
+        };
+        let result = try_decompress(&header, &payload, header.original_size, u64::MAX)
+            .map_err(CmprsError::Decode)
+            .and_then(|decompressed| {
+                let actual = Sha256::digest(&decompressed);
+                if actual == expected {
+                    Ok(())
+                } else {
+                    Err(CmprsError::HashMismatch {
+                        expected: to_hex(&expected),
+                        actual: to_hex(&actual),
+                    })
+                }
+            });
+        if let Err(err) = result {
+            println!("FAIL: {name}: {err}");
+            process::exit(err.exit_code());
+        }
+        names.push(name);
+    }
+    println!(
+        "OK: a {} byte sample decoded intact ({}), in {:?}",
+        sample.len(),
+        names.join(", "),
+        selftest_start.elapsed()
+    );
+    process::exit(0);
+}
+
+/// Hashes what's written to it, and counts it
+struct HashingWriter {
+    hasher: Sha256,
+    written: u64,
+}
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.update(buf);
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The decompression cap from DCMPRS_MAX_SIZE (in bytes), or the default
+fn max_decompressed_size() -> u64 {
+    let Some(value) = env::var_os("DCMPRS_MAX_SIZE") else {
+        return DEFAULT_MAX_SIZE;
+    };
+    match value.to_str().and_then(|value| value.parse().ok()) {
+        Some(max_size) => max_size,
+        None => exit_with(CmprsError::InvalidOptions(format!(
+            "DCMPRS_MAX_SIZE must be a number of bytes, got {value:?}"
+        ))),
+    }
+}
+
+/// Check the payload against its stored SHA256, unless the marker next to the
+/// packed file records that a payload with this hash already passed. A replaced
+/// binary has another hash and is checked again.
+fn verify_once(current_exe: &Path, sha256: &[u8], payload: &[u8]) {
+    let expected = to_hex(sha256);
+    let marker_path = verified_marker_path(current_exe);
+    if fs::read_to_string(&marker_path).is_ok_and(|verified| verified == expected) {
+        info!("Payload {expected} was verified on an earlier run, skipping the SHA256 check");
+        return;
+    }
+
+    verify_payload(sha256, payload);
+    if let Err(err) = fs::write(&marker_path, &expected) {
+        warn!(
+            "Failed to write {}, the SHA256 will be checked again next run: {}",
+            marker_path.display(),
+            err
+        );
+    }
+}
+
+/// Check the payload against its stored SHA256, or exit with a hash mismatch
+fn verify_payload(sha256: &[u8], payload: &[u8]) {
+    let verify_start = Instant::now();
+    let expected = to_hex(sha256);
+    let actual = to_hex(&Sha256::digest(payload));
+    if actual != expected {
+        exit_with(CmprsError::HashMismatch { expected, actual });
+    }
+    info!(
+        "Verified the payload's SHA256 in {:?}",
+        verify_start.elapsed()
+    );
+}
+
+/// The stricter of `packed`, the policy the file was packed with, and the
+/// one DCMPRS_VERIFY_ONCE=1 or DCMPRS_VERIFY_ALWAYS=1 ask for. The
+/// environment can't weaken the packed policy, so DCMPRS_SKIP_VERIFY=1 only
+/// turns off what the environment asked for.
+fn verify_policy(packed: VerifyPolicy) -> VerifyPolicy {
+    let set = |name| env::var_os(name).is_some_and(|value| value == "1");
+    let requested = if set("DCMPRS_SKIP_VERIFY") {
+        VerifyPolicy::Never
+    } else if set("DCMPRS_VERIFY_ALWAYS") {
+        VerifyPolicy::Always
+    } else if set("DCMPRS_VERIFY_ONCE") {
+        VerifyPolicy::Once
+    } else {
+        VerifyPolicy::Never
+    };
+    if set("DCMPRS_SKIP_VERIFY") && packed != VerifyPolicy::Never {
+        eprintln!(
+            "dcmprs: ignoring DCMPRS_SKIP_VERIFY, this file was packed to verify its payload {}",
+            match packed {
+                VerifyPolicy::Once => "on its first run",
+                _ => "on every run",
+            }
+        );
+    }
+    packed.max(requested)
+}
+
+/// Exit unless `stub` is the one the header says the file was packed with.
+/// Everything before the magic is hashed, the header and its hash come after.
+fn verify_stub(header: &Header, stub: &[u8]) {
+    let start = Instant::now();
+    let matches = header
+        .stub_sha256
+        .is_some_and(|expected| Sha256::digest(stub)[..] == expected);
+    debug!(
+        "Checked the {} byte stub in {:?}",
+        stub.len(),
+        start.elapsed()
+    );
+    if !matches {
+        eprintln!(
+            "dcmprs: stub/payload mismatch, this loader isn't the one the file was packed \
+             with and may have been tampered with, refusing to run"
+        );
+        process::exit(5);
+    }
+}
+
+/// `.<name>.dcmprs-verified` next to the packed file
+fn verified_marker_path(current_exe: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(current_exe.file_name().unwrap_or_default());
+    name.push(".dcmprs-verified");
+    current_exe.with_file_name(name)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Whether `version` is a later release than `own` and may write fields we
+/// don't know: a later major version, or a later minor one before 1.0
+fn newer_release(version: &str, own: &str) -> bool {
+    let major_minor = |version: &str| -> Option<(u64, u64)> {
+        let mut parts = version.split('.');
+        Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+    };
+    match (major_minor(version), major_minor(own)) {
+        (Some((0, minor)), Some((0, own_minor))) => minor > own_minor,
+        (Some((major, _)), Some((own_major, _))) => major > own_major,
+        _ => false,
+    }
+}
+
+/// Refuse to go on when the payload was packed with --min-loader-version for a
+/// newer dcmprs than this one, instead of failing on whatever it relies on
+fn require_loader_version(header: &Header) {
+    let Some(min_version) = &header.min_loader_version else {
+        return;
+    };
+    let own = env!("CARGO_PKG_VERSION");
+    if too_old_for(header) {
+        eprintln!(
+            "dcmprs: this payload requires dcmprs >= {min_version}, but this loader is {own}; \
+             please restub"
+        );
+        process::exit(4);
+    }
+}
+
+/// Whether the payload was packed with --min-loader-version for a newer
+/// dcmprs than this one
+fn too_old_for(header: &Header) -> bool {
+    header
+        .min_loader_version
+        .as_ref()
+        .is_some_and(|min_version| {
+            cmprs_format::parse_version(min_version)
+                > cmprs_format::parse_version(env!("CARGO_PKG_VERSION"))
+        })
+}
+
+/// Whether `path` can be exec'd, which takes its exec bit and a file system
+/// that isn't mounted noexec
+fn is_executable(path: &Path) -> bool {
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: path is a valid NUL-terminated string that outlives the call
+    unsafe { libc::access(path.as_ptr(), libc::X_OK) == 0 }
+}
+
+fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and can't fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// `args` with each `@file` replaced by the lines of the file, one argument
+/// per line, like a compiler's response files. An `@file` that can't be read
+/// is passed on as it is.
+fn expand_argfiles(args: &[String]) -> Vec<OsString> {
+    let mut expanded = Vec::with_capacity(args.len());
+    for arg in args {
+        let contents = arg
+            .strip_prefix('@')
+            .filter(|path| !path.is_empty())
+            .and_then(|path| {
+                fs::read(path)
+                    .inspect_err(|err| debug!("Passing {arg} on as it is, can't read it: {err}"))
+                    .ok()
+            });
+        let Some(contents) = contents else {
+            expanded.push(OsString::from(arg));
+            continue;
+        };
+        debug!("Expanding {arg} into the arguments it holds");
+        let contents = contents.strip_suffix(b"\n").unwrap_or(&contents);
+        if contents.is_empty() {
+            continue;
+        }
+        expanded.extend(contents.split(|&byte| byte == b'\n').map(|line| {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            OsStr::from_bytes(line).to_os_string()
+        }));
+    }
+    expanded
+}
+
+/// Run the packed file again through DCMPRS_ESCALATE (e.g. `sudo`), or explain
+/// how to run it as root when that isn't set
+fn escalate(current_exe: &Path, args: &[String]) -> ! {
+    // Split like DCMPRS_EXEC_WRAPPER, so `sudo -u 'svc user'` keeps its quotes
+    let escalate = match env::var_os("DCMPRS_ESCALATE") {
+        None => Vec::new(),
+        Some(value) => match value.to_str().map(shell_words::split) {
+            Some(Ok(words)) => words,
+            _ => exit_with(CmprsError::InvalidOptions(format!(
+                "DCMPRS_ESCALATE must be a command like `sudo -E`, got {value:?}"
+            ))),
+        },
+    };
+    let mut escalate = escalate.into_iter();
+    let Some(program) = escalate.next() else {
+        eprintln!(
+            "dcmprs: {} needs to run as root. Run it with sudo, or set DCMPRS_ESCALATE=sudo to do that automatically",
+            current_exe.display()
+        );
+        process::exit(1);
+    };
+
+    info!("Not running as root, re-running through {program}");
+    let err = Command::new(&program)
+        .args(escalate)
+        .arg(current_exe)
+        .args(args)
+        .exec();
+    eprintln!("dcmprs: failed to run {program} to get root: {err}");
+    process::exit(1);
+}
+
+/// Print what the header of `packed` records about the original program, one
+/// `key: value` line each, for whoever got the packed file without cmprs.
+/// `stub` is what precedes the payload, checked against the recorded hash.
+fn print_info(packed: &Packed, stub: &[u8], current_exe: &Path) {
+    let header = &packed.header;
+    let mut info = vec![("file", current_exe.display().to_string())];
+    if let Some(name) = &header.name {
+        info.push(("name", name.to_string_lossy().into_owned()));
+    }
+    if let Some(source_path) = &header.source_path {
+        info.push(("packed from", source_path.to_string_lossy().into_owned()));
+    }
+    if let Some(size) = header.original_size {
+        info.push(("size", format!("{size} bytes")));
+    }
+    info.push(("sha256", to_hex(packed.sha256)));
+    if let Some(mode) = header.mode {
+        info.push(("mode", format!("{mode:o}")));
+    }
+    info.push(("algorithm", header.algorithm.name().to_string()));
+    if header.split_payload {
+        let data_path = split_payload_path(current_exe);
+        info.push(("payload", format!("in {}", data_path.display())));
+    } else {
+        let copies = if header.redundant {
+            ", stored twice"
+        } else {
+            ""
+        };
+        info.push(("payload", format!("{} bytes{copies}", packed.payload.len())));
+    }
+    if let Some(expected) = &header.stub_sha256 {
+        let stub_check = if Sha256::digest(stub)[..] == expected[..] {
+            "matches the one it was packed with"
+        } else {
+            "stub/payload mismatch, it isn't the one this file was packed with"
+        };
+        let enforced = if header.verify_stub {
+            ", checked before every run"
+        } else {
+            ""
+        };
+        info.push(("stub", format!("{stub_check}{enforced}")));
+    }
+    if let Some(bundle) = &header.bundle {
+        let running = if bundle.entry.is_empty() {
+            "its subcommands".into()
+        } else {
+            bundle.entry.to_string_lossy()
+        };
+        info.push((
+            "bundle",
+            format!("{} files, running {running}", bundle.files.len()),
+        ));
+        if !bundle.subcommands.is_empty() {
+            let subcommands: Vec<String> = bundle
+                .subcommands
+                .iter()
+                .map(|(name, path)| format!("{name} ({})", path.to_string_lossy()))
+                .collect();
+            info.push(("subcommands", subcommands.join(", ")));
+        }
+    }
+    if !header.launcher.is_empty() {
+        info.push(("launcher", format!("{:?}", header.launcher)));
+    }
+    if !header.entry_args.is_empty() {
+        info.push(("entry args", format!("{:?}", header.entry_args)));
+    }
+    if !header.env.is_empty() {
+        let names: Vec<_> = header
+            .env
+            .iter()
+            .map(|(name, _)| name.to_string_lossy())
+            .collect();
+        info.push(("env", names.join(", ")));
+    }
+    if let Some(content_type) = header.content_type {
+        info.push(("content type", content_type.name().to_string()));
+    }
+    if !header.interpreter.is_empty() {
+        let interpreter: Vec<_> = header
+            .interpreter
+            .iter()
+            .map(|arg| arg.to_string_lossy())
+            .collect();
+        info.push(("interpreter", interpreter.join(" ")));
+    }
+    if header.needs_root {
+        info.push(("needs root", "yes".to_string()));
+    }
+    if let Some(filter) = header.filter {
+        info.push(("filter", filter.name()));
+    }
+    if header.verify_policy != VerifyPolicy::Never {
+        info.push(("verify", header.verify_policy.name().to_string()));
+    }
+    info.push((
+        "packed by",
+        match &header.packer_version {
+            Some(version) => format!("cmprs {version} (format v{})", packed.version),
+            None => format!("cmprs (format v{})", packed.version),
+        },
+    ));
+    if let Some(min_version) = &header.min_loader_version {
+        info.push(("needs dcmprs", format!(">= {min_version}")));
+    }
+    if let Some(marker) = &header.marker {
+        info.push(("marker", marker.clone()));
+    }
+
+    let mut stdout = io::stdout().lock();
+    let result = info
+        .iter()
+        .try_for_each(|(key, value)| writeln!(stdout, "{key}: {value}"))
+        .and_then(|()| stdout.flush());
+    if let Err(err) = result {
+        eprintln!("dcmprs: failed to write to stdout: {err}");
+        process::exit(1);
+    }
+}
+
+/// Report an error that happened before the program could run, with the
+/// exit code documented on `CmprsError::exit_code`
+fn exit_with(err: CmprsError) -> ! {
+    eprintln!("dcmprs: {err}");
+    process::exit(err.exit_code());
+}
+
+/// Replace the payload of the packed file at `current_exe`, which starts with
+/// `stub`, with the one packed in `new_path`, once that decompresses to the
+/// SHA256 it records. The updated file is written next to the old one and
+/// renamed over it, so a failed update leaves the old one as it was.
+fn update(current_exe: &Path, stub: &[u8], header: &Header, new_path: &Path) -> ! {
+    let new_buffer = fs::read(new_path).unwrap_or_else(|err| {
+        exit_with(CmprsError::Io(io::Error::new(
+            err.kind(),
+            format!("failed to read {}: {err}", new_path.display()),
+        )))
+    });
+    let new = Packed::parse(&new_buffer).unwrap_or_else(|err| exit_with(err));
+    if header.split_payload || new.header.split_payload {
+        eprintln!("dcmprs: files packed with --split keep their payload apart and can't be updated in place");
+        process::exit(2);
+    }
+    if let Err(err) = new.header.check_payload_size(0, new.payload.len() as u64) {
+        exit_with(err);
+    }
+    // The new payload keeps this stub, so it has to be new enough for it
+    require_loader_version(&new.header);
+
+    // Only a payload that checks out replaces the one that works now
+    let (payload, _) = new.header.payload_copies(new.payload);
+    let max_size = max_decompressed_size();
+    let data = decompress(&new.header, payload, new.header.original_size, max_size);
+    if let Some(expected) = new.header.original_size {
+        let actual = data.len() as u64;
+        if actual != expected {
+            exit_with(CmprsError::SizeMismatch { expected, actual });
+        }
+    }
+    verify_payload(new.sha256, &data);
+
+    // The new payload runs with this stub, so the header records its hash
+    let new_header = Header {
+        stub_sha256: new.header.stub_sha256.map(|_| Sha256::digest(stub).into()),
+        ..new.header.clone()
+    };
+    let mut updated = stub.to_vec();
+    updated.reserve(new_buffer.len() - new.stub_len);
+    let result = cmprs_format::write_preamble(&mut updated).and_then(|_| {
+        updated.extend_from_slice(new.sha256);
+        updated.extend_from_slice(&new_header.to_bytes(new.sha256));
+        updated.extend_from_slice(new.payload);
+        replace_file(current_exe, &updated)
+    });
+    if let Err(err) = result {
+        eprintln!("dcmprs: failed to update {}: {err}", current_exe.display());
+        process::exit(CmprsError::from(err).exit_code());
+    }
+    eprintln!(
+        "dcmprs: updated {} to the payload of {} (sha256 {})",
+        current_exe.display(),
+        new_path.display(),
+        to_hex(new.sha256)
+    );
+    process::exit(0);
+}
+
+/// Atomically replace the file at `path` with `content`, keeping its permissions
+fn replace_file(path: &Path, content: &[u8]) -> io::Result<()> {
+    // Wait out a launch that is replacing the file with its program right now
+    let lock_file = File::open(path)?;
+    lock_file.lock_exclusive()?;
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut replacement = NamedTempFile::new_in(dir)?;
+    replacement.write_all(content)?;
+    replacement
+        .as_file()
+        .set_permissions(lock_file.metadata()?.permissions())?;
+    replacement.as_file().sync_all()?;
+    replacement.persist(path).map_err(|err| err.error)?;
+    Ok(())
+}
+
+/// Write the program to `output_path` for --dcmprs-extract. It's written to a
+/// temp file next to it and renamed into place once complete and synced, so
+/// the output is either what was there before or the whole program, never
+/// part of it, even to readers racing the extraction. Devices and pipes are
+/// written to directly, keeping their own mode, times and attributes, and a
+/// symlink's target is replaced rather than the link.
+fn extract_to(output_path: &Path, content: &[u8], header: &Header) -> io::Result<()> {
+    let output_path = fs::canonicalize(output_path).unwrap_or_else(|_| output_path.to_path_buf());
+    let (mut output, temp_path) = match fs::metadata(&output_path) {
+        Ok(metadata) if !metadata.is_file() => (File::create(&output_path)?, None),
+        _ => {
+            let dir = match output_path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            let (file, temp_path) = tempfile::Builder::new()
+                .prefix(".dcmprs-extract")
+                .tempfile_in(dir)?
+                .into_parts();
+            (file, Some(temp_path))
+        }
+    };
+
+    output.write_all(content)?;
+    // A device or pipe only gets the bytes, its mode and times are its own
+    let Some(temp_path) = temp_path else {
+        return Ok(());
+    };
+    output.set_permissions(fs::Permissions::from_mode(
+        header.mode.unwrap_or(DEFAULT_MODE),
+    ))?;
+    if let Some(mtime) = header.mtime {
+        output.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+    }
+    restore_xattrs(&temp_path, header);
+    output.sync_all()?;
+    temp_path.persist(&output_path).map_err(|err| err.error)?;
+    debug!(
+        "Renamed the extracted program into place at {}",
+        output_path.display()
+    );
+    Ok(())
+}
+
+fn throughput_mb_per_sec(bytes: usize, elapsed: Duration) -> f64 {
+    bytes as f64 / 1_000_000.0 / elapsed.as_secs_f64().max(f64::EPSILON)
+}
+
+/// Atomically replace the original executable with the decompressed content.
+/// Truncating the running executable in place fails with ETXTBSY on some systems
+/// and leaves a broken binary if we're killed mid-write, so the content is written
+/// to a sibling temp file and renamed over the original once it's fully on disk.
+///
+/// Concurrent launches serialize on an advisory lock on the packed file: only
+/// the first one rewrites it, the others skip since they'd write the same bytes.
+fn replace_original(current_exe: &Path, packed_len: u64, content: &[u8]) -> io::Result<()> {
+    let lock_file = File::open(current_exe)?;
+    match lock_file.try_lock_exclusive() {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+            debug!("Another launch is already replacing the original file, skipping");
+            return Ok(());
+        }
+        Err(err) => warn!(
+            "Failed to lock original file, replacing without a lock: {}",
+            err
+        ),
+    }
+
+    // A concurrent launch may have finished replacing the file before we opened it
+    if lock_file.metadata()?.len() != packed_len {
+        debug!("Original file was already replaced by another launch");
+        return Ok(());
+    }
+
+    let dir = current_exe.parent().unwrap_or_else(|| Path::new("."));
+    let permissions = lock_file.metadata()?.permissions();
+
+    let mut replacement = NamedTempFile::new_in(dir)?;
+    replacement.write_all(content)?;
+    replacement.as_file().set_permissions(permissions)?;
+    replacement.as_file().sync_all()?;
+
+    replacement.persist(current_exe).map_err(|err| err.error)?;
+
+    // The lock is released when lock_file is dropped, after the rename
+    Ok(())
+}
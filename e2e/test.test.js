@@ -2,8 +2,8 @@
 
 import { test, expect, beforeAll, afterAll } from "bun:test";
 import { spawn } from "bun";
-import { mkdtemp, rm, chmod, stat } from "fs/promises";
-import { join } from "path";
+import { mkdtemp, readdir, rename, rm, chmod, stat, symlink, link, utimes } from "fs/promises";
+import { basename, join } from "path";
 import { tmpdir, platform } from "os";
 
 const REPO_ROOT = join(import.meta.dir, "..");
@@ -141,6 +141,51 @@ test("Run decompressed binary (second time - direct execution)", async () => {
   console.log("   ✓ Output matches original");
 });
 
+test("Killing the first run never leaves a truncated binary behind", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.killed.cmprs`;
+  const originalSize = (await stat(binaryPath)).size;
+
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, binaryPath]);
+  const compressedSize = (await stat(compressedPath)).size;
+
+  // Kill the launch while the self-replacement may still be in flight
+  const proc = spawn([compressedPath], { stdio: ["inherit", "pipe", "pipe"] });
+  await Bun.sleep(5);
+  proc.kill("SIGKILL");
+  await proc.exited;
+
+  // The file is either still the packed binary or already fully replaced
+  const afterSize = (await stat(compressedPath)).size;
+  expect([compressedSize, originalSize]).toContain(afterSize);
+
+  const result = await runCommand(compressedPath);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("hello world");
+
+  console.log("   ✓ Interrupted self-replacement left a runnable binary");
+});
+
+test("Concurrent first launches don't corrupt the replaced binary", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.concurrent.cmprs`;
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, binaryPath]);
+
+  const results = await Promise.all(
+    Array.from({ length: 8 }, () => runCommand(compressedPath)),
+  );
+  for (const result of results) {
+    expect(result.exitCode).toBe(0);
+    expect(result.stdout).toBe("hello world");
+  }
+
+  const hash = async (path) =>
+    new Bun.CryptoHasher("sha256").update(await Bun.file(path).arrayBuffer()).digest("hex");
+  expect(await hash(compressedPath)).toBe(await hash(binaryPath));
+
+  console.log("   ✓ 8 concurrent launches left an intact binary");
+});
+
 test("Compressed binary handles arguments", async () => {
   // Create a binary that echoes its arguments
   const argScriptPath = join(tempDir, "args-script.js");
@@ -190,42 +235,3636 @@ test("Compression reduces file size", async () => {
   expect(compressedStat.size).toBeGreaterThan(0);
 });
 
-test.skipIf(platform() !== "darwin")("Build macOS universal binary with --build-universal-macos", async () => {
+test.skipIf(!Bun.which("zstd"))("Shell script format runs with system zstd", async () => {
   const binaryPath = await createTestBinary(tempDir);
-  const universalPath = `${binaryPath}.universal.cmprs`;
-  
-  // Compress the binary with --build-universal-macos flag
+  const scriptPath = `${binaryPath}.cmprs.sh`;
+
   const compressResult = await runCommand(CMPRS_BIN, [
-    "--build-universal-macos",
-    "--output", universalPath,
+    "--format", "shell",
     binaryPath,
   ]);
-  
   expect(compressResult.exitCode).toBe(0);
-  
-  // Check that universal binary file exists and is executable
-  const universalStat = await stat(universalPath);
-  expect(universalStat.mode & 0o111).toBeTruthy();
-  
-  console.log("   ✓ Universal binary created successfully");
-  console.log(`   ✓ Universal binary is executable`);
-  
-  // Test that the universal binary works
-  const result = await runCommand(universalPath);
+
+  const script = await Bun.file(scriptPath).text();
+  expect(script.startsWith("#!/bin/sh\n")).toBe(true);
+  expect(script).toContain("__CMPRS_PAYLOAD_BELOW__");
+
+  const result = await runCommand(scriptPath);
   expect(result.exitCode).toBe(0);
   expect(result.stdout).toBe("hello world");
-  
-  console.log("   ✓ Universal binary executed successfully");
-  console.log("   ✓ Output matches original");
-  
-  // Check if it's actually a universal binary using the `file` command
-  const fileResult = await runCommand("file", [universalPath]);
-  expect(fileResult.exitCode).toBe(0);
-  
-  // Universal binaries should contain multiple architectures
-  if (fileResult.stdout.includes("universal") || fileResult.stdout.includes("fat")) {
-    console.log("   ✓ Confirmed as universal/fat binary");
-  } else {
-    console.log(`   ℹ️  File type: ${fileResult.stdout}`);
+
+  console.log("   ✓ Shell script self-extractor executed successfully");
+});
+
+test.skipIf(!Bun.which("tar"))("--oci-layer writes a layer tar with the program at the given path", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const layerPath = `${binaryPath}.tar.gz`;
+
+  const pack = await runCommand(CMPRS_BIN, [
+    "--oci-layer", "path=/usr/local/bin/tool", "--algorithm", "gzip", "--json-stats", binaryPath,
+  ]);
+  expect(pack.exitCode).toBe(0);
+  expect(JSON.parse(pack.stdout).format).toBe("oci-layer");
+  expect(pack.stderr).toContain("application/vnd.oci.image.layer.v1.tar+gzip layer digest sha256:");
+
+  // Parent directories first, so runtimes can apply the layer in order
+  const listing = await runCommand("tar", ["-tvzf", layerPath]);
+  expect(listing.exitCode).toBe(0);
+  const entries = listing.stdout.split("\n");
+  expect(entries.map((line) => line.split(/\s+/).pop())).toEqual([
+    "usr/", "usr/local/", "usr/local/bin/", "usr/local/bin/tool",
+  ]);
+  expect(entries[3]).toMatch(/^-rwxr-xr-x root\/root /);
+
+  const extractedDir = await mkdtemp(join(tempDir, "oci-layer-"));
+  expect((await runCommand("tar", ["-xzf", layerPath, "-C", extractedDir])).exitCode).toBe(0);
+  const toolPath = join(extractedDir, "usr/local/bin/tool");
+  expect((await stat(toolPath)).mode & 0o111).toBe(0o111);
+  expect((await runCommand(toolPath)).stdout).toBe("hello world");
+
+  const xz = await runCommand(CMPRS_BIN, ["--oci-layer", "path=/usr/local/bin/tool", "--algorithm", "xz", binaryPath]);
+  expect(xz.exitCode).toBe(2);
+  const relative = await runCommand(CMPRS_BIN, ["--oci-layer", "path=bin/tool", binaryPath]);
+  expect(relative.exitCode).toBe(2);
+  // An mtime past ustar's eleven octal digits is refused, not a panic
+  const farFuture = await runCommand(CMPRS_BIN, ["--oci-layer", "path=/usr/local/bin/tool", binaryPath], {
+    env: { ...process.env, SOURCE_DATE_EPOCH: "99999999999" },
+  });
+  expect(farFuture.exitCode).toBe(2);
+  expect(farFuture.stderr).toContain("later than a ustar layer can record");
+
+  console.log("   ✓ Layer holds an executable usr/local/bin/tool");
+});
+
+test("Extract round-trips the original binary", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.cmprs`;
+  const extractedPath = `${binaryPath}.extracted`;
+
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, binaryPath]);
+
+  const extractResult = await runCommand(CMPRS_BIN, [
+    "extract", compressedPath,
+    "--output", extractedPath,
+  ]);
+  expect(extractResult.exitCode).toBe(0);
+
+  const original = new Uint8Array(await Bun.file(binaryPath).arrayBuffer());
+  const extracted = new Uint8Array(await Bun.file(extractedPath).arrayBuffer());
+  expect(extracted).toEqual(original);
+
+  const originalStat = await stat(binaryPath);
+  const extractedStat = await stat(extractedPath);
+  expect(extractedStat.mode & 0o777).toBe(originalStat.mode & 0o777);
+
+  console.log("   ✓ Extracted binary matches original byte-for-byte");
+});
+
+test.skipIf(platform() !== "linux" || !Bun.which("setfattr"))("Extended attributes survive pack and extract with --preserve-xattr", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.xattr.cmprs`;
+  const extractedPath = `${binaryPath}.xattr.extracted`;
+
+  const setResult = await runCommand("setfattr", ["-n", "user.cmprs.test", "-v", "hello", binaryPath]);
+  expect(setResult.exitCode).toBe(0);
+
+  await runCommand(CMPRS_BIN, ["--preserve-xattr", "--output", compressedPath, binaryPath]);
+  await runCommand(CMPRS_BIN, ["extract", compressedPath, "--output", extractedPath]);
+
+  const getResult = await runCommand("getfattr", ["--only-values", "-n", "user.cmprs.test", extractedPath]);
+  expect(getResult.exitCode).toBe(0);
+  expect(getResult.stdout).toBe("hello");
+
+  console.log("   ✓ Extended attribute restored on extraction");
+});
+
+test("Non-executable input only warns by default", async () => {
+  const dataPath = join(tempDir, "data.txt");
+  await Bun.write(dataPath, "not a program");
+  await chmod(dataPath, 0o644);
+
+  const result = await runCommand(CMPRS_BIN, ["--output", `${dataPath}.cmprs`, dataPath]);
+  expect(result.exitCode).toBe(0);
+
+  console.log("   ✓ Non-executable input packed with a warning");
+});
+
+test("--require-executable rejects non-executable input", async () => {
+  const dataPath = join(tempDir, "strict-data.txt");
+  await Bun.write(dataPath, "not a program");
+  await chmod(dataPath, 0o644);
+
+  const result = await runCommand(CMPRS_BIN, [
+    "--require-executable",
+    "--output", `${dataPath}.cmprs`,
+    dataPath,
+  ]);
+  expect(result.exitCode).not.toBe(0);
+  expect(result.stderr).toContain("is not executable");
+
+  console.log("   ✓ Non-executable input rejected in strict mode");
+});
+
+test("A data file packed without a launcher refuses to run with a clear error", async () => {
+  const dataPath = join(tempDir, "notes.txt");
+  await Bun.write(dataPath, "just some notes\n");
+  await chmod(dataPath, 0o755);
+  const packedPath = `${dataPath}.cmprs`;
+
+  const pack = await runCommand(CMPRS_BIN, ["--output", packedPath, dataPath]);
+  expect(pack.exitCode).toBe(0);
+  expect(pack.stderr).toContain("isn't an executable or a #! script");
+
+  const run = await runCommand(packedPath, []);
+  expect(run.exitCode).toBe(126);
+  expect(run.stderr).toContain("the packed file is not an executable and no launcher was configured");
+  expect(run.stderr).not.toContain("Exec format error");
+  expect((await runCommand(packedPath, ["--dcmprs-info"])).stdout).toContain("content type: data");
+  expect((await runCommand(packedPath, ["--dcmprs-cat"])).stdout).toBe("just some notes\n");
+
+  // With a launcher it opens fine
+  const launched = await runCommand(CMPRS_BIN, ["--launcher", "cat", "--output", `${dataPath}.cat.cmprs`, dataPath]);
+  expect(launched.stderr).not.toContain("isn't an executable");
+  expect((await runCommand(`${dataPath}.cat.cmprs`, [])).stdout).toBe("just some notes\n");
+
+  console.log("   ✓ Data payload refused before exec, with a hint about --launcher");
+});
+
+test("CMPRS_LEVEL sets the compression level when --level is absent", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+
+  const result = await runCommand(CMPRS_BIN, ["--output", `${binaryPath}.env-level.cmprs`, binaryPath], {
+    env: { ...process.env, CMPRS_LEVEL: "19", RUST_LOG: "debug" },
+  });
+  expect(result.exitCode).toBe(0);
+  expect(result.stderr).toContain("(level 19)");
+
+  const overridden = await runCommand(CMPRS_BIN, ["--level", "5", "--output", `${binaryPath}.env-level.cmprs`, binaryPath], {
+    env: { ...process.env, CMPRS_LEVEL: "19", RUST_LOG: "debug" },
+  });
+  expect(overridden.exitCode).toBe(0);
+  expect(overridden.stderr).toContain("(level 5)");
+
+  const invalid = await runCommand(CMPRS_BIN, ["--output", `${binaryPath}.env-level.cmprs`, binaryPath], {
+    env: { ...process.env, CMPRS_LEVEL: "99" },
+  });
+  expect(invalid.exitCode).not.toBe(0);
+
+  console.log("   ✓ CMPRS_LEVEL respected, --level takes precedence");
+});
+
+test("--filter bcj shrinks a native program's payload and restores it exactly", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const plain = await runCommand(CMPRS_BIN, ["--json-stats", "--output", `${binaryPath}.plain.cmprs`, binaryPath]);
+  const filteredPath = `${binaryPath}.bcj.cmprs`;
+  const filtered = await runCommand(CMPRS_BIN, ["--json-stats", "--filter", "bcj", "--output", filteredPath, binaryPath]);
+  expect(filtered.exitCode).toBe(0);
+  expect(JSON.parse(filtered.stdout).compressed_size).toBeLessThan(JSON.parse(plain.stdout).compressed_size);
+
+  expect((await runCommand(filteredPath, ["--dcmprs-info"])).stdout).toMatch(/filter: (x86|arm64)/);
+  const extractedPath = join(tempDir, "bcj-extracted");
+  expect((await runCommand(filteredPath, ["--dcmprs-extract", extractedPath])).exitCode).toBe(0);
+  const sha256 = async (path) => new Bun.CryptoHasher("sha256").update(await Bun.file(path).arrayBuffer()).digest("hex");
+  expect(await sha256(extractedPath)).toBe(await sha256(binaryPath));
+  const run = await runCommand(filteredPath);
+  expect(run.exitCode).toBe(0);
+
+  const script = join(tempDir, "not-native.sh");
+  await Bun.write(script, "#!/bin/sh\necho hi\n");
+  await chmod(script, 0o755);
+  const refused = await runCommand(CMPRS_BIN, ["--filter", "bcj", "--output", `${script}.cmprs`, script]);
+  expect(refused.exitCode).toBe(2);
+
+  console.log("   ✓ BCJ-filtered payload is smaller and round-trips");
+});
+
+test("--json-stats prints consistent machine-readable stats", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.stats.cmprs`;
+
+  const result = await runCommand(CMPRS_BIN, ["--json-stats", "--output", compressedPath, binaryPath]);
+  expect(result.exitCode).toBe(0);
+
+  const stats = JSON.parse(result.stdout);
+  const original = await Bun.file(binaryPath).arrayBuffer();
+  const expectedHash = new Bun.CryptoHasher("sha256").update(original).digest("hex");
+
+  expect(stats.input_size).toBe(original.byteLength);
+  expect(stats.output_size).toBe((await stat(compressedPath)).size);
+  expect(stats.sha256).toBe(expectedHash);
+  expect(stats.algorithm).toBe("zstd");
+  expect(stats.level).toBe(3);
+  expect(stats.stub_size).toBeGreaterThan(0);
+  expect(stats.stub_size + stats.compressed_size).toBeLessThan(stats.output_size);
+  expect(stats.ratio).toBeCloseTo(stats.compressed_size / stats.input_size, 5);
+  for (const key of ["read", "hash", "compress", "write", "total"]) {
+    expect(typeof stats.timings_ms[key]).toBe("number");
+  }
+
+  console.log("   ✓ JSON stats parsed and consistent");
+});
+
+test("--progress-json writes monotonic progress events ending at 100%", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const parse = (text) =>
+    text
+      .split("\n")
+      .filter((line) => line.startsWith("{"))
+      .map((line) => JSON.parse(line));
+  const check = (events, total) => {
+    expect(events.length).toBeGreaterThan(0);
+    let previous = 0;
+    for (const event of events) {
+      expect(event.stage).toBe("compress");
+      expect(event.bytes_total).toBe(total);
+      expect(event.bytes_done).toBeGreaterThanOrEqual(previous);
+      previous = event.bytes_done;
+    }
+    expect(events.at(-1).bytes_done).toBe(total);
+  };
+  const inputSize = (await stat(binaryPath)).size;
+
+  const onStderr = await runCommand(CMPRS_BIN, ["--progress-json", "--output", `${binaryPath}.progress.cmprs`, binaryPath]);
+  expect(onStderr.exitCode).toBe(0);
+  check(parse(onStderr.stderr), inputSize);
+
+  const eventsPath = join(tempDir, "progress.jsonl");
+  const toFile = await runCommand(CMPRS_BIN, [`--progress-json=${eventsPath}`, "--force", "--output", `${binaryPath}.progress.cmprs`, binaryPath]);
+  expect(toFile.exitCode).toBe(0);
+  check(parse(await Bun.file(eventsPath).text()), inputSize);
+  expect(toFile.stderr).not.toContain('"stage"');
+
+  console.log("   ✓ Progress events parsed and monotonic");
+});
+
+test("--suffix customizes the derived output name", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+
+  const result = await runCommand(CMPRS_BIN, ["--suffix", "packed", binaryPath]);
+  expect(result.exitCode).toBe(0);
+
+  const packedStat = await stat(`${binaryPath}.packed`);
+  expect(packedStat.mode & 0o111).toBeTruthy();
+
+  console.log("   ✓ Output written to <input>.packed");
+});
+
+test("Empty --suffix packs in place only with --force", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const originalSize = (await stat(binaryPath)).size;
+
+  const refused = await runCommand(CMPRS_BIN, ["--suffix", "", binaryPath]);
+  expect(refused.exitCode).not.toBe(0);
+  expect(refused.stderr).toContain("would overwrite the input");
+  expect((await stat(binaryPath)).size).toBe(originalSize);
+
+  const forced = await runCommand(CMPRS_BIN, ["--suffix", "", "--force", binaryPath]);
+  expect(forced.exitCode).toBe(0);
+  expect((await stat(binaryPath)).size).not.toBe(originalSize);
+
+  const result = await runCommand(binaryPath);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("hello world");
+
+  console.log("   ✓ In-place packing guarded by --force");
+});
+
+test("Packing through a symlink stores the resolved file name", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const linkPath = join(tempDir, "linked-binary");
+  await symlink(binaryPath, linkPath);
+
+  const packResult = await runCommand(CMPRS_BIN, [linkPath]);
+  expect(packResult.exitCode).toBe(0);
+
+  const extractResult = await runCommand(CMPRS_BIN, [
+    "extract", `${linkPath}.cmprs`,
+    "--output", `${linkPath}.extracted`,
+  ], { env: { ...process.env, RUST_LOG: "info" } });
+  expect(extractResult.exitCode).toBe(0);
+  expect(extractResult.stderr).toContain("Original file name: test-binary");
+
+  console.log("   ✓ Symlink followed and resolved name stored");
+});
+
+test("dcmprs logs decompression throughput", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.throughput.cmprs`;
+  await runCommand(CMPRS_BIN, ["--level", "19", "--output", compressedPath, binaryPath]);
+
+  const result = await runCommand(compressedPath, [], {
+    env: { ...process.env, DCMPRS_LOG_LEVEL: "info" },
+  });
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("hello world");
+  expect(result.stderr).toMatch(/Decompression throughput: \d+\.\d MB\/s/);
+
+  console.log("   ✓ Throughput reported for a large payload");
+});
+
+test("--minimize-stub prepends a smaller stub that still runs", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const regularPath = `${binaryPath}.regular-stub.cmprs`;
+  const minimalPath = `${binaryPath}.minimal-stub.cmprs`;
+
+  const regular = await runCommand(CMPRS_BIN, ["--json-stats", "--output", regularPath, binaryPath]);
+  const minimal = await runCommand(CMPRS_BIN, ["--minimize-stub", "--json-stats", "--output", minimalPath, binaryPath]);
+  expect(minimal.exitCode).toBe(0);
+
+  const regularStats = JSON.parse(regular.stdout);
+  const minimalStats = JSON.parse(minimal.stdout);
+  expect(minimalStats.stub_size).toBeLessThan(regularStats.stub_size * 0.75);
+
+  const result = await runCommand(minimalPath);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toContain("hello world");
+
+  console.log(`   ✓ Minimal stub is ${minimalStats.stub_size} bytes (regular: ${regularStats.stub_size})`);
+});
+
+test.skipIf(!Bun.which("python3"))("--launcher runs the payload through an interpreter", async () => {
+  const scriptPath = join(tempDir, "greet.py");
+  await Bun.write(scriptPath, 'import sys\nprint("hello from python", *sys.argv[1:])\n');
+  await chmod(scriptPath, 0o644);
+  const compressedPath = `${scriptPath}.cmprs`;
+
+  const pack = await runCommand(CMPRS_BIN, ["--launcher", "python3", "--output", compressedPath, scriptPath]);
+  expect(pack.exitCode).toBe(0);
+  expect((await stat(compressedPath)).mode & 0o111).not.toBe(0);
+
+  for (let i = 0; i < 2; i++) {
+    const result = await runCommand(compressedPath, ["--flag", "two words"]);
+    expect(result.exitCode).toBe(0);
+    expect(result.stdout).toBe("hello from python --flag two words");
+  }
+
+  console.log("   ✓ Python script ran through the launcher with arguments forwarded");
+});
+
+test("--level 0 and --store embed the payload uncompressed", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const original = await Bun.file(binaryPath).arrayBuffer();
+  const originalHash = new Bun.CryptoHasher("sha256").update(original).digest("hex");
+
+  for (const flags of [["--level", "0"], ["--store"]]) {
+    const compressedPath = `${binaryPath}.store${flags[0]}.cmprs`;
+    const pack = await runCommand(CMPRS_BIN, [...flags, "--json-stats", "--output", compressedPath, binaryPath]);
+    expect(pack.exitCode).toBe(0);
+
+    const stats = JSON.parse(pack.stdout);
+    expect(stats.algorithm).toBe("none");
+    expect(stats.compressed_size).toBe(original.byteLength);
+    expect(stats.sha256).toBe(originalHash);
+
+    const extractedPath = `${compressedPath}.extracted`;
+    const extract = await runCommand(CMPRS_BIN, ["extract", compressedPath, "--output", extractedPath]);
+    expect(extract.exitCode).toBe(0);
+    const extracted = await Bun.file(extractedPath).arrayBuffer();
+    expect(new Bun.CryptoHasher("sha256").update(extracted).digest("hex")).toBe(originalHash);
+
+    const result = await runCommand(compressedPath);
+    expect(result.exitCode).toBe(0);
+    expect(result.stdout).toBe("hello world");
+  }
+
+  console.log("   ✓ Stored payload round-trips with a matching SHA256");
+});
+
+test.skipIf(!Bun.which("cc") || !Bun.which("strip"))("--strip-input ships a stripped copy of the input", async () => {
+  const sourcePath = join(tempDir, "debug.c");
+  const binaryPath = join(tempDir, "debug-binary");
+  await Bun.write(sourcePath, '#include <stdio.h>\nint main(void) { puts("hello world"); return 0; }\n');
+  const build = await runCommand("cc", ["-g", "-o", binaryPath, sourcePath]);
+  expect(build.exitCode).toBe(0);
+  const originalSize = (await stat(binaryPath)).size;
+
+  const compressedPath = `${binaryPath}.cmprs`;
+  const pack = await runCommand(CMPRS_BIN, ["--strip-input", "--output", compressedPath, binaryPath]);
+  expect(pack.exitCode).toBe(0);
+  // The original is left untouched
+  expect((await stat(binaryPath)).size).toBe(originalSize);
+
+  const extractedPath = `${binaryPath}.extracted`;
+  const extract = await runCommand(CMPRS_BIN, ["extract", compressedPath, "--output", extractedPath]);
+  expect(extract.exitCode).toBe(0);
+  expect((await stat(extractedPath)).size).toBeLessThan(originalSize);
+
+  const result = await runCommand(extractedPath);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("hello world");
+
+  console.log(`   ✓ Payload stripped from ${originalSize} to ${(await stat(extractedPath)).size} bytes`);
+});
+
+test("--dcmprs-extract writes the original without running it", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.self-extract.cmprs`;
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, binaryPath]);
+
+  const extractedPath = join(tempDir, "self-extracted");
+  const result = await runCommand(compressedPath, ["--dcmprs-extract", extractedPath]);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("");
+
+  const original = await Bun.file(binaryPath).arrayBuffer();
+  const extracted = await Bun.file(extractedPath).arrayBuffer();
+  expect(Buffer.from(extracted).equals(Buffer.from(original))).toBe(true);
+  expect((await stat(extractedPath)).mode & 0o777).toBe((await stat(binaryPath)).mode & 0o777);
+
+  // With --no-stub-flags the argument reaches the program untouched
+  const passthroughPath = `${binaryPath}.no-stub-flags.cmprs`;
+  await runCommand(CMPRS_BIN, ["--no-stub-flags", "--output", passthroughPath, binaryPath]);
+  const passthrough = await runCommand(passthroughPath, ["--dcmprs-extract", join(tempDir, "not-written")]);
+  expect(passthrough.exitCode).toBe(0);
+  expect(passthrough.stdout).toBe("hello world");
+  expect(await Bun.file(join(tempDir, "not-written")).exists()).toBe(false);
+
+  console.log("   ✓ Packed binary extracted itself");
+});
+
+test("--dcmprs-update swaps in the payload of another packed file", async () => {
+  const dir = await mkdtemp(join(tempDir, "update-"));
+  const versions = {};
+  for (const version of ["v1", "v2"]) {
+    const scriptPath = join(dir, `${version}.sh`);
+    await Bun.write(scriptPath, `echo ${version} "$@"\n# ${"padding ".repeat(30)}\n`);
+    versions[version] = join(dir, `${version}.cmprs`);
+    // A launcher pack stays packed, so it can be updated after it has run
+    const pack = await runCommand(CMPRS_BIN, ["--launcher", "sh", "--output", versions[version], scriptPath]);
+    expect(pack.exitCode).toBe(0);
   }
-});
\ No newline at end of file
+  const toolPath = join(dir, "tool");
+  await Bun.write(toolPath, Bun.file(versions.v1));
+  await chmod(toolPath, 0o750);
+  expect((await runCommand(toolPath, ["before"])).stdout).toBe("v1 before");
+
+  const update = await runCommand(toolPath, ["--dcmprs-update", versions.v2]);
+  expect(update.exitCode).toBe(0);
+  expect(update.stderr).toContain("updated");
+  expect((await runCommand(toolPath, ["after"])).stdout).toBe("v2 after");
+  expect((await stat(toolPath)).mode & 0o777).toBe(0o750);
+  const info = await runCommand(toolPath, ["--dcmprs-info"]);
+  expect(info.stdout).toContain("stub: matches the one it was packed with");
+
+  // A payload that doesn't decompress to its SHA256 is refused, leaving the tool as it was
+  const corruptPath = join(dir, "corrupt.cmprs");
+  const corrupt = Buffer.from(await Bun.file(versions.v1).arrayBuffer());
+  corrupt[corrupt.length - 5] ^= 0xff;
+  await Bun.write(corruptPath, corrupt);
+  const refused = await runCommand(toolPath, ["--dcmprs-update", corruptPath]);
+  expect(refused.exitCode).not.toBe(0);
+  expect((await runCommand(toolPath, ["still"])).stdout).toBe("v2 still");
+
+  const missing = await runCommand(toolPath, ["--dcmprs-update"]);
+  expect(missing.exitCode).toBe(2);
+
+  console.log("   ✓ Updated from v1 to v2 in place");
+});
+
+test("Packer and stub agree on the magic header", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.magic.cmprs`;
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, binaryPath]);
+
+  const packed = Buffer.from(await Bun.file(compressedPath).arrayBuffer());
+  const magic = Buffer.from("DCMPRS_DATA_HERE");
+  // cmprs writes the magic right after the stub...
+  const boundary = packed.indexOf(Buffer.concat([magic, Buffer.from(";;")]));
+  expect(boundary).toBeGreaterThan(0);
+  // ...and the stub, built from the same cmprs-format crate, embeds the identical constant
+  expect(packed.subarray(0, boundary).indexOf(magic)).not.toBe(-1);
+
+  console.log("   ✓ Both halves use the same magic header");
+});
+
+test("Corrupted headers are rejected as malformed", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.corrupt-header.cmprs`;
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, binaryPath]);
+
+  const packed = Buffer.from(await Bun.file(compressedPath).arrayBuffer());
+  const boundary = packed.indexOf(Buffer.from("DCMPRS_DATA_HERE;;"));
+  // magic + separator + version + sha256 + length + checksum, then the first field
+  const firstField = boundary + 18 + 1 + 32 + 4 + 4;
+  packed[firstField + 5] ^= 0xff;
+  await Bun.write(compressedPath, packed);
+  await chmod(compressedPath, 0o755);
+
+  const run = await runCommand(compressedPath);
+  expect(run.exitCode).toBe(4);
+  expect(run.stderr).toContain("malformed cmprs header");
+
+  const extract = await runCommand(CMPRS_BIN, ["extract", compressedPath, "--output", `${compressedPath}.out`]);
+  expect(extract.exitCode).not.toBe(0);
+  expect(extract.stderr).toContain("malformed cmprs header");
+
+  console.log("   ✓ Header corruption reported as a malformed header");
+});
+
+test("Inputs larger than the available memory are refused", async () => {
+  // A sparse 1 TiB file: refused before a single byte is read
+  const hugePath = join(tempDir, "huge-input");
+  const create = await runCommand("truncate", ["-s", "1T", hugePath]);
+  expect(create.exitCode).toBe(0);
+  await chmod(hugePath, 0o755);
+
+  const result = await runCommand(CMPRS_BIN, ["--output", `${hugePath}.cmprs`, hugePath]);
+  expect(result.exitCode).not.toBe(0);
+  expect(result.stderr).toContain("--allow-large");
+  await rm(hugePath);
+
+  console.log("   ✓ Oversized input refused without --allow-large");
+});
+
+test.skipIf(platform() !== "linux")("Write failures exit nonzero without panicking", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+
+  // Every write to /dev/full fails with ENOSPC
+  const result = await runCommand(CMPRS_BIN, ["--output", "/dev/full", binaryPath]);
+  expect(result.exitCode).toBe(1);
+  expect(result.stderr).toContain("No space left on device");
+  expect(result.stderr).not.toContain("panicked");
+
+  console.log("   ✓ Disk-full write reported as a clean error");
+});
+
+// Rebuilds cmprs from scratch, so it only runs when asked for
+test.skipIf(!process.env.CMPRS_E2E_REBUILD)("A missing embedded stub is a clean error", async () => {
+  const emptyStub = join(tempDir, "empty-stub");
+  await Bun.write(emptyStub, "");
+  const targetDir = join(tempDir, "empty-stub-target");
+
+  const build = await runCommand("cargo", ["build", "--release", "--target-dir", targetDir], {
+    cwd: CMPRS_ROOT,
+    env: { ...process.env, CMPRS_STUB_PATH: emptyStub },
+  });
+  expect(build.exitCode).toBe(0);
+
+  const binaryPath = await createTestBinary(tempDir);
+  const result = await runCommand(join(targetDir, "release/cmprs"), ["--output", `${binaryPath}.no-stub.cmprs`, binaryPath]);
+  expect(result.exitCode).toBe(1);
+  expect(result.stderr).toContain("embedded dcmprs stub missing; rebuild cmprs");
+  expect(result.stderr).not.toContain("panicked");
+
+  console.log("   ✓ Missing stub reported without a panic");
+}, 600_000);
+
+// Rebuilds cmprs from scratch too, with only zstd
+test.skipIf(!process.env.CMPRS_E2E_REBUILD)("Algorithms left out at build time fail clearly", async () => {
+  const targetDir = join(tempDir, "zstd-only-target");
+  const build = await runCommand("cargo", [
+    "build", "--release", "--no-default-features", "--features", "stub", "--target-dir", targetDir,
+  ], { cwd: CMPRS_ROOT });
+  expect(build.exitCode).toBe(0);
+  const zstdOnly = join(targetDir, "release/cmprs");
+
+  const algorithms = await runCommand(zstdOnly, ["algorithms", "--json"]);
+  expect(JSON.parse(algorithms.stdout).map((algorithm) => algorithm.name)).toEqual(["zstd", "none"]);
+
+  const binaryPath = await createTestBinary(tempDir);
+  const gzip = await runCommand(zstdOnly, ["--algorithm", "gzip", "--output", `${binaryPath}.gz.cmprs`, binaryPath]);
+  expect(gzip.exitCode).toBe(4);
+  expect(gzip.stderr).toContain("gzip support isn't compiled into this build");
+  const xz = await runCommand(zstdOnly, ["--xz-preset", "6", "--output", `${binaryPath}.xz.cmprs`, binaryPath]);
+  expect(xz.exitCode).toBe(4);
+  expect(xz.stderr).toContain("xz support isn't compiled into this build");
+
+  // A file another build packed with xz is refused rather than misread
+  const xzPath = `${binaryPath}.full-xz.cmprs`;
+  expect((await runCommand(CMPRS_BIN, ["--xz-preset", "6", "--output", xzPath, binaryPath])).exitCode).toBe(0);
+  const extract = await runCommand(zstdOnly, ["extract", xzPath, "--output", `${binaryPath}.extracted`]);
+  expect(extract.exitCode).toBe(4);
+  expect(extract.stderr).toContain("xz support isn't compiled into this build");
+
+  const zstd = await runCommand(zstdOnly, ["--output", `${binaryPath}.zstd.cmprs`, binaryPath]);
+  expect(zstd.exitCode).toBe(0);
+  expect((await runCommand(`${binaryPath}.zstd.cmprs`)).stdout).toBe("hello world");
+
+  console.log("   ✓ A zstd-only build named the missing features");
+}, 600_000);
+
+test.skipIf(platform() !== "darwin")("--codesign produces a verifiable signature", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.signed.cmprs`;
+  const identity = process.env.CMPRS_CODESIGN_IDENTITY || "-";
+
+  const pack = await runCommand(CMPRS_BIN, ["--codesign", identity, "--output", compressedPath, binaryPath]);
+  expect(pack.exitCode).toBe(0);
+
+  const verify = await runCommand("codesign", ["--verify", "--verbose", compressedPath]);
+  expect(verify.exitCode).toBe(0);
+
+  const result = await runCommand(compressedPath);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("hello world");
+
+  console.log(`   ✓ Output signed as ${identity} and verified`);
+});
+
+test("Packing the same input twice is byte-for-byte identical", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const firstPath = `${binaryPath}.first.cmprs`;
+  const secondPath = `${binaryPath}.second.cmprs`;
+
+  expect((await runCommand(CMPRS_BIN, ["--output", firstPath, binaryPath])).exitCode).toBe(0);
+  expect((await runCommand(CMPRS_BIN, ["--output", secondPath, binaryPath])).exitCode).toBe(0);
+  const first = Buffer.from(await Bun.file(firstPath).arrayBuffer());
+  const second = Buffer.from(await Bun.file(secondPath).arrayBuffer());
+  expect(first.equals(second)).toBe(true);
+
+  const check = await runCommand(CMPRS_BIN, ["--check-reproducible", "--output", `${binaryPath}.checked.cmprs`, binaryPath]);
+  expect(check.exitCode).toBe(0);
+
+  console.log("   ✓ Output is reproducible");
+});
+
+test("extract streams into the output and removes it when the payload is corrupt", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.corrupt-payload.cmprs`;
+  await runCommand(CMPRS_BIN, ["--store", "--output", compressedPath, binaryPath]);
+
+  const packed = Buffer.from(await Bun.file(compressedPath).arrayBuffer());
+  packed[packed.length - 1] ^= 0xff;
+  await Bun.write(compressedPath, packed);
+
+  const extractedPath = `${compressedPath}.out`;
+  const result = await runCommand(CMPRS_BIN, ["extract", compressedPath, "--output", extractedPath]);
+  expect(result.exitCode).toBe(5);
+  expect(result.stderr).toContain("SHA256 mismatch");
+  expect(await Bun.file(extractedPath).exists()).toBe(false);
+
+  console.log("   ✓ Corrupt payload rejected without leaving a file behind");
+});
+
+test("extract and repack can write over their own input", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const original = await Bun.file(binaryPath).bytes();
+  const pack = async (name) => {
+    const path = join(tempDir, name);
+    expect((await runCommand(CMPRS_BIN, ["--output", path, binaryPath])).exitCode).toBe(0);
+    return path;
+  };
+
+  // The input is mapped while it's read, so writing straight over it would
+  // truncate it under cmprs
+  const extractPath = await pack("extract-over-input.cmprs");
+  const extract = await runCommand(CMPRS_BIN, ["extract", extractPath, "--output", extractPath]);
+  expect(extract.exitCode).toBe(0);
+  expect(await Bun.file(extractPath).bytes()).toEqual(original);
+
+  const recompressPath = await pack("recompress-over-input.cmprs");
+  const recompress = await runCommand(CMPRS_BIN, [
+    "repack", "--recompress-only", "--level", "19", recompressPath, "--output", recompressPath,
+  ]);
+  expect(recompress.exitCode).toBe(0);
+  expect((await runCommand(CMPRS_BIN, ["extract", recompressPath, "--output", `${recompressPath}.out`])).exitCode).toBe(0);
+  expect(await Bun.file(`${recompressPath}.out`).bytes()).toEqual(original);
+
+  // A hard link is the same file under another name, which gets the output
+  const linkedPath = await pack("repack-linked.cmprs");
+  const hardLink = join(tempDir, "repack-hard-link.cmprs");
+  await link(linkedPath, hardLink);
+  const before = await Bun.file(linkedPath).bytes();
+  const repack = await runCommand(CMPRS_BIN, ["repack", "--level", "1", linkedPath, "--output", hardLink]);
+  expect(repack.exitCode).toBe(0);
+  expect(await Bun.file(linkedPath).bytes()).toEqual(before);
+  expect((await runCommand(CMPRS_BIN, ["extract", hardLink, "--output", `${hardLink}.out`])).exitCode).toBe(0);
+  expect(await Bun.file(`${hardLink}.out`).bytes()).toEqual(original);
+
+  console.log("   ✓ Outputs written over the input without truncating it first");
+});
+
+test("Each kind of failure has its own error and exit code", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.errors.cmprs`;
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, binaryPath]);
+  const packed = Buffer.from(await Bun.file(compressedPath).arrayBuffer());
+  const versionPos = packed.indexOf(Buffer.from("DCMPRS_DATA_HERE;;")) + 18;
+  const headerPos = versionPos + 1 + 32;
+
+  const extract = async (name, bytes) => {
+    const path = join(tempDir, name);
+    await Bun.write(path, bytes);
+    return runCommand(CMPRS_BIN, ["extract", path, "--output", `${path}.out`]);
+  };
+
+  // MagicNotFound
+  const notPacked = await extract("not-packed", "just some bytes");
+  expect(notPacked.exitCode).toBe(3);
+  expect(notPacked.stderr).toContain("no magic header found");
+
+  // UnsupportedVersion
+  const newer = Buffer.from(packed);
+  newer[versionPos] = 99;
+  const version = await extract("newer-version", newer);
+  expect(version.exitCode).toBe(4);
+  expect(version.stderr).toContain("unsupported format version 99");
+
+  // UnsupportedAlgorithm: rewrite the algorithm field and fix up the checksum
+  const unknownAlgorithm = Buffer.from(packed);
+  const fieldsLen = unknownAlgorithm.readUInt32LE(headerPos);
+  const fieldsStart = headerPos + 8;
+  for (let pos = fieldsStart; pos < fieldsStart + fieldsLen; ) {
+    const valueLen = unknownAlgorithm.readUInt32LE(pos + 1);
+    if (unknownAlgorithm[pos] === 5) unknownAlgorithm[pos + 5] = 42;
+    pos += 5 + valueLen;
+  }
+  const checksummed = Buffer.concat([
+    unknownAlgorithm.subarray(versionPos + 1, headerPos + 4),
+    unknownAlgorithm.subarray(fieldsStart, fieldsStart + fieldsLen),
+  ]);
+  unknownAlgorithm.writeUInt32LE(Bun.hash.crc32(checksummed), headerPos + 4);
+  const algorithm = await extract("unknown-algorithm", unknownAlgorithm);
+  expect(algorithm.exitCode).toBe(4);
+  expect(algorithm.stderr).toContain("unsupported compression algorithm id 42");
+
+  // SizeMismatch: record one byte more than the payload holds, in both extract and the stub
+  const wrongSize = Buffer.from(packed);
+  for (let pos = fieldsStart; pos < fieldsStart + fieldsLen; ) {
+    const valueLen = wrongSize.readUInt32LE(pos + 1);
+    if (wrongSize[pos] === 7) wrongSize.writeBigUInt64LE(wrongSize.readBigUInt64LE(pos + 5) + 1n, pos + 5);
+    pos += 5 + valueLen;
+  }
+  const sizeChecksummed = Buffer.concat([
+    wrongSize.subarray(versionPos + 1, headerPos + 4),
+    wrongSize.subarray(fieldsStart, fieldsStart + fieldsLen),
+  ]);
+  wrongSize.writeUInt32LE(Bun.hash.crc32(sizeChecksummed), headerPos + 4);
+  const size = await extract("wrong-size", wrongSize);
+  expect(size.exitCode).toBe(5);
+  expect(size.stderr).toContain("decompressed size mismatch: expected");
+  const wrongSizePath = join(tempDir, "wrong-size");
+  await chmod(wrongSizePath, 0o755);
+  const sizeRun = await runCommand(wrongSizePath);
+  expect(sizeRun.exitCode).toBe(5);
+  expect(sizeRun.stderr).toContain("decompressed size mismatch");
+
+  // Malformed
+  const mangled = Buffer.from(packed);
+  mangled[fieldsStart] ^= 0xff;
+  const malformed = await extract("malformed", mangled);
+  expect(malformed.exitCode).toBe(4);
+  expect(malformed.stderr).toContain("malformed cmprs header");
+
+  // Decode, in both extract and the stub
+  const truncated = await extract("truncated", packed.subarray(0, packed.length - 16));
+  expect(truncated.exitCode).toBe(6);
+  expect(truncated.stderr).toContain("failed to decompress payload");
+  expect(truncated.stderr).toContain("may be corrupted or built with an incompatible cmprs version");
+  const truncatedPath = join(tempDir, "truncated");
+  await chmod(truncatedPath, 0o755);
+  const truncatedRun = await runCommand(truncatedPath);
+  expect(truncatedRun.exitCode).toBe(6);
+  expect(truncatedRun.stderr).toContain("dcmprs: failed to decompress payload");
+
+  // InvalidOptions
+  const options = await runCommand(CMPRS_BIN, ["--level", "30", binaryPath]);
+  expect(options.exitCode).toBe(2);
+
+  console.log("   ✓ Errors mapped to distinct exit codes");
+});
+
+test("DCMPRS_EXTRACT_BESIDE extracts next to the packed binary", async () => {
+  const dir = await mkdtemp(join(tempDir, "beside-"));
+  const scriptPath = join(dir, "where");
+  await Bun.write(scriptPath, '#!/bin/sh\necho "$0"\n');
+  await chmod(scriptPath, 0o755);
+  const compressedPath = join(dir, "where.cmprs");
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, scriptPath]);
+
+  const result = await runCommand(compressedPath, [], {
+    env: { ...process.env, DCMPRS_EXTRACT_BESIDE: "1" },
+  });
+  expect(result.exitCode).toBe(0);
+  const extractedTo = result.stdout;
+  expect(extractedTo.startsWith(join(dir, ".dcmprs-"))).toBe(true);
+
+  console.log(`   ✓ Extracted to ${extractedTo}`);
+});
+
+test.skipIf(platform() !== "linux" || !Bun.which("python3"))("A packed binary replaced on disk still runs through /proc/self/exe", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.replaced.cmprs`;
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, binaryPath]);
+
+  // Keep the packed file open, rename something else over its path, and run
+  // it through the open descriptor so current_exe points at a deleted file
+  const script = `
+import os, sys
+fd = os.open(sys.argv[1], os.O_RDONLY)
+with open(sys.argv[1] + ".new", "w") as f:
+    f.write("not packed")
+os.rename(sys.argv[1] + ".new", sys.argv[1])
+os.execv(f"/proc/self/fd/{fd}", [sys.argv[1]])
+`;
+  const result = await runCommand("python3", ["-c", script, compressedPath]);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("hello world");
+  // The file now at that path isn't ours to replace
+  expect(await Bun.file(compressedPath).text()).toBe("not packed");
+
+  console.log("   ✓ Payload read from the running binary");
+});
+
+test("DCMPRS_CLEAR_ENV and DCMPRS_KEEP_ENV control the program's environment", async () => {
+  const scriptPath = join(tempDir, "print-env");
+  await Bun.write(scriptPath, '#!/bin/sh\necho "FOO=$FOO BAR=$BAR"\n');
+  await chmod(scriptPath, 0o755);
+  const compressedPath = `${scriptPath}.cmprs`;
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, scriptPath]);
+
+  const run = async (extraEnv) => {
+    const result = await runCommand(compressedPath, [], {
+      env: { ...process.env, FOO: "foo", BAR: "bar", ...extraEnv },
+    });
+    expect(result.exitCode).toBe(0);
+    return result.stdout;
+  };
+
+  // The first launch replaces the packed file, so pack again before each run
+  expect(await run({})).toBe("FOO=foo BAR=bar");
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, scriptPath]);
+  expect(await run({ DCMPRS_CLEAR_ENV: "1" })).toBe("FOO= BAR=");
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, scriptPath]);
+  expect(await run({ DCMPRS_KEEP_ENV: "FOO" })).toBe("FOO=foo BAR=");
+
+  console.log("   ✓ Inherited, cleared and allow-listed environments");
+});
+
+test("The program inherits the full environment", async () => {
+  const scriptPath = join(tempDir, "count-env");
+  await Bun.write(scriptPath, "#!/bin/sh\nenv | grep -c '^CMPRS_E2E_VAR_'\necho \"$CMPRS_E2E_VAR_299\"\n");
+  await chmod(scriptPath, 0o755);
+  const compressedPath = `${scriptPath}.cmprs`;
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, scriptPath]);
+
+  const env = { ...process.env };
+  for (let i = 0; i < 300; i++) env[`CMPRS_E2E_VAR_${i}`] = `value ${i}`;
+  const result = await runCommand(compressedPath, [], { env });
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("300\nvalue 299");
+
+  console.log("   ✓ All 300 variables reached the program");
+});
+
+test("repack recompresses a packed file at a higher level", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const level3Path = `${binaryPath}.level3.cmprs`;
+  const level19Path = `${binaryPath}.level19.cmprs`;
+  await runCommand(CMPRS_BIN, ["--level", "3", "--output", level3Path, binaryPath]);
+
+  const result = await runCommand(CMPRS_BIN, ["repack", level3Path, "--level", "19", "--output", level19Path]);
+  expect(result.exitCode).toBe(0);
+  expect((await stat(level19Path)).size).toBeLessThan((await stat(level3Path)).size);
+  expect((await stat(level19Path)).mode & 0o777).toBe((await stat(binaryPath)).mode & 0o777);
+
+  const run = await runCommand(level19Path);
+  expect(run.exitCode).toBe(0);
+  expect(run.stdout).toBe("hello world");
+
+  console.log("   ✓ Repacked at level 19 without the original file");
+});
+
+test("repack --recompress-only changes nothing but the payload", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const envPath = `${binaryPath}.env`;
+  await Bun.write(envPath, "GREETING=hi\n");
+  const packedPath = `${binaryPath}.recompress.cmprs`;
+  const pack = await runCommand(CMPRS_BIN, [
+    "--level", "3", "--marker", "recompress test", "--env-file", envPath, "--strip-env", "LD_PRELOAD",
+    "--entry-args", "--quiet", "--json-stats", "--output", packedPath, binaryPath,
+  ]);
+  expect(pack.exitCode).toBe(0);
+  await chmod(packedPath, 0o711);
+  const before = await Bun.file(packedPath).bytes();
+  const infoBefore = (await runCommand(packedPath, ["--dcmprs-info"])).stdout.split("\n");
+
+  const repack = await runCommand(CMPRS_BIN, ["repack", "--recompress-only", "--level", "19", packedPath]);
+  expect(repack.exitCode).toBe(0);
+  const after = await Bun.file(packedPath).bytes();
+  const infoAfter = (await runCommand(packedPath, ["--dcmprs-info"])).stdout.split("\n");
+
+  // Only the payload size differs, the hash, name, mode, stub and the rest don't
+  const changed = infoAfter.filter((line, i) => line !== infoBefore[i]);
+  expect(changed.length).toBe(1);
+  expect(changed[0]).toStartWith("payload: ");
+  expect(infoAfter.length).toBe(infoBefore.length);
+  expect(after.length).toBeLessThan(before.length);
+  expect((await stat(packedPath)).mode & 0o777).toBe(0o711);
+  // The stub in front is the very same
+  const stubSize = JSON.parse(pack.stdout).stub_size;
+  expect(stubSize).toBeGreaterThan(0);
+  expect(after.subarray(0, stubSize)).toEqual(before.subarray(0, stubSize));
+
+  const extracted = `${binaryPath}.recompressed`;
+  expect((await runCommand(CMPRS_BIN, ["extract", packedPath, "--output", extracted])).exitCode).toBe(0);
+  expect(await Bun.file(extracted).bytes()).toEqual(await Bun.file(binaryPath).bytes());
+
+  console.log("   ✓ Recompressed at level 19 with every other field as it was");
+});
+
+test("repack --upgrade converts a file from before the header to the current format", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const packedPath = `${binaryPath}.upgrade.cmprs`;
+  const pack = await runCommand(CMPRS_BIN, ["--json-stats", "--output", packedPath, binaryPath]);
+  expect(pack.exitCode).toBe(0);
+  const stats = JSON.parse(pack.stdout);
+
+  // The version 1 layout: stub, magic, ";;;", the hash and the zstd payload, no header
+  const packed = await Bun.file(packedPath).bytes();
+  const oldPath = `${binaryPath}.v1.cmprs`;
+  await Bun.write(oldPath, Buffer.concat([
+    packed.subarray(0, stats.stub_size),
+    Buffer.from("DCMPRS_DATA_HERE;;;"),
+    Buffer.from(stats.sha256, "hex"),
+    packed.subarray(packed.length - stats.compressed_size),
+  ]));
+  await chmod(oldPath, 0o755);
+
+  const upgradedPath = `${binaryPath}.v3.cmprs`;
+  const upgrade = await runCommand(CMPRS_BIN, ["repack", "--upgrade", "--output", upgradedPath, oldPath]);
+  expect(upgrade.exitCode).toBe(0);
+  const info = await runCommand(upgradedPath, ["--dcmprs-info"]);
+  expect(info.stdout).toContain("(format v3)");
+  expect(info.stdout).toContain(`sha256: ${stats.sha256}`);
+  expect((await runCommand(upgradedPath, ["--dcmprs-check"])).exitCode).toBe(0);
+  const run = await runCommand(upgradedPath, []);
+  expect(run.exitCode).toBe(0);
+  expect(run.stdout).toContain("hello world");
+
+  // Only ever to a newer format
+  const again = await runCommand(CMPRS_BIN, ["repack", "--upgrade", packedPath]);
+  expect(again.exitCode).toBe(2);
+  expect(again.stderr).toContain("already in format version 3");
+
+  console.log("   ✓ Upgraded a version 1 file, which verifies and runs under the current loader");
+});
+
+test("A directory packed with --entry runs its entry next to its resources", async () => {
+  const dir = await mkdtemp(join(tempDir, "bundle-"));
+  const toolDir = join(dir, "mytool-dir");
+  await Bun.write(join(toolDir, "share", "greeting.txt"), "hello from a resource");
+  const entryPath = join(toolDir, "bin", "mytool");
+  await Bun.write(entryPath, '#!/bin/sh\ncat "$(dirname "$0")/../share/greeting.txt"\necho " $*"\n');
+  await chmod(entryPath, 0o755);
+
+  const missingEntry = await runCommand(CMPRS_BIN, [toolDir]);
+  expect(missingEntry.exitCode).toBe(2);
+  expect(missingEntry.stderr).toContain("--entry");
+
+  const compressedPath = join(dir, "mytool.cmprs");
+  const pack = await runCommand(CMPRS_BIN, ["--entry", "bin/mytool", "--output", compressedPath, toolDir]);
+  expect(pack.exitCode).toBe(0);
+
+  // Bundles are never replaced, so every launch unpacks them again
+  for (let i = 0; i < 2; i++) {
+    const result = await runCommand(compressedPath, ["arg"]);
+    expect(result.exitCode).toBe(0);
+    expect(result.stdout).toBe("hello from a resource arg");
+  }
+
+  const extractedDir = join(dir, "extracted");
+  const extract = await runCommand(CMPRS_BIN, ["extract", compressedPath, "--output", extractedDir]);
+  expect(extract.exitCode).toBe(0);
+  expect(await Bun.file(join(extractedDir, "share", "greeting.txt")).text()).toBe("hello from a resource");
+  expect((await stat(join(extractedDir, "bin", "mytool"))).mode & 0o777).toBe(0o755);
+
+  console.log("   ✓ Bundle ran its entry and extracted with its layout");
+});
+
+test("--extract-entry-only launches a bundle without its other files", async () => {
+  const dir = await mkdtemp(join(tempDir, "entry-only-"));
+  const toolDir = join(dir, "tool-dir");
+  // Compressible, so it's kept compressed, and large enough to be slow to unpack
+  await Bun.write(join(toolDir, "share", "huge.dat"), "resource\n".repeat(8 * 1024 * 1024));
+  const entryPath = join(toolDir, "bin", "tool");
+  await Bun.write(entryPath, '#!/bin/sh\ncd "$(dirname "$0")/.." && find . -type f | sort\n');
+  await chmod(entryPath, 0o755);
+
+  const withoutEntry = await runCommand(CMPRS_BIN, ["--extract-entry-only", join(dir, "missing")]);
+  expect(withoutEntry.exitCode).toBe(2);
+
+  const compressedPath = join(dir, "tool.cmprs");
+  const pack = await runCommand(CMPRS_BIN, [
+    "--entry", "bin/tool", "--extract-entry-only", "--output", compressedPath, toolDir,
+  ]);
+  expect(pack.exitCode).toBe(0);
+
+  // Only the entry is materialized next to itself
+  const result = await runCommand(compressedPath);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("./bin/tool");
+
+  // Everything is still in there for extraction
+  const extractedDir = join(dir, "extracted");
+  const extract = await runCommand(CMPRS_BIN, ["extract", compressedPath, "--output", extractedDir]);
+  expect(extract.exitCode).toBe(0);
+  expect((await stat(join(extractedDir, "share", "huge.dat"))).size).toBe(9 * 8 * 1024 * 1024);
+
+  console.log("   ✓ Bundle entry ran alone, extracted from its own frame");
+});
+
+test("--chmod sets the output mode, which defaults to 0755 for non-executable inputs", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const chmodPath = `${binaryPath}.chmod.cmprs`;
+  const result = await runCommand(CMPRS_BIN, ["--chmod", "0700", "--output", chmodPath, binaryPath]);
+  expect(result.exitCode).toBe(0);
+  expect((await stat(chmodPath)).mode & 0o7777).toBe(0o700);
+
+  const plainPath = join(tempDir, "not-executable");
+  await Bun.write(plainPath, "#!/bin/sh\necho plain\n");
+  await chmod(plainPath, 0o644);
+  await runCommand(CMPRS_BIN, ["--output", `${plainPath}.cmprs`, plainPath]);
+  expect((await stat(`${plainPath}.cmprs`)).mode & 0o7777).toBe(0o755);
+
+  const run = await runCommand(`${plainPath}.cmprs`);
+  expect(run.stdout).toBe("plain");
+
+  console.log("   ✓ Output modes set as requested");
+});
+
+test("--chmod wins over the input's mode, and stdin counts as 0755", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const privatePath = join(tempDir, "private-program");
+  await Bun.write(privatePath, Bun.file(binaryPath));
+  await chmod(privatePath, 0o700);
+
+  await runCommand(CMPRS_BIN, ["--output", `${privatePath}.cmprs`, privatePath]);
+  expect((await stat(`${privatePath}.cmprs`)).mode & 0o7777).toBe(0o700);
+  await runCommand(CMPRS_BIN, ["--chmod", "0750", "--output", `${privatePath}.chmod.cmprs`, privatePath]);
+  expect((await stat(`${privatePath}.chmod.cmprs`)).mode & 0o7777).toBe(0o750);
+
+  const stdinPath = join(tempDir, "from-stdin.cmprs");
+  const fromStdin = await runCommand(CMPRS_BIN, ["--output", stdinPath, "-"], {
+    stdio: [Bun.file(privatePath), "pipe", "pipe"],
+  });
+  expect(fromStdin.exitCode).toBe(0);
+  expect((await stat(stdinPath)).mode & 0o7777).toBe(0o755);
+  expect((await runCommand(stdinPath)).exitCode).toBe(0);
+
+  const stdinChmodPath = join(tempDir, "from-stdin.chmod.cmprs");
+  await runCommand(CMPRS_BIN, ["--chmod", "0711", "--output", stdinChmodPath, "-"], {
+    stdio: [Bun.file(privatePath), "pipe", "pipe"],
+  });
+  expect((await stat(stdinChmodPath)).mode & 0o7777).toBe(0o711);
+
+  const noOutput = await runCommand(CMPRS_BIN, ["-"], { stdio: [Bun.file(privatePath), "pipe", "pipe"] });
+  expect(noOutput.exitCode).toBe(2);
+
+  console.log("   ✓ Output modes follow --chmod, then the input, then 0755");
+});
+
+test("Incompressible input is stored uncompressed unless --no-auto-store", async () => {
+  const randomPath = join(tempDir, "random-data");
+  await Bun.write(randomPath, crypto.getRandomValues(new Uint8Array(1024 * 1024)));
+  await chmod(randomPath, 0o755);
+
+  const auto = await runCommand(CMPRS_BIN, ["--json-stats", "--output", `${randomPath}.cmprs`, randomPath]);
+  expect(auto.exitCode).toBe(0);
+  const autoStats = JSON.parse(auto.stdout);
+  expect(autoStats.algorithm).toBe("none");
+  expect(autoStats.compressed_size).toBe(autoStats.input_size);
+
+  const forced = await runCommand(CMPRS_BIN, ["--json-stats", "--no-auto-store", "--output", `${randomPath}.zstd.cmprs`, randomPath]);
+  expect(JSON.parse(forced.stdout).algorithm).toBe("zstd");
+
+  const extracted = await runCommand(CMPRS_BIN, ["extract", `${randomPath}.cmprs`, "--output", `${randomPath}.out`]);
+  expect(extracted.exitCode).toBe(0);
+  expect(Buffer.compare(
+    Buffer.from(await Bun.file(`${randomPath}.out`).arrayBuffer()),
+    Buffer.from(await Bun.file(randomPath).arrayBuffer()),
+  )).toBe(0);
+
+  console.log("   ✓ Random data stored instead of compressed");
+});
+
+test("--max-output-size picks the first level that fits, or fails when none does", async () => {
+  // cmprs itself: a real program that packs quickly even at level 22
+  const inputPath = join(tempDir, "budget-input");
+  await Bun.write(inputPath, Bun.file(CMPRS_BIN));
+  await chmod(inputPath, 0o755);
+  const outputPath = join(tempDir, "budget.cmprs");
+  const pack = async (...args) => {
+    const result = await runCommand(CMPRS_BIN, ["--json-stats", ...args, "--output", outputPath, inputPath]);
+    return { ...result, stats: result.exitCode === 0 ? JSON.parse(result.stdout) : null };
+  };
+
+  const sizes = {};
+  for (const level of [3, 6, 9, 12, 15, 19, 22]) {
+    sizes[level] = (await pack("--level", String(level))).stats.output_size;
+  }
+  // The first level above the default that's smaller than every one before it
+  const levels = [6, 9, 12, 15, 19, 22];
+  const target = levels.find((level, i) => sizes[level] < Math.min(sizes[3], ...levels.slice(0, i).map((l) => sizes[l])));
+  expect(target).toBeDefined();
+
+  const fitted = await pack("--max-output-size", String(sizes[target]));
+  expect(fitted.exitCode).toBe(0);
+  expect(fitted.stats.algorithm).toBe("zstd");
+  expect(fitted.stats.level).toBe(target);
+  expect(fitted.stats.output_size).toBeLessThanOrEqual(sizes[target]);
+  expect(fitted.stderr).toContain(`zstd level ${target} packs`);
+  expect((await runCommand(CMPRS_BIN, ["extract", outputPath, "--output", `${outputPath}.out`])).exitCode).toBe(0);
+
+  await rm(outputPath);
+  const impossible = await pack("--max-output-size", "1000");
+  expect(impossible.exitCode).toBe(1);
+  expect(impossible.stderr).toContain("doesn't fit in --max-output-size 1000 bytes");
+  expect(await Bun.file(outputPath).exists()).toBe(false);
+
+  console.log(`   ✓ A ${sizes[target]} byte budget picked level ${target}`);
+});
+
+test("--store-if-ratio-above moves the line between storing and compressing", async () => {
+  // Half random, half zeros: compresses to about half the input
+  const halfPath = join(tempDir, "half-random");
+  const half = new Uint8Array(1024 * 1024);
+  half.set(crypto.getRandomValues(new Uint8Array(512 * 1024)));
+  await Bun.write(halfPath, half);
+  await chmod(halfPath, 0o755);
+
+  const pack = async (...args) => {
+    const result = await runCommand(CMPRS_BIN, ["--json-stats", ...args, "--output", `${halfPath}.cmprs`, halfPath]);
+    expect(result.exitCode).toBe(0);
+    return JSON.parse(result.stdout).algorithm;
+  };
+  expect(await pack()).toBe("zstd");
+  expect(await pack("--store-if-ratio-above", "60")).toBe("zstd");
+  expect(await pack("--store-if-ratio-above", "40")).toBe("none");
+  expect(await pack("--store-if-ratio-above", "0")).toBe("none");
+  expect(await pack("--store-if-ratio-above", "0", "--no-auto-store")).toBe("zstd");
+
+  const invalid = await runCommand(CMPRS_BIN, ["--store-if-ratio-above", "101", halfPath]);
+  expect(invalid.exitCode).toBe(2);
+  expect(invalid.stderr).toContain("not a percentage from 0 to 100");
+
+  console.log("   ✓ The auto-store threshold flips the decision at the ratio asked for");
+});
+
+test("DCMPRS_DUMP writes the decompressed program and still runs it", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.dump.cmprs`;
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, binaryPath]);
+
+  const dumpPath = join(tempDir, "dumped-binary");
+  const result = await runCommand(compressedPath, [], {
+    env: { ...process.env, DCMPRS_DUMP: dumpPath },
+  });
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("hello world");
+
+  const original = new Bun.CryptoHasher("sha256").update(await Bun.file(binaryPath).arrayBuffer()).digest("hex");
+  const dumped = new Bun.CryptoHasher("sha256").update(await Bun.file(dumpPath).arrayBuffer()).digest("hex");
+  expect(dumped).toBe(original);
+
+  console.log("   ✓ Dumped payload matches the original");
+});
+
+test("Streaming the payload into the output leaves the hash and header in place", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.layout.cmprs`;
+  const result = await runCommand(CMPRS_BIN, ["--json-stats", "--output", compressedPath, binaryPath]);
+  expect(result.exitCode).toBe(0);
+  const stats = JSON.parse(result.stdout);
+
+  const packed = Buffer.from(await Bun.file(compressedPath).arrayBuffer());
+  const magicPos = packed.indexOf(Buffer.from("DCMPRS_DATA_HERE;;"));
+  expect(magicPos).toBe(stats.stub_size);
+  const versionPos = magicPos + 18;
+  expect(packed[versionPos]).toBe(3);
+
+  const original = await Bun.file(binaryPath).arrayBuffer();
+  const sha256 = new Bun.CryptoHasher("sha256").update(original).digest("hex");
+  expect(packed.subarray(versionPos + 1, versionPos + 33).toString("hex")).toBe(sha256);
+
+  const headerPos = versionPos + 33;
+  const payloadPos = headerPos + 8 + packed.readUInt32LE(headerPos);
+  expect(packed.length - payloadPos).toBe(stats.compressed_size);
+  expect(packed.length).toBe(stats.output_size);
+
+  const run = await runCommand(compressedPath);
+  expect(run.stdout).toBe("hello world");
+
+  console.log("   ✓ Stub, hash, header and payload laid out back to back");
+});
+
+test("--name-by-hash names the output after the input's SHA256", async () => {
+  const dir = await mkdtemp(join(tempDir, "by-hash-"));
+  const binaryPath = join(dir, "tool");
+  await Bun.write(binaryPath, Bun.file(await createTestBinary(tempDir)));
+  await chmod(binaryPath, 0o755);
+  const sha256 = new Bun.CryptoHasher("sha256").update(await Bun.file(binaryPath).arrayBuffer()).digest("hex");
+  const expectedPath = join(dir, `${sha256.slice(0, 16)}.cmprs`);
+
+  const first = await runCommand(CMPRS_BIN, ["--name-by-hash", binaryPath]);
+  expect(first.exitCode).toBe(0);
+  expect(await Bun.file(expectedPath).exists()).toBe(true);
+
+  // Same content again is left alone, different content needs --force
+  const again = await runCommand(CMPRS_BIN, ["--name-by-hash", binaryPath]);
+  expect(again.exitCode).toBe(0);
+  const different = await runCommand(CMPRS_BIN, ["--name-by-hash", "--minimize-stub", binaryPath]);
+  expect(different.exitCode).not.toBe(0);
+  expect(different.stderr).toContain("already exists with different content");
+  const forced = await runCommand(CMPRS_BIN, ["--name-by-hash", "--minimize-stub", "--force", binaryPath]);
+  expect(forced.exitCode).toBe(0);
+
+  const run = await runCommand(expectedPath);
+  expect(run.stdout).toBe("hello world");
+
+  console.log(`   ✓ Packed to ${sha256.slice(0, 16)}.cmprs`);
+});
+
+test("--dcmprs-cat writes the original program to stdout", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.cat.cmprs`;
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, binaryPath]);
+
+  const proc = spawn({ cmd: [compressedPath, "--dcmprs-cat"], stdout: "pipe", stderr: "pipe" });
+  const catted = await new Response(proc.stdout).arrayBuffer();
+  expect(await proc.exited).toBe(0);
+
+  const original = new Bun.CryptoHasher("sha256").update(await Bun.file(binaryPath).arrayBuffer()).digest("hex");
+  expect(new Bun.CryptoHasher("sha256").update(catted).digest("hex")).toBe(original);
+  // Nothing ran, so the packed file wasn't replaced
+  const packed = await Bun.file(compressedPath).arrayBuffer();
+  expect(Buffer.from(packed).includes(Buffer.from("DCMPRS_DATA_HERE"))).toBe(true);
+
+  console.log("   ✓ Piped output matches the original");
+});
+
+test("--dcmprs-extract renames a verified file into place, never a partial one", async () => {
+  const sha256 = async (path) => new Bun.CryptoHasher("sha256").update(await Bun.file(path).arrayBuffer()).digest("hex");
+  const binaryPath = await createTestBinary(tempDir);
+  const expectedSize = (await stat(binaryPath)).size;
+  const packedPath = `${binaryPath}.atomic.cmprs`;
+  expect((await runCommand(CMPRS_BIN, ["--output", packedPath, binaryPath])).exitCode).toBe(0);
+
+  // Watch the target for as long as the extraction runs
+  const deployDir = await mkdtemp(join(tempDir, "deploy-"));
+  const targetPath = join(deployDir, "program");
+  const extraction = runCommand(packedPath, ["--dcmprs-extract", targetPath]);
+  let done = false;
+  extraction.finally(() => { done = true; });
+  const seenSizes = new Set();
+  while (!done) {
+    try {
+      seenSizes.add((await stat(targetPath)).size);
+    } catch {}
+    await Bun.sleep(1);
+  }
+  expect((await extraction).exitCode).toBe(0);
+  seenSizes.add((await stat(targetPath)).size);
+  expect([...seenSizes]).toEqual([expectedSize]);
+  expect(await sha256(targetPath)).toBe(await sha256(binaryPath));
+  expect(await readdir(deployDir)).toEqual(["program"]);
+
+  // A payload that fails its SHA256 never lands, even over an existing file
+  const storedPath = `${binaryPath}.atomic-store.cmprs`;
+  await runCommand(CMPRS_BIN, ["--store", "--output", storedPath, binaryPath]);
+  const stored = Buffer.from(await Bun.file(storedPath).arrayBuffer());
+  stored[stored.length - 100] ^= 0xff;
+  await Bun.write(storedPath, stored);
+  await chmod(storedPath, 0o755);
+  const corrupted = await runCommand(storedPath, ["--dcmprs-extract", targetPath]);
+  expect(corrupted.exitCode).toBe(5);
+  expect(corrupted.stderr).toContain("SHA256 mismatch");
+  expect(await sha256(targetPath)).toBe(await sha256(binaryPath));
+  expect(await readdir(deployDir)).toEqual(["program"]);
+
+  console.log("   ✓ The target only ever held the whole, verified program");
+});
+
+test("--dcmprs-selftest decodes the built-in sample with every algorithm, whatever the payload", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  for (const [name, args] of [["regular", []], ["minimal", ["--minimize-stub"]], ["stored", ["--store"]]]) {
+    const packedPath = `${binaryPath}.selftest-${name}.cmprs`;
+    expect((await runCommand(CMPRS_BIN, [...args, "--output", packedPath, binaryPath])).exitCode).toBe(0);
+    const selftest = await runCommand(packedPath, ["--dcmprs-selftest"]);
+    expect(selftest.exitCode).toBe(0);
+    expect(selftest.stdout).toStartWith("OK:");
+    for (const part of ["zstd", "xz", "gzip", "x86 filter", "arm64 filter"]) {
+      expect(selftest.stdout).toContain(part);
+    }
+    // Nothing was extracted or run
+    expect((await runCommand(packedPath, [])).stdout).toBe("hello world");
+  }
+
+  const forwardedPath = `${binaryPath}.selftest-forwarded.cmprs`;
+  await runCommand(CMPRS_BIN, ["--no-stub-flags", "--output", forwardedPath, binaryPath]);
+  const forwarded = await runCommand(forwardedPath, ["--dcmprs-selftest"]);
+  expect(forwarded.stdout).not.toStartWith("OK:");
+
+  console.log("   ✓ The stub's decoders pass their self-test");
+});
+
+test("--dcmprs-check reports OK for an intact payload and FAIL for a corrupted one", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const original = new Bun.CryptoHasher("sha256").update(await Bun.file(binaryPath).arrayBuffer()).digest("hex");
+
+  const goodPath = `${binaryPath}.check.cmprs`;
+  await runCommand(CMPRS_BIN, ["--output", goodPath, binaryPath]);
+  const good = await runCommand(goodPath, ["--dcmprs-check"]);
+  expect(good.exitCode).toBe(0);
+  expect(good.stdout).toStartWith("OK:");
+  expect(good.stdout).toContain(original);
+  // Nothing was extracted or run, so the packed file is still in place
+  expect(Buffer.from(await Bun.file(goodPath).arrayBuffer()).includes(Buffer.from("DCMPRS_DATA_HERE"))).toBe(true);
+
+  // Stored uncompressed, a flipped byte decodes fine but fails the SHA256
+  const storedPath = `${binaryPath}.check-store.cmprs`;
+  await runCommand(CMPRS_BIN, ["--store", "--output", storedPath, binaryPath]);
+  const stored = Buffer.from(await Bun.file(storedPath).arrayBuffer());
+  stored[stored.length - 100] ^= 0xff;
+  await Bun.write(storedPath, stored);
+  await chmod(storedPath, 0o755);
+  const mismatch = await runCommand(storedPath, ["--dcmprs-check"]);
+  expect(mismatch.exitCode).toBe(5);
+  expect(mismatch.stdout).toStartWith("FAIL: SHA256 mismatch");
+
+  // Compressed, the decoder itself rejects it
+  const corrupt = Buffer.from(await Bun.file(goodPath).arrayBuffer());
+  corrupt[corrupt.length - 100] ^= 0xff;
+  const corruptPath = `${binaryPath}.check-corrupt.cmprs`;
+  await Bun.write(corruptPath, corrupt);
+  await chmod(corruptPath, 0o755);
+  const failed = await runCommand(corruptPath, ["--dcmprs-check"]);
+  expect([5, 6]).toContain(failed.exitCode);
+  expect(failed.stdout).toStartWith("FAIL:");
+
+  console.log("   ✓ Health check tells intact from corrupted payloads");
+});
+
+test("SOURCE_DATE_EPOCH is the embedded timestamp", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const epoch = 1700000000;
+  const pack = async (name) => {
+    const path = `${binaryPath}.${name}.cmprs`;
+    const result = await runCommand(CMPRS_BIN, ["--output", path, binaryPath], {
+      env: { ...process.env, SOURCE_DATE_EPOCH: String(epoch) },
+    });
+    expect(result.exitCode).toBe(0);
+    return path;
+  };
+  const firstPath = await pack("epoch1");
+  const secondPath = await pack("epoch2");
+
+  // Read the mtime field (tag 9) out of the header
+  const packed = Buffer.from(await Bun.file(firstPath).arrayBuffer());
+  const headerPos = packed.indexOf(Buffer.from("DCMPRS_DATA_HERE;;")) + 18 + 1 + 32;
+  const fieldsStart = headerPos + 8;
+  const fieldsEnd = fieldsStart + packed.readUInt32LE(headerPos);
+  let mtime;
+  for (let pos = fieldsStart; pos < fieldsEnd; pos += 5 + packed.readUInt32LE(pos + 1)) {
+    if (packed[pos] === 9) mtime = packed.readBigUInt64LE(pos + 5);
+  }
+  expect(mtime).toBe(BigInt(epoch));
+
+  // Restored on extraction, and two builds at different times are identical
+  const extractedPath = `${firstPath}.out`;
+  await runCommand(CMPRS_BIN, ["extract", firstPath, "--output", extractedPath]);
+  expect(Math.floor((await stat(extractedPath)).mtimeMs / 1000)).toBe(epoch);
+  expect(Buffer.compare(packed, Buffer.from(await Bun.file(secondPath).arrayBuffer()))).toBe(0);
+
+  console.log("   ✓ Timestamp taken from SOURCE_DATE_EPOCH");
+});
+
+test("DCMPRS_MAX_SIZE aborts before decompressing an oversized payload", async () => {
+  // 1 MB of zeros compresses to almost nothing, like a decompression bomb would
+  const bombPath = join(tempDir, "zeros.sh");
+  await Bun.write(bombPath, "#!/bin/sh\necho ok\n#" + "0".repeat(1024 * 1024) + "\n");
+  await chmod(bombPath, 0o755);
+  const packedPath = `${bombPath}.cmprs`;
+  expect((await runCommand(CMPRS_BIN, ["--output", packedPath, bombPath])).exitCode).toBe(0);
+
+  const capped = await runCommand(packedPath, [], {
+    env: { ...process.env, DCMPRS_MAX_SIZE: "1000" },
+  });
+  expect(capped.exitCode).toBe(5);
+  expect(capped.stderr).toContain("exceeds the limit of 1000 bytes");
+
+  const invalid = await runCommand(packedPath, [], {
+    env: { ...process.env, DCMPRS_MAX_SIZE: "lots" },
+  });
+  expect(invalid.exitCode).toBe(2);
+
+  // Nothing was replaced, and the default limit lets it run
+  const result = await runCommand(packedPath);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toContain("ok");
+
+  console.log("   ✓ Oversized payload rejected cleanly");
+});
+
+test("DCMPRS_PAYLOAD_OFFSET reads the payload without searching for it", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const packedPath = `${binaryPath}.offset.cmprs`;
+  expect((await runCommand(CMPRS_BIN, ["--output", packedPath, binaryPath])).exitCode).toBe(0);
+
+  const packed = Buffer.from(await Bun.file(packedPath).arrayBuffer());
+  const offset = packed.indexOf("DCMPRS_DATA_HERE;;");
+  expect(offset).toBeGreaterThan(0);
+
+  const scanned = await runCommand(packedPath, ["--dcmprs-cat"]);
+  const explicit = await runCommand(packedPath, ["--dcmprs-cat"], {
+    env: { ...process.env, DCMPRS_PAYLOAD_OFFSET: String(offset) },
+  });
+  expect(explicit.exitCode).toBe(0);
+  expect(explicit.stdout).toBe(scanned.stdout);
+
+  // With the magic overwritten only the explicit offset finds the payload
+  const unmarkedPath = `${binaryPath}.unmarked.cmprs`;
+  packed.fill("X", offset, offset + 16);
+  await Bun.write(unmarkedPath, packed);
+  await chmod(unmarkedPath, 0o755);
+  expect((await runCommand(unmarkedPath, ["--dcmprs-cat"])).exitCode).toBe(3);
+  const unmarked = await runCommand(unmarkedPath, ["--dcmprs-cat"], {
+    env: { ...process.env, DCMPRS_PAYLOAD_OFFSET: String(offset) },
+  });
+  expect(unmarked.exitCode).toBe(0);
+  expect(unmarked.stdout).toBe(scanned.stdout);
+
+  const outOfBounds = await runCommand(packedPath, ["--dcmprs-cat"], {
+    env: { ...process.env, DCMPRS_PAYLOAD_OFFSET: String(packed.length) },
+  });
+  expect(outOfBounds.exitCode).toBe(2);
+  expect(outOfBounds.stderr).toContain("past the end");
+
+  console.log("   ✓ Explicit offset matches the scanned payload");
+});
+
+test.skipIf(platform() !== "linux")("DCMPRS_TEMP_MODE sets the mode of the extracted temp file", async () => {
+  // The program reports the mode of the file it was exec'd from
+  const scriptPath = join(tempDir, "own-mode.sh");
+  await Bun.write(scriptPath, '#!/bin/sh\nstat -c %a "$0"\n');
+  await chmod(scriptPath, 0o755);
+  const packedPath = `${scriptPath}.cmprs`;
+  const pack = () => runCommand(CMPRS_BIN, ["--output", packedPath, scriptPath]);
+
+  expect((await pack()).exitCode).toBe(0);
+  const { DCMPRS_TEMP_MODE, ...envWithoutMode } = process.env;
+  const defaultMode = await runCommand(packedPath, [], { env: envWithoutMode });
+  expect(defaultMode.exitCode).toBe(0);
+  expect(defaultMode.stdout).toBe("700");
+
+  expect((await pack()).exitCode).toBe(0);
+  const customMode = await runCommand(packedPath, [], {
+    env: { ...envWithoutMode, DCMPRS_TEMP_MODE: "0750" },
+  });
+  expect(customMode.exitCode).toBe(0);
+  expect(customMode.stdout).toBe("750");
+
+  expect((await pack()).exitCode).toBe(0);
+  const invalid = await runCommand(packedPath, [], {
+    env: { ...envWithoutMode, DCMPRS_TEMP_MODE: "rwx" },
+  });
+  expect(invalid.exitCode).toBe(2);
+
+  console.log("   ✓ Temp file mode defaults to 0700 and follows DCMPRS_TEMP_MODE");
+});
+
+test("Several inputs are packed to one output each", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const inputs = [];
+  for (const name of ["batch-a", "batch-b", "batch-c"]) {
+    const inputPath = join(tempDir, name);
+    await Bun.write(inputPath, Bun.file(binaryPath));
+    await chmod(inputPath, 0o755);
+    inputs.push(inputPath);
+  }
+
+  const result = await runCommand(CMPRS_BIN, inputs);
+  expect(result.exitCode).toBe(0);
+  expect(result.stderr).toContain("packed 3 of 3 inputs");
+  for (const inputPath of inputs) {
+    expect(await Bun.file(`${inputPath}.cmprs`).exists()).toBe(true);
+  }
+  expect(await Bun.file(`${inputs[0]}.cmprs.cmprs`).exists()).toBe(false);
+
+  // A missing input doesn't stop the others, but fails the run
+  const missingPath = join(tempDir, "batch-missing");
+  const partial = await runCommand(CMPRS_BIN, [missingPath, ...inputs, "--suffix", "again"]);
+  expect(partial.exitCode).toBe(1);
+  expect(partial.stderr).toContain("packed 3 of 4 inputs");
+  expect(await Bun.file(`${inputs[2]}.again`).exists()).toBe(true);
+
+  const failFast = await runCommand(CMPRS_BIN, [missingPath, ...inputs, "--suffix", "fast", "--fail-fast"]);
+  expect(failFast.exitCode).toBe(1);
+  expect(await Bun.file(`${inputs[0]}.fast`).exists()).toBe(false);
+
+  const withOutput = await runCommand(CMPRS_BIN, [...inputs, "--output", join(tempDir, "batch.out")]);
+  expect(withOutput.exitCode).toBe(2);
+
+  console.log("   ✓ Batch of three inputs packed");
+});
+
+test("--block-size packs zstd in small blocks that decode the same", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const defaultPath = `${binaryPath}.blocks-default.cmprs`;
+  const smallPath = `${binaryPath}.blocks-small.cmprs`;
+  const packDefault = await runCommand(CMPRS_BIN, ["--json-stats", "--output", defaultPath, binaryPath]);
+  expect(packDefault.exitCode).toBe(0);
+  const packSmall = await runCommand(CMPRS_BIN, ["--block-size", "1340", "--json-stats", "--output", smallPath, binaryPath]);
+  expect(packSmall.exitCode).toBe(0);
+
+  const extracted = `${binaryPath}.blocks-extracted`;
+  expect((await runCommand(CMPRS_BIN, ["extract", smallPath, "--output", extracted])).exitCode).toBe(0);
+  expect(await Bun.file(extracted).bytes()).toEqual(await Bun.file(binaryPath).bytes());
+  const run = await runCommand(smallPath, []);
+  expect(run.stdout).toContain("hello world");
+
+  // Smaller blocks cost some ratio, about 1% at the smallest size
+  const defaultSize = JSON.parse(packDefault.stdout).compressed_size;
+  const smallSize = JSON.parse(packSmall.stdout).compressed_size;
+  expect(smallSize).toBeGreaterThan(defaultSize);
+  console.log(`   Block size 1340: ${smallSize} bytes, ${((smallSize / defaultSize - 1) * 100).toFixed(1)}% over the default`);
+
+  const outOfRange = await runCommand(CMPRS_BIN, ["--block-size", "512", binaryPath]);
+  expect(outOfRange.exitCode).toBe(2);
+  const notZstd = await runCommand(CMPRS_BIN, ["--block-size", "4K", "--xz-preset", "6", binaryPath]);
+  expect(notZstd.exitCode).toBe(2);
+
+  console.log("   ✓ Small zstd blocks round-trip");
+});
+
+test("--report-only-larger fails when packing makes the output larger", async () => {
+  // Random bytes don't compress, so the stub is all the output adds
+  const randomPath = join(tempDir, "larger-random.bin");
+  await Bun.write(randomPath, crypto.getRandomValues(new Uint8Array(64 * 1024)));
+  const outputPath = `${randomPath}.cmprs`;
+
+  const plain = await runCommand(CMPRS_BIN, ["--output", outputPath, randomPath]);
+  expect(plain.exitCode).toBe(0);
+
+  const gated = await runCommand(CMPRS_BIN, ["--report-only-larger", "--output", outputPath, randomPath]);
+  expect(gated.exitCode).toBe(1);
+  expect(gated.stderr).toContain("is larger than its input");
+  // Only reported, the output is written all the same
+  expect(await Bun.file(outputPath).exists()).toBe(true);
+
+  // An input that compresses well passes
+  const binaryPath = await createTestBinary(tempDir);
+  const passed = await runCommand(CMPRS_BIN, ["--report-only-larger", "--output", `${binaryPath}.gated`, binaryPath]);
+  expect(passed.exitCode).toBe(0);
+
+  console.log("   ✓ Incompressible input rejected under --report-only-larger");
+});
+
+test("--files-from packs each listed file into --output-dir", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const listDir = await mkdtemp(join(tempDir, "files-from-"));
+  const inputs = [];
+  for (const name of ["list-a", "list-b", join("nested", "list-c")]) {
+    const inputPath = join(listDir, name);
+    await Bun.write(inputPath, Bun.file(binaryPath));
+    await chmod(inputPath, 0o755);
+    inputs.push(inputPath);
+  }
+  const listPath = join(listDir, "inputs.txt");
+  await Bun.write(listPath, `# built by the release job\n${inputs[0]}\n\n   \n${inputs[1]}\r\n${inputs[2]}\n`);
+
+  const outputDir = join(listDir, "out");
+  const result = await runCommand(CMPRS_BIN, ["--files-from", listPath, "--output-dir", outputDir]);
+  expect(result.exitCode).toBe(0);
+  expect(result.stderr).toContain("packed 3 of 3 inputs");
+  expect((await readdir(outputDir)).sort()).toEqual(["list-a.cmprs", "list-b.cmprs", "list-c.cmprs"]);
+  const run = await runCommand(join(outputDir, "list-c.cmprs"), []);
+  expect(run.stdout).toContain("hello world");
+
+  // Two inputs of the same name would overwrite each other there
+  await Bun.write(join(listDir, "nested", "list-a"), Bun.file(binaryPath));
+  const clash = await runCommand(CMPRS_BIN, [
+    "--files-from", listPath, join(listDir, "nested", "list-a"), "--output-dir", outputDir,
+  ]);
+  expect(clash.exitCode).toBe(2);
+  expect(clash.stderr).toContain("overwrite each other");
+
+  console.log("   ✓ Three listed files packed into the output directory");
+});
+
+test("DCMPRS_VERIFY_ONCE checks the SHA256 on the first run only", async () => {
+  // A launcher pack stays packed, so it decompresses on every run
+  const scriptPath = join(tempDir, "verify-once.sh");
+  await Bun.write(scriptPath, "echo verified-run\n");
+  const packedPath = join(tempDir, "verify-once.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--launcher", "sh", "--output", packedPath, scriptPath])).exitCode).toBe(0);
+
+  const env = { ...process.env, DCMPRS_VERIFY_ONCE: "1", DCMPRS_LOG_LEVEL: "info" };
+  const first = await runCommand(packedPath, [], { env });
+  expect(first.exitCode).toBe(0);
+  expect(first.stdout).toBe("verified-run");
+  expect(first.stderr).toContain("Verified the payload's SHA256");
+
+  const second = await runCommand(packedPath, [], { env });
+  expect(second.exitCode).toBe(0);
+  expect(second.stderr).toContain("verified on an earlier run");
+  expect(second.stderr).not.toContain("Verified the payload's SHA256");
+
+  // The marker is keyed by the hash, so a stale one doesn't skip the check
+  const markerPath = join(tempDir, ".verify-once.cmprs.dcmprs-verified");
+  await Bun.write(markerPath, "0".repeat(64));
+  const stale = await runCommand(packedPath, [], { env });
+  expect(stale.stderr).toContain("Verified the payload's SHA256");
+
+  console.log("   ✓ SHA256 verified once, then skipped");
+});
+
+test("--verify-policy always checks the SHA256 on every run, whatever the environment says", async () => {
+  const dir = await mkdtemp(join(tempDir, "verify-policy-"));
+  const scriptPath = join(dir, "tool");
+  // Stored uncompressed, so a flipped byte in the comment still runs unless it's caught
+  await Bun.write(scriptPath, `#!/bin/sh\necho policy-run\n# ${"x".repeat(200)}\n`);
+  await chmod(scriptPath, 0o755);
+  const packedPath = join(dir, "tool.cmprs");
+  const pack = await runCommand(CMPRS_BIN, ["--verify-policy", "always", "--store", "--output", packedPath, scriptPath]);
+  expect(pack.exitCode).toBe(0);
+  expect((await runCommand(packedPath, ["--dcmprs-info"])).stdout).toContain("verify: always");
+
+  const env = { ...process.env, DCMPRS_SKIP_VERIFY: "1", DCMPRS_LOG_LEVEL: "info" };
+  for (const run of [1, 2]) {
+    const result = await runCommand(packedPath, [], { env });
+    expect(result.exitCode).toBe(0);
+    expect(result.stdout).toBe("policy-run");
+    expect(result.stderr).toContain("ignoring DCMPRS_SKIP_VERIFY");
+    expect(result.stderr).toContain("Verified the payload's SHA256");
+  }
+  // Left packed, rather than replaced by a program nothing checks
+  expect((await runCommand(packedPath, ["--dcmprs-info"])).exitCode).toBe(0);
+
+  const packed = Buffer.from(await Bun.file(packedPath).arrayBuffer());
+  packed[packed.lastIndexOf("xxxx")] = "y".charCodeAt(0);
+  await Bun.write(packedPath, packed);
+  const corrupted = await runCommand(packedPath, [], { env });
+  expect(corrupted.exitCode).not.toBe(0);
+  expect(corrupted.stdout).toBe("");
+  expect(corrupted.stderr).toContain("SHA256 mismatch");
+
+  console.log("   ✓ Packed policy verified every run despite DCMPRS_SKIP_VERIFY");
+});
+
+test("--split writes the payload to a .data file that the launcher reads", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const launcherPath = join(tempDir, "split.cmprs");
+  const pack = await runCommand(CMPRS_BIN, ["--split", "--output", launcherPath, binaryPath]);
+  expect(pack.exitCode).toBe(0);
+
+  // The launcher is just the stub and header, the payload is all in the sidecar
+  const launcher = Buffer.from(await Bun.file(launcherPath).arrayBuffer());
+  const payloadSize = (await stat(`${launcherPath}.data`)).size;
+  expect(payloadSize).toBeGreaterThan(0);
+  const wholePath = `${binaryPath}.whole.cmprs`;
+  expect((await runCommand(CMPRS_BIN, ["--output", wholePath, binaryPath])).exitCode).toBe(0);
+  expect(launcher.length + payloadSize).toBe((await stat(wholePath)).size);
+
+  const extractedPath = `${launcherPath}.out`;
+  const extract = await runCommand(CMPRS_BIN, ["extract", launcherPath, "--output", extractedPath]);
+  expect(extract.exitCode).toBe(0);
+  const original = Buffer.from(await Bun.file(binaryPath).arrayBuffer());
+  expect(Buffer.compare(Buffer.from(await Bun.file(extractedPath).arrayBuffer()), original)).toBe(0);
+
+  const run = await runCommand(launcherPath);
+  expect(run.exitCode).toBe(0);
+  expect(run.stdout).toBe("hello world");
+
+  // Without its sidecar the launcher can't run
+  const loneLauncherPath = join(tempDir, "lone.cmprs");
+  await Bun.write(loneLauncherPath, launcher);
+  await chmod(loneLauncherPath, 0o755);
+  const lone = await runCommand(loneLauncherPath);
+  expect(lone.exitCode).toBe(1);
+  expect(lone.stderr).toContain("lone.cmprs.data");
+
+  console.log("   ✓ Payload loaded from the sidecar");
+});
+
+test("--xz-preset 9 --xz-extreme packs smaller than zstd level 22 and round-trips", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const zstdPath = `${binaryPath}.zstd22.cmprs`;
+  const xzPath = `${binaryPath}.xz9e.cmprs`;
+
+  expect((await runCommand(CMPRS_BIN, ["--level", "22", "--no-auto-store", "--output", zstdPath, binaryPath])).exitCode).toBe(0);
+  const xz = await runCommand(CMPRS_BIN, ["--xz-preset", "9", "--xz-extreme", "--no-auto-store", "--output", xzPath, binaryPath]);
+  expect(xz.exitCode).toBe(0);
+  expect(xz.stderr).toContain("xz compresses many times slower");
+
+  // Same stub and header size, so the difference is all payload
+  expect((await stat(xzPath)).size).toBeLessThan((await stat(zstdPath)).size);
+
+  const extractedPath = `${xzPath}.out`;
+  expect((await runCommand(CMPRS_BIN, ["extract", xzPath, "--output", extractedPath])).exitCode).toBe(0);
+  const original = Buffer.from(await Bun.file(binaryPath).arrayBuffer());
+  expect(Buffer.compare(Buffer.from(await Bun.file(extractedPath).arrayBuffer()), original)).toBe(0);
+
+  const run = await runCommand(xzPath);
+  expect(run.exitCode).toBe(0);
+  expect(run.stdout).toBe("hello world");
+
+  const shell = await runCommand(CMPRS_BIN, ["--xz-preset", "6", "--format", "shell", binaryPath]);
+  expect(shell.exitCode).toBe(2);
+
+  console.log("   ✓ xz -9e beat zstd -22 and decompressed correctly");
+});
+
+test("--prefer-ratio and --prefer-speed pick coordinated options that flags override", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const pack = async (name, flags) => {
+    const result = await runCommand(CMPRS_BIN, [...flags, "--json-stats", "--output", `${binaryPath}.${name}.cmprs`, binaryPath]);
+    expect(result.exitCode).toBe(0);
+    return JSON.parse(result.stdout);
+  };
+  const plain = await pack("profile-default", []);
+  const ratio = await pack("profile-ratio", ["--prefer-ratio"]);
+  const speed = await pack("profile-speed", ["--prefer-speed"]);
+
+  expect([ratio.algorithm, ratio.level]).toEqual(["xz", 9]);
+  expect([speed.algorithm, speed.level]).toEqual(["zstd", 1]);
+  // Smallest and slowest, then the default, then largest and fastest
+  expect(ratio.compressed_size).toBeLessThan(plain.compressed_size);
+  expect(speed.compressed_size).toBeGreaterThan(plain.compressed_size);
+  expect(ratio.timings_ms.compress).toBeGreaterThan(speed.timings_ms.compress);
+  // The bcj filter comes with the ratio profile
+  const info = await runCommand(`${binaryPath}.profile-ratio.cmprs`, ["--dcmprs-info"]);
+  expect(info.stdout).toMatch(/filter: (x86|arm64)/);
+  for (const name of ["profile-ratio", "profile-speed"]) {
+    const run = await runCommand(`${binaryPath}.${name}.cmprs`, []);
+    expect(run.stdout).toBe("hello world");
+  }
+
+  // An explicit flag replaces the profile's choice for that option alone
+  const ratioZstd = await pack("profile-ratio-zstd", ["--prefer-ratio", "--algorithm", "zstd"]);
+  expect([ratioZstd.algorithm, ratioZstd.level]).toEqual(["zstd", 19]);
+  const speedLevel = await pack("profile-speed-level", ["--prefer-speed", "--level", "5"]);
+  expect([speedLevel.algorithm, speedLevel.level]).toEqual(["zstd", 5]);
+  const unfiltered = await pack("profile-ratio-unfiltered", ["--prefer-ratio", "--filter", "none"]);
+  expect(unfiltered.algorithm).toBe("xz");
+  const unfilteredInfo = await runCommand(`${binaryPath}.profile-ratio-unfiltered.cmprs`, ["--dcmprs-info"]);
+  expect(unfilteredInfo.stdout).not.toContain("filter:");
+
+  const both = await runCommand(CMPRS_BIN, ["--prefer-ratio", "--prefer-speed", binaryPath]);
+  expect(both.exitCode).toBe(2);
+
+  console.log(`   ✓ Profiles: ratio ${ratio.compressed_size}, default ${plain.compressed_size}, speed ${speed.compressed_size} bytes`);
+});
+
+test("--time-limit stores the payload uncompressed when compression runs over", async () => {
+  // Incompressible data, and a limit that runs out after the first chunk
+  const randomPath = join(tempDir, "random.sh");
+  const random = Buffer.alloc(4 * 1024 * 1024);
+  for (let i = 0; i < random.length; i += 65536) {
+    crypto.getRandomValues(random.subarray(i, i + 65536));
+  }
+  await Bun.write(randomPath, Buffer.concat([Buffer.from("#!/bin/sh\necho random\nexit 0\n"), random]));
+  await chmod(randomPath, 0o755);
+
+  const packedPath = `${randomPath}.cmprs`;
+  const pack = await runCommand(CMPRS_BIN, ["--time-limit", "0", "--level", "19", "--no-auto-store", "--output", packedPath, randomPath]);
+  expect(pack.exitCode).toBe(0);
+  expect(pack.stderr).toContain("time limit, storing the payload uncompressed");
+
+  const run = await runCommand(packedPath, [], { env: { ...process.env, DCMPRS_LOG_LEVEL: "info" } });
+  expect(run.exitCode).toBe(0);
+  expect(run.stdout).toBe("random");
+  expect(run.stderr).toContain("(none)");
+
+  // The shell format can't fall back, so it fails instead
+  const shell = await runCommand(CMPRS_BIN, ["--time-limit", "0", "--format", "shell", randomPath]);
+  expect(shell.exitCode).toBe(1);
+  expect(shell.stderr).toContain("time limit");
+
+  console.log("   ✓ Compression abandoned at the deadline");
+});
+
+test("--marker stores an identifying note that dcmprs ignores", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const defaultPath = `${binaryPath}.marker.cmprs`;
+  const customPath = `${binaryPath}.custom-marker.cmprs`;
+
+  expect((await runCommand(CMPRS_BIN, ["--marker", "--output", defaultPath, binaryPath])).exitCode).toBe(0);
+  const packed = Buffer.from(await Bun.file(defaultPath).arrayBuffer());
+  expect(packed.includes(Buffer.from("packed with cmprs "))).toBe(true);
+
+  expect((await runCommand(CMPRS_BIN, ["--marker=ACME build 42", "--output", customPath, binaryPath])).exitCode).toBe(0);
+  expect(Buffer.from(await Bun.file(customPath).arrayBuffer()).includes(Buffer.from("ACME build 42"))).toBe(true);
+
+  const run = await runCommand(customPath);
+  expect(run.exitCode).toBe(0);
+  expect(run.stdout).toBe("hello world");
+
+  const nonAscii = await runCommand(CMPRS_BIN, ["--marker=héllo", binaryPath]);
+  expect(nonAscii.exitCode).toBe(2);
+  expect(nonAscii.stderr).toContain("printable ASCII");
+
+  console.log("   ✓ Marker stored and ignored at launch");
+});
+
+test.skipIf(platform() !== "linux" || process.getuid() !== 0)("--preserve-caps keeps setcap capabilities through pack and extract", async () => {
+  // A program of its own, so no earlier DCMPRS_EPHEMERAL run extracted it without capabilities
+  const capsPath = join(tempDir, "caps-true");
+  const unique = crypto.getRandomValues(new Uint8Array(16));
+  await Bun.write(capsPath, Buffer.concat([await Bun.file("/bin/true").bytes(), unique]));
+  await chmod(capsPath, 0o755);
+  const setcap = await runCommand("setcap", ["cap_net_bind_service+ep", capsPath]);
+  expect(setcap.exitCode).toBe(0);
+
+  // Left out unless asked for, even with --preserve-xattr
+  const withoutPath = `${capsPath}.xattr.cmprs`;
+  const without = await runCommand(CMPRS_BIN, ["--preserve-xattr", "--output", withoutPath, capsPath]);
+  expect(without.exitCode).toBe(0);
+  expect(without.stderr).toContain("aren't stored without --preserve-caps");
+  const withoutExtracted = `${capsPath}.xattr.extracted`;
+  expect((await runCommand(CMPRS_BIN, ["extract", withoutPath, "--output", withoutExtracted])).exitCode).toBe(0);
+  expect((await runCommand("getcap", [withoutExtracted])).stdout).not.toContain("cap_net_bind_service");
+
+  const packedPath = `${capsPath}.cmprs`;
+  expect((await runCommand(CMPRS_BIN, ["--preserve-caps", "--output", packedPath, capsPath])).exitCode).toBe(0);
+  const extracted = `${capsPath}.extracted`;
+  expect((await runCommand(CMPRS_BIN, ["extract", packedPath, "--output", extracted])).exitCode).toBe(0);
+  expect((await runCommand("getcap", [extracted])).stdout).toContain("cap_net_bind_service=ep");
+  const stubExtracted = `${capsPath}.stub-extracted`;
+  expect((await runCommand(packedPath, ["--dcmprs-extract", stubExtracted])).exitCode).toBe(0);
+  expect((await runCommand("getcap", [stubExtracted])).stdout).toContain("cap_net_bind_service=ep");
+
+  // The temp file dcmprs runs gets them too
+  const ephemeral = await runCommand(packedPath, [], { env: { ...process.env, DCMPRS_EPHEMERAL: "1" } });
+  expect(ephemeral.exitCode).toBe(0);
+  expect(ephemeral.stderr).not.toContain("capabilities");
+  const sha256 = new Bun.CryptoHasher("sha256").update(await Bun.file(capsPath).bytes()).digest("hex");
+  expect((await runCommand("getcap", [join(tmpdir(), `dcmprs-${sha256}`)])).stdout).toContain("cap_net_bind_service=ep");
+
+  console.log("   ✓ File capabilities survived pack and extract");
+});
+
+test.skipIf(platform() === "win32" || process.getuid() === 0)("--needs-root stops non-root launches with a hint, or escalates", async () => {
+  const scriptPath = join(tempDir, "root-only.sh");
+  await Bun.write(scriptPath, "echo ran \"$@\"\n");
+  const packedPath = join(tempDir, "root-only.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--needs-root", "--launcher", "sh", "--output", packedPath, scriptPath])).exitCode).toBe(0);
+
+  const refused = await runCommand(packedPath, ["arg"]);
+  expect(refused.exitCode).toBe(1);
+  expect(refused.stderr).toContain("needs to run as root");
+  expect(refused.stdout).toBe("");
+
+  // DCMPRS_ESCALATE runs the packed file again through the given command
+  const escalatePath = join(tempDir, "fake-sudo");
+  await Bun.write(escalatePath, '#!/bin/sh\necho escalated "$@"\n');
+  await chmod(escalatePath, 0o755);
+  const escalated = await runCommand(packedPath, ["arg"], {
+    env: { ...process.env, DCMPRS_ESCALATE: `${escalatePath} -E` },
+  });
+  expect(escalated.exitCode).toBe(0);
+  expect(escalated.stdout).toBe(`escalated -E ${packedPath} arg`);
+
+  // Split like a shell would, quotes included
+  const quoted = await runCommand(packedPath, ["arg"], {
+    env: { ...process.env, DCMPRS_ESCALATE: `${escalatePath} -u 'svc user'` },
+  });
+  expect(quoted.exitCode).toBe(0);
+  expect(quoted.stdout).toBe(`escalated -u svc user ${packedPath} arg`);
+  const unbalanced = await runCommand(packedPath, ["arg"], {
+    env: { ...process.env, DCMPRS_ESCALATE: "sudo 'oops" },
+  });
+  expect(unbalanced.exitCode).toBe(2);
+  expect(unbalanced.stderr).toContain("DCMPRS_ESCALATE must be a command");
+
+  // Extracting doesn't need root
+  const cat = await runCommand(packedPath, ["--dcmprs-cat"]);
+  expect(cat.exitCode).toBe(0);
+  expect(cat.stdout).toContain("echo ran");
+
+  console.log("   ✓ Non-root launch refused with a hint");
+});
+
+test.skipIf(platform() === "win32" || process.getuid() !== 0)("--needs-root keeps the packed file, so the check holds on later runs", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const packedPath = join(tempDir, "root-kept.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--needs-root", "--output", packedPath, binaryPath])).exitCode).toBe(0);
+  const packedSize = (await stat(packedPath)).size;
+
+  for (let run = 0; run < 2; run++) {
+    expect((await runCommand(packedPath)).exitCode).toBe(0);
+    expect((await stat(packedPath)).size).toBe(packedSize);
+  }
+
+  console.log("   ✓ Root-only program stayed packed across runs");
+});
+
+test("cmprs.toml sets defaults that flags override", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const projectDir = await mkdtemp(join(tempDir, "config-"));
+  const inputPath = join(projectDir, "tool");
+  await Bun.write(inputPath, Bun.file(binaryPath));
+  await chmod(inputPath, 0o755);
+  const pack = (args, cwd = tempDir) =>
+    runCommand(CMPRS_BIN, ["--json-stats", "--no-auto-store", ...args, inputPath], { cwd });
+
+  // Found next to the input, even when running from elsewhere
+  await Bun.write(join(projectDir, "cmprs.toml"), 'level = 19\nsuffix = "packed"\n');
+  const fromConfig = await pack([]);
+  expect(fromConfig.exitCode).toBe(0);
+  const configStats = JSON.parse(fromConfig.stdout);
+  expect(configStats.level).toBe(19);
+  expect(configStats.output).toBe(`${inputPath}.packed`);
+
+  // Flags win over the file
+  const overridden = JSON.parse((await pack(["--level", "5", "--suffix", "flag"])).stdout);
+  expect(overridden.level).toBe(5);
+  expect(overridden.output).toBe(`${inputPath}.flag`);
+
+  // ...and the current directory is searched too
+  await rm(join(projectDir, "cmprs.toml"));
+  const cwdDir = await mkdtemp(join(tempDir, "config-cwd-"));
+  await Bun.write(join(cwdDir, ".cmprs.toml"), 'algorithm = "xz"\nlevel = 1\n');
+  const fromCwd = JSON.parse((await pack(["--suffix", "cwd"], cwdDir)).stdout);
+  expect(fromCwd.algorithm).toBe("xz");
+  expect(fromCwd.level).toBe(1);
+
+  // Unknown keys and bad syntax are errors, not silently ignored
+  await Bun.write(join(projectDir, "cmprs.toml"), "threads = 4\n");
+  const unknown = await pack([]);
+  expect(unknown.exitCode).toBe(2);
+  expect(unknown.stderr).toContain("unknown field `threads`");
+  await Bun.write(join(projectDir, "cmprs.toml"), "level = \n");
+  const malformed = await pack([]);
+  expect(malformed.exitCode).toBe(2);
+  expect(malformed.stderr).toContain("cmprs.toml");
+
+  console.log("   ✓ Config discovered and merged below flags");
+});
+
+test("--no-stub writes a bare payload that extracts", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const payloadPath = `${binaryPath}.cmprz`;
+  const extractedPath = `${binaryPath}.from-cmprz`;
+
+  const packResult = await runCommand(CMPRS_BIN, ["--no-stub", binaryPath]);
+  expect(packResult.exitCode).toBe(0);
+
+  const payload = new Uint8Array(await Bun.file(payloadPath).arrayBuffer());
+  expect(new TextDecoder().decode(payload.slice(0, 16))).toBe("DCMPRS_DATA_HERE");
+  expect((await stat(payloadPath)).mode & 0o111).toBe(0);
+
+  const extractResult = await runCommand(CMPRS_BIN, ["extract", payloadPath, "--output", extractedPath]);
+  expect(extractResult.exitCode).toBe(0);
+  const original = new Uint8Array(await Bun.file(binaryPath).arrayBuffer());
+  const extracted = new Uint8Array(await Bun.file(extractedPath).arrayBuffer());
+  expect(extracted).toEqual(original);
+
+  const conflict = await runCommand(CMPRS_BIN, ["--no-stub", "--split", binaryPath]);
+  expect(conflict.exitCode).toBe(2);
+
+  console.log("   ✓ Stub-less payload starts with the magic and round-trips");
+});
+
+test("An interrupted pack leaves no partial output", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const outputPath = `${binaryPath}.interrupted.cmprs`;
+
+  // xz at its slowest keeps cmprs writing long enough to kill it midway
+  const proc = spawn([CMPRS_BIN, "--xz-preset", "9", "--xz-extreme", "--output", outputPath, binaryPath], {
+    stdio: ["inherit", "pipe", "pipe"],
+  });
+  await Bun.sleep(500);
+  proc.kill("SIGKILL");
+  await proc.exited;
+  expect(await Bun.file(outputPath).exists()).toBe(false);
+
+  // Packing again completes and puts the output in place
+  expect((await runCommand(CMPRS_BIN, ["--output", outputPath, binaryPath])).exitCode).toBe(0);
+  expect(await Bun.file(outputPath).exists()).toBe(true);
+
+  console.log("   ✓ Output only appears once it's complete");
+});
+
+test("--train-dict compresses with a dictionary stored in the header", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.dict.cmprs`;
+  const extractedPath = `${binaryPath}.dict.extracted`;
+
+  const pack = await runCommand(CMPRS_BIN, ["--train-dict", "--output", compressedPath, binaryPath]);
+  expect(pack.exitCode).toBe(0);
+  expect(pack.stderr).toMatch(/byte dictionary, vs \d+ bytes without one \([+-]\d+\.\d%\)/);
+
+  const extract = await runCommand(CMPRS_BIN, ["extract", compressedPath, "--output", extractedPath]);
+  expect(extract.exitCode).toBe(0);
+  const original = new Uint8Array(await Bun.file(binaryPath).arrayBuffer());
+  const extracted = new Uint8Array(await Bun.file(extractedPath).arrayBuffer());
+  expect(extracted).toEqual(original);
+
+  // dcmprs decodes with the dictionary too
+  const result = await runCommand(compressedPath);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toContain("hello world");
+
+  const conflict = await runCommand(CMPRS_BIN, ["--train-dict", "--store", binaryPath]);
+  expect(conflict.exitCode).toBe(2);
+
+  console.log("   ✓ Dictionary-compressed payload round-trips");
+});
+
+test("Tiny inputs are packed inline, with a note that it doesn't pay off", async () => {
+  const tinyPath = join(tempDir, "tiny.sh");
+  await Bun.write(tinyPath, "#!/bin/sh\necho tiny ok\n#" + "x".repeat(480) + "\n");
+  await chmod(tinyPath, 0o755);
+  const compressedPath = `${tinyPath}.cmprs`;
+
+  const pack = await runCommand(CMPRS_BIN, ["--output", compressedPath, tinyPath], {
+    env: { ...process.env, RUST_LOG: "debug" },
+  });
+  expect(pack.exitCode).toBe(0);
+  expect(pack.stderr).toContain("cmprs: note:");
+  expect(pack.stderr).toContain("inline");
+  expect(pack.stderr).not.toContain("Starting compression thread");
+  expect(pack.stderr).not.toContain("Starting SHA256 thread");
+
+  const result = await runCommand(compressedPath);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("tiny ok");
+
+  console.log("   ✓ Tiny input packed without worker threads and still runs");
+});
+
+test("cmprs stubs lists the embedded stubs", async () => {
+  const table = await runCommand(CMPRS_BIN, ["stubs"]);
+  expect(table.exitCode).toBe(0);
+  expect(table.stdout).toMatch(/^main\s+\d+ bytes\s+\S+\s+\(default\)$/m);
+  expect(table.stdout).toMatch(/^minimal\s+\d+ bytes\s+\S+\s+--minimize-stub$/m);
+
+  const json = await runCommand(CMPRS_BIN, ["stubs", "--json"]);
+  expect(json.exitCode).toBe(0);
+  const stubs = JSON.parse(json.stdout);
+  expect(stubs.map((stub) => stub.name).slice(0, 2)).toEqual(["main", "minimal"]);
+  for (const stub of stubs) {
+    expect(stub.size).toBeGreaterThan(0);
+    expect(stub.target.length).toBeGreaterThan(0);
+  }
+  if (platform() === "darwin") {
+    expect(stubs.map((stub) => stub.name)).toContain("macos_universal");
+  }
+
+  console.log("   ✓ Embedded stubs listed with sizes and targets");
+});
+
+test("cmprs inspect-stub reports the size, arch and version of each stub", async () => {
+  const stubs = JSON.parse((await runCommand(CMPRS_BIN, ["stubs", "--json"])).stdout);
+
+  const table = await runCommand(CMPRS_BIN, ["inspect-stub"]);
+  expect(table.exitCode).toBe(0);
+  const mainSize = stubs.find((stub) => stub.name === "main").size;
+  expect(table.stdout).toMatch(new RegExp(`^main\\s+${mainSize} bytes\\s+\\S`, "m"));
+
+  const json = await runCommand(CMPRS_BIN, ["inspect-stub", "--json"]);
+  expect(json.exitCode).toBe(0);
+  const inspections = JSON.parse(json.stdout);
+  expect(inspections.map((stub) => stub.name)).toEqual(stubs.map((stub) => stub.name));
+  const main = inspections.find((stub) => stub.name === "main");
+  expect(main.size).toBe(mainSize);
+  if (platform() === "linux") {
+    expect(main.format).toBe("ELF 64-bit");
+    expect(main.arch).toBe({ x64: "x86_64", arm64: "aarch64" }[process.arch] ?? main.arch);
+  } else {
+    expect(main.format).toStartWith("Mach-O");
+    expect(main.arch.length).toBeGreaterThan(0);
+  }
+  expect(typeof main.stripped).toBe("boolean");
+  expect(main.version).toMatch(/^\d+\.\d+\.\d+$/);
+
+  console.log("   ✓ Embedded stubs parsed for size, format, arch and version");
+});
+
+test("--stub-only writes the embedded stub alone, executable", async () => {
+  const stubs = JSON.parse((await runCommand(CMPRS_BIN, ["stubs", "--json"])).stdout);
+  const binaryPath = await createTestBinary(tempDir);
+
+  for (const [name, flags] of [["main", []], ["minimal", ["--minimize-stub"]]]) {
+    const loaderPath = join(tempDir, `loader-${name}`);
+    const result = await runCommand(CMPRS_BIN, ["--stub-only", ...flags, "--output", loaderPath]);
+    expect(result.exitCode).toBe(0);
+    expect((await stat(loaderPath)).mode & 0o111).toBe(0o111);
+    const loader = new Uint8Array(await Bun.file(loaderPath).arrayBuffer());
+    expect(loader.length).toBe(stubs.find((stub) => stub.name === name).size);
+
+    // The same bytes a packed file starts with
+    const packedPath = join(tempDir, `stub-only-${name}.cmprs`);
+    await runCommand(CMPRS_BIN, [...flags, "--output", packedPath, binaryPath]);
+    await runCommand(CMPRS_BIN, ["split", packedPath]);
+    const prepended = new Uint8Array(await Bun.file(`${packedPath}.stub`).arrayBuffer());
+    expect(Buffer.from(loader).equals(Buffer.from(prepended))).toBe(true);
+  }
+
+  const noOutput = await runCommand(CMPRS_BIN, ["--stub-only"]);
+  expect(noOutput.exitCode).toBe(2);
+
+  console.log("   ✓ Stub written byte for byte");
+});
+
+test("--verify reads the fresh output back and checks it decompresses to its SHA256", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const packedPath = join(tempDir, "verified.cmprs");
+
+  const pack = await runCommand(CMPRS_BIN, ["--verify", "--output", packedPath, binaryPath], {
+    env: { ...process.env, RUST_LOG: "info" },
+  });
+  expect(pack.exitCode).toBe(0);
+  expect(pack.stderr).toContain(`Verified ${packedPath} decompresses to its SHA256`);
+
+  const result = await runCommand(packedPath);
+  expect(result.exitCode).toBe(0);
+
+  console.log("   ✓ Output passed the self-check and runs");
+});
+
+test("A packed binary packed again is unwrapped in memory and exec'd once", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const innerPath = join(tempDir, "nested-inner.cmprs");
+  const outerPath = join(tempDir, "nested-outer.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--output", innerPath, binaryPath])).exitCode).toBe(0);
+  expect((await runCommand(CMPRS_BIN, ["--output", outerPath, innerPath])).exitCode).toBe(0);
+
+  const result = await runCommand(outerPath, ["nested"], {
+    env: { ...process.env, DCMPRS_LOG_LEVEL: "debug" },
+  });
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("hello world");
+  expect(result.stderr).toContain("unwrapping layer 1 in memory");
+  expect(result.stderr.match(/Executing decompressed program/g)).toHaveLength(1);
+
+  // The packed file is replaced by the innermost program, not the inner layer
+  const original = Buffer.from(await Bun.file(binaryPath).arrayBuffer());
+  const replaced = Buffer.from(await Bun.file(outerPath).arrayBuffer());
+  expect(replaced.equals(original)).toBe(true);
+
+  console.log("   ✓ Doubly packed binary ran the original with a single exec");
+});
+
+test("DCMPRS_TRACE_FILE gets a JSON line with the launch's stage timings and sizes", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const packedPath = join(tempDir, "traced.cmprs");
+  const tracePath = join(tempDir, "launch-trace.jsonl");
+  expect((await runCommand(CMPRS_BIN, ["--output", packedPath, binaryPath])).exitCode).toBe(0);
+  // The first run replaces the packed file
+  const packedSize = (await stat(packedPath)).size;
+
+  const result = await runCommand(packedPath, [], {
+    env: { ...process.env, DCMPRS_TRACE_FILE: tracePath },
+  });
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("hello world");
+
+  const lines = (await Bun.file(tracePath).text()).trim().split("\n");
+  expect(lines).toHaveLength(1);
+  const trace = JSON.parse(lines[0]);
+  for (const stage of ["read", "magic_scan", "decompress", "temp_write", "exec_prep", "total"]) {
+    expect(typeof trace[`${stage}_us`]).toBe("number");
+  }
+  expect(trace.packed_size).toBe(packedSize);
+  expect(trace.decompressed_size).toBe((await stat(binaryPath)).size);
+  expect(trace.algorithm).toBe("zstd");
+
+  console.log(`   ✓ Launch took ${trace.total_us}us, ${trace.decompress_us}us of it decompressing`);
+});
+
+test("CMPRS_LOG_FORMAT and DCMPRS_LOG_FORMAT write the logs as JSON lines", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const packedPath = join(tempDir, "json-logs.cmprs");
+  const parseLogs = (stderr) => stderr
+    .trim()
+    .split("\n")
+    // cmprs' notes and warnings are for people, not log records
+    .filter((line) => !line.startsWith("cmprs: "))
+    .map((line) => JSON.parse(line));
+
+  const pack = await runCommand(CMPRS_BIN, ["--json-stats", "--output", packedPath, binaryPath], {
+    env: { ...process.env, RUST_LOG: "debug", CMPRS_LOG_FORMAT: "json" },
+  });
+  expect(pack.exitCode).toBe(0);
+  const packLogs = parseLogs(pack.stderr);
+  for (const line of packLogs) {
+    expect(Object.keys(line)).toEqual(expect.arrayContaining(["ts_ms", "level", "target", "message"]));
+  }
+  const compress = packLogs.find((line) => line.stage === "compress");
+  expect(compress.bytes).toBe((await stat(binaryPath)).size);
+  expect(typeof compress.duration_ms).toBe("number");
+
+  const launch = await runCommand(packedPath, [], {
+    env: { ...process.env, DCMPRS_LOG_LEVEL: "debug", DCMPRS_LOG_FORMAT: "json" },
+  });
+  expect(launch.exitCode).toBe(0);
+  expect(launch.stdout).toBe("hello world");
+  const launchLogs = parseLogs(launch.stderr);
+  expect(launchLogs.map((line) => line.level)).toContain("DEBUG");
+  const stages = launchLogs.filter((line) => line.stage).map((line) => line.stage);
+  expect(stages).toEqual(["read", "decompress", "temp_write"]);
+
+  const human = await runCommand(CMPRS_BIN, ["--output", packedPath, binaryPath], {
+    env: { ...process.env, RUST_LOG: "info" },
+  });
+  expect(human.stderr).toContain(" INFO ");
+  expect(human.stderr).not.toContain("\"stage\"");
+
+  console.log(`   ✓ ${packLogs.length + launchLogs.length} log lines parsed as JSON`);
+});
+
+test("--sha256 embeds a hash computed upstream without hashing the input", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const sha256 = new Bun.CryptoHasher("sha256").update(await Bun.file(binaryPath).arrayBuffer()).digest("hex");
+  const packedPath = join(tempDir, "given-hash.cmprs");
+
+  const pack = await runCommand(CMPRS_BIN, ["--sha256", sha256, "--output", packedPath, binaryPath], {
+    env: { ...process.env, RUST_LOG: "debug" },
+  });
+  expect(pack.exitCode).toBe(0);
+  expect(pack.stderr).toContain("Using the SHA256 from --sha256, skipping hashing");
+  expect(pack.stderr).not.toContain("Starting SHA256 thread");
+
+  const result = await runCommand(packedPath);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("hello world");
+
+  console.log("   ✓ Packed with the given SHA256 and no hash thread");
+});
+
+test("--verify-hash fails when --sha256 doesn't match the input", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const packedPath = join(tempDir, "wrong-hash.cmprs");
+
+  const pack = await runCommand(CMPRS_BIN, ["--sha256", "00".repeat(32), "--verify-hash", "--output", packedPath, binaryPath]);
+  expect(pack.exitCode).toBe(2);
+  expect(pack.stderr).toContain("doesn't match the input");
+  expect(await Bun.file(packedPath).exists()).toBe(false);
+
+  console.log("   ✓ Wrong SHA256 rejected under --verify-hash");
+});
+
+test("The pack summary is colored only when allowed, never with NO_COLOR", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const packedPath = join(tempDir, "colors.cmprs");
+  const ansi = /\x1b\[/;
+
+  // stderr is a pipe here, so CLICOLOR_FORCE stands in for a terminal
+  const forced = await runCommand(CMPRS_BIN, ["--output", packedPath, binaryPath], {
+    env: { ...process.env, CLICOLOR_FORCE: "1", NO_COLOR: "" },
+  });
+  expect(forced.exitCode).toBe(0);
+  expect(forced.stderr).toMatch(ansi);
+  expect(forced.stderr).toContain("net");
+
+  for (const [env, args] of [
+    [{ NO_COLOR: "1" }, []],
+    [{ NO_COLOR: "1", CLICOLOR_FORCE: "1" }, []],
+    [{ CLICOLOR_FORCE: "1" }, ["--no-color"]],
+  ]) {
+    const result = await runCommand(CMPRS_BIN, [...args, "--output", packedPath, binaryPath], {
+      env: { ...process.env, ...env },
+    });
+    expect(result.exitCode).toBe(0);
+    expect(result.stderr).toContain(`cmprs: packed ${binaryPath} to ${packedPath}`);
+    expect(result.stderr).not.toMatch(ansi);
+  }
+
+  console.log("   ✓ No ANSI codes with NO_COLOR or --no-color");
+});
+
+test("--entry-args are passed to the program ahead of the runtime arguments", async () => {
+  const scriptPath = join(tempDir, "print-args.sh");
+  await Bun.write(scriptPath, '#!/bin/sh\nfor arg; do echo "[$arg]"; done\n');
+  await chmod(scriptPath, 0o755);
+  const packedPath = join(tempDir, "print-args.cmprs");
+
+  const pack = await runCommand(CMPRS_BIN, [
+    "--entry-args", "--config '/etc/my foo.toml'",
+    "--output", packedPath, scriptPath,
+  ]);
+  expect(pack.exitCode).toBe(0);
+
+  const result = await runCommand(packedPath, ["run", "two words"]);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("[--config]\n[/etc/my foo.toml]\n[run]\n[two words]");
+
+  // Still packed after the first run, so the arguments aren't lost
+  const again = await runCommand(packedPath, ["again"]);
+  expect(again.exitCode).toBe(0);
+  expect(again.stdout).toBe("[--config]\n[/etc/my foo.toml]\n[again]");
+
+  const unbalanced = await runCommand(CMPRS_BIN, ["--entry-args", "'oops", "--output", packedPath, scriptPath]);
+  expect(unbalanced.exitCode).toBe(2);
+
+  console.log("   ✓ Baked-in arguments came before the runtime ones");
+});
+
+test.skipIf(platform() !== "linux")("Packing a dynamically linked binary lists the libraries it needs", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const pack = await runCommand(CMPRS_BIN, ["--output", join(tempDir, "dynamic.cmprs"), binaryPath]);
+  expect(pack.exitCode).toBe(0);
+  expect(pack.stderr).toContain(`cmprs: note: ${binaryPath} is dynamically linked, machines running it need`);
+  expect(pack.stderr).toContain("libc.so.6");
+
+  console.log("   ✓ Shared library dependencies listed");
+});
+
+test("Packing a statically linked binary says so", async () => {
+  // The smallest x86_64 ELF executable: one loadable segment that calls exit(0)
+  const staticPath = join(tempDir, "static-elf");
+  await Bun.write(staticPath, Buffer.from(
+    "7f454c4602010100000000000000000002003e000100000078004000000000004000000000000000" +
+    "000000000000000000000000400038000100000000000000010000000500000000000000000000000000" +
+    "400000000000000040000000000081000000000000008100000000000000001000000000000031ffb83c" +
+    "0000000f05",
+    "hex",
+  ));
+  await chmod(staticPath, 0o755);
+
+  const pack = await runCommand(CMPRS_BIN, ["--output", join(tempDir, "static-elf.cmprs"), staticPath], {
+    env: { ...process.env, RUST_LOG: "info" },
+  });
+  expect(pack.exitCode).toBe(0);
+  expect(pack.stderr).toContain(`${staticPath} is statically linked`);
+  expect(pack.stderr).not.toContain("is dynamically linked");
+
+  console.log("   ✓ Static binary recognized");
+});
+
+test("--stub-file prepends the given loader, the embedded stub giving the same output", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const defaultPath = join(tempDir, "default-stub.cmprs");
+  const customPath = join(tempDir, "custom-stub.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--output", defaultPath, binaryPath])).exitCode).toBe(0);
+
+  // The embedded stub is everything in front of the magic header
+  const packed = Buffer.from(await Bun.file(defaultPath).arrayBuffer());
+  const stubPath = join(tempDir, "dcmprs-stub");
+  await Bun.write(stubPath, packed.subarray(0, packed.indexOf("DCMPRS_DATA_HERE;;")));
+  await chmod(stubPath, 0o755);
+
+  const pack = await runCommand(CMPRS_BIN, ["--stub-file", stubPath, "--output", customPath, binaryPath]);
+  expect(pack.exitCode).toBe(0);
+  expect(pack.stderr).not.toContain("doesn't look like a dcmprs stub");
+  const custom = Buffer.from(await Bun.file(customPath).arrayBuffer());
+  expect(custom.equals(packed)).toBe(true);
+
+  const result = await runCommand(customPath);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("hello world");
+
+  // A packed file as the stub would hide the new payload behind its own
+  const nested = await runCommand(CMPRS_BIN, ["--stub-file", defaultPath, "--output", customPath, binaryPath]);
+  expect(nested.exitCode).toBe(2);
+  expect(nested.stderr).toContain("already has a payload packed into it");
+
+  console.log("   ✓ Explicit stub produced the same output as the embedded one");
+});
+
+test("--single-threaded packs on the main thread to the same bytes as the threaded path", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const threadedPath = join(tempDir, "threaded.cmprs");
+  const singlePath = join(tempDir, "single-threaded.cmprs");
+
+  const threaded = await runCommand(CMPRS_BIN, ["--level", "9", "--output", threadedPath, binaryPath], {
+    env: { ...process.env, RUST_LOG: "debug" },
+  });
+  expect(threaded.exitCode).toBe(0);
+  expect(threaded.stderr).toContain("Starting SHA256 thread");
+
+  const single = await runCommand(CMPRS_BIN, ["--single-threaded", "--level", "9", "--output", singlePath, binaryPath], {
+    env: { ...process.env, RUST_LOG: "debug" },
+  });
+  expect(single.exitCode).toBe(0);
+  expect(single.stderr).toContain("Hashing and compressing the input inline");
+  expect(single.stderr).not.toContain("Starting SHA256 thread");
+  expect(single.stderr).not.toContain("Starting compression thread");
+
+  const threadedBytes = Buffer.from(await Bun.file(threadedPath).arrayBuffer());
+  const singleBytes = Buffer.from(await Bun.file(singlePath).arrayBuffer());
+  expect(singleBytes.equals(threadedBytes)).toBe(true);
+
+  console.log("   ✓ Single-threaded output is byte-identical");
+});
+
+test("--deterministic-threads packs to the same bytes with any number of threads", async () => {
+  const inputPath = join(tempDir, "threads-input");
+  // Large enough for zstd to cut into several jobs
+  const input = new Uint8Array(24 * 1024 * 1024);
+  for (let i = 0; i < input.length; i++) {
+    input[i] = (i % 251) ^ (i >> 12);
+  }
+  await Bun.write(inputPath, input);
+  await chmod(inputPath, 0o755);
+
+  const packWith = async threads => {
+    const packedPath = join(tempDir, `threads-${threads}.cmprs`);
+    const pack = await runCommand(CMPRS_BIN, ["--deterministic-threads", `${threads}`, "--output", packedPath, inputPath]);
+    expect(pack.exitCode).toBe(0);
+    return Buffer.from(await Bun.file(packedPath).arrayBuffer());
+  };
+  const one = await packWith(1);
+  expect((await packWith(4)).equals(one)).toBe(true);
+  expect((await packWith(8)).equals(one)).toBe(true);
+
+  const check = await runCommand(join(tempDir, "threads-8.cmprs"), ["--dcmprs-check"]);
+  expect(check.exitCode).toBe(0);
+
+  const xz = await runCommand(CMPRS_BIN, ["--deterministic-threads", "4", "--algorithm", "xz", "--output", join(tempDir, "threads-xz.cmprs"), inputPath]);
+  expect(xz.exitCode).toBe(2);
+  expect(xz.stderr).toContain("only apply to zstd");
+
+  console.log("   ✓ 1, 4 and 8 threads gave byte-identical output");
+});
+
+test.skipIf(platform() === "win32")("The pack reports its peak memory, which grows with the input", async () => {
+  const peakFor = async (name, size) => {
+    const inputPath = join(tempDir, name);
+    const input = new Uint8Array(size);
+    for (let i = 0; i < size; i += 65536) {
+      crypto.getRandomValues(input.subarray(i, i + 65536));
+    }
+    await Bun.write(inputPath, input);
+    await chmod(inputPath, 0o755);
+    const pack = await runCommand(CMPRS_BIN, ["--json-stats", "--output", `${inputPath}.cmprs`, inputPath]);
+    expect(pack.exitCode).toBe(0);
+    return JSON.parse(pack.stdout).peak_rss;
+  };
+
+  const small = await peakFor("rss-small", 64 * 1024);
+  const large = await peakFor("rss-large", 64 * 1024 * 1024);
+  expect(small).toBeGreaterThan(0);
+  expect(large).toBeGreaterThan(small + 64 * 1024 * 1024);
+
+  const summary = await runCommand(CMPRS_BIN, ["--output", join(tempDir, "rss-summary.cmprs"), join(tempDir, "rss-small")]);
+  expect(summary.stderr).toMatch(/memory +[0-9.]+ MiB peak resident/);
+
+  console.log(`   ✓ Peak RSS ${small} bytes for 64 KiB, ${large} bytes for 64 MiB`);
+});
+
+test("DCMPRS_KEEP_TEMP=1 keeps the extracted program and says where it is", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const packedPath = join(tempDir, "keep-temp.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--output", packedPath, binaryPath])).exitCode).toBe(0);
+
+  const env = { ...process.env, DCMPRS_KEEP_TEMP: "1" };
+  const result = await runCommand(packedPath, [], { env });
+  expect(result.exitCode).toBe(0);
+  const keptPath = result.stderr.match(/keeping the extracted program at (.+)/)[1];
+  expect(await Bun.file(keptPath).exists()).toBe(true);
+  expect(Buffer.from(await Bun.file(keptPath).arrayBuffer()).equals(Buffer.from(await Bun.file(binaryPath).arrayBuffer()))).toBe(true);
+  await rm(keptPath);
+
+  // Even when exec fails, which otherwise cleans it up
+  const brokenPath = join(tempDir, "keep-temp-broken.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--launcher", "no-such-launcher", "--output", brokenPath, binaryPath])).exitCode).toBe(0);
+  const broken = await runCommand(brokenPath, [], { env });
+  expect(broken.exitCode).not.toBe(0);
+  const brokenKeptPath = broken.stderr.match(/keeping the extracted program at (.+)/)[1];
+  expect(await Bun.file(brokenKeptPath).exists()).toBe(true);
+  await rm(brokenKeptPath);
+
+  console.log("   ✓ Extracted program kept after the run");
+});
+
+test.skipIf(platform() !== "linux" || !Bun.which("cc"))("A failing chmod of the output only warns", async () => {
+  // Make fchmod fail the way it does on filesystems without mode bits
+  const shimSource = join(tempDir, "failing-fchmod.c");
+  const shimPath = join(tempDir, "failing-fchmod.so");
+  await Bun.write(shimSource, [
+    "#include <errno.h>",
+    "#include <sys/types.h>",
+    "int fchmod(int fd, mode_t mode) { (void)fd; (void)mode; errno = EPERM; return -1; }",
+    "",
+  ].join("\n"));
+  expect((await runCommand("cc", ["-shared", "-fPIC", "-o", shimPath, shimSource])).exitCode).toBe(0);
+
+  const binaryPath = await createTestBinary(tempDir);
+  const packedPath = join(tempDir, "no-chmod.cmprs");
+  const pack = await runCommand(CMPRS_BIN, ["--output", packedPath, binaryPath], {
+    env: { ...process.env, LD_PRELOAD: shimPath },
+  });
+  expect(pack.exitCode).toBe(0);
+  expect(pack.stderr).toContain(`cmprs: warning: can't set the permissions of ${packedPath}`);
+  expect(pack.stderr).toContain("run `chmod 755` on it");
+
+  // Everything but the mode made it
+  await chmod(packedPath, 0o755);
+  const result = await runCommand(packedPath);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("hello world");
+
+  console.log("   ✓ Pack survived a failing chmod with a warning");
+});
+
+test("split writes the stub and the payload, which concatenate back to the packed file", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = join(tempDir, "split-me.cmprs");
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, binaryPath]);
+
+  const split = await runCommand(CMPRS_BIN, ["split", compressedPath]);
+  expect(split.exitCode).toBe(0);
+
+  const packed = new Uint8Array(await Bun.file(compressedPath).arrayBuffer());
+  const stub = new Uint8Array(await Bun.file(`${compressedPath}.stub`).arrayBuffer());
+  const payload = new Uint8Array(await Bun.file(`${compressedPath}.payload`).arrayBuffer());
+  expect(new TextDecoder().decode(payload.subarray(0, 16))).toBe("DCMPRS_DATA_HERE");
+
+  const reassembled = new Uint8Array(stub.length + payload.length);
+  reassembled.set(stub);
+  reassembled.set(payload, stub.length);
+  expect(Buffer.from(reassembled).equals(Buffer.from(packed))).toBe(true);
+
+  // Existing halves are left alone
+  const again = await runCommand(CMPRS_BIN, ["split", compressedPath]);
+  expect(again.exitCode).not.toBe(0);
+  expect(again.stderr).toContain("already exists");
+
+  console.log("   ✓ Stub and payload reassemble byte-for-byte");
+});
+
+test("DCMPRS_EXEC_WRAPPER runs the program under the wrapper", async () => {
+  const scriptPath = join(tempDir, "show-wrapped");
+  await Bun.write(scriptPath, '#!/bin/sh\necho "wrapped=$WRAPPED args=$*"\n');
+  await chmod(scriptPath, 0o755);
+  const compressedPath = join(tempDir, "show-wrapped.cmprs");
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, scriptPath]);
+
+  // Quoted words stay together, and nothing in the value reaches a shell
+  const result = await runCommand(compressedPath, ["one", "two"], {
+    env: { ...process.env, DCMPRS_EXEC_WRAPPER: "env 'WRAPPED=a b; exit 9'" },
+  });
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("wrapped=a b; exit 9 args=one two");
+
+  console.log("   ✓ Program ran under the wrapper");
+});
+
+test("The header records the cmprs version that packed the file", async () => {
+  const manifest = await Bun.file(join(import.meta.dir, "..", "cmprs", "Cargo.toml")).text();
+  const version = manifest.match(/^version = "(.+)"$/m)[1];
+
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = join(tempDir, "versioned.cmprs");
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, binaryPath]);
+
+  const extract = await runCommand(CMPRS_BIN, ["extract", compressedPath, "--output", join(tempDir, "versioned")], {
+    env: { ...process.env, RUST_LOG: "info" },
+  });
+  expect(extract.exitCode).toBe(0);
+  expect(extract.stderr).toContain(`Packed by cmprs ${version}`);
+
+  console.log(`   ✓ Packed file records cmprs ${version}`);
+});
+
+test("--record-path records where the input was packed from, rewritten by --path-prefix-map", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const packedPath = join(tempDir, "recorded-path.cmprs");
+  const info = async (...args) => {
+    expect((await runCommand(CMPRS_BIN, [...args, "--output", packedPath, binaryPath])).exitCode).toBe(0);
+    return (await runCommand(packedPath, ["--dcmprs-info"])).stdout;
+  };
+
+  // Off unless asked for, it's a build machine path
+  expect(await info()).not.toContain("packed from:");
+  expect(await info("--record-path")).toContain(`packed from: ${binaryPath}\n`);
+  expect(await info("--record-path", "--path-prefix-map", `${tempDir}=/src`)).toContain("packed from: /src/test-binary\n");
+  // The last matching map wins
+  const both = await info("--record-path", "--path-prefix-map", `${tempDir}=/first`, "--path-prefix-map", `${tempDir}=/second`);
+  expect(both).toContain("packed from: /second/test-binary\n");
+
+  const extract = await runCommand(CMPRS_BIN, ["extract", packedPath, "--output", `${packedPath}.out`], {
+    env: { ...process.env, RUST_LOG: "info" },
+  });
+  expect(extract.stderr).toContain("Packed from /second/test-binary");
+
+  const withoutRecord = await runCommand(CMPRS_BIN, ["--path-prefix-map", "/a=/b", binaryPath]);
+  expect(withoutRecord.exitCode).toBe(2);
+
+  console.log("   ✓ The recorded path follows the prefix map");
+});
+
+test("--min-loader-version refuses to run under an older dcmprs", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const tooNewPath = join(tempDir, "too-new.cmprs");
+  const pack = await runCommand(CMPRS_BIN, ["--min-loader-version", "999.0.0", "--output", tooNewPath, binaryPath]);
+  expect(pack.exitCode).toBe(0);
+  expect(pack.stderr).toContain("won't start until it's restubbed");
+
+  const run = await runCommand(tooNewPath);
+  expect(run.exitCode).toBe(4);
+  expect(run.stderr).toMatch(/this payload requires dcmprs >= 999\.0\.0, but this loader is [\d.]+; please restub/);
+  expect((await runCommand(tooNewPath, ["--dcmprs-info"])).stdout).toContain("needs dcmprs: >= 999.0.0");
+
+  const currentPath = join(tempDir, "current-loader.cmprs");
+  await runCommand(CMPRS_BIN, ["--min-loader-version", "0.0.1", "--output", currentPath, binaryPath]);
+  expect((await runCommand(currentPath)).exitCode).toBe(0);
+
+  const invalid = await runCommand(CMPRS_BIN, ["--min-loader-version", "latest", "--output", join(tempDir, "x"), binaryPath]);
+  expect(invalid.exitCode).toBe(2);
+
+  console.log("   ✓ Payloads needing a newer loader refuse to start");
+});
+
+test("--strip-env removes matching variables before the program starts", async () => {
+  const scriptPath = join(tempDir, "show-env");
+  await Bun.write(scriptPath, '#!/bin/sh\necho "preload=$LD_PRELOAD proxy=$HTTPS_PROXY kept=$KEPT"\n');
+  await chmod(scriptPath, 0o755);
+  const compressedPath = join(tempDir, "show-env.cmprs");
+  await runCommand(CMPRS_BIN, ["--strip-env", "LD_PRELOAD,*_PROXY", "--output", compressedPath, scriptPath]);
+
+  // A preload that doesn't exist only makes the loader complain, on stderr
+  const env = { ...process.env, LD_PRELOAD: "/nonexistent/inject.so", HTTPS_PROXY: "http://proxy", KEPT: "yes" };
+  // The second run goes through the stub too, which a replaced file wouldn't
+  for (let run = 0; run < 2; run++) {
+    const result = await runCommand(compressedPath, [], { env });
+    expect(result.exitCode).toBe(0);
+    expect(result.stdout).toBe("preload= proxy= kept=yes");
+  }
+
+  console.log("   ✓ Stripped variables never reached the program, on any run");
+});
+
+test("--redundant recovers from a corrupted first copy of the payload", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = join(tempDir, "redundant.cmprs");
+  const pack = await runCommand(CMPRS_BIN, ["--redundant", "--json-stats", "--output", compressedPath, binaryPath]);
+  expect(pack.exitCode).toBe(0);
+  const stats = JSON.parse(pack.stdout);
+
+  // The payload is the last compressed_size bytes, two copies of the same length
+  const packed = new Uint8Array(await Bun.file(compressedPath).arrayBuffer());
+  const payloadStart = packed.length - stats.compressed_size;
+  const copyLength = stats.compressed_size / 2;
+  expect(Buffer.from(packed.subarray(payloadStart, payloadStart + copyLength)).equals(Buffer.from(packed.subarray(payloadStart + copyLength)))).toBe(true);
+
+  packed.fill(0x55, payloadStart + 100, payloadStart + 400);
+  await Bun.write(compressedPath, packed);
+  await chmod(compressedPath, 0o755);
+
+  const result = await runCommand(compressedPath);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("hello world");
+  expect(result.stderr).toContain("recovering it from its redundant copy");
+
+  console.log("   ✓ Ran from the redundant copy");
+});
+
+test("algorithms lists the compiled-in codecs", async () => {
+  const result = await runCommand(CMPRS_BIN, ["algorithms", "--json"]);
+  expect(result.exitCode).toBe(0);
+  const algorithms = JSON.parse(result.stdout);
+  expect(algorithms.map((algorithm) => algorithm.name)).toEqual(["zstd", "none", "xz", "gzip"]);
+  expect(algorithms.find((algorithm) => algorithm.name === "zstd")).toEqual({
+    id: 0,
+    name: "zstd",
+    selected_by: null,
+    levels: "1-22, fast -22 to -1",
+  });
+
+  const table = await runCommand(CMPRS_BIN, ["algorithms"]);
+  expect(table.stdout).toContain("--xz-preset");
+
+  console.log("   ✓ Listed zstd, none, xz and gzip");
+});
+
+test("bench-startup times the launch of each compiled-in codec", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const result = await runCommand(CMPRS_BIN, ["bench-startup", "--runs", "2", "--json", binaryPath]);
+  expect(result.exitCode).toBe(0);
+  const benches = JSON.parse(result.stdout);
+  const algorithms = JSON.parse((await runCommand(CMPRS_BIN, ["algorithms", "--json"])).stdout);
+  expect(benches.map((bench) => bench.algorithm)).toEqual(algorithms.map((algorithm) => algorithm.name));
+
+  const inputSize = (await stat(binaryPath)).size;
+  for (const bench of benches) {
+    expect(bench.payload_size).toBeGreaterThan(0);
+    expect(bench.size).toBeGreaterThan(bench.payload_size);
+    expect(bench.ratio).toBeCloseTo(bench.payload_size / inputSize, 5);
+    expect(bench.launch_ms_min).toBeGreaterThan(0);
+    expect(bench.launch_ms_min).toBeLessThanOrEqual(bench.launch_ms_median);
+    expect(bench.launch_ms_median).toBeLessThan(60_000);
+  }
+  const stored = benches.find((bench) => bench.algorithm === "none");
+  expect(stored.payload_size).toBe(inputSize);
+  expect(benches.find((bench) => bench.algorithm === "zstd").payload_size).toBeLessThan(inputSize);
+
+  const table = await runCommand(CMPRS_BIN, ["bench-startup", "--runs", "1", binaryPath]);
+  expect(table.exitCode).toBe(0);
+  const rows = table.stdout.split("\n");
+  expect(rows[0]).toMatch(/^algorithm\s+level\s+size\s+payload\s+ratio\s+median\s+fastest$/);
+  expect(rows.slice(1).map((row) => row.split(/\s+/)[0])).toEqual(algorithms.map((algorithm) => algorithm.name));
+
+  const noRuns = await runCommand(CMPRS_BIN, ["bench-startup", "--runs", "0", binaryPath]);
+  expect(noRuns.exitCode).toBe(2);
+
+  console.log(`   ✓ Timed ${benches.length} codecs`);
+});
+
+test("--chunk-size doesn't change the output", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const outputs = [];
+  for (const chunkSize of ["1K", "64K", "4M"]) {
+    const outputPath = join(tempDir, `chunked-${chunkSize}.cmprs`);
+    const pack = await runCommand(CMPRS_BIN, ["--chunk-size", chunkSize, "--level", "19", "--output", outputPath, binaryPath], {
+      env: { ...process.env, SOURCE_DATE_EPOCH: "0" },
+    });
+    expect(pack.exitCode).toBe(0);
+    outputs.push(Buffer.from(await Bun.file(outputPath).arrayBuffer()));
+  }
+  expect(outputs[0].equals(outputs[1])).toBe(true);
+  expect(outputs[0].equals(outputs[2])).toBe(true);
+
+  const invalid = await runCommand(CMPRS_BIN, ["--chunk-size", "0", binaryPath]);
+  expect(invalid.exitCode).not.toBe(0);
+
+  console.log("   ✓ Same output for every chunk size");
+});
+
+test("--dcmprs-info prints the header's metadata without running the program", async () => {
+  const markerPath = join(tempDir, "info-ran");
+  const scriptPath = join(tempDir, "info-me");
+  await Bun.write(scriptPath, `#!/bin/sh\ntouch ${markerPath}\n`);
+  await chmod(scriptPath, 0o755);
+  const compressedPath = join(tempDir, "info-me.cmprs");
+  await runCommand(CMPRS_BIN, ["--output", compressedPath, scriptPath]);
+  const sha256 = new Bun.CryptoHasher("sha256").update(await Bun.file(scriptPath).arrayBuffer()).digest("hex");
+
+  const info = await runCommand(compressedPath, ["--dcmprs-info"]);
+  expect(info.exitCode).toBe(0);
+  expect(info.stdout).toContain("name: info-me");
+  expect(info.stdout).toContain(`size: ${Bun.file(scriptPath).size} bytes`);
+  expect(info.stdout).toContain(`sha256: ${sha256}`);
+  expect(info.stdout).toMatch(/^algorithm: \w+$/m);
+  expect(info.stdout).toMatch(/^packed by: cmprs \d+\.\d+\.\d+/m);
+  expect(await Bun.file(markerPath).exists()).toBe(false);
+
+  // --no-stub-flags hands it to the program instead
+  const forwardedPath = join(tempDir, "info-forwarded.cmprs");
+  await runCommand(CMPRS_BIN, ["--no-stub-flags", "--output", forwardedPath, scriptPath]);
+  const forwarded = await runCommand(forwardedPath, ["--dcmprs-info"]);
+  expect(forwarded.exitCode).toBe(0);
+  expect(await Bun.file(markerPath).exists()).toBe(true);
+
+  console.log("   ✓ Printed the metadata without running the program");
+});
+
+test.skipIf(!Bun.which("zstd"))("The zstd frame records its content size and a checksum", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = join(tempDir, "framed.cmprs");
+  // --split leaves the bare zstd frame in framed.cmprs.data
+  await runCommand(CMPRS_BIN, ["--split", "--no-auto-store", "--output", compressedPath, binaryPath]);
+
+  const list = await runCommand("zstd", ["-lv", `${compressedPath}.data`]);
+  expect(list.exitCode).toBe(0);
+  expect(list.stdout).toContain(`(${Bun.file(binaryPath).size} B)`);
+  expect(list.stdout).toContain("Check: XXH64");
+
+  const result = await runCommand(compressedPath);
+  expect(result.stdout).toBe("hello world");
+
+  console.log("   ✓ zstd -l reports the original size");
+});
+
+test.skipIf(platform() === "win32")("Packing into a FIFO streams the output to its reader", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const fifoPath = join(tempDir, "packed.fifo");
+  expect((await runCommand("mkfifo", [fifoPath])).exitCode).toBe(0);
+
+  const reader = spawn(["cat", fifoPath], { stdout: "pipe" });
+  const received = new Response(reader.stdout).arrayBuffer();
+  const pack = await runCommand(CMPRS_BIN, ["--output", fifoPath, binaryPath]);
+  expect(pack.exitCode).toBe(0);
+  await reader.exited;
+
+  // Byte for byte what a regular output gets, and the FIFO is still a FIFO
+  const regularPath = join(tempDir, "packed.regular");
+  await runCommand(CMPRS_BIN, ["--output", regularPath, binaryPath]);
+  const expected = Buffer.from(await Bun.file(regularPath).arrayBuffer());
+  expect(Buffer.from(await received).equals(expected)).toBe(true);
+  expect((await stat(fifoPath)).isFIFO()).toBe(true);
+
+  console.log("   ✓ Output arrived intact through the FIFO");
+});
+
+test.skipIf(platform() === "win32")("--dcmprs-extract into a FIFO leaves its mode alone", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const packedPath = join(tempDir, "extract-fifo.cmprs");
+  await runCommand(CMPRS_BIN, ["--output", packedPath, binaryPath]);
+  const fifoPath = join(tempDir, "extract.fifo");
+  expect((await runCommand("mkfifo", ["-m", "600", fifoPath])).exitCode).toBe(0);
+
+  const reader = spawn(["cat", fifoPath], { stdout: "pipe" });
+  const received = new Response(reader.stdout).arrayBuffer();
+  const extract = await runCommand(packedPath, ["--dcmprs-extract", fifoPath]);
+  expect(extract.exitCode).toBe(0);
+  await reader.exited;
+
+  // The program's bytes, but not its mode, which is the FIFO's own
+  expect(Buffer.from(await received).equals(Buffer.from(await Bun.file(binaryPath).arrayBuffer()))).toBe(true);
+  expect((await stat(fifoPath)).mode & 0o777).toBe(0o600);
+
+  console.log("   ✓ Extracted through the FIFO without chmodding it");
+});
+
+test("--json-stats breaks a bundle's payload down per file", async () => {
+  const dir = await mkdtemp(join(tempDir, "bundle-stats-"));
+  const toolDir = join(dir, "tool");
+  const entryPath = join(toolDir, "run.sh");
+  await Bun.write(entryPath, "#!/bin/sh\necho run\n");
+  await chmod(entryPath, 0o755);
+  const data = "the same line over and over\n".repeat(10000);
+  await Bun.write(join(toolDir, "data.txt"), data);
+
+  const compressedPath = join(dir, "tool.cmprs");
+  const pack = await runCommand(CMPRS_BIN, ["--entry", "run.sh", "--json-stats", "--output", compressedPath, toolDir]);
+  expect(pack.exitCode).toBe(0);
+
+  const stats = JSON.parse(pack.stdout);
+  const files = Object.fromEntries(stats.files.map((file) => [file.path, file]));
+  expect(Object.keys(files).sort()).toEqual(["data.txt", "run.sh"]);
+  expect(files["data.txt"].size).toBe(data.length);
+  expect(files["run.sh"].size).toBe(19);
+  expect(files["data.txt"].compressed_size).toBeGreaterThan(0);
+  expect(files["data.txt"].compressed_size).toBeLessThan(data.length / 10);
+  expect(files["data.txt"].compressed_size + files["run.sh"].compressed_size).toBe(stats.compressed_size);
+
+  const human = await runCommand(CMPRS_BIN, ["--entry", "run.sh", "--force", "--output", compressedPath, toolDir]);
+  expect(human.exitCode).toBe(0);
+  expect(human.stderr).toContain("data.txt from 280000 bytes");
+  expect(human.stderr).toContain("run.sh from 19 bytes");
+
+  console.log("   ✓ Per-file sizes reported for a two-file bundle");
+});
+
+test("A truncated binary fails with a length mismatch before decompressing", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = `${binaryPath}.truncated.cmprs`;
+  const pack = await runCommand(CMPRS_BIN, ["--output", compressedPath, binaryPath]);
+  expect(pack.exitCode).toBe(0);
+
+  const packed = new Uint8Array(await Bun.file(compressedPath).arrayBuffer());
+  await Bun.write(compressedPath, packed.subarray(0, packed.length - 100));
+  await chmod(compressedPath, 0o755);
+
+  const result = await runCommand(compressedPath, []);
+  expect(result.exitCode).toBe(5);
+  expect(result.stderr).toContain(
+    `binary appears truncated (expected ${packed.length} bytes, have ${packed.length - 100})`,
+  );
+  expect(result.stderr).not.toContain("failed to decompress");
+
+  const extract = await runCommand(CMPRS_BIN, ["extract", compressedPath, "--output", `${compressedPath}.out`]);
+  expect(extract.exitCode).toBe(5);
+  expect(extract.stderr).toContain("binary appears truncated");
+
+  console.log("   ✓ Truncated binary rejected up front");
+});
+
+test("watch packs the input again when it changes, including when it's renamed over", async () => {
+  const dir = await mkdtemp(join(tempDir, "watch-"));
+  const inputPath = join(dir, "app");
+  const writeVersion = async (path, version) => {
+    await Bun.write(path, `#!/bin/sh\necho ${version}\n`);
+    await chmod(path, 0o755);
+  };
+  await writeVersion(inputPath, "v1");
+  const outputPath = join(dir, "app.cmprs");
+  const watcher = spawn([CMPRS_BIN, "watch", inputPath, "-o", outputPath, "--debounce-ms", "100"], {
+    stdout: "pipe",
+    stderr: "pipe",
+  });
+
+  // Runs a copy, as running the packed file replaces it with the program
+  const runsAs = async version => {
+    for (let i = 0; i < 100; i++) {
+      if (await Bun.file(outputPath).exists()) {
+        const copyPath = join(dir, `run-${version}-${i}`);
+        await Bun.write(copyPath, Bun.file(outputPath));
+        await chmod(copyPath, 0o755);
+        const run = await runCommand(copyPath);
+        if (run.exitCode === 0 && run.stdout === version) {
+          return true;
+        }
+      }
+      await Bun.sleep(100);
+    }
+    return false;
+  };
+
+  try {
+    expect(await runsAs("v1")).toBe(true);
+    await writeVersion(inputPath, "v2");
+    expect(await runsAs("v2")).toBe(true);
+    // Written elsewhere and renamed over it, like editors and linkers do
+    await writeVersion(join(dir, "app.tmp"), "v3");
+    await rename(join(dir, "app.tmp"), inputPath);
+    expect(await runsAs("v3")).toBe(true);
+  } finally {
+    watcher.kill();
+  }
+  const stderr = await new Response(watcher.stderr).text();
+  expect(stderr).toContain("watching");
+  expect(stderr).toMatch(/packed .*app to .*app\.cmprs in [0-9.]+\S*s, 18 to [0-9]+ bytes/);
+
+  console.log("   ✓ Each change was packed to a runnable output");
+});
+
+test("--level is checked against each algorithm's range, and aliases map to each one's own", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const packWith = async (name, args) =>
+    runCommand(CMPRS_BIN, [...args, "--output", join(tempDir, `level-${name}.cmprs`), binaryPath]);
+
+  const rejected = [
+    [["--level", "23"], "zstd level must be between 1 and 22"],
+    [["--algorithm", "gzip", "--level", "22"], "gzip level must be between 1 and 9, got 22"],
+    [["--algorithm", "xz", "--level", "10"], "xz preset must be between 0 and 9, got 10"],
+    [["--level", "turbo"], "expected fast, default or best"],
+  ];
+  for (const [args, message] of rejected) {
+    const result = await packWith("rejected", args);
+    expect(result.exitCode).toBe(2);
+    expect(result.stderr).toContain(message);
+  }
+
+  const mapped = [
+    [["--level", "best"], "zstd level 19"],
+    [["--level", "fast"], "zstd level 1"],
+    [["--algorithm", "gzip", "--level", "best"], "gzip level 9"],
+    [["--algorithm", "gzip", "--level", "fast"], "gzip level 1"],
+    [["--algorithm", "xz", "--level", "best"], "xz level 9"],
+    [["--algorithm", "xz", "--level", "default"], "xz level 6"],
+    [["--algorithm", "none", "--level", "best"], "stored uncompressed"],
+  ];
+  for (const [args, setting] of mapped) {
+    const result = await packWith(args.join("-"), args);
+    expect(result.exitCode).toBe(0);
+    expect(result.stderr).toContain(setting);
+    const run = await runCommand(join(tempDir, `level-${args.join("-")}.cmprs`), ["--dcmprs-check"]);
+    expect(run.exitCode).toBe(0);
+  }
+
+  console.log("   ✓ Out-of-range levels were rejected per algorithm, aliases mapped");
+});
+
+test("--algorithm gzip writes a payload gzip tools read", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const compressedPath = join(tempDir, "gzipped.cmprs");
+  // --split leaves the bare gzip stream in gzipped.cmprs.data
+  const pack = await runCommand(CMPRS_BIN, [
+    "--algorithm", "gzip", "--split", "--no-auto-store", "--json-stats", "--output", compressedPath, binaryPath,
+  ]);
+  expect(pack.exitCode).toBe(0);
+  expect(JSON.parse(pack.stdout).algorithm).toBe("gzip");
+
+  const payload = new Uint8Array(await Bun.file(`${compressedPath}.data`).arrayBuffer());
+  const original = new Uint8Array(await Bun.file(binaryPath).arrayBuffer());
+  expect(Bun.gunzipSync(payload)).toEqual(original);
+
+  const result = await runCommand(compressedPath);
+  expect(result.stdout).toBe("hello world");
+
+  const badLevel = await runCommand(CMPRS_BIN, ["--algorithm", "gzip", "--level", "12", binaryPath]);
+  expect(badLevel.exitCode).toBe(2);
+
+  console.log("   ✓ gunzip read the payload and the launcher ran it");
+});
+
+test("DCMPRS_PRESERVE_MTIME=1 gives the extracted program the original's mtime", async () => {
+  const dir = await mkdtemp(join(tempDir, "mtime-"));
+  const scriptPath = join(dir, "own-mtime");
+  await Bun.write(scriptPath, '#!/bin/sh\ndate -r "$0" +%s\n');
+  await chmod(scriptPath, 0o755);
+  const mtime = 1_500_000_000;
+  await utimes(scriptPath, mtime, mtime);
+
+  const compressedPath = join(dir, "own-mtime.cmprs");
+  const pack = await runCommand(CMPRS_BIN, ["--output", compressedPath, scriptPath]);
+  expect(pack.exitCode).toBe(0);
+
+  const packed = await Bun.file(compressedPath).arrayBuffer();
+  const preserved = await runCommand(compressedPath, [], { env: { ...process.env, DCMPRS_PRESERVE_MTIME: "1" } });
+  expect(preserved.exitCode).toBe(0);
+  expect(Number(preserved.stdout)).toBe(mtime);
+
+  // Off by default: the temp file is as fresh as its extraction
+  await Bun.write(compressedPath, packed);
+  await chmod(compressedPath, 0o755);
+  const fresh = await runCommand(compressedPath);
+  expect(Number(fresh.stdout)).toBeGreaterThan(mtime);
+
+  console.log("   ✓ Temp file carried the embedded mtime under the flag");
+});
+
+test("A swapped stub is reported as a stub/payload mismatch", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const regularPath = join(tempDir, "stub-hash.cmprs");
+  const minimalPath = join(tempDir, "stub-hash-minimal.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--output", regularPath, binaryPath])).exitCode).toBe(0);
+  expect((await runCommand(CMPRS_BIN, ["--minimize-stub", "--output", minimalPath, binaryPath])).exitCode).toBe(0);
+
+  const info = await runCommand(regularPath, ["--dcmprs-info"]);
+  expect(info.stdout).toContain("stub: matches the one it was packed with");
+
+  // The minimal stub in front of the regular file's payload
+  const magic = "DCMPRS_DATA_HERE;;";
+  const regular = Buffer.from(await Bun.file(regularPath).arrayBuffer());
+  const minimal = Buffer.from(await Bun.file(minimalPath).arrayBuffer());
+  const swappedPath = join(tempDir, "stub-hash-swapped.cmprs");
+  await Bun.write(
+    swappedPath,
+    Buffer.concat([minimal.subarray(0, minimal.indexOf(magic)), regular.subarray(regular.indexOf(magic))]),
+  );
+  await chmod(swappedPath, 0o755);
+
+  const extract = await runCommand(CMPRS_BIN, ["extract", swappedPath, "--output", `${swappedPath}.out`]);
+  expect(extract.exitCode).toBe(0);
+  expect(extract.stderr).toContain("stub/payload mismatch");
+  const swappedInfo = await runCommand(swappedPath, ["--dcmprs-info"]);
+  expect(swappedInfo.stdout).toContain("stub: stub/payload mismatch");
+
+  const untouched = await runCommand(CMPRS_BIN, ["extract", regularPath, "--output", `${regularPath}.out`]);
+  expect(untouched.stderr).not.toContain("mismatch");
+
+  console.log("   ✓ Swapped stub flagged by extract and --dcmprs-info");
+});
+
+test("--comment stores a line strings finds after the stub, without changing the program", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const packedPath = join(tempDir, "comment.cmprs");
+  const comment = "test-binary 1.4.2 built 2026-10-15";
+  const pack = await runCommand(CMPRS_BIN, ["--comment", comment, "--output", packedPath, binaryPath]);
+  expect(pack.exitCode).toBe(0);
+
+  const packed = Buffer.from(await Bun.file(packedPath).arrayBuffer());
+  const magic = packed.indexOf("DCMPRS_DATA_HERE;;");
+  const line = packed.indexOf(`\n${comment}\n`);
+  expect(line).toBeGreaterThan(magic);
+  expect(line - magic).toBeLessThan(64);
+
+  const strings = await runCommand("strings", [packedPath]);
+  expect(strings.stdout.split("\n")).toContain(comment);
+
+  const extractedPath = join(tempDir, "comment.out");
+  const extract = await runCommand(CMPRS_BIN, ["extract", packedPath, "--output", extractedPath], {
+    env: { ...process.env, RUST_LOG: "info" },
+  });
+  expect(extract.exitCode).toBe(0);
+  expect(extract.stderr).toContain(`Comment: ${comment}`);
+  expect(Buffer.from(await Bun.file(extractedPath).arrayBuffer()).equals(
+    Buffer.from(await Bun.file(binaryPath).arrayBuffer()),
+  )).toBe(true);
+  const run = await runCommand(packedPath);
+  expect(run.exitCode).toBe(0);
+  expect(run.stdout).toBe("hello world");
+
+  const multiline = await runCommand(CMPRS_BIN, ["--comment", "two\nlines", "--output", join(tempDir, "comment-bad"), binaryPath]);
+  expect(multiline.exitCode).toBe(2);
+  expect(multiline.stderr).toContain("single line of printable ASCII");
+
+  console.log("   ✓ Comment found by strings, program extracted and ran unchanged");
+});
+
+test("--verify-stub refuses to run a file whose stub was tampered with", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const packedPath = join(tempDir, "verify-stub.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--verify-stub", "--output", packedPath, binaryPath])).exitCode).toBe(0);
+
+  const info = await runCommand(packedPath, ["--dcmprs-info"]);
+  expect(info.stdout).toContain("stub: matches the one it was packed with, checked before every run");
+
+  // One bit of a string in the stub, which doesn't stop it from running
+  const packed = Buffer.from(await Bun.file(packedPath).arrayBuffer());
+  const target = packed.indexOf(".dcmprs-verified");
+  expect(target).toBeGreaterThan(0);
+  expect(target).toBeLessThan(packed.indexOf("DCMPRS_DATA_HERE;;"));
+  const tampered = Buffer.from(packed);
+  tampered[target + 1] ^= 0x20;
+  const tamperedPath = join(tempDir, "verify-stub-tampered.cmprs");
+  await Bun.write(tamperedPath, tampered);
+  await chmod(tamperedPath, 0o755);
+
+  const run = await runCommand(tamperedPath);
+  expect(run.exitCode).toBe(5);
+  expect(run.stderr).toContain("stub/payload mismatch");
+  expect(run.stderr).toContain("refusing to run");
+
+  const intact = await runCommand(packedPath);
+  expect(intact.exitCode).toBe(0);
+  expect(intact.stdout).toBe("hello world");
+
+  const signed = await runCommand(CMPRS_BIN, ["--verify-stub", "--codesign", "-", "--output", join(tempDir, "verify-stub-signed"), binaryPath]);
+  expect(signed.exitCode).toBe(2);
+
+  console.log("   ✓ A flipped stub bit stopped the launch with exit code 5");
+});
+
+test("DCMPRS_EPHEMERAL=1 reuses a predictable temp path and leaves the packed file alone", async () => {
+  const dir = await mkdtemp(join(tempDir, "ephemeral-"));
+  const scriptPath = join(dir, "where-am-i");
+  await Bun.write(scriptPath, '#!/bin/sh\necho "$0"\n');
+  await chmod(scriptPath, 0o755);
+  const compressedPath = join(dir, "where-am-i.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--output", compressedPath, scriptPath])).exitCode).toBe(0);
+  const packedSize = (await stat(compressedPath)).size;
+
+  const env = { ...process.env, DCMPRS_EPHEMERAL: "1", TMPDIR: dir };
+  const first = await runCommand(compressedPath, [], { env });
+  expect(first.exitCode).toBe(0);
+  expect(first.stdout).toMatch(/dcmprs-[0-9a-f]{64}$/);
+  const extracted = (await stat(first.stdout)).mtimeMs;
+  // No self-overwrite: the packed file is still packed
+  expect((await stat(compressedPath)).size).toBe(packedSize);
+
+  const second = await runCommand(compressedPath, [], { env: { ...env, DCMPRS_LOG_LEVEL: "info" } });
+  expect(second.exitCode).toBe(0);
+  expect(second.stdout).toBe(first.stdout);
+  expect(second.stderr).toContain("extracted by an earlier run");
+  expect((await stat(first.stdout)).mtimeMs).toBe(extracted);
+  expect((await stat(compressedPath)).size).toBe(packedSize);
+
+  console.log("   ✓ Ran twice from the same extracted file");
+});
+
+test("DCMPRS_EPHEMERAL=1 reuses the extracted program after a restub", async () => {
+  const dir = await mkdtemp(join(tempDir, "ephemeral-restub-"));
+  const scriptPath = join(dir, "where-am-i");
+  await Bun.write(scriptPath, '#!/bin/sh\necho "$0"\n');
+  await chmod(scriptPath, 0o755);
+  const packedPath = join(dir, "where-am-i.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--output", packedPath, scriptPath])).exitCode).toBe(0);
+
+  const env = { ...process.env, DCMPRS_EPHEMERAL: "1", TMPDIR: dir };
+  const first = await runCommand(packedPath, [], { env });
+  expect(first.exitCode).toBe(0);
+  const extracted = (await stat(first.stdout)).mtimeMs;
+
+  // Another stub, and another level for good measure, in front of the same program
+  const restubbedPath = join(dir, "where-am-i.restubbed");
+  const repack = await runCommand(CMPRS_BIN, [
+    "repack", "--minimize-stub", "--level", "19", "--output", restubbedPath, packedPath,
+  ]);
+  expect(repack.exitCode).toBe(0);
+  expect(await Bun.file(restubbedPath).bytes()).not.toEqual(await Bun.file(packedPath).bytes());
+
+  const second = await runCommand(restubbedPath, [], { env });
+  expect(second.exitCode).toBe(0);
+  expect(second.stdout).toBe(first.stdout);
+  expect((await stat(first.stdout)).mtimeMs).toBe(extracted);
+
+  console.log("   ✓ Restubbed binary ran the program the first one extracted");
+});
+
+test("DCMPRS_INSTALL_DIR installs the program on the first run and runs that copy after", async () => {
+  const dir = await mkdtemp(join(tempDir, "install-dir-"));
+  const scriptPath = join(dir, "where-am-i");
+  await Bun.write(scriptPath, '#!/bin/sh\necho "$0 $*"\n');
+  await chmod(scriptPath, 0o755);
+  const packedPath = join(dir, "where-am-i.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--output", packedPath, scriptPath])).exitCode).toBe(0);
+
+  const installDir = join(dir, "installed");
+  const env = { ...process.env, DCMPRS_INSTALL_DIR: installDir, DCMPRS_LOG_LEVEL: "info" };
+  const first = await runCommand(packedPath, ["first"], { env });
+  expect(first.exitCode).toBe(0);
+  expect(first.stderr).toContain("Decompressed");
+  expect(first.stderr).toContain("Installed the program to");
+  const [installedPath] = first.stdout.split(" ");
+  expect(installedPath.startsWith(join(installDir, ""))).toBe(true);
+  expect(basename(installedPath)).toBe("where-am-i");
+  const installed = (await stat(installedPath)).mtimeMs;
+
+  // The packed file stays, and runs the installed copy without decompressing
+  expect(await Bun.file(packedPath).size).toBeGreaterThan(1000);
+  const second = await runCommand(packedPath, ["second"], { env });
+  expect(second.exitCode).toBe(0);
+  expect(second.stdout).toBe(`${installedPath} second`);
+  expect(second.stderr).toContain("installed by an earlier run");
+  expect(second.stderr).not.toContain("Decompressed");
+  expect((await stat(installedPath)).mtimeMs).toBe(installed);
+
+  // A copy others can write to isn't run, but installed over
+  await chmod(installedPath, 0o777);
+  const tampered = await runCommand(packedPath, ["third"], { env });
+  expect(tampered.exitCode).toBe(0);
+  expect(tampered.stderr).toContain("installing over it");
+  expect((await stat(installedPath)).mode & 0o022).toBe(0);
+
+  console.log("   ✓ Second run exec'd the installed copy without decompressing");
+});
+
+test("Negative levels use zstd's fast mode, round-tripping faster than level 1", async () => {
+  const dir = await mkdtemp(join(tempDir, "fast-"));
+  const inputPath = join(dir, "large.txt");
+  const lines = [];
+  for (let i = 0; i < 800_000; i++) {
+    lines.push(`line ${i} has value ${(i * 7919) % 104729} and ${i % 97}\n`);
+  }
+  await Bun.write(inputPath, lines.join(""));
+
+  // The best of a few runs each, to keep a busy machine from deciding it
+  const pack = async (level) => {
+    const runs = [];
+    for (let i = 0; i < 3; i++) {
+      const outputPath = join(dir, `large.${level}.${i}.cmprs`);
+      const result = await runCommand(CMPRS_BIN, [
+        "--level", String(level), "--no-auto-store", "--json-stats", "--output", outputPath, inputPath,
+      ]);
+      expect(result.exitCode).toBe(0);
+      runs.push({ outputPath, stderr: result.stderr, stats: JSON.parse(result.stdout) });
+    }
+    return runs;
+  };
+  const fast = await pack(-5);
+  const regular = await pack(1);
+  const fastest = (runs) => Math.min(...runs.map((run) => run.stats.timings_ms.compress));
+
+  expect(fast[0].stats.level).toBe(-5);
+  expect(fast[0].stderr).toContain("fast mode");
+  expect(fast[0].stats.compressed_size).toBeGreaterThan(regular[0].stats.compressed_size);
+  expect(fastest(fast)).toBeLessThan(fastest(regular));
+
+  const extractedPath = join(dir, "large.extracted");
+  const extract = await runCommand(CMPRS_BIN, ["extract", fast[0].outputPath, "--output", extractedPath]);
+  expect(extract.exitCode).toBe(0);
+  expect(await Bun.file(extractedPath).text()).toBe(await Bun.file(inputPath).text());
+
+  const tooFast = await runCommand(CMPRS_BIN, ["--level", "-23", inputPath]);
+  expect(tooFast.exitCode).toBe(2);
+
+  console.log("   ✓ Level -5 packed faster and extracted intact");
+});
+
+test("--env-file embeds variables the runtime environment can override", async () => {
+  const dir = await mkdtemp(join(tempDir, "env-file-"));
+  const scriptPath = join(dir, "show-env");
+  await Bun.write(scriptPath, '#!/bin/sh\nprintf "%s|%s|%s\\n" "$GREETING" "$TARGET" "$LITERAL"\n');
+  await chmod(scriptPath, 0o755);
+  const envPath = join(dir, "defaults.env");
+  await Bun.write(envPath, [
+    "# packed defaults",
+    "export GREETING=hello # trailing comment",
+    'TARGET="the world"',
+    "LITERAL='$HOME stays as is'",
+    "",
+  ].join("\n"));
+
+  const packedPath = join(dir, "show-env.cmprs");
+  const pack = await runCommand(CMPRS_BIN, ["--env-file", envPath, "--output", packedPath, scriptPath]);
+  expect(pack.exitCode).toBe(0);
+
+  const { GREETING, TARGET, LITERAL, ...env } = process.env;
+  // Twice, since the packed file has to stay packed to keep setting them
+  for (let i = 0; i < 2; i++) {
+    const result = await runCommand(packedPath, [], { env });
+    expect(result.exitCode).toBe(0);
+    expect(result.stdout).toBe("hello|the world|$HOME stays as is");
+  }
+  const overridden = await runCommand(packedPath, [], { env: { ...env, TARGET: "runtime" } });
+  expect(overridden.stdout).toBe("hello|runtime|$HOME stays as is");
+
+  await Bun.write(envPath, "NOT A VAR=1\n");
+  const invalid = await runCommand(CMPRS_BIN, ["--env-file", envPath, "--output", join(dir, "invalid"), scriptPath]);
+  expect(invalid.exitCode).toBe(2);
+  expect(invalid.stderr).toContain("defaults.env:1");
+
+  console.log("   ✓ Packed variables reached the program, and runtime ones won");
+});
+
+test("--argfiles expands @file arguments into the lines of the file", async () => {
+  const dir = await mkdtemp(join(tempDir, "argfiles-"));
+  const scriptPath = join(dir, "show-args");
+  await Bun.write(scriptPath, '#!/bin/sh\nfor arg in "$@"; do echo "[$arg]"; done\n');
+  await chmod(scriptPath, 0o755);
+  const argsPath = join(dir, "args.txt");
+  await Bun.write(argsPath, "one two\r\n--flag=x\n");
+
+  const packedPath = join(dir, "show-args.cmprs");
+  const pack = await runCommand(CMPRS_BIN, ["--argfiles", "--output", packedPath, scriptPath]);
+  expect(pack.exitCode).toBe(0);
+
+  // Twice, since the packed file has to stay packed to keep expanding them
+  for (let i = 0; i < 2; i++) {
+    const result = await runCommand(packedPath, ["first", `@${argsPath}`, "@missing.txt"]);
+    expect(result.exitCode).toBe(0);
+    expect(result.stdout).toBe("[first]\n[one two]\n[--flag=x]\n[@missing.txt]");
+  }
+
+  const plainPath = join(dir, "plain.cmprs");
+  await runCommand(CMPRS_BIN, ["--output", plainPath, scriptPath]);
+  const plain = await runCommand(plainPath, [`@${argsPath}`]);
+  expect(plain.stdout).toBe(`[@${argsPath}]`);
+
+  console.log("   ✓ @file arguments were expanded only with --argfiles");
+});
+
+test("--emit-checksum writes the packed file's SHA256 for sha256sum -c", async () => {
+  const dir = await mkdtemp(join(tempDir, "checksum-"));
+  const scriptPath = join(dir, "tool");
+  await Bun.write(scriptPath, '#!/bin/sh\necho checked\n');
+  await chmod(scriptPath, 0o755);
+  const packedPath = join(dir, "tool.cmprs");
+  const pack = await runCommand(CMPRS_BIN, ["--emit-checksum", "--output", packedPath, scriptPath]);
+  expect(pack.exitCode).toBe(0);
+
+  const sidecar = await Bun.file(`${packedPath}.sha256`).text();
+  const sha256sum = await runCommand("sha256sum", ["tool.cmprs"], { cwd: dir });
+  expect(sidecar.trim()).toBe(sha256sum.stdout);
+  // The packed file's hash, not the payload's that the header records
+  const info = await runCommand(packedPath, ["--dcmprs-info"]);
+  expect(info.stdout).not.toContain(sidecar.split(" ")[0]);
+  expect((await runCommand("sha256sum", ["-c", "tool.cmprs.sha256"], { cwd: dir })).exitCode).toBe(0);
+
+  const toDevice = await runCommand(CMPRS_BIN, ["--emit-checksum", "--output", "/dev/null", scriptPath]);
+  expect(toDevice.exitCode).toBe(2);
+
+  console.log("   ✓ Sidecar checksum matched sha256sum of the output");
+});
+
+test("--exclude leaves matching files and directories out of a bundle", async () => {
+  const dir = await mkdtemp(join(tempDir, "exclude-"));
+  const toolDir = join(dir, "project");
+  const files = ["run.sh", "src/main.c", ".git/HEAD", ".git/objects/ab", "target/debug/main.o", "lib/util.o", "lib/util.c"];
+  for (const file of files) {
+    await Bun.write(join(toolDir, file), file);
+  }
+  await chmod(join(toolDir, "run.sh"), 0o755);
+
+  const compressedPath = join(dir, "project.cmprs");
+  const pack = await runCommand(CMPRS_BIN, [
+    "--entry", "run.sh", "--exclude", ".git", "--exclude", "*.o", "--exclude", "target/debug",
+    "--json-stats", "--output", compressedPath, toolDir,
+  ]);
+  expect(pack.exitCode).toBe(0);
+  const bundled = JSON.parse(pack.stdout).files.map((file) => file.path);
+  expect(bundled).toEqual(["lib/util.c", "run.sh", "src/main.c"]);
+  expect(pack.stderr).toContain("bundling 3 files");
+  expect(pack.stderr).toContain("excluded 3 files and directories");
+
+  const extractedDir = join(dir, "extracted");
+  expect((await runCommand(CMPRS_BIN, ["extract", compressedPath, "--output", extractedDir])).exitCode).toBe(0);
+  expect(await Bun.file(join(extractedDir, ".git", "HEAD")).exists()).toBe(false);
+  expect(await Bun.file(join(extractedDir, "lib", "util.c")).text()).toBe("lib/util.c");
+
+  const notADirectory = await runCommand(CMPRS_BIN, ["--exclude", "*.o", join(toolDir, "run.sh")]);
+  expect(notADirectory.exitCode).toBe(2);
+
+  console.log("   ✓ Excluded paths were left out of the bundle");
+});
+
+test("A bundle packed with --subcommand dispatches on its first argument", async () => {
+  const dir = await mkdtemp(join(tempDir, "subcommands-"));
+  const toolDir = join(dir, "multi");
+  for (const name of ["build", "test", "help"]) {
+    const path = join(toolDir, "bin", name);
+    await Bun.write(path, `#!/bin/sh\necho "${name}: $*"\n`);
+    await chmod(path, 0o755);
+  }
+
+  const compressedPath = join(dir, "multi.cmprs");
+  const pack = await runCommand(CMPRS_BIN, [
+    "--subcommand", "build=bin/build", "--subcommand", "test=bin/test", "--output", compressedPath, toolDir,
+  ]);
+  expect(pack.exitCode).toBe(0);
+
+  const build = await runCommand(compressedPath, ["build", "--release", "a b"]);
+  expect(build.exitCode).toBe(0);
+  expect(build.stdout).toBe("build: --release a b");
+  const test = await runCommand(compressedPath, ["test", "build"]);
+  expect(test.exitCode).toBe(0);
+  expect(test.stdout).toBe("test: build");
+
+  // Without an --entry to fall back to, anything else prints the subcommands
+  const unknown = await runCommand(compressedPath, ["deploy"]);
+  expect(unknown.exitCode).toBe(2);
+  expect(unknown.stderr).toContain("usage: multi.cmprs <command>");
+  expect(unknown.stderr).toContain("one of: build, test");
+  expect((await runCommand(compressedPath)).exitCode).toBe(2);
+
+  const withEntryPath = join(dir, "multi-entry.cmprs");
+  const withEntry = await runCommand(CMPRS_BIN, [
+    "--subcommand", "build=bin/build", "--entry", "bin/help", "--output", withEntryPath, toolDir,
+  ]);
+  expect(withEntry.exitCode).toBe(0);
+  expect((await runCommand(withEntryPath, ["deploy", "now"])).stdout).toBe("help: deploy now");
+  expect((await runCommand(withEntryPath, ["build", "now"])).stdout).toBe("build: now");
+
+  const duplicate = await runCommand(CMPRS_BIN, [
+    "--subcommand", "a=bin/build", "--subcommand", "a=bin/test", "--output", join(dir, "dup"), toolDir,
+  ]);
+  expect(duplicate.exitCode).toBe(2);
+  expect(duplicate.stderr).toContain("more than once");
+
+  console.log("   ✓ Subcommands ran their own programs with the remaining arguments");
+});
+
+// The smallest WASI command: a module exporting an empty _start
+const TRIVIAL_WASM = new Uint8Array([
+  0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // magic, version
+  0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type section: () -> ()
+  0x03, 0x02, 0x01, 0x00, // function section
+  0x07, 0x0a, 0x01, 0x06, 0x5f, 0x73, 0x74, 0x61, 0x72, 0x74, 0x00, 0x00, // export "_start"
+  0x0a, 0x04, 0x01, 0x02, 0x00, 0x0b, // code section: empty body
+]);
+
+test("A #! script runs through its interpreter with its arguments", async () => {
+  const dir = await mkdtemp(join(tempDir, "script-"));
+  const scriptPath = join(dir, "greet");
+  // Not executable, and long enough to be compressed rather than stored
+  await Bun.write(
+    scriptPath,
+    `#!/bin/sh -e\nprintf '%s|' "$@"\necho "opts:$-"\n# ${"padding ".repeat(30)}\n`,
+  );
+  await chmod(scriptPath, 0o644);
+  const packedPath = join(dir, "greet.cmprs");
+  const pack = await runCommand(CMPRS_BIN, ["--require-executable", "--output", packedPath, scriptPath]);
+  expect(pack.exitCode).toBe(0);
+  expect(pack.stderr).not.toContain("dynamically linked");
+
+  const info = await runCommand(packedPath, ["--dcmprs-info"]);
+  expect(info.stdout).toContain("content type: script");
+  expect(info.stdout).toContain("interpreter: /bin/sh -e");
+
+  const result = await runCommand(packedPath, ["three", "--four"]);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("three|--four|opts:e");
+
+  // Without the exec bit on the temp file, dcmprs runs the interpreter itself.
+  // The first run replaced the packed file with the script, so pack it again.
+  expect((await runCommand(CMPRS_BIN, ["--output", packedPath, scriptPath])).exitCode).toBe(0);
+  const env = { ...process.env, DCMPRS_TEMP_MODE: "0600", DCMPRS_LOG_LEVEL: "info" };
+  const noExec = await runCommand(packedPath, ["one", "two words"], { env });
+  expect(noExec.exitCode).toBe(0);
+  expect(noExec.stdout).toBe("one|two words|opts:e");
+  expect(noExec.stderr).toContain("running it through");
+
+  const missingPath = join(dir, "missing");
+  await Bun.write(missingPath, `#!/nonexistent/interpreter\n# ${"padding ".repeat(30)}\n`);
+  const missingPacked = join(dir, "missing.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--output", missingPacked, missingPath])).exitCode).toBe(0);
+  const missing = await runCommand(missingPacked);
+  expect(missing.exitCode).toBe(127);
+  expect(missing.stderr).toContain("/nonexistent/interpreter, which isn't installed");
+
+  console.log("   ✓ Script ran through /bin/sh -e with its arguments forwarded");
+});
+
+test("WebAssembly modules need a --launcher and run through it as a .wasm file", async () => {
+  const wasmPath = join(tempDir, "trivial.wasm");
+  await Bun.write(wasmPath, TRIVIAL_WASM);
+
+  const noLauncher = await runCommand(CMPRS_BIN, [wasmPath]);
+  expect(noLauncher.exitCode).toBe(2);
+  expect(noLauncher.stderr).toContain("--launcher wasmtime");
+
+  const notWasmPath = join(tempDir, "not-really.wasm");
+  await Bun.write(notWasmPath, "plain text");
+  const notWasm = await runCommand(CMPRS_BIN, ["--launcher", "wasmtime", notWasmPath]);
+  expect(notWasm.exitCode).toBe(2);
+  expect(notWasm.stderr).toContain("isn't a WebAssembly module");
+
+  // A stand-in runtime that shows what it was given
+  const runtimePath = join(tempDir, "fake-wasm-runtime");
+  await Bun.write(runtimePath, '#!/bin/sh\ncase "$1" in *.wasm) echo "module $(head -c 4 "$1" | tail -c 3)";; esac\nshift\necho "args $*"\n');
+  await chmod(runtimePath, 0o755);
+  const packedPath = join(tempDir, "trivial-fake.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--launcher", runtimePath, "--output", packedPath, wasmPath])).exitCode).toBe(0);
+
+  const result = await runCommand(packedPath, ["--flag", "two words"]);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("module asm\nargs --flag two words");
+
+  const missingPath = join(tempDir, "trivial-missing.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--launcher", "no-such-wasm-runtime", "--output", missingPath, wasmPath])).exitCode).toBe(0);
+  const missing = await runCommand(missingPath);
+  expect(missing.exitCode).toBe(127);
+  expect(missing.stderr).toContain("install no-such-wasm-runtime");
+
+  console.log("   ✓ WebAssembly module ran through its runtime with arguments forwarded");
+});
+
+test.skipIf(!Bun.which("wasmtime"))("A packed WebAssembly module runs under wasmtime", async () => {
+  const wasmPath = join(tempDir, "trivial-wasmtime.wasm");
+  await Bun.write(wasmPath, TRIVIAL_WASM);
+  const packedPath = join(tempDir, "trivial-wasmtime.cmprs");
+  expect((await runCommand(CMPRS_BIN, ["--launcher", "wasmtime", "--output", packedPath, wasmPath])).exitCode).toBe(0);
+
+  for (let i = 0; i < 2; i++) {
+    const result = await runCommand(packedPath, ["ignored-arg"]);
+    expect(result.exitCode).toBe(0);
+  }
+
+  console.log("   ✓ wasmtime ran the packed module, which stays packed");
+});
+
+test("Unknown --stub-target fails with the list of embedded stubs", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+
+  const result = await runCommand(CMPRS_BIN, [
+    "--stub-target", "riscv64-unknown-none-elf",
+    "--output", `${binaryPath}.riscv.cmprs`,
+    binaryPath,
+  ]);
+
+  expect(result.exitCode).not.toBe(0);
+  expect(result.stderr).toContain("no dcmprs stub was embedded for target riscv64-unknown-none-elf");
+  expect(result.stderr).toContain("available:");
+
+  console.log("   ✓ Missing stub target reported clearly");
+});
+
+test.skipIf(platform() !== "linux")("32-bit programs run under the 64-bit stub, other machines are refused", async () => {
+  const sourcePath = join(tempDir, "hello32.c");
+  await Bun.write(sourcePath, '#include <stdio.h>\nint main(void) { puts("hello from 32 bits"); return 0; }\n');
+  const program32 = join(tempDir, "hello32");
+  const compile = await runCommand("cc", ["-m32", "-o", program32, sourcePath]);
+  if (compile.exitCode === 0) {
+    const packedPath = `${program32}.cmprs`;
+    const pack = await runCommand(CMPRS_BIN, ["--output", packedPath, program32]);
+    expect(pack.exitCode).toBe(0);
+    expect(pack.stderr).toContain("is a 32-bit");
+    const run = await runCommand(packedPath);
+    expect(run.exitCode).toBe(0);
+    expect(run.stdout).toBe("hello from 32 bits");
+  } else {
+    console.log("   (no multilib toolchain, skipping the 32-bit run)");
+  }
+
+  // An s390 program, which no stub built here can start
+  const binaryPath = await createTestBinary(tempDir);
+  const foreignPath = join(tempDir, "foreign-machine");
+  const foreign = new Uint8Array(await Bun.file(binaryPath).arrayBuffer());
+  foreign[18] = 22;
+  foreign[19] = 0;
+  await Bun.write(foreignPath, foreign);
+  await chmod(foreignPath, 0o755);
+  const refused = await runCommand(CMPRS_BIN, ["--output", `${foreignPath}.cmprs`, foreignPath]);
+  expect(refused.exitCode).toBe(2);
+  expect(refused.stderr).toContain("but the stub is built for");
+
+  console.log("   ✓ Stub and program machines checked");
+});
+
+test.skipIf(platform() !== "darwin")("Build macOS universal binary with --build-universal-macos", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const universalPath = `${binaryPath}.universal.cmprs`;
+  
+  // Compress the binary with --build-universal-macos flag
+  const compressResult = await runCommand(CMPRS_BIN, [
+    "--build-universal-macos",
+    "--output", universalPath,
+    binaryPath,
+  ]);
+  
+  expect(compressResult.exitCode).toBe(0);
+  
+  // Check that universal binary file exists and is executable
+  const universalStat = await stat(universalPath);
+  expect(universalStat.mode & 0o111).toBeTruthy();
+  
+  console.log("   ✓ Universal binary created successfully");
+  console.log(`   ✓ Universal binary is executable`);
+  
+  // Test that the universal binary works
+  const result = await runCommand(universalPath);
+  expect(result.exitCode).toBe(0);
+  expect(result.stdout).toBe("hello world");
+  
+  console.log("   ✓ Universal binary executed successfully");
+  console.log("   ✓ Output matches original");
+  
+  // Check if it's actually a universal binary using the `file` command
+  const fileResult = await runCommand("file", [universalPath]);
+  expect(fileResult.exitCode).toBe(0);
+  
+  // Universal binaries should contain multiple architectures
+  if (fileResult.stdout.includes("universal") || fileResult.stdout.includes("fat")) {
+    console.log("   ✓ Confirmed as universal/fat binary");
+  } else {
+    console.log(`   ℹ️  File type: ${fileResult.stdout}`);
+  }
+});
+test.skipIf(platform() !== "darwin")("--stub-variant prepends the matching thin or fat stub", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const expectations = {
+    arm64: ["arm64"],
+    x86_64: ["x86_64"],
+    universal: ["arm64", "x86_64"],
+  };
+
+  for (const [variant, architectures] of Object.entries(expectations)) {
+    const outputPath = `${binaryPath}.${variant}.cmprs`;
+    const result = await runCommand(CMPRS_BIN, ["--stub-variant", variant, "--output", outputPath, binaryPath]);
+    expect(result.exitCode).toBe(0);
+
+    // lipo only looks at the Mach-O stub at the start of the file
+    const archs = await runCommand("lipo", ["-archs", outputPath]);
+    expect(archs.stdout.split(" ").sort()).toEqual(architectures);
+    console.log(`   ✓ ${variant}: ${archs.stdout}`);
+  }
+});
+
+test.skipIf(platform() === "darwin")("--stub-variant fails when the variant wasn't embedded", async () => {
+  const binaryPath = await createTestBinary(tempDir);
+  const outputPath = `${binaryPath}.variant.cmprs`;
+
+  const result = await runCommand(CMPRS_BIN, ["--stub-variant", "arm64", "--output", outputPath, binaryPath]);
+  expect(result.exitCode).not.toBe(0);
+  expect(result.stderr).toContain("no arm64 dcmprs stub was embedded");
+  expect(await Bun.file(outputPath).exists()).toBe(false);
+
+  console.log("   ✓ Missing variant reported");
+});